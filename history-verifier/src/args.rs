@@ -1,9 +1,61 @@
 use std::path::PathBuf;
 use clap::Parser;
+use time::OffsetDateTime;
+use crate::window::parse_timestamp;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    /// Emit detected conflicts as a JSON array instead of colored text. Other reports go to stderr
+    #[arg(long, default_value = "false")]
+    pub json: bool,
+    /// Write the conflict graph to the given path as a Graphviz DOT digraph
+    #[arg(long)]
+    pub dot: Option<PathBuf>,
+    /// Only analyze transactions that were still running at or after this ISO-8601 timestamp
+    #[arg(long, value_parser = parse_timestamp)]
+    pub since: Option<OffsetDateTime>,
+    /// Only analyze transactions that started at or before this ISO-8601 timestamp
+    #[arg(long, value_parser = parse_timestamp)]
+    pub until: Option<OffsetDateTime>,
+    /// Report how long each transaction ran, longest first
+    #[arg(long, default_value = "false")]
+    pub durations: bool,
+    /// Transactions that run longer than this many milliseconds are flagged as long-running
+    #[arg(long, default_value = "1000")]
+    pub long_transaction_ms: i64,
+    /// Print a breakdown of the history: how many conflicts of each kind there were, transactions per site,
+    /// how many of each kind of action there were, read-only versus read-write transactions, and the most
+    /// contended tables
+    #[arg(long, default_value = "false")]
+    pub stats: bool,
+    /// Report how long each phase took as JSON on stderr
+    #[arg(long, default_value = "false")]
+    pub bench: bool,
+    /// Treat each replication as its originating transaction committing at the site it was applied at, so
+    /// only actions at the same site conflict
+    #[arg(long, default_value = "false")]
+    pub replication_as_commit: bool,
+    /// Print the serial transaction order the history is equivalent to when it's conflict free
+    #[arg(long, default_value = "false")]
+    pub show_serial: bool,
+    /// Print a serial order equivalent to the history, if there is one
+    #[arg(long, default_value = "false")]
+    pub serial_view: bool,
+    /// Keep each transaction's actions together in the serial order
+    #[arg(long, default_value = "false")]
+    pub atomic_transactions: bool,
+    /// How many milliseconds the clocks at different sites may disagree by. Actions logged this close
+    /// together are treated as possibly concurrent, so conflicts between them are found whichever order
+    /// their timestamps put them in
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(i64).range(0..))]
+    pub clock_skew_ms: i64,
+    /// Write the organized transactions and their actions to the given path as JSON
+    #[arg(long)]
+    pub dump_map: Option<PathBuf>,
+    /// JSON file with `action_line` and `replication_line` regexes describing the history line format
+    #[arg(long)]
+    pub line_format: Option<PathBuf>,
     /// Path to the file that contains histories
     pub history_file_paths: Vec<PathBuf>
 }
\ No newline at end of file
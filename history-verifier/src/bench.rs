@@ -0,0 +1,126 @@
+use std::io::BufRead;
+use std::time::{Duration, Instant};
+use log::{debug, info};
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use time::OffsetDateTime;
+use crate::history_file_parser::action::Action;
+use crate::history_file_parser::ActionParser;
+use crate::organize::AssociatedActionMap;
+use crate::verify::{build_conflict_graph, ConflictDiagnosis, ConflictGraph, verify_conflict_graph};
+use crate::window::filter_to_window;
+
+/// How long each phase of verification took
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PhaseTimings {
+    pub parse: Duration,
+    pub sort: Duration,
+    pub association: Duration,
+    pub verification: Duration,
+}
+
+impl PhaseTimings {
+    pub fn total(&self) -> Duration {
+        self.parse + self.sort + self.association + self.verification
+    }
+}
+
+impl Serialize for PhaseTimings {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("PhaseTimings", 5)?;
+        state.serialize_field("parse_ms", &(self.parse.as_secs_f64() * 1000f64))?;
+        state.serialize_field("sort_ms", &(self.sort.as_secs_f64() * 1000f64))?;
+        state.serialize_field("association_ms", &(self.association.as_secs_f64() * 1000f64))?;
+        state.serialize_field("verification_ms", &(self.verification.as_secs_f64() * 1000f64))?;
+        state.serialize_field("total_ms", &(self.total().as_secs_f64() * 1000f64))?;
+        state.end()
+    }
+}
+
+/// Runs a phase, adding how long it took to the given duration
+pub fn timed<ResultT>(duration: &mut Duration, phase: impl FnOnce() -> ResultT) -> ResultT {
+    let start = Instant::now();
+    let result = phase();
+    *duration += start.elapsed();
+    result
+}
+
+/// Parses every history, then sorts and associates their actions, timing each phase. Actions outside of
+/// the window between `since` and `until` are dropped before they're sorted
+pub fn associate_histories<SourceT: BufRead>(parsers: Vec<ActionParser<SourceT>>, since: Option<OffsetDateTime>, until: Option<OffsetDateTime>, clock_skew: time::Duration, timings: &mut PhaseTimings) -> AssociatedActionMap {
+    let file_count = parsers.len();
+    let mut actions: Vec<Action> = timed(&mut timings.parse, || parsers.into_iter()
+        .flat_map(|mut parser| std::iter::from_fn(move || parser.parse_next()))
+        .inspect(|action| debug!("Parsed action {:?}", action))
+        .collect());
+    info!("Parsed {} items from {} files", actions.len(), file_count);
+
+    if since.is_some() || until.is_some() {
+        actions = filter_to_window(actions, since, until);
+        info!("Kept {} items within the time window", actions.len());
+    }
+
+    info!("Sorting actions chronologically...");
+    timed(&mut timings.sort, || actions.sort_by_key(|action| action.instant));
+
+    info!("Associating actions...");
+    let associated_actions = timed(&mut timings.association, || AssociatedActionMap::new()
+        .with_clock_skew(clock_skew)
+        .build(actions));
+    info!("Associated actions!");
+    associated_actions
+}
+
+/// Builds the conflict graph of the associated actions and checks it for conflicts, timing both as the
+/// verification phase
+pub fn verify_histories<'actions>(associated_actions: &'actions AssociatedActionMap, replication_as_commit: bool, timings: &mut PhaseTimings) -> (ConflictGraph<'actions>, Result<(), Vec<ConflictDiagnosis<'actions>>>) {
+    info!("Verifying chronological actions...");
+    let conflict_graph = timed(&mut timings.verification, || build_conflict_graph(associated_actions, replication_as_commit));
+    let verification = timed(&mut timings.verification, || verify_conflict_graph(&conflict_graph, associated_actions));
+    (conflict_graph, verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+    use crate::bench::{associate_histories, PhaseTimings, verify_histories};
+    use crate::history_file_parser::ActionParser;
+
+    /// Makes a history of many short transactions on a handful of tables
+    fn make_synthetic_history(transaction_count: u32) -> String {
+        let mut history = String::new();
+        let mut second = 0u32;
+        let mut line = |txn: u32, action: &str| {
+            history.push_str(&format!("2023-12-01T{:02}:{:02}:{:02}.000000000Z | site=1, client={}, txn={}: {}\n",
+                                      second / 3600, (second / 60) % 60, second % 60, txn, txn, action));
+            second += 1;
+        };
+
+        for txn in 0..transaction_count {
+            let table = format!("table_{}", txn % 5);
+            line(txn, "Begin Txn");
+            line(txn, &format!("Read([\"{}\"])", table));
+            line(txn, &format!("Write([\"{}\"])", table));
+            line(txn, "COMMIT");
+        }
+
+        history
+    }
+
+    #[test]
+    fn phases_report_nonzero_durations() {
+        let history = make_synthetic_history(5_000);
+        let mut timings = PhaseTimings::default();
+        let parsers = vec![ActionParser::<Cursor<&str>>::new(Cursor::new(history.as_str()))];
+        let associated_actions = associate_histories(parsers, None, None, time::Duration::ZERO, &mut timings);
+        let (_, verification) = verify_histories(&associated_actions, false, &mut timings);
+        assert!(verification.is_ok());
+
+        assert!(timings.parse > Duration::ZERO);
+        assert!(timings.sort > Duration::ZERO);
+        assert!(timings.association > Duration::ZERO);
+        assert!(timings.verification > Duration::ZERO);
+        assert_eq!(timings.total(), timings.parse + timings.sort + timings.association + timings.verification);
+    }
+}
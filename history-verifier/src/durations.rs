@@ -0,0 +1,104 @@
+use std::fmt::{Display, Formatter};
+use colored::Colorize;
+use time::{Duration, OffsetDateTime};
+use crate::history_file_parser::action::ActionKind;
+use crate::organize::AssociatedActionMap;
+use crate::transaction_id::TransactionId;
+
+/// How long a single transaction ran, from its begin to its commit or rollback
+#[derive(Debug, PartialEq)]
+pub struct TransactionDuration {
+    pub transaction_id: TransactionId,
+    pub begin: OffsetDateTime,
+    pub end: OffsetDateTime,
+    /// true if the transaction ran for longer than the threshold
+    pub long_running: bool,
+}
+
+impl TransactionDuration {
+    pub fn duration(&self) -> Duration {
+        self.end - self.begin
+    }
+}
+
+impl Display for TransactionDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let line = format!("{} ran for {}", self.transaction_id, self.duration());
+        if self.long_running {
+            write!(f, "{} {}", line.yellow(), "(long-running)".yellow().bold())
+        } else {
+            write!(f, "{}", line)
+        }
+    }
+}
+
+/// Finds how long each transaction ran, longest first. Transactions that never finished and replications
+/// are skipped since they don't have a duration. Transactions that ran longer than `threshold` are flagged,
+/// as they are likely holding locks that other transactions are waiting on
+pub fn transaction_durations(associated_action_map: &AssociatedActionMap, threshold: Duration) -> Vec<TransactionDuration> {
+    let mut durations = associated_action_map.get_all_transaction_ids().into_iter()
+        .filter(|transaction_id| !transaction_id.is_replication())
+        .filter_map(|transaction_id| {
+            let actions = associated_action_map.borrow_transaction(&transaction_id)?;
+            let begin = actions.iter()
+                .find(|action| action.action == ActionKind::BeginTransaction)?
+                .instant;
+            let end = actions.iter()
+                .find(|action| matches!(action.action, ActionKind::CommitTransaction | ActionKind::RollbackTransaction))?
+                .instant;
+
+            Some(TransactionDuration {
+                transaction_id,
+                begin,
+                end,
+                long_running: end - begin > threshold,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    durations.sort_by(|left, right| right.duration().cmp(&left.duration())
+        .then_with(|| left.transaction_id.cmp(&right.transaction_id)));
+    durations
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use time::{Duration, OffsetDateTime};
+    use crate::durations::transaction_durations;
+    use crate::history_file_parser::action::{ActionKind, make_action_at};
+    use crate::organize::AssociatedActionMap;
+    use crate::transaction_id::TransactionId;
+
+    fn at(millis: i64) -> OffsetDateTime {
+        OffsetDateTime::UNIX_EPOCH + Duration::milliseconds(millis)
+    }
+
+    #[test]
+    fn durations_match_timestamps() {
+        let actions = vec![
+            make_action_at(at(0), 1, ActionKind::BeginTransaction),
+            make_action_at(at(100), 2, ActionKind::BeginTransaction),
+            make_action_at(at(150), 1, ActionKind::Query { read_set: HashSet::from([String::from("flights")]), write_set: HashSet::new() }),
+            make_action_at(at(250), 2, ActionKind::RollbackTransaction),
+            make_action_at(at(3000), 1, ActionKind::CommitTransaction),
+            // never finishes, so it has no duration
+            make_action_at(at(3100), 3, ActionKind::BeginTransaction),
+        ];
+
+        let associated_actions = AssociatedActionMap::new().build(actions);
+        let durations = transaction_durations(&associated_actions, Duration::seconds(1));
+
+        assert_eq!(durations.len(), 2);
+
+        assert_eq!(durations[0].transaction_id, TransactionId(1, 1, 1));
+        assert_eq!(durations[0].begin, at(0));
+        assert_eq!(durations[0].end, at(3000));
+        assert_eq!(durations[0].duration(), Duration::milliseconds(3000));
+        assert!(durations[0].long_running);
+
+        assert_eq!(durations[1].transaction_id, TransactionId(1, 2, 2));
+        assert_eq!(durations[1].duration(), Duration::milliseconds(150));
+        assert!(!durations[1].long_running);
+    }
+}
@@ -1,71 +1,156 @@
 pub mod action;
+pub mod line_format;
 
 use std::collections::HashSet;
+use std::error::Error;
 use std::io::BufRead;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use log::warn;
-use regex::{Regex, RegexSet};
+use regex::{Captures, Regex, RegexSet};
 use time::{OffsetDateTime};
 use time::format_description::well_known::Iso8601;
-use crate::history_file_parser::action::{Action, ActionKind};
+use sddms_shared::history_record::{HistoryEvent, HistoryRecord};
+use crate::history_file_parser::action::{Action, ActionKind, ActionSource, REPLICATION_CLIENT_ID};
+use crate::history_file_parser::line_format::LineFormat;
+
+/// Replications don't have a transaction id of their own, so each one is given a unique id. This is
+/// shared between parsers so that replications from different files never collide
+static NEXT_REPLICATION_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Turns a structured history record into an action read from the given place
+fn record_action(record: HistoryRecord, source: Option<ActionSource>) -> Option<Action> {
+    let instant = parse_instant(&record.instant)?;
+    let action = match record.event {
+        HistoryEvent::Begin { site, client, txn } => Action { instant, site_id: site, client_id: client, transaction_id: txn, action: ActionKind::BeginTransaction, source },
+        HistoryEvent::Commit { site, client, txn } => Action { instant, site_id: site, client_id: client, transaction_id: txn, action: ActionKind::CommitTransaction, source },
+        HistoryEvent::Rollback { site, client, txn } => Action { instant, site_id: site, client_id: client, transaction_id: txn, action: ActionKind::RollbackTransaction, source },
+        HistoryEvent::Query { site, client, txn, read_set, write_set } => {
+            let action_kind = ActionKind::Query { read_set: read_set.into_iter().collect(), write_set: write_set.into_iter().collect() };
+            Action { instant, site_id: site, client_id: client, transaction_id: txn, action: action_kind, source }
+        }
+        HistoryEvent::Replication { site, orig_site, write_set } => {
+            let replication_id = NEXT_REPLICATION_ID.fetch_add(1, Ordering::Relaxed);
+            let action_kind = ActionKind::Replication { write_set: write_set.into_iter().collect(), originating_site: orig_site, destination_site: Some(site) };
+            Action { instant, site_id: orig_site, client_id: REPLICATION_CLIENT_ID, transaction_id: replication_id, action: action_kind, source }
+        }
+    };
+
+    Some(action)
+}
+
+/// Reads actions straight from history records kept in memory, skipping any with a bad timestamp
+#[cfg(test)]
+pub fn actions_from_records<RecordsT: IntoIterator<Item = HistoryRecord>>(records: RecordsT) -> Vec<Action> {
+    records.into_iter()
+        .filter_map(|record| record_action(record, None))
+        .collect()
+}
+
+/// Reads a numeric id out of a line. A custom line format can capture anything for an id, so it may not be
+/// a number at all
+fn parse_id(captures: &Captures, name: &str) -> Option<u32> {
+    captures.name(name)?.as_str().parse::<u32>().ok()
+}
+
+fn parse_instant(timestamp_str: &str) -> Option<OffsetDateTime> {
+    let format = Iso8601::DATE_TIME_OFFSET;
+    OffsetDateTime::parse(timestamp_str.trim(), &format).ok()
+}
 
 pub struct ActionParser<LineSourceT: BufRead> {
     reader: LineSourceT,
-    line_identifier: RegexSet,
+    action_line: Regex,
+    replication_line: Regex,
     action_identifier: RegexSet,
+    /// the file being parsed, which every action is tagged with
+    source_file: Option<Arc<Path>>,
+    /// the number of the last line read
+    line_number: usize,
 }
 
 impl<LineSourceT: BufRead> ActionParser<LineSourceT> {
     pub fn new<CreateT: Into<LineSourceT>>(inner: CreateT) -> Self {
-        let regex_set = RegexSet::new([
-            r"^([^|]+) \| site=(\d+), client=(\d+), txn=(\d+): (.*)$",
-            r"^([^|]+) \| replication: orig_site=(\d+): (.*)$"
-        ])
-            .unwrap();
+        Self::with_format(inner, &LineFormat::default())
+            .unwrap()
+    }
+
+    /// Creates a parser that recognizes lines using the given format
+    pub fn with_format<CreateT: Into<LineSourceT>>(inner: CreateT, line_format: &LineFormat) -> Result<Self, Box<dyn Error>> {
+        let (action_line, replication_line) = line_format.compile()?;
 
         let action_kind_identifier = RegexSet::new([
-            r"Begin Txn",
-            r"ROLLBACK",
-            r"COMMIT",
-            r"(Read\(([^)]+)\))?,?(Write\(([^)]+)\))?",
+            r"^Begin Txn$",
+            r"^ROLLBACK$",
+            r"^COMMIT$",
         ]).unwrap();
 
-        Self {
+        Ok(Self {
             reader: inner.into(),
-            line_identifier: regex_set,
-            action_identifier: action_kind_identifier
+            action_line,
+            replication_line,
+            action_identifier: action_kind_identifier,
+            source_file: None,
+            line_number: 0,
+        })
+    }
+
+    /// Tags every parsed action with the file it came from
+    pub fn with_source_file<PathT: AsRef<Path>>(mut self, source_file: PathT) -> Self {
+        self.source_file = Some(Arc::from(source_file.as_ref()));
+        self
+    }
+
+    fn current_source(&self) -> Option<ActionSource> {
+        self.source_file.as_ref()
+            .map(|file| ActionSource { file: file.clone(), line: self.line_number })
+    }
+
+    /// Describes where the current line is for warnings
+    fn location(&self) -> String {
+        match self.current_source() {
+            Some(source) => source.to_string(),
+            None => format!("line {}", self.line_number),
         }
     }
 
-    fn parse_action_kind(&self, str: &str) -> ActionKind {
+    fn parse_action_kind(&self, str: &str) -> Option<ActionKind> {
+        let str = str.trim();
         let matching_index = self.action_identifier.matches(str).iter()
-            .next().unwrap();
+            .next();
 
         match matching_index {
-            0 => ActionKind::BeginTransaction,
-            1 => ActionKind::RollbackTransaction,
-            2 => ActionKind::CommitTransaction,
-            3 => {
-                let query_matcher = self.action_identifier.patterns().get(3).unwrap();
-                let query_regex = Regex::new(query_matcher).unwrap();
-                let captures_info = query_regex.captures(str).unwrap();
-                let read_set = if let Some(read_set) = captures_info.get(2) {
-                    serde_json::from_str::<HashSet<String>>(read_set.as_str()).unwrap()
-                } else {
-                    HashSet::default()
-                };
-
-                let write_set = if let Some(write_set) = captures_info.get(4) {
-                    serde_json::from_str::<HashSet<String>>(write_set.as_str()).unwrap()
-                } else {
-                    HashSet::default()
-                };
+            Some(0) => Some(ActionKind::BeginTransaction),
+            Some(1) => Some(ActionKind::RollbackTransaction),
+            Some(2) => Some(ActionKind::CommitTransaction),
+            _ => {
+                let (read_set, rest) = Self::parse_table_set(str, "Read(")?;
+                let rest = rest.strip_prefix(',').unwrap_or(rest);
+                let (write_set, rest) = Self::parse_table_set(rest, "Write(")?;
+                if !rest.is_empty() {
+                    return None;
+                }
 
-                ActionKind::Query { read_set, write_set }
+                Some(ActionKind::Query { read_set, write_set })
             }
-            _ => unreachable!()
         }
     }
 
+    /// Parses a set of table names written as `<prefix>[...json...])`. The set is read as JSON rather than
+    /// by regex so that table names containing parentheses, quotes, or other special characters survive. If
+    /// the string doesn't start with the prefix, the set is empty. Gives the set and whatever follows it
+    fn parse_table_set<'str>(str: &'str str, prefix: &str) -> Option<(HashSet<String>, &'str str)> {
+        let Some(json_start) = str.strip_prefix(prefix) else {
+            return Some((HashSet::default(), str));
+        };
+
+        let mut json_sets = serde_json::Deserializer::from_str(json_start).into_iter::<HashSet<String>>();
+        let table_set = json_sets.next()?.ok()?;
+        let rest = json_start[json_sets.byte_offset()..].strip_prefix(')')?;
+        Some((table_set, rest))
+    }
+
     pub fn parse_next(&mut self) -> Option<Action> {
         loop {
             let mut line = String::new();
@@ -75,46 +160,239 @@ impl<LineSourceT: BufRead> ActionParser<LineSourceT> {
             if result.is_err() || result.is_ok_and(|byte_count| byte_count == 0) {
                 break None;
             }
+            self.line_number += 1;
 
             let trimmed_line = line.trim();
             if trimmed_line.is_empty() {
                 continue;
             }
 
-            let match_result = self.line_identifier.matches(trimmed_line);
-            let matching_index = match_result.iter().next();
-
-            if let Some(matching_index) = matching_index {
-                match matching_index {
-                    0 => {
-                        let info_extractor_pattern = self.line_identifier.patterns().get(0).unwrap();
-                        let info_extractor = Regex::new(info_extractor_pattern).unwrap();
-                        let captures = info_extractor.captures(trimmed_line).unwrap();
-                        let timestamp_str = captures.get(1).unwrap().as_str().trim();
-                        let format = Iso8601::DATE_TIME_OFFSET;
-                        let Ok(instant) = OffsetDateTime::parse(timestamp_str, &format) else {
-                            // not great
-                            warn!("Skipping line '{}' due to bad timestamp", trimmed_line);
-                            continue;
-                        };
-
-                        let site_id = captures.get(2).unwrap()
-                            .as_str()
-                            .parse::<u32>().unwrap();
-                        let client_id = captures.get(3).unwrap().as_str().parse::<u32>().unwrap();
-                        let transaction_id = captures.get(4).unwrap().as_str().parse::<u32>().unwrap();
-                        let action_kind = self.parse_action_kind(captures.get(5).unwrap().as_str());
-
-                        break Some(Action{ instant, site_id, client_id, transaction_id, action: action_kind })
-                    }
-                    1 => { /* skip replication lines */ }
-                    _ => unreachable!()
-                }
+            // JSON-lines histories are structured, so they skip the line format entirely
+            if trimmed_line.starts_with('{') {
+                let Ok(record) = HistoryRecord::from_json_line(trimmed_line) else {
+                    warn!("Skipping line '{}' at {} because it was not a history record", trimmed_line, self.location());
+                    continue;
+                };
+
+                let Some(action) = record_action(record, self.current_source()) else {
+                    warn!("Skipping line '{}' at {} due to bad timestamp", trimmed_line, self.location());
+                    continue;
+                };
+
+                break Some(action);
+            }
+
+            if let Some(captures) = self.action_line.captures(trimmed_line) {
+                let Some(instant) = parse_instant(&captures["instant"]) else {
+                    // not great
+                    warn!("Skipping line '{}' at {} due to bad timestamp", trimmed_line, self.location());
+                    continue;
+                };
+
+                let (Some(site_id), Some(client_id), Some(transaction_id)) = (parse_id(&captures, "site"), parse_id(&captures, "client"), parse_id(&captures, "txn")) else {
+                    warn!("Skipping line '{}' at {} because its ids were not numbers", trimmed_line, self.location());
+                    continue;
+                };
+                let Some(action_kind) = self.parse_action_kind(&captures["action"]) else {
+                    warn!("Skipping line '{}' at {} because its action was ill-formed", trimmed_line, self.location());
+                    continue;
+                };
+
+                break Some(Action{ instant, site_id, client_id, transaction_id, action: action_kind, source: self.current_source() })
+            } else if let Some(captures) = self.replication_line.captures(trimmed_line) {
+                let Some(instant) = parse_instant(&captures["instant"]) else {
+                    warn!("Skipping line '{}' at {} due to bad timestamp", trimmed_line, self.location());
+                    continue;
+                };
+
+                // the site a replication was applied at is optional, but has to be a number when it's there
+                let destination_site = parse_id(&captures, "site");
+                let Some(originating_site) = parse_id(&captures, "orig_site").filter(|_| destination_site.is_some() || captures.name("site").is_none()) else {
+                    warn!("Skipping replication line '{}' at {} because its sites were not numbers", trimmed_line, self.location());
+                    continue;
+                };
+                let Some(ActionKind::Query { write_set, .. }) = self.parse_action_kind(&captures["action"]) else {
+                    warn!("Skipping replication line '{}' at {} because it has no write set", trimmed_line, self.location());
+                    continue;
+                };
+
+                let replication_id = NEXT_REPLICATION_ID.fetch_add(1, Ordering::Relaxed);
+                let action_kind = ActionKind::Replication { write_set, originating_site, destination_site };
+                break Some(Action { instant, site_id: originating_site, client_id: REPLICATION_CLIENT_ID, transaction_id: replication_id, action: action_kind, source: self.current_source() })
             } else {
-                warn!("Skipping line '{}' because it was ill-formed", trimmed_line)
+                warn!("Skipping line '{}' at {} because it was ill-formed", trimmed_line, self.location())
             }
             // just in case?
             line.clear();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use crate::history_file_parser::action::ActionKind;
+    use crate::history_file_parser::ActionParser;
+    use crate::history_file_parser::line_format::LineFormat;
+    use sddms_shared::history_record::{action_text_line, HistoryEvent, HistoryRecord, instant_now, query_text_action, replication_text_line};
+
+    #[test]
+    fn site_text_history_round_trips() {
+        // the same formatting the site's history logger writes lines with
+        let instant = instant_now().unwrap();
+        let query = query_text_action(&[String::from("flights")], &[String::from("airports")]).unwrap();
+        let history = [
+            action_text_line(&instant, 1, 2, 3, "Begin Txn"),
+            action_text_line(&instant, 1, 2, 3, &query),
+            action_text_line(&instant, 1, 2, 3, "COMMIT"),
+            replication_text_line(&instant, 4, 1, &[String::from("flights")]).unwrap(),
+        ].join("\n");
+
+        let mut parser: ActionParser<Cursor<String>> = ActionParser::new(Cursor::new(history));
+
+        let begin = parser.parse_next().unwrap();
+        assert_eq!((begin.site_id, begin.client_id, begin.transaction_id), (1, 2, 3));
+        assert_eq!(begin.action, ActionKind::BeginTransaction);
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::Query {
+            read_set: HashSet::from([String::from("airports")]),
+            write_set: HashSet::from([String::from("flights")]),
+        });
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::CommitTransaction);
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::Replication {
+            write_set: HashSet::from([String::from("flights")]),
+            originating_site: 1,
+            destination_site: Some(4),
+        });
+        assert!(parser.parse_next().is_none());
+    }
+
+    #[test]
+    fn parses_custom_line_format() {
+        let line_format = LineFormat {
+            action_line: String::from(r"^\[(?<instant>[^\]]+)\] txn=(?<txn>\d+)@(?<site>\d+)/(?<client>\d+) -> (?<action>.*)$"),
+            replication_line: String::from(r"^\[(?<instant>[^\]]+)\] replicated from (?<orig_site>\d+) -> (?<action>.*)$"),
+        };
+
+        let history = "[2023-12-01T10:00:00.000000000Z] txn=7@2/3 -> Begin Txn\n\
+            [2023-12-01T10:00:01.000000000Z] txn=7@2/3 -> Read([\"flights\"])\n\
+            [2023-12-01T10:00:02.000000000Z] replicated from 1 -> Write([\"flights\"])\n";
+        let mut parser: ActionParser<Cursor<&str>> = ActionParser::with_format(Cursor::new(history), &line_format).unwrap();
+
+        let begin = parser.parse_next().unwrap();
+        assert_eq!((begin.site_id, begin.client_id, begin.transaction_id), (2, 3, 7));
+        assert_eq!(begin.action, ActionKind::BeginTransaction);
+
+        let read = parser.parse_next().unwrap();
+        assert_eq!(read.action, ActionKind::Query { read_set: HashSet::from([String::from("flights")]), write_set: HashSet::new() });
+
+        let replication = parser.parse_next().unwrap();
+        assert_eq!(replication.action, ActionKind::Replication { write_set: HashSet::from([String::from("flights")]), originating_site: 1, destination_site: None });
+
+        assert!(parser.parse_next().is_none());
+    }
+
+    #[test]
+    fn rejects_format_missing_capture_group() {
+        let line_format = LineFormat {
+            action_line: String::from(r"^(?<instant>[^|]+) \| txn=(?<txn>\d+): (?<action>.*)$"),
+            ..LineFormat::default()
+        };
+
+        assert!(ActionParser::<Cursor<&str>>::with_format(Cursor::new(""), &line_format).is_err());
+    }
+
+    #[test]
+    fn lines_with_ids_that_are_not_numbers_are_skipped() {
+        let line_format = LineFormat {
+            action_line: String::from(r"^(?<instant>[^|]+) \| site=(?<site>\w+), client=(?<client>\w+), txn=(?<txn>\w+):\s*(?<action>.*)$"),
+            replication_line: String::from(r"^(?<instant>[^|]+) \| replication: (?:site=(?<site>\w+), )?orig_site=(?<orig_site>\w+): (?<action>.*)$"),
+        };
+
+        let history = "2023-12-01T10:00:00.000000000Z | site=one, client=1, txn=1: Begin Txn\n\
+            2023-12-01T10:00:01.000000000Z | replication: site=two, orig_site=1: Write([\"flights\"])\n\
+            2023-12-01T10:00:02.000000000Z | replication: orig_site=one: Write([\"flights\"])\n\
+            2023-12-01T10:00:03.000000000Z | site=1, client=1, txn=2: Begin Txn\n";
+        let mut parser: ActionParser<Cursor<&str>> = ActionParser::with_format(Cursor::new(history), &line_format).unwrap();
+
+        let begin = parser.parse_next().unwrap();
+        assert_eq!(begin.transaction_id, 2);
+        assert!(parser.parse_next().is_none());
+    }
+
+    #[test]
+    fn parses_table_names_with_special_characters() {
+        let history = r#"2023-12-01T10:00:00.000000000Z | site=1, client=1, txn=1: Read(["odd \"table\" (1)","COMMIT"]),Write(["back\\slash, ok)"])"#;
+        let mut parser: ActionParser<Cursor<&str>> = ActionParser::new(Cursor::new(history));
+
+        let action = parser.parse_next().unwrap();
+        assert_eq!(action.action, ActionKind::Query {
+            read_set: HashSet::from([String::from("odd \"table\" (1)"), String::from("COMMIT")]),
+            write_set: HashSet::from([String::from("back\\slash, ok)")]),
+        });
+    }
+
+    #[test]
+    fn skips_malformed_table_set() {
+        let history = "2023-12-01T10:00:00.000000000Z | site=1, client=1, txn=1: Read([\"flights\"\n\
+            2023-12-01T10:00:01.000000000Z | site=1, client=1, txn=1: COMMIT\n";
+        let mut parser: ActionParser<Cursor<&str>> = ActionParser::new(Cursor::new(history));
+
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::CommitTransaction);
+    }
+
+    #[test]
+    fn parses_empty_table_sets() {
+        // older histories logged nothing at all for a statement that touched no tables
+        let history = "2023-12-01T10:00:00.000000000Z | site=1, client=1, txn=1: Read([]),Write([])\n\
+            2023-12-01T10:00:01.000000000Z | site=1, client=1, txn=1: \n";
+        let mut parser: ActionParser<Cursor<&str>> = ActionParser::new(Cursor::new(history));
+        let empty_query = ActionKind::Query { read_set: HashSet::new(), write_set: HashSet::new() };
+
+        assert_eq!(parser.parse_next().unwrap().action, empty_query);
+        assert_eq!(parser.parse_next().unwrap().action, empty_query);
+        assert!(parser.parse_next().is_none());
+    }
+
+    #[test]
+    fn parses_json_lines_history() {
+        let records = [
+            HistoryEvent::Begin { site: 1, client: 2, txn: 3 },
+            HistoryEvent::Query { site: 1, client: 2, txn: 3, read_set: vec![String::from("odd \"table\" (1)")], write_set: vec![String::from("flights")] },
+            HistoryEvent::Commit { site: 1, client: 2, txn: 3 },
+            HistoryEvent::Replication { site: 4, orig_site: 1, write_set: vec![String::from("flights")] },
+        ];
+        let history = records.into_iter()
+            .map(|event| HistoryRecord { instant: String::from("2023-12-01T10:00:00.000000000Z"), event }.to_json_line().unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut parser: ActionParser<Cursor<String>> = ActionParser::new(Cursor::new(history));
+
+        let begin = parser.parse_next().unwrap();
+        assert_eq!((begin.site_id, begin.client_id, begin.transaction_id), (1, 2, 3));
+        assert_eq!(begin.action, ActionKind::BeginTransaction);
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::Query {
+            read_set: HashSet::from([String::from("odd \"table\" (1)")]),
+            write_set: HashSet::from([String::from("flights")]),
+        });
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::CommitTransaction);
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::Replication {
+            write_set: HashSet::from([String::from("flights")]),
+            originating_site: 1,
+            destination_site: Some(4),
+        });
+        assert!(parser.parse_next().is_none());
+    }
+
+    #[test]
+    fn json_and_text_lines_can_be_mixed() {
+        let history = "2023-12-01T10:00:00.000000000Z | site=1, client=1, txn=1: Begin Txn\n\
+            {\"instant\":\"2023-12-01T10:00:01.000000000Z\",\"kind\":\"commit\",\"site\":1,\"client\":1,\"txn\":1}\n\
+            {\"instant\":\"2023-12-01T10:00:02Z\",\"kind\":\"unknown\"}\n";
+        let mut parser: ActionParser<Cursor<&str>> = ActionParser::new(Cursor::new(history));
+
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::BeginTransaction);
+        assert_eq!(parser.parse_next().unwrap().action, ActionKind::CommitTransaction);
+        assert!(parser.parse_next().is_none());
+    }
+}
@@ -1,15 +1,25 @@
 use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::Arc;
+use serde::{Serialize, Serializer};
+use serde::ser::{Error, SerializeStruct};
+use time::format_description::well_known::Iso8601;
 use time::OffsetDateTime;
 use crate::transaction_id::TransactionId;
 
-#[derive(Debug, PartialEq, Eq)]
+/// Client id given to replication actions, which don't belong to any client
+pub const REPLICATION_CLIENT_ID: u32 = u32::MAX;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
 #[repr(u16)]
 pub enum ActionKind {
     BeginTransaction = 0,
     CommitTransaction,
     RollbackTransaction,
     Query { read_set: HashSet<String>, write_set: HashSet<String> },
+    /// `destination_site` is where the replication was applied, if the history recorded it
+    Replication { write_set: HashSet<String>, originating_site: u32, destination_site: Option<u32> },
 }
 
 impl Display for ActionKind {
@@ -18,11 +28,26 @@ impl Display for ActionKind {
             ActionKind::BeginTransaction => write!(f, "BEGIN TRANSACTION"),
             ActionKind::CommitTransaction => write!(f, "COMMIT"),
             ActionKind::RollbackTransaction => write!(f, "ROLLBACK"),
-            ActionKind::Query { read_set, write_set } => write!(f, "Read({:?}),Write({:?})", read_set, write_set)
+            ActionKind::Query { read_set, write_set } => write!(f, "Read({:?}),Write({:?})", read_set, write_set),
+            ActionKind::Replication { write_set, originating_site, destination_site: Some(destination_site) } => write!(f, "Replication(orig_site={}, site={}),Write({:?})", originating_site, destination_site, write_set),
+            ActionKind::Replication { write_set, originating_site, destination_site: None } => write!(f, "Replication(orig_site={}),Write({:?})", originating_site, write_set),
         }
     }
 }
 
+/// Where in which history file an action was read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionSource {
+    pub(crate) file: Arc<Path>,
+    pub(crate) line: usize,
+}
+
+impl Display for ActionSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Action {
     pub(crate) instant: OffsetDateTime,
@@ -30,11 +55,52 @@ pub struct Action {
     pub(crate) client_id: u32,
     pub(crate) transaction_id: u32,
     pub(crate) action: ActionKind,
+    /// the file this action came from, if it was read from one
+    pub(crate) source: Option<ActionSource>,
 }
 
 impl Display for Action {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let transaction_id = TransactionId::from(self);
-        write!(f, "{} {} {}", self.instant, transaction_id, self.action)
+        write!(f, "{} {} {}", self.instant, transaction_id, self.action)?;
+        if let Some(source) = &self.source {
+            write!(f, " ({})", source)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let instant = self.instant.format(&Iso8601::DEFAULT)
+            .map_err(S::Error::custom)?;
+
+        let mut state = serializer.serialize_struct("Action", 4)?;
+        state.serialize_field("instant", &instant)?;
+        state.serialize_field("transaction", &TransactionId::from(self))?;
+        state.serialize_field("action", &self.action)?;
+        state.serialize_field("source", &self.source.as_ref().map(|source| source.to_string()))?;
+        state.end()
+    }
+}
+
+/// Makes an action for tests, taken at site 1 the given number of seconds after the epoch. The client has
+/// the same id as the transaction
+#[cfg(test)]
+pub(crate) fn make_action(second: i64, transaction_id: u32, action: ActionKind) -> Action {
+    make_action_at(OffsetDateTime::UNIX_EPOCH + time::Duration::seconds(second), transaction_id, action)
+}
+
+/// Like `make_action`, but at any instant
+#[cfg(test)]
+pub(crate) fn make_action_at(instant: OffsetDateTime, transaction_id: u32, action: ActionKind) -> Action {
+    Action {
+        instant,
+        site_id: 1,
+        client_id: transaction_id,
+        transaction_id,
+        action,
+        source: None,
     }
 }
@@ -0,0 +1,57 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use regex::Regex;
+use serde::Deserialize;
+
+/// The regexes used to recognize lines in a history file. These need to be kept in sync with whatever
+/// format the site's history logger writes. Fields are pulled out of lines by named capture groups, so
+/// a custom format can put them in any order
+#[derive(Debug, Clone, Deserialize)]
+pub struct LineFormat {
+    /// matches an action taken by a client. Must capture `instant`, `site`, `client`, `txn`, and `action`
+    pub action_line: String,
+    /// matches a replication from another site. Must capture `instant`, `orig_site`, and `action`, and may
+    /// capture `site`, the site the replication was applied at
+    pub replication_line: String,
+}
+
+impl Default for LineFormat {
+    fn default() -> Self {
+        Self {
+            action_line: String::from(r"^(?<instant>[^|]+) \| site=(?<site>\d+), client=(?<client>\d+), txn=(?<txn>\d+):\s*(?<action>.*)$"),
+            replication_line: String::from(r"^(?<instant>[^|]+) \| replication: (?:site=(?<site>\d+), )?orig_site=(?<orig_site>\d+): (?<action>.*)$"),
+        }
+    }
+}
+
+impl LineFormat {
+    pub const ACTION_LINE_GROUPS: [&'static str; 5] = ["instant", "site", "client", "txn", "action"];
+    pub const REPLICATION_LINE_GROUPS: [&'static str; 3] = ["instant", "orig_site", "action"];
+
+    /// Loads a line format from a JSON file with `action_line` and `replication_line` keys
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)?;
+        let format = serde_json::from_reader(BufReader::new(file))?;
+        Ok(format)
+    }
+
+    /// Compiles the action and replication line regexes, making sure they capture every required field
+    pub(super) fn compile(&self) -> Result<(Regex, Regex), Box<dyn Error>> {
+        let action_line = compile_with_groups(&self.action_line, &Self::ACTION_LINE_GROUPS)?;
+        let replication_line = compile_with_groups(&self.replication_line, &Self::REPLICATION_LINE_GROUPS)?;
+        Ok((action_line, replication_line))
+    }
+}
+
+fn compile_with_groups(pattern: &str, required_groups: &[&str]) -> Result<Regex, Box<dyn Error>> {
+    let regex = Regex::new(pattern)?;
+    for group in required_groups {
+        if !regex.capture_names().any(|name| name == Some(*group)) {
+            return Err(format!("line format '{}' is missing capture group '{}'", pattern, group).into());
+        }
+    }
+
+    Ok(regex)
+}
@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use crate::history_file_parser::action::ActionKind;
+use crate::organize::AssociatedActionMap;
+use crate::verify::ConflictGraph;
+
+/// How many of the most contended tables are listed
+const CONTENDED_TABLE_COUNT: usize = 5;
+
+/// A breakdown of what a history contains, for getting a feel for a workload whether or not it has any
+/// conflicts
+#[derive(Debug, Default, PartialEq)]
+pub struct HistoryStatistics {
+    /// the number of client transactions that ran at each site
+    pub transactions_per_site: BTreeMap<u32, usize>,
+    pub replication_count: usize,
+    pub query_count: usize,
+    pub begin_count: usize,
+    pub commit_count: usize,
+    pub rollback_count: usize,
+    /// client transactions that never wrote anything
+    pub read_only_count: usize,
+    /// client transactions that wrote at least one table
+    pub read_write_count: usize,
+    /// the tables that show up in the most conflicts, most contended first
+    pub contended_tables: Vec<(String, usize)>,
+}
+
+impl HistoryStatistics {
+    pub fn new(conflict_graph: &ConflictGraph, associated_action_map: &AssociatedActionMap) -> Self {
+        let mut statistics = Self::default();
+        for transaction_id in associated_action_map.get_all_transaction_ids() {
+            if transaction_id.is_replication() {
+                statistics.replication_count += 1;
+                continue;
+            }
+
+            *statistics.transactions_per_site.entry(transaction_id.0).or_default() += 1;
+            let writes = associated_action_map.borrow_transaction(&transaction_id).unwrap().into_iter()
+                .any(|action| matches!(&action.action, ActionKind::Query { write_set, .. } if !write_set.is_empty()));
+            if writes {
+                statistics.read_write_count += 1;
+            } else {
+                statistics.read_only_count += 1;
+            }
+        }
+
+        for action in associated_action_map.all_actions() {
+            match action.action {
+                ActionKind::BeginTransaction => statistics.begin_count += 1,
+                ActionKind::CommitTransaction => statistics.commit_count += 1,
+                ActionKind::RollbackTransaction => statistics.rollback_count += 1,
+                ActionKind::Query { .. } => statistics.query_count += 1,
+                ActionKind::Replication { .. } => {}
+            }
+        }
+
+        let mut table_counts: HashMap<&String, usize> = HashMap::new();
+        for conflict in conflict_graph.conflicts() {
+            for table in conflict.edge().conflicting_tables() {
+                *table_counts.entry(*table).or_default() += 1;
+            }
+        }
+
+        // break ties by name so the order is stable
+        let mut contended_tables = table_counts.into_iter()
+            .map(|(table, count)| (table.clone(), count))
+            .collect::<Vec<_>>();
+        contended_tables.sort_by(|(left_table, left_count), (right_table, right_count)| right_count.cmp(left_count)
+            .then_with(|| left_table.cmp(right_table)));
+        contended_tables.truncate(CONTENDED_TABLE_COUNT);
+        statistics.contended_tables = contended_tables;
+
+        statistics
+    }
+}
+
+/// Writes a two column table with the columns padded to line up
+fn write_table(f: &mut Formatter<'_>, headers: (&str, &str), rows: &[(String, String)]) -> std::fmt::Result {
+    let left_width = rows.iter()
+        .map(|(left, _)| left.chars().count())
+        .chain(std::iter::once(headers.0.len()))
+        .max()
+        .unwrap_or(0);
+    let right_width = rows.iter()
+        .map(|(_, right)| right.chars().count())
+        .chain(std::iter::once(headers.1.len()))
+        .max()
+        .unwrap_or(0);
+
+    writeln!(f, "| {:left_width$} | {:right_width$} |", headers.0, headers.1)?;
+    writeln!(f, "|{}|{}|", "-".repeat(left_width + 2), "-".repeat(right_width + 2))?;
+    for (left, right) in rows {
+        writeln!(f, "| {:left_width$} | {:right_width$} |", left, right)?;
+    }
+
+    Ok(())
+}
+
+impl Display for HistoryStatistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "History Statistics:")?;
+
+        let site_rows = self.transactions_per_site.iter()
+            .map(|(site, count)| (site.to_string(), count.to_string()))
+            .collect::<Vec<_>>();
+        write_table(f, ("site", "transactions"), &site_rows)?;
+        writeln!(f)?;
+
+        let action_rows = [
+            ("queries", self.query_count),
+            ("begins", self.begin_count),
+            ("commits", self.commit_count),
+            ("rollbacks", self.rollback_count),
+            ("replications", self.replication_count),
+        ].map(|(action, count)| (action.to_string(), count.to_string()));
+        write_table(f, ("action", "count"), &action_rows)?;
+        writeln!(f)?;
+
+        let transaction_rows = [
+            ("read-only", self.read_only_count),
+            ("read-write", self.read_write_count),
+        ].map(|(kind, count)| (kind.to_string(), count.to_string()));
+        write_table(f, ("transaction kind", "count"), &transaction_rows)?;
+        writeln!(f)?;
+
+        let table_rows = self.contended_tables.iter()
+            .map(|(table, count)| (table.clone(), count.to_string()))
+            .collect::<Vec<_>>();
+        write_table(f, ("contended table", "conflicts"), &table_rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+    use crate::history_file_parser::ActionParser;
+    use crate::history_statistics::HistoryStatistics;
+    use crate::organize::AssociatedActionMap;
+    use crate::verify::build_conflict_graph;
+
+    #[test]
+    fn statistics_break_down_the_history() {
+        let history = include_str!("../fixtures/replication_conflict.history");
+        let mut parser: ActionParser<Cursor<&str>> = ActionParser::new(Cursor::new(history));
+        let mut actions = std::iter::from_fn(|| parser.parse_next()).collect::<Vec<_>>();
+        actions.sort_by(|left, right| left.instant.cmp(&right.instant));
+        let action_map = AssociatedActionMap::new().build(actions);
+        let conflict_graph = build_conflict_graph(&action_map, false);
+
+        let statistics = HistoryStatistics::new(&conflict_graph, &action_map);
+        assert_eq!(statistics, HistoryStatistics {
+            transactions_per_site: BTreeMap::from([(1, 1)]),
+            replication_count: 1,
+            query_count: 2,
+            begin_count: 1,
+            commit_count: 1,
+            rollback_count: 0,
+            read_only_count: 0,
+            read_write_count: 1,
+            contended_tables: vec![(String::from("flights"), 2)],
+        });
+
+        let rendered = statistics.to_string();
+        assert!(rendered.contains("| site | transactions |"), "{}", rendered);
+        assert!(rendered.contains("| flights         | 2         |"), "{}", rendered);
+    }
+}
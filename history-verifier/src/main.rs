@@ -1,14 +1,19 @@
 use std::error::Error;
+use std::fmt::Display;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::process::ExitCode;
 use clap::Parser;
-use log::{debug, error, info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use crate::args::Args;
+use crate::bench::{associate_histories, PhaseTimings, verify_histories};
+use crate::durations::transaction_durations;
 use crate::history_file_parser::ActionParser;
-use crate::history_file_parser::action::Action;
-use crate::organize::AssociatedActionMap;
-use crate::verify::verify_action_history;
+use crate::history_file_parser::line_format::LineFormat;
+use crate::history_statistics::HistoryStatistics;
+use crate::serial_view::{ConflictPolicy, SerialView};
+use crate::verify::ConflictStatistics;
 
 mod history_file_parser;
 mod args;
@@ -16,6 +21,20 @@ mod organize;
 mod verify;
 mod transaction_id;
 mod serial_view;
+mod window;
+mod durations;
+mod bench;
+mod history_statistics;
+
+/// Prints a human-readable report. With `--json`, stdout is reserved for the conflict array, so
+/// reports go to stderr instead.
+fn print_report(json: bool, report: impl Display) {
+    if json {
+        eprintln!("{}", report);
+    } else {
+        println!("{}", report);
+    }
+}
 
 fn main() -> Result<ExitCode, Box<dyn Error>> {
 
@@ -30,41 +49,95 @@ fn main() -> Result<ExitCode, Box<dyn Error>> {
         return Ok(ExitCode::SUCCESS)
     }
 
-    let file_count = args.history_file_paths.len();
-    let mut actions: Vec<Action> = Vec::new();
-    for history_file_path in args.history_file_paths {
+    let line_format = args.line_format.as_deref()
+        .map(LineFormat::load)
+        .transpose()?;
+
+    let mut parsers = Vec::with_capacity(args.history_file_paths.len());
+    for history_file_path in &args.history_file_paths {
         info!("Parsing file {}", history_file_path.display());
+        let buf_reader = BufReader::new(File::open(history_file_path)?);
+        let parser: ActionParser<BufReader<File>> = match &line_format {
+            Some(line_format) => ActionParser::with_format(buf_reader, line_format)?,
+            None => ActionParser::new(buf_reader),
+        };
+        parsers.push(parser.with_source_file(history_file_path));
+    }
 
-        let action_file = File::open(history_file_path)?;
-        let buf_reader = BufReader::new(action_file);
-        let mut parser: ActionParser<BufReader<File>> = ActionParser::new(buf_reader);
+    let mut timings = PhaseTimings::default();
+    let clock_skew = time::Duration::milliseconds(args.clock_skew_ms);
+    let associated_actions = associate_histories(parsers, args.since, args.until, clock_skew, &mut timings);
+
+    if let Some(dump_path) = &args.dump_map {
+        info!("Writing associated actions to {}", dump_path.display());
+        fs::write(dump_path, serde_json::to_string_pretty(&associated_actions)?)?;
+    }
+
+    for dangling_transaction in associated_actions.get_dangling_transactions() {
+        warn!("Transaction {} never committed or rolled back", dangling_transaction);
+    }
 
-        while let Some(next) = parser.parse_next() {
-            debug!("Parsed action {:?}", next);
-            actions.push(next);
+    if args.durations {
+        let threshold = time::Duration::milliseconds(args.long_transaction_ms);
+        let durations = transaction_durations(&associated_actions, threshold);
+        let long_running_count = durations.iter().filter(|duration| duration.long_running).count();
+        let mut report = String::from("Transaction Durations:\n");
+        for duration in &durations {
+            report.push_str(&format!("{}\n", duration));
         }
+        print_report(args.json, report);
+        info!("{} of {} transactions ran longer than {}", long_running_count, durations.len(), threshold);
     }
 
-    info!("Parsed {} items from {} files", actions.len(), file_count);
+    let (conflict_graph, verification) = verify_histories(&associated_actions, args.replication_as_commit, &mut timings);
 
-    info!("Sorting actions chronologically...");
-    actions.sort_by(|left, right| left.instant.cmp(&right.instant));
+    if args.bench {
+        eprintln!("{}", serde_json::to_string(&timings)?);
+    }
 
-    info!("Associating actions...");
-    let associated_actions = AssociatedActionMap::new()
-        .build(actions);
-    info!("Associated actions!");
+    if let Some(dot_path) = &args.dot {
+        info!("Writing conflict graph to {}", dot_path.display());
+        fs::write(dot_path, conflict_graph.to_dot())?;
+    }
 
-    info!("Verifying chronological actions...");
-    match verify_action_history(&associated_actions) {
+    if args.stats {
+        print_report(args.json, format!("{}\n", ConflictStatistics::new(&conflict_graph, &associated_actions)));
+        print_report(args.json, HistoryStatistics::new(&conflict_graph, &associated_actions));
+    }
+
+    if args.serial_view {
+        let policy = ConflictPolicy { atomic_transactions: args.atomic_transactions };
+        match SerialView::from_conflict_graph(&conflict_graph, &associated_actions, policy) {
+            Some(serial_view) => print_report(args.json, serial_view),
+            None => info!("There is no serial order equivalent to this history"),
+        }
+    }
+
+    match verification {
         Ok(_) => {
             info!("History is conflict free!");
+            if args.show_serial {
+                // a conflict free graph is acyclic, so there is always an order
+                if let Some(serial_order) = conflict_graph.serial_order() {
+                    let serial_order = serial_order.iter()
+                        .map(|transaction_id| transaction_id.to_string())
+                        .collect::<Vec<_>>();
+                    print_report(args.json, format!("Serial Order: {}", serial_order.join(" -> ")));
+                }
+            }
+            if args.json {
+                println!("[]");
+            }
             Ok(ExitCode::SUCCESS)
         }
         Err(conflict_error) => {
             let error_count = conflict_error.len();
-            for err in conflict_error {
-                println!("{}\n", err);
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&conflict_error)?);
+            } else {
+                for err in conflict_error {
+                    println!("{}\n", err);
+                }
             }
             error!("There was/were {} conflicts", error_count);
             Ok(ExitCode::FAILURE)
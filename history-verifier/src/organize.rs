@@ -2,7 +2,9 @@ use std::collections::{Bound, HashMap, HashSet};
 use std::ops::{RangeBounds};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use crate::history_file_parser::action::Action;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeSeq;
+use crate::history_file_parser::action::{Action, ActionKind};
 use crate::transaction_id::TransactionId;
 
 type TransactionMap = HashMap<u32, Vec<usize>>;
@@ -15,6 +17,9 @@ pub struct AssociatedActionMap {
     actions: Vec<Action>,
     site_map: SiteMap,
     chrono_sorted_order: Vec<usize>,
+    /// how far apart the clocks at different sites may be. Actions this close together could have
+    /// happened in either order
+    clock_skew: time::Duration,
 }
 
 impl AssociatedActionMap {
@@ -23,9 +28,28 @@ impl AssociatedActionMap {
             actions: Vec::new(),
             site_map: SiteMap::default(),
             chrono_sorted_order: Vec::new(),
+            clock_skew: time::Duration::ZERO,
         }
     }
 
+    /// Treats actions within `clock_skew` of each other as possibly concurrent, since their timestamps
+    /// could have come from clocks that disagree by that much. Every range given out by this map, such as
+    /// a transaction's range, is widened by `clock_skew` on both ends so that it also takes in actions
+    /// logged just before the range began or just after it ended
+    pub fn with_clock_skew(mut self, clock_skew: time::Duration) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    pub fn clock_skew(&self) -> time::Duration {
+        self.clock_skew
+    }
+
+    /// True if the two actions are close enough together that clock skew could have swapped their order
+    pub fn within_clock_skew(&self, left: &Action, right: &Action) -> bool {
+        self.clock_skew.is_positive() && (left.instant - right.instant).abs() <= self.clock_skew
+    }
+
     pub fn build(mut self, actions: Vec<Action>) -> Self {
         for (idx, action) in actions.iter().enumerate() {
             self.get_or_add_transaction_mut(action.site_id, action.client_id, action.transaction_id).push(idx);
@@ -104,9 +128,56 @@ impl AssociatedActionMap {
             }
         }
 
+        let (min, max) = self.widen_by_clock_skew(min, max);
         self.get_action_range(min..=max)
     }
 
+    /// Widens the range of action indices to take in every action whose timestamp is within the clock skew
+    /// of the range's first or last action. Actions are sorted by timestamp, so those are all next to the range
+    fn widen_by_clock_skew(&self, mut min: usize, mut max: usize) -> (usize, usize) {
+        if !self.clock_skew.is_positive() {
+            return (min, max);
+        }
+
+        let earliest = self.actions[min].instant - self.clock_skew;
+        while min > 0 && self.actions[min - 1].instant >= earliest {
+            min -= 1;
+        }
+
+        let latest = self.actions[max].instant + self.clock_skew;
+        while max + 1 < self.actions.len() && self.actions[max + 1].instant <= latest {
+            max += 1;
+        }
+
+        (min, max)
+    }
+
+    /// Gets the range of actions that a replication could conflict with. A replication is only a single
+    /// action, so its range is extended through every transaction that was in progress when it was applied.
+    /// With clock skew, transactions that started or finished within the skew of the replication count as
+    /// in progress too
+    pub fn get_replication_range(&self, replication_id: TransactionId) -> &[Action] {
+        let replication_index = *self.get_transaction_indices(replication_id.0, replication_id.1, replication_id.2)
+            .and_then(|indices| indices.first())
+            .unwrap();
+        let replication_instant = self.actions[replication_index].instant;
+
+        let mut transactions = HashSet::from([replication_id]);
+        for transaction_id in self.get_all_transaction_ids() {
+            let indices = self.get_transaction_indices(transaction_id.0, transaction_id.1, transaction_id.2).unwrap();
+            let smallest = *indices.iter().min().unwrap();
+            let largest = *indices.iter().max().unwrap();
+            let in_progress = smallest <= replication_index && replication_index <= largest;
+            let within_skew = self.actions[smallest].instant - self.clock_skew <= replication_instant
+                && replication_instant <= self.actions[largest].instant + self.clock_skew;
+            if in_progress || (self.clock_skew.is_positive() && within_skew) {
+                transactions.insert(transaction_id);
+            }
+        }
+
+        self.get_transactions_range(&transactions)
+    }
+
     fn get_action_range<RangeT: RangeBounds<usize>>(&self, range: RangeT) -> &[Action] {
 
         let lower = match range.start_bound() {
@@ -138,6 +209,23 @@ impl AssociatedActionMap {
         vec
     }
 
+    /// Finds transactions that began but never committed or rolled back, which happens when a client crashes
+    /// or is reaped while idle. Transactions without a begin, such as replications and single statement
+    /// transactions in histories from before sites logged them with a begin, are never considered dangling
+    pub fn get_dangling_transactions(&self) -> Vec<TransactionId> {
+        self.get_all_transaction_ids().into_iter()
+            .filter(|transaction_id| {
+                let actions = self.borrow_transaction(transaction_id).unwrap();
+                let began = actions.iter()
+                    .any(|action| action.action == ActionKind::BeginTransaction);
+                let finished = actions.iter()
+                    .any(|action| matches!(action.action, ActionKind::CommitTransaction | ActionKind::RollbackTransaction));
+
+                began && !finished
+            })
+            .collect()
+    }
+
     /// Get the other transactions that are running concurrently with the given transaction
     pub fn get_concurrent_transactions(&self, transaction_id: &TransactionId) -> HashSet<TransactionId> {
         self.get_transactions_range(&HashSet::from([*transaction_id])).into_iter()
@@ -178,3 +266,76 @@ impl AssociatedActionMap {
         transaction_map.get_mut(&trans_id).unwrap()
     }
 }
+
+/// A transaction and its actions in the order they happened, as it appears in the dumped map
+#[derive(Serialize)]
+struct SerializedTransaction<'actions> {
+    transaction: TransactionId,
+    actions: Vec<&'actions Action>,
+}
+
+/// Serializes as a list of transactions, ordered by transaction id
+impl Serialize for AssociatedActionMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let transaction_ids = self.get_all_transaction_ids();
+        let mut seq = serializer.serialize_seq(Some(transaction_ids.len()))?;
+        for transaction in transaction_ids {
+            let actions = self.borrow_transaction(&transaction).unwrap();
+            seq.serialize_element(&SerializedTransaction { transaction, actions })?;
+        }
+
+        seq.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::history_file_parser::action::{ActionKind, make_action};
+    use crate::organize::AssociatedActionMap;
+    use crate::transaction_id::TransactionId;
+
+    #[test]
+    fn dangling_transaction_is_flagged() {
+        let query = || ActionKind::Query {
+            read_set: HashSet::from([String::from("flights")]),
+            write_set: HashSet::new(),
+        };
+
+        let actions = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 2, ActionKind::BeginTransaction),
+            make_action(2, 1, query()),
+            make_action(3, 2, query()),
+            make_action(4, 3, query()),
+            make_action(5, 1, ActionKind::CommitTransaction),
+        ];
+
+        let associated_actions = AssociatedActionMap::new().build(actions);
+
+        // transaction 3 is a single statement transaction, so it has nothing to finish
+        assert_eq!(associated_actions.get_dangling_transactions(), vec![TransactionId(1, 2, 2)]);
+    }
+
+    #[test]
+    fn dumped_map_contains_transactions() {
+        let actions = vec![
+            make_action(0, 2, ActionKind::BeginTransaction),
+            make_action(1, 1, ActionKind::BeginTransaction),
+            make_action(2, 2, ActionKind::CommitTransaction),
+            make_action(3, 1, ActionKind::RollbackTransaction),
+        ];
+
+        let associated_actions = AssociatedActionMap::new().build(actions);
+        let dumped: serde_json::Value = serde_json::to_value(&associated_actions).unwrap();
+
+        let transactions = dumped.as_array().unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0]["transaction"], serde_json::json!({ "site": 1, "client": 1, "transaction": 1 }));
+        assert_eq!(transactions[0]["actions"].as_array().unwrap().iter()
+                       .map(|action| action["action"].as_str().unwrap())
+                       .collect::<Vec<_>>(), vec!["BeginTransaction", "RollbackTransaction"]);
+        assert_eq!(transactions[1]["transaction"]["transaction"], 2);
+        assert_eq!(transactions[1]["actions"][1]["action"], "CommitTransaction");
+    }
+}
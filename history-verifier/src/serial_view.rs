@@ -1,25 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use crate::history_file_parser::action::Action;
 use crate::organize::AssociatedActionMap;
+use crate::transaction_id::TransactionId;
+use crate::verify::{ConflictGraph, topological_sort};
+
+/// Controls which orderings the serial view has to respect besides the conflicts themselves
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConflictPolicy {
+    /// Treat each transaction's begin-before-commit structure as happens-before edges, so every
+    /// transaction's actions stay together in the serial order instead of being interleaved
+    pub atomic_transactions: bool,
+}
 
 pub struct SerialView<'actions> {
     serial_view: Vec<&'actions Action>,
 }
 
 impl<'actions> SerialView<'actions> {
-    pub fn new() -> Self {
-        Self {
-            serial_view: Vec::default()
+    /// Derives a serial order from the conflict graph with a topological sort, so that every action comes
+    /// after the actions it conflicts with. Ties are broken chronologically. Gives nothing if the transactions'
+    /// conflicts form a cycle, since then there isn't an equivalent serial order
+    pub fn from_conflict_graph(conflict_graph: &ConflictGraph<'actions>, associated_action_map: &'actions AssociatedActionMap, policy: ConflictPolicy) -> Option<Self> {
+        // action-level edges can't see a cycle between transactions, so check the transaction graph first
+        conflict_graph.serial_order()?;
+
+        let serial_view = if policy.atomic_transactions {
+            Self::sort_transactions(conflict_graph, associated_action_map)?
+        } else {
+            Self::sort_actions(conflict_graph, associated_action_map)?
+        };
+
+        Some(Self {
+            serial_view
+        })
+    }
+
+    pub fn actions(&self) -> &[&'actions Action] {
+        &self.serial_view
+    }
+
+    /// Orders whole transactions, then lays out each transaction's actions in the order they happened
+    fn sort_transactions(conflict_graph: &ConflictGraph<'actions>, associated_action_map: &'actions AssociatedActionMap) -> Option<Vec<&'actions Action>> {
+        let action_indices = Self::action_indices(associated_action_map);
+        let mut transactions: HashMap<TransactionId, Vec<&'actions Action>> = associated_action_map.get_all_transaction_ids().into_iter()
+            .map(|transaction_id| (transaction_id, associated_action_map.borrow_transaction(&transaction_id).unwrap()))
+            .collect();
+
+        // a transaction is placed by when it started
+        let starts = transactions.iter()
+            .map(|(transaction_id, actions)| {
+                let start = actions.iter().map(|action| action_indices[&Self::action_key(action)]).min().unwrap_or(0);
+                (*transaction_id, start)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let order = topological_sort(starts.keys().copied(), conflict_graph.transaction_edges(), |transaction_id| starts[transaction_id])?;
+        Some(order.into_iter()
+            .flat_map(|transaction_id| transactions.remove(&transaction_id).unwrap())
+            .collect())
+    }
+
+    /// Orders individual actions, only keeping each transaction's actions in program order
+    fn sort_actions(conflict_graph: &ConflictGraph<'actions>, associated_action_map: &'actions AssociatedActionMap) -> Option<Vec<&'actions Action>> {
+        let all_actions = associated_action_map.all_actions();
+        let action_indices = Self::action_indices(associated_action_map);
+
+        let mut edges = HashSet::new();
+        for transaction_id in associated_action_map.get_all_transaction_ids() {
+            let transaction_actions = associated_action_map.borrow_transaction(&transaction_id).unwrap();
+            for pair in transaction_actions.windows(2) {
+                edges.insert((action_indices[&Self::action_key(pair[0])], action_indices[&Self::action_key(pair[1])]));
+            }
+        }
+
+        for conflict in conflict_graph.conflicts() {
+            let edge = conflict.edge();
+            edges.insert((action_indices[&Self::action_key(edge.causing_action())], action_indices[&Self::action_key(edge.conflicted_action())]));
         }
+
+        let order = topological_sort(0..all_actions.len(), edges, |index| *index)?;
+        Some(order.into_iter()
+            .map(|index| &all_actions[index])
+            .collect())
     }
 
-    pub fn build(mut self, associated_action_map: &'actions AssociatedActionMap) -> Self {
-        let transaction_ids = associated_action_map.get_all_transaction_ids();
+    /// Actions are identified by their address, since every action lives in the map's action list
+    fn action_key(action: &Action) -> *const Action {
+        action as *const Action
+    }
 
-        for trans_id in transaction_ids {
-            let mut transaction_actions = associated_action_map.borrow_transaction(&trans_id).unwrap();
-            self.serial_view.append(&mut transaction_actions);
+    fn action_indices(associated_action_map: &AssociatedActionMap) -> HashMap<*const Action, usize> {
+        associated_action_map.all_actions().iter().enumerate()
+            .map(|(index, action)| (Self::action_key(action), index))
+            .collect()
+    }
+}
+
+impl<'actions> Display for SerialView<'actions> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Serial View:")?;
+        for action in self.actions() {
+            writeln!(f, "{}", action)?;
         }
 
-        self
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use time::OffsetDateTime;
+    use crate::history_file_parser::action::{Action, ActionKind, make_action};
+    use crate::organize::AssociatedActionMap;
+    use crate::serial_view::{ConflictPolicy, SerialView};
+    use crate::transaction_id::TransactionId;
+    use crate::verify::build_conflict_graph;
+
+    fn query(read: &[&str], write: &[&str]) -> ActionKind {
+        ActionKind::Query {
+            read_set: read.iter().map(|table| table.to_string()).collect::<HashSet<_>>(),
+            write_set: write.iter().map(|table| table.to_string()).collect::<HashSet<_>>(),
+        }
+    }
+
+    /// Two interleaved transactions where the second reads what the first wrote
+    fn interleaved_history() -> Vec<Action> {
+        vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 2, ActionKind::BeginTransaction),
+            make_action(2, 1, query(&[], &["flights"])),
+            make_action(3, 2, query(&["seats"], &[])),
+            make_action(4, 1, query(&[], &["tickets"])),
+            make_action(5, 2, query(&["flights"], &[])),
+            make_action(6, 1, ActionKind::CommitTransaction),
+            make_action(7, 2, ActionKind::CommitTransaction),
+        ]
+    }
+
+    #[test]
+    fn atomic_serial_order_never_splits_transactions() {
+        let associated_actions = AssociatedActionMap::new().build(interleaved_history());
+        let graph = build_conflict_graph(&associated_actions, false);
+        let policy = ConflictPolicy { atomic_transactions: true };
+        let serial_view = SerialView::from_conflict_graph(&graph, &associated_actions, policy).unwrap();
+
+        let transaction_order = serial_view.actions().iter()
+            .map(|action| TransactionId::from(*action))
+            .collect::<Vec<_>>();
+
+        // once a transaction is left, it never shows up again
+        let mut finished = HashSet::new();
+        for pair in transaction_order.windows(2) {
+            if pair[0] != pair[1] {
+                assert!(finished.insert(pair[0]));
+                assert!(!finished.contains(&pair[1]));
+            }
+        }
+
+        assert_eq!(transaction_order.len(), 8);
+        assert_eq!(transaction_order[0], TransactionId(1, 1, 1));
+        assert_eq!(transaction_order[7], TransactionId(1, 2, 2));
+    }
+
+    #[test]
+    fn atomic_serial_order_can_differ_from_log_order() {
+        // transaction 1 starts first, but reads what transaction 2 wrote, so all of 2 has to come first
+        let history = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 2, ActionKind::BeginTransaction),
+            make_action(2, 2, query(&[], &["flights"])),
+            make_action(3, 1, query(&["flights"], &[])),
+            make_action(4, 2, ActionKind::CommitTransaction),
+            make_action(5, 1, ActionKind::CommitTransaction),
+        ];
+        let associated_actions = AssociatedActionMap::new().build(history);
+        let graph = build_conflict_graph(&associated_actions, false);
+        let policy = ConflictPolicy { atomic_transactions: true };
+        let serial_view = SerialView::from_conflict_graph(&graph, &associated_actions, policy).unwrap();
+
+        let seconds = serial_view.actions().iter()
+            .map(|action| (action.instant - OffsetDateTime::UNIX_EPOCH).whole_seconds())
+            .collect::<Vec<_>>();
+        assert_eq!(seconds, vec![1, 2, 4, 0, 3, 5]);
+    }
+
+    #[test]
+    fn cyclic_history_has_no_serial_order() {
+        // 1 reads flights before 2 writes it, and 2 reads seats before 1 writes it
+        let history = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 2, ActionKind::BeginTransaction),
+            make_action(2, 1, query(&["flights"], &[])),
+            make_action(3, 2, query(&[], &["flights"])),
+            make_action(4, 2, query(&["seats"], &[])),
+            make_action(5, 1, query(&[], &["seats"])),
+            make_action(6, 1, ActionKind::CommitTransaction),
+            make_action(7, 2, ActionKind::CommitTransaction),
+        ];
+        let associated_actions = AssociatedActionMap::new().build(history);
+        let graph = build_conflict_graph(&associated_actions, false);
+
+        assert!(graph.serial_order().is_none());
+        for atomic_transactions in [false, true] {
+            let policy = ConflictPolicy { atomic_transactions };
+            assert!(SerialView::from_conflict_graph(&graph, &associated_actions, policy).is_none());
+        }
+    }
+
+    #[test]
+    fn atomic_serial_order_follows_read_then_write() {
+        // transaction 0 has the lower id, but transaction 1 reads before it writes, so 1 has to come first
+        let history = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 0, ActionKind::BeginTransaction),
+            make_action(2, 1, query(&["flights"], &[])),
+            make_action(3, 0, query(&[], &["flights"])),
+            make_action(4, 1, ActionKind::CommitTransaction),
+            make_action(5, 0, ActionKind::CommitTransaction),
+            make_action(6, 2, ActionKind::BeginTransaction),
+            make_action(7, 2, query(&["seats"], &[])),
+            make_action(8, 2, ActionKind::CommitTransaction),
+        ];
+        let associated_actions = AssociatedActionMap::new().build(history);
+        let graph = build_conflict_graph(&associated_actions, false);
+        let policy = ConflictPolicy { atomic_transactions: true };
+        let serial_view = SerialView::from_conflict_graph(&graph, &associated_actions, policy).unwrap();
+
+        let mut transaction_order = serial_view.actions().iter()
+            .map(|action| TransactionId::from(*action))
+            .collect::<Vec<_>>();
+        transaction_order.dedup();
+        assert_eq!(transaction_order, vec![TransactionId(1, 1, 1), TransactionId(1, 0, 0), TransactionId(1, 2, 2)]);
     }
 }
@@ -1,6 +1,8 @@
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
-use crate::history_file_parser::action::Action;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use crate::history_file_parser::action::{Action, REPLICATION_CLIENT_ID};
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct TransactionId(pub(crate) u32, pub(crate) u32, pub(crate) u32);
@@ -11,9 +13,30 @@ impl From<&Action> for TransactionId {
     }
 }
 
+impl TransactionId {
+    /// true if this id refers to a replication rather than a client transaction
+    pub fn is_replication(&self) -> bool {
+        self.1 == REPLICATION_CLIENT_ID
+    }
+}
+
 impl Display for TransactionId {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<{},{},{}>", self.0, self.1, self.2)
+        if self.is_replication() {
+            write!(f, "<repl:{},{}>", self.0, self.2)
+        } else {
+            write!(f, "<{},{},{}>", self.0, self.1, self.2)
+        }
+    }
+}
+
+impl Serialize for TransactionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("TransactionId", 3)?;
+        state.serialize_field("site", &self.0)?;
+        state.serialize_field("client", &self.1)?;
+        state.serialize_field("transaction", &self.2)?;
+        state.end()
     }
 }
 
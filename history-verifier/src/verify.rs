@@ -1,24 +1,277 @@
 mod conflict_diagnosis;
 mod conflict_graph;
+mod conflict_statistics;
 mod conflict_type;
 
-use conflict_graph::ConflictGraph;
+pub use conflict_graph::ConflictGraph;
+pub(crate) use conflict_graph::topological_sort;
 use crate::organize::AssociatedActionMap;
-use crate::verify::conflict_diagnosis::ConflictDiagnosis;
-
-pub fn verify_action_history(associated_action_map: &AssociatedActionMap) -> Result<(), Vec<ConflictDiagnosis>> {
+pub use crate::verify::conflict_diagnosis::ConflictDiagnosis;
+pub use crate::verify::conflict_statistics::ConflictStatistics;
 
+/// Builds the conflict graph, optionally treating each replication as its originating transaction
+/// committing at the destination site
+pub fn build_conflict_graph(associated_action_map: &AssociatedActionMap, replication_as_commit: bool) -> ConflictGraph<'_> {
     let all_transaction_ids = associated_action_map.get_all_transaction_ids();
 
-    let conflict_graph = ConflictGraph::new(all_transaction_ids)
-        .build(&associated_action_map);
+    ConflictGraph::new(all_transaction_ids)
+        .replication_as_commit(replication_as_commit)
+        .build(&associated_action_map)
+}
 
+pub fn verify_conflict_graph<'action>(conflict_graph: &ConflictGraph<'action>, associated_action_map: &'action AssociatedActionMap) -> Result<(), Vec<ConflictDiagnosis<'action>>> {
     let cycles = conflict_graph.detect_cycles();
     if cycles.is_empty() {
         Ok(())
     } else {
-        Err(cycles.into_iter()
-            .map(|cycle| ConflictDiagnosis::new(cycle, &conflict_graph, associated_action_map))
+        Err(cycles.iter()
+            .map(|cycle| ConflictDiagnosis::new(cycle.clone(), conflict_graph, associated_action_map))
             .collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::history_file_parser::action::{Action, ActionKind};
+    use crate::history_file_parser::{actions_from_records, ActionParser};
+    use sddms_shared::history_record::MemoryHistory;
+    use sddms_site::history_logger::{HistoryLogger, MemoryHistoryLogger};
+    use crate::organize::AssociatedActionMap;
+    use crate::transaction_id::TransactionId;
+    use crate::verify::{build_conflict_graph, ConflictStatistics, verify_conflict_graph};
+
+    fn parse_history(history: &str) -> AssociatedActionMap {
+        parse_history_files(&[("", history)])
+    }
+
+    /// Parses each history as if it came from a file with the given name
+    fn parse_history_files(histories: &[(&str, &str)]) -> AssociatedActionMap {
+        let mut actions: Vec<Action> = Vec::new();
+        for (file_name, history) in histories {
+            let mut parser: ActionParser<Cursor<&str>> = ActionParser::new(Cursor::new(*history));
+            if !file_name.is_empty() {
+                parser = parser.with_source_file(file_name);
+            }
+
+            while let Some(next) = parser.parse_next() {
+                actions.push(next);
+            }
+        }
+
+        actions.sort_by(|left, right| left.instant.cmp(&right.instant));
+        AssociatedActionMap::new().build(actions)
+    }
+
+    #[test]
+    fn replication_conflicts_with_local_transaction() {
+        let history = include_str!("../fixtures/replication_conflict.history");
+        let action_map = parse_history(history);
+
+        let replications = action_map.all_actions().iter()
+            .filter(|action| matches!(action.action, ActionKind::Replication { .. }))
+            .count();
+        assert_eq!(replications, 1);
+
+        let conflict_graph = build_conflict_graph(&action_map, false);
+        let conflicts = verify_conflict_graph(&conflict_graph, &action_map).unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+    }
+
+    #[test]
+    fn diagnosis_includes_source_files() {
+        let history = include_str!("../fixtures/replication_conflict.history");
+        let (replication_lines, local_lines): (Vec<_>, Vec<_>) = history.lines()
+            .partition(|line| line.contains("replication:"));
+        let action_map = parse_history_files(&[
+            ("site1.history", &local_lines.join("\n")),
+            ("site2.history", &replication_lines.join("\n")),
+        ]);
+
+        let conflict_graph = build_conflict_graph(&action_map, false);
+        let conflicts = verify_conflict_graph(&conflict_graph, &action_map).unwrap_err();
+
+        let diagnosis = conflicts[0].to_string();
+        assert!(diagnosis.contains("site1.history:2"));
+        assert!(diagnosis.contains("site1.history:3"));
+        assert!(diagnosis.contains("site2.history:1"));
+
+        let json = serde_json::to_value(&conflicts[0]).unwrap();
+        let sources = json["conflict_range"].as_array().unwrap().iter()
+            .map(|action| action["source"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sources, vec!["site1.history:1", "site1.history:2", "site2.history:1", "site1.history:3", "site1.history:4"]);
+    }
+
+    #[test]
+    fn replication_as_commit_conflicts_only_at_destination() {
+        let site1 = "2023-12-01T10:00:00.000000000Z | site=1, client=1, txn=1: Begin Txn\n\
+            2023-12-01T10:00:01.000000000Z | site=1, client=1, txn=1: Read([\"flights\"])\n\
+            2023-12-01T10:00:03.000000000Z | replication: site=1, orig_site=2: Write([\"flights\"])\n\
+            2023-12-01T10:00:04.000000000Z | site=1, client=1, txn=1: Write([\"flights\"])\n\
+            2023-12-01T10:00:05.000000000Z | site=1, client=1, txn=1: COMMIT\n";
+        let site2 = "2023-12-01T10:00:00.500000000Z | site=2, client=1, txn=7: Begin Txn\n\
+            2023-12-01T10:00:01.500000000Z | site=2, client=1, txn=7: Write([\"flights\"])\n\
+            2023-12-01T10:00:02.000000000Z | site=2, client=1, txn=7: COMMIT\n";
+        let site3 = "2023-12-01T10:00:02.500000000Z | site=3, client=1, txn=4: Begin Txn\n\
+            2023-12-01T10:00:03.500000000Z | site=3, client=1, txn=4: Write([\"flights\"])\n\
+            2023-12-01T10:00:04.500000000Z | site=3, client=1, txn=4: COMMIT\n";
+        let action_map = parse_history_files(&[("site1.history", site1), ("site2.history", site2), ("site3.history", site3)]);
+        let replication_id = action_map.get_all_transaction_ids().into_iter()
+            .find(|transaction_id| transaction_id.is_replication())
+            .unwrap();
+        let local_id = TransactionId(1, 1, 1);
+        let origin_id = TransactionId(2, 1, 7);
+        let remote_id = TransactionId(3, 1, 4);
+
+        let conflict_graph = build_conflict_graph(&action_map, true);
+
+        // the replication lands in the middle of the local transaction at site 1
+        assert!(!conflict_graph.get_conflict_vec(&local_id, &replication_id).unwrap().is_empty());
+        assert!(!conflict_graph.get_conflict_vec(&replication_id, &local_id).unwrap().is_empty());
+        // it applies the writes of the transaction that committed at site 2
+        assert!(!conflict_graph.get_conflict_vec(&origin_id, &replication_id).unwrap().is_empty());
+        // site 3 has its own copy of the table, so it never conflicts with site 1
+        assert!(conflict_graph.get_conflict_vec(&local_id, &remote_id).unwrap().is_empty());
+        assert!(conflict_graph.get_conflict_vec(&replication_id, &remote_id).unwrap().is_empty());
+
+        let conflicts = verify_conflict_graph(&conflict_graph, &action_map).unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+
+        // without the option, every site is treated as sharing one database
+        let global_graph = build_conflict_graph(&action_map, false);
+        assert!(!global_graph.get_conflict_vec(&local_id, &remote_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_without_replication_is_conflict_free() {
+        let history = include_str!("../fixtures/replication_conflict.history").lines()
+            .filter(|line| !line.contains("replication:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let action_map = parse_history(&history);
+
+        let conflict_graph = build_conflict_graph(&action_map, false);
+        assert!(verify_conflict_graph(&conflict_graph, &action_map).is_ok());
+    }
+
+    #[test]
+    fn single_stmt_transactions_are_well_formed() {
+        let history = include_str!("../fixtures/single_stmt_transactions.history");
+        let action_map = parse_history(history);
+
+        // each single statement transaction is wrapped in its own begin and commit or rollback
+        for transaction_id in action_map.get_all_transaction_ids() {
+            let actions = action_map.borrow_transaction(&transaction_id).unwrap();
+            assert_eq!(actions.first().unwrap().action, ActionKind::BeginTransaction, "{:?}", transaction_id);
+            assert!(matches!(actions.last().unwrap().action, ActionKind::CommitTransaction | ActionKind::RollbackTransaction), "{:?}", transaction_id);
+        }
+        assert!(action_map.get_dangling_transactions().is_empty());
+
+        let conflict_graph = build_conflict_graph(&action_map, false);
+        assert!(verify_conflict_graph(&conflict_graph, &action_map).is_ok());
+    }
+
+    #[test]
+    fn statistics_match_history() {
+        let history = include_str!("../fixtures/replication_conflict.history");
+        let action_map = parse_history(history);
+        let conflict_graph = build_conflict_graph(&action_map, false);
+
+        let statistics = ConflictStatistics::new(&conflict_graph, &action_map);
+        assert_eq!(statistics, ConflictStatistics {
+            transaction_count: 2,
+            cycle_transaction_count: 2,
+            read_write_count: 1,
+            write_read_count: 0,
+            write_write_count: 1,
+        });
+    }
+
+    /// One step of a site's workload, as the site server hands it to its history logger
+    enum Step {
+        Begin(u32, u32),
+        Query(u32, u32, &'static [&'static str], &'static [&'static str]),
+        Commit(u32, u32),
+    }
+
+    /// Runs a workload through a site's in-memory history logger and captures what it logged
+    fn record_workload(steps: &[Step]) -> MemoryHistory {
+        let tables = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let history = MemoryHistory::default();
+        let mut logger: Box<dyn HistoryLogger> = Box::new(MemoryHistoryLogger::new(history.clone()));
+        for step in steps {
+            match step {
+                Step::Begin(client, txn) => logger.log(*client, 1, *txn, "Begin Txn").unwrap(),
+                Step::Query(client, txn, read_set, write_set) => logger.log_query(*client, 1, *txn, &tables(write_set), &tables(read_set)).unwrap(),
+                Step::Commit(client, txn) => logger.log(*client, 1, *txn, "COMMIT").unwrap(),
+            }
+        }
+        history
+    }
+
+    fn verify_memory_history(history: &MemoryHistory) -> usize {
+        let mut actions = actions_from_records(history.records());
+        actions.sort_by_key(|action| action.instant);
+        let action_map = AssociatedActionMap::new().build(actions);
+        let conflict_graph = build_conflict_graph(&action_map, false);
+        verify_conflict_graph(&conflict_graph, &action_map)
+            .map_or_else(|conflicts| conflicts.len(), |_| 0)
+    }
+
+    #[test]
+    fn memory_history_of_conflicting_workload_is_not_serializable() {
+        // client 2 writes flights between client 1 reading and writing it
+        let history = record_workload(&[
+            Step::Begin(1, 1),
+            Step::Begin(2, 2),
+            Step::Query(1, 1, &["flights"], &[]),
+            Step::Query(2, 2, &[], &["flights"]),
+            Step::Query(1, 1, &[], &["flights"]),
+            Step::Commit(1, 1),
+            Step::Commit(2, 2),
+        ]);
+        assert_eq!(history.records().len(), 7);
+        assert_eq!(verify_memory_history(&history), 1);
+
+        // the same transactions run one after the other are fine
+        let history = record_workload(&[
+            Step::Begin(1, 1),
+            Step::Query(1, 1, &["flights"], &["flights"]),
+            Step::Commit(1, 1),
+            Step::Begin(2, 2),
+            Step::Query(2, 2, &["flights"], &["flights"]),
+            Step::Commit(2, 2),
+        ]);
+        assert_eq!(verify_memory_history(&history), 0);
+    }
+
+    #[test]
+    fn clock_skew_treats_nearby_actions_as_concurrent() {
+        // site 2's transaction starts just after site 1's commits, but the clocks could be off by a few
+        // milliseconds, so the writes could have happened in either order
+        let history = "2023-12-01T10:00:00.000000000Z | site=1, client=1, txn=1: Begin Txn\n\
+            2023-12-01T10:00:00.010000000Z | site=1, client=1, txn=1: Write([\"flights\"])\n\
+            2023-12-01T10:00:00.012000000Z | site=1, client=1, txn=1: COMMIT\n\
+            2023-12-01T10:00:00.013000000Z | site=2, client=1, txn=2: Begin Txn\n\
+            2023-12-01T10:00:00.014000000Z | site=2, client=1, txn=2: Write([\"flights\"])\n\
+            2023-12-01T10:00:00.020000000Z | site=2, client=1, txn=2: COMMIT\n";
+        let parse_actions = || {
+            let mut parser: ActionParser<Cursor<&str>> = ActionParser::new(Cursor::new(history));
+            std::iter::from_fn(|| parser.parse_next()).collect::<Vec<_>>()
+        };
+
+        let action_map = AssociatedActionMap::new().build(parse_actions());
+        let conflict_graph = build_conflict_graph(&action_map, false);
+        assert!(verify_conflict_graph(&conflict_graph, &action_map).is_ok());
+
+        let skewed_map = AssociatedActionMap::new()
+            .with_clock_skew(time::Duration::milliseconds(5))
+            .build(parse_actions());
+        assert_eq!(skewed_map.get_transaction_range(TransactionId(1, 1, 1)).len(), 5);
+        let skewed_graph = build_conflict_graph(&skewed_map, false);
+        assert!(!skewed_graph.get_conflict_vec(&TransactionId(1, 1, 1), &TransactionId(2, 1, 2)).unwrap().is_empty());
+        assert!(!skewed_graph.get_conflict_vec(&TransactionId(2, 1, 2), &TransactionId(1, 1, 1)).unwrap().is_empty());
+        assert_eq!(verify_conflict_graph(&skewed_graph, &skewed_map).unwrap_err().len(), 1);
+    }
+}
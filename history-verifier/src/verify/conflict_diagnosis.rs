@@ -5,6 +5,8 @@ use std::fmt::{Display, Formatter};
 use colored::{Color, Colorize};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
 use crate::history_file_parser::action::Action;
 use crate::organize::AssociatedActionMap;
 use crate::transaction_id::{TransactionId};
@@ -105,6 +107,35 @@ fn format_conflicts<ColorGetterT>(f: &mut Formatter<'_>, conflict_vector: &Confl
     Ok(())
 }
 
+/// A single step through the conflict cycle, as it appears in serialized diagnoses
+#[derive(Serialize)]
+struct ConflictStep<'diag, 'action> {
+    causing_transaction: &'diag TransactionId,
+    conflicted_transaction: &'diag TransactionId,
+    conflicts: &'diag ConflictVector<'action>,
+}
+
+impl<'action> Serialize for ConflictDiagnosis<'action> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut transactions = self.conflicting_transactions.iter().collect::<Vec<_>>();
+        transactions.sort();
+
+        let sequence = self.conflict_sequence.iter()
+            .map(|(causing, conflicted, conflicts)| ConflictStep {
+                causing_transaction: causing,
+                conflicted_transaction: conflicted,
+                conflicts,
+            })
+            .collect::<Vec<_>>();
+
+        let mut state = serializer.serialize_struct("ConflictDiagnosis", 3)?;
+        state.serialize_field("transactions", &transactions)?;
+        state.serialize_field("conflict_sequence", &sequence)?;
+        state.serialize_field("conflict_range", self.conflict_range)?;
+        state.end()
+    }
+}
+
 impl<'action> Display for ConflictDiagnosis<'action> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
 
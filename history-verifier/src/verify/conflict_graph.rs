@@ -1,14 +1,84 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::OnceLock;
+use rayon::prelude::*;
 use crate::verify::conflict_type::{ConflictEdge, ConflictType, ConflictVector};
-use crate::history_file_parser::action::ActionKind;
+use crate::history_file_parser::action::{Action, ActionKind};
 use crate::organize::AssociatedActionMap;
 use crate::transaction_id::TransactionId;
 
+/// Gets the read and write sets of an action, if it accesses any tables. Replications only write
+fn access_sets(action: &ActionKind) -> Option<(&HashSet<String>, &HashSet<String>)> {
+    static EMPTY_SET: OnceLock<HashSet<String>> = OnceLock::new();
+
+    match action {
+        ActionKind::Query { read_set, write_set } => Some((read_set, write_set)),
+        ActionKind::Replication { write_set, .. } => Some((EMPTY_SET.get_or_init(HashSet::new), write_set)),
+        _ => None,
+    }
+}
+
+/// Table names as they are compared, mapped to the name that was logged
+type NormalizedTableSet<'action> = HashMap<String, &'action String>;
+
+/// Normalizes a table name the same way SQLite resolves it: identifiers are case-insensitive and may be
+/// quoted with double quotes, backticks, or square brackets
+fn normalize_table_name(table_name: &str) -> String {
+    let table_name = table_name.trim();
+    let unquoted = [('"', '"'), ('`', '`'), ('[', ']')].into_iter()
+        .find_map(|(open, close)| table_name.strip_prefix(open).and_then(|name| name.strip_suffix(close)))
+        .unwrap_or(table_name);
+
+    unquoted.to_lowercase()
+}
+
+fn normalize_table_set(table_set: &HashSet<String>) -> NormalizedTableSet<'_> {
+    table_set.iter()
+        .map(|table_name| (normalize_table_name(table_name), table_name))
+        .collect()
+}
+
+/// Gets the normalized read and write sets of an action, if it accesses any tables
+fn normalized_access_sets(action: &ActionKind) -> Option<(NormalizedTableSet<'_>, NormalizedTableSet<'_>)> {
+    access_sets(action)
+        .map(|(read_set, write_set)| (normalize_table_set(read_set), normalize_table_set(write_set)))
+}
+
+/// Finds the tables in both sets, giving the names logged in `outer`
+fn overlapping_tables<'action>(outer: &NormalizedTableSet<'action>, inner: &NormalizedTableSet<'action>) -> HashSet<&'action String> {
+    outer.iter()
+        .filter(|(normalized, _)| inner.contains_key(*normalized))
+        .map(|(_, table_name)| *table_name)
+        .collect()
+}
+
+/// The site an action took effect at. Replications take effect where they were applied, which older
+/// histories didn't record
+fn action_site(action: &Action) -> Option<u32> {
+    match action.action {
+        ActionKind::Replication { destination_site, .. } => destination_site,
+        _ => Some(action.site_id),
+    }
+}
+
+/// True if both actions could have touched the same copy of the database
+fn at_same_site(left: &Action, right: &Action) -> bool {
+    match (action_site(left), action_site(right)) {
+        (Some(left_site), Some(right_site)) => left_site == right_site,
+        // without a destination, a replication has to be assumed to be anywhere
+        _ => true,
+    }
+}
+
 pub struct ConflictGraph<'action> {
     /// Maps a transaction to the node id
     node_ids: HashMap<TransactionId, usize>,
     /// the actual graph
     graph: Vec<Vec<ConflictVector<'action>>>,
+    /// model each replication as committing its originating transaction's writes at the site it was applied at
+    replication_as_commit: bool,
+    /// the cycles in the graph, found the first time they are asked for
+    cycles: OnceLock<Vec<Vec<TransactionId>>>,
 }
 
 impl<'action> ConflictGraph<'action> {
@@ -25,10 +95,20 @@ impl<'action> ConflictGraph<'action> {
 
         Self {
             node_ids: id_map,
-            graph: outer
+            graph: outer,
+            replication_as_commit: false,
+            cycles: OnceLock::new(),
         }
     }
 
+    /// Models each replication as its originating transaction committing at the replication's destination.
+    /// Actions only conflict if they happened at the same site, and each replication is ordered after the
+    /// transaction whose writes it applied
+    pub fn replication_as_commit(mut self, enabled: bool) -> Self {
+        self.replication_as_commit = enabled;
+        self
+    }
+
     fn add_edge(&mut self, causing: TransactionId, conflicting: TransactionId, edge: ConflictType<'action>) {
         let cause_id = self.node_ids.get(&causing).unwrap();
         let conflict_id = self.node_ids.get(&conflicting).unwrap();
@@ -62,63 +142,218 @@ impl<'action> ConflictGraph<'action> {
 
         transaction_ids.sort();
 
-        for outer_transaction_id in transaction_ids {
-            // get the instructions found in the range of this transaction
-            let transaction_range = actions_map.get_transaction_range(outer_transaction_id);
+        // edges caused by a transaction only depend on that transaction's range, so each transaction
+        // can be searched independently. Every cell is owned by a single causing transaction, so merging
+        // the results in order keeps the edges deterministic
+        let site_local = self.replication_as_commit;
+        let transaction_edges = transaction_ids.into_par_iter()
+            .map(|transaction_id| Self::find_edges(transaction_id, actions_map, site_local))
+            .collect::<Vec<_>>();
+
+        for (causing, edges) in transaction_edges {
+            for (conflicting, edge) in edges {
+                self.add_edge(causing, conflicting, edge);
+            }
+        }
 
-            let mut range_iter = transaction_range.into_iter();
-            'outer: while let Some(outer_action) = range_iter.next() {
+        if self.replication_as_commit {
+            for (origin, replication, edge) in Self::find_origin_edges(actions_map) {
+                self.add_edge(origin, replication, edge);
+            }
+        }
 
-                // Only look at actions in this range from this transaction
-                let outer_action_txn_id = TransactionId::from(outer_action);
-                if outer_transaction_id != outer_action_txn_id {
+        self
+    }
+
+    /// Finds all edges caused by the given transaction. If `site_local` is set, only actions at the same
+    /// site can conflict. Actions within the map's clock skew of each other are treated as possibly
+    /// concurrent, so they get edges in both directions
+    fn find_edges(outer_transaction_id: TransactionId, actions_map: &'action AssociatedActionMap, site_local: bool) -> (TransactionId, Vec<(TransactionId, ConflictType<'action>)>) {
+        let mut edges = Vec::new();
+
+        // get the instructions found in the range of this transaction
+        let transaction_range = if outer_transaction_id.is_replication() {
+            actions_map.get_replication_range(outer_transaction_id)
+        } else {
+            actions_map.get_transaction_range(outer_transaction_id)
+        };
+
+        // normalize every action's tables once up front rather than for every pair
+        let range_access_sets = transaction_range.iter()
+            .map(|action| normalized_access_sets(&action.action))
+            .collect::<Vec<_>>();
+
+        for (outer_idx, outer_action) in transaction_range.iter().enumerate() {
+
+            // Only look at actions in this range from this transaction
+            let outer_action_txn_id = TransactionId::from(outer_action);
+            if outer_transaction_id != outer_action_txn_id {
+                continue;
+            }
+
+            // get the information about this action
+            let Some((outer_read_set, outer_write_set)) = &range_access_sets[outer_idx] else {
+                continue;
+            };
+
+            // with clock skew, actions logged just before this one could have happened after it
+            let first_inner_idx = if actions_map.clock_skew().is_positive() { 0 } else { outer_idx + 1 };
+            for (inner_idx, inner_action) in transaction_range.iter().enumerate().skip(first_inner_idx) {
+                let inner_transaction_id = TransactionId::from(inner_action);
+
+                if inner_transaction_id == outer_transaction_id {
+                    // don't permit self-edges
                     continue;
                 }
 
-                // get the information about this action
-                let ActionKind::Query { read_set: outer_read_set, write_set: outer_write_set } = &outer_action.action else {
-                    continue 'outer;
+                // actions are only ordered after the outer action if they came later, unless they're close
+                // enough that clock skew could have put them in either order
+                if inner_idx <= outer_idx && !actions_map.within_clock_skew(outer_action, inner_action) {
+                    continue;
+                }
+
+                if site_local && !at_same_site(outer_action, inner_action) {
+                    continue;
+                }
+
+                let Some((inner_read_set, inner_write_set)) = &range_access_sets[inner_idx] else {
+                    continue;
                 };
 
-                'inner: for inner_action in range_iter.clone() {
-                    let inner_transaction_id = TransactionId::from(inner_action);
-
-                    if inner_transaction_id == outer_transaction_id {
-                        // don't permit self-edges
-                        continue;
-                    }
-
-                    let ActionKind::Query { read_set: inner_read_set, write_set: inner_write_set } = &inner_action.action else {
-                        continue 'inner;
-                    };
-
-                    //
-                    // check each overlap
-                    //
-
-                    let write_after_read_tables = outer_read_set.intersection(inner_write_set).collect::<HashSet<_>>();
-                    if !write_after_read_tables.is_empty() {
-                        let edge = ConflictType::ReadWrite(ConflictEdge::new(outer_action, inner_action, write_after_read_tables));
-                        self.add_edge(outer_transaction_id, inner_transaction_id, edge);
-                    }
-
-                    let read_after_write_tables = outer_write_set.intersection(inner_read_set).collect::<HashSet<_>>();
-                    if !read_after_write_tables.is_empty() {
-                        let edge = ConflictType::WriteRead(ConflictEdge::new(outer_action, inner_action, read_after_write_tables));
-                        self.add_edge(outer_transaction_id, inner_transaction_id, edge);
-                    }
-
-                    let write_after_write_tables = outer_write_set.intersection(inner_write_set).collect::<HashSet<_>>();
-                    if !write_after_write_tables.is_empty() {
-                        let edge = ConflictType::WriteWrite(ConflictEdge::new(outer_action, inner_action, write_after_write_tables));
-                        self.add_edge(outer_transaction_id, inner_transaction_id, edge);
-                    }
+                //
+                // check each overlap
+                //
+
+                let write_after_read_tables = overlapping_tables(outer_read_set, inner_write_set);
+                if !write_after_read_tables.is_empty() {
+                    let edge = ConflictType::ReadWrite(ConflictEdge::new(outer_action, inner_action, write_after_read_tables));
+                    edges.push((inner_transaction_id, edge));
+                }
+
+                let read_after_write_tables = overlapping_tables(outer_write_set, inner_read_set);
+                if !read_after_write_tables.is_empty() {
+                    let edge = ConflictType::WriteRead(ConflictEdge::new(outer_action, inner_action, read_after_write_tables));
+                    edges.push((inner_transaction_id, edge));
+                }
+
+                let write_after_write_tables = overlapping_tables(outer_write_set, inner_write_set);
+                if !write_after_write_tables.is_empty() {
+                    let edge = ConflictType::WriteWrite(ConflictEdge::new(outer_action, inner_action, write_after_write_tables));
+                    edges.push((inner_transaction_id, edge));
                 }
             }
         }
 
+        (outer_transaction_id, edges)
+    }
 
-        self
+    /// Links each replication to the transaction it replicated, which is taken to be the last transaction
+    /// at the originating site to commit before the replication that wrote every replicated table. The
+    /// originating transaction's writes are ordered before the replication's
+    fn find_origin_edges(actions_map: &'action AssociatedActionMap) -> Vec<(TransactionId, TransactionId, ConflictType<'action>)> {
+        let transactions = actions_map.get_all_transaction_ids().into_iter()
+            .filter(|transaction_id| !transaction_id.is_replication())
+            .map(|transaction_id| (transaction_id, actions_map.borrow_transaction(&transaction_id).unwrap()))
+            .filter(|(_, actions)| actions.last()
+                .is_some_and(|last| last.action != ActionKind::RollbackTransaction))
+            .collect::<Vec<_>>();
+
+        let mut edges = Vec::new();
+        for replication in actions_map.all_actions() {
+            let ActionKind::Replication { write_set, originating_site, .. } = &replication.action else {
+                continue;
+            };
+
+            let replicated_tables = normalize_table_set(write_set);
+            let origin = transactions.iter()
+                .filter(|(transaction_id, actions)| transaction_id.0 == *originating_site
+                    && actions.last().unwrap().instant <= replication.instant)
+                .filter(|(_, actions)| {
+                    let written_tables = actions.iter()
+                        .filter_map(|action| normalized_access_sets(&action.action))
+                        .flat_map(|(_, write_set)| write_set.into_keys())
+                        .collect::<HashSet<_>>();
+
+                    replicated_tables.keys().all(|table| written_tables.contains(table))
+                })
+                .max_by_key(|(_, actions)| actions.last().unwrap().instant);
+
+            let Some((origin_id, origin_actions)) = origin else {
+                continue;
+            };
+
+            for origin_action in origin_actions {
+                let Some((_, origin_write_set)) = normalized_access_sets(&origin_action.action) else {
+                    continue;
+                };
+
+                let tables = overlapping_tables(&origin_write_set, &replicated_tables);
+                if !tables.is_empty() {
+                    let edge = ConflictType::WriteWrite(ConflictEdge::new(origin_action, replication, tables));
+                    edges.push((*origin_id, TransactionId::from(replication), edge));
+                }
+            }
+        }
+
+        edges
+    }
+
+    /// Iterates over every conflict in the graph
+    pub fn conflicts(&self) -> impl Iterator<Item=&ConflictType<'action>> {
+        self.graph.iter()
+            .flatten()
+            .flatten()
+    }
+
+    /// Writes this graph as a Graphviz DOT digraph. Every pair of conflicting transactions gets one edge
+    /// labeled with each of its conflicts. Edges that are part of a cycle are colored red
+    pub fn to_dot(&self) -> String {
+        let cycle_edges = self.detect_cycles().iter()
+            .flat_map(|cycle| {
+                let next = cycle.iter().cycle().skip(1).take(cycle.len()).cloned().collect::<Vec<_>>();
+                cycle.iter().cloned().zip(next).collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+
+        let mut transaction_ids = self.node_ids.iter().collect::<Vec<_>>();
+        transaction_ids.sort();
+
+        let mut dot = String::from("digraph conflicts {\n");
+        for (transaction_id, _) in &transaction_ids {
+            dot.push_str(&format!("    \"{}\";\n", transaction_id));
+        }
+
+        for (causing_id, causing_idx) in &transaction_ids {
+            for (conflicting_id, conflicting_idx) in &transaction_ids {
+                let conflict_vector = &self.graph[**causing_idx][**conflicting_idx];
+                if conflict_vector.is_empty() {
+                    continue;
+                }
+
+                let label = conflict_vector.iter()
+                    .map(|conflict| {
+                        let mut tables = conflict.edge().conflicting_tables().iter()
+                            .map(|table| table.as_str())
+                            .collect::<Vec<_>>();
+                        tables.sort();
+                        format!("{} {}", conflict.short_name(), tables.join(","))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\\n")
+                    .replace('"', "\\\"");
+
+                let color = if cycle_edges.contains(&(**causing_id, **conflicting_id)) {
+                    ", color=red, fontcolor=red"
+                } else {
+                    ""
+                };
+
+                dot.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}\"{}];\n", causing_id, conflicting_id, label, color));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
     }
 
     fn dfs(
@@ -173,12 +408,201 @@ impl<'action> ConflictGraph<'action> {
         (cycles, reverse_lookup)
     }
 
-    pub fn detect_cycles(&self) -> Vec<Vec<TransactionId>> {
-        let (cycles, reverse_lookup) = self.find_cycles();
-        cycles.into_iter()
-            .map(|cycle| cycle.into_iter()
-                .map(|index| *reverse_lookup.get(&index).unwrap())
-                .collect::<Vec<_>>())
-            .collect::<Vec<_>>()
+    /// The cycles in the graph. The search only runs once, so checking a history, drawing it and summarizing
+    /// it all share the same cycles
+    pub fn detect_cycles(&self) -> &[Vec<TransactionId>] {
+        self.cycles.get_or_init(|| {
+            let (cycles, reverse_lookup) = self.find_cycles();
+            cycles.into_iter()
+                .map(|cycle| cycle.into_iter()
+                    .map(|index| *reverse_lookup.get(&index).unwrap())
+                    .collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        })
+    }
+
+    /// Every pair of transactions where the first has to come before the second
+    pub fn transaction_edges(&self) -> HashSet<(TransactionId, TransactionId)> {
+        self.conflicts()
+            .map(|conflict| {
+                let edge = conflict.edge();
+                (TransactionId::from(edge.causing_action()), TransactionId::from(edge.conflicted_action()))
+            })
+            .collect()
+    }
+
+    /// Topologically sorts the transactions so that every transaction comes after the ones it conflicts
+    /// with. Ties are broken by transaction id. Gives nothing if the graph has a cycle, since then the
+    /// history isn't equivalent to any serial order
+    pub fn serial_order(&self) -> Option<Vec<TransactionId>> {
+        topological_sort(self.node_ids.keys().copied(), self.transaction_edges(), |transaction_id| *transaction_id)
+    }
+}
+
+/// Kahn's algorithm over the given nodes and edges, always taking the available node with the smallest
+/// priority next. Gives nothing if there is a cycle
+pub(crate) fn topological_sort<NodeT, PriorityT>(nodes: impl Iterator<Item=NodeT>, edges: HashSet<(NodeT, NodeT)>, priority: impl Fn(&NodeT) -> PriorityT) -> Option<Vec<NodeT>>
+    where NodeT: Copy + Eq + std::hash::Hash + Ord,
+          PriorityT: Ord,
+{
+    let mut in_degrees = nodes.map(|node| (node, 0usize)).collect::<HashMap<_, _>>();
+    let mut successors: HashMap<NodeT, Vec<NodeT>> = HashMap::new();
+    for (from, to) in edges {
+        *in_degrees.get_mut(&to).unwrap() += 1;
+        successors.entry(from).or_default().push(to);
+    }
+
+    let mut available = in_degrees.iter()
+        .filter(|(_, in_degree)| **in_degree == 0)
+        .map(|(node, _)| Reverse((priority(node), *node)))
+        .collect::<BinaryHeap<_>>();
+
+    let mut order = Vec::with_capacity(in_degrees.len());
+    while let Some(Reverse((_, node))) = available.pop() {
+        order.push(node);
+        for successor in successors.remove(&node).unwrap_or_default() {
+            let in_degree = in_degrees.get_mut(&successor).unwrap();
+            *in_degree -= 1;
+            if *in_degree == 0 {
+                available.push(Reverse((priority(&successor), successor)));
+            }
+        }
+    }
+
+    if order.len() == in_degrees.len() {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::history_file_parser::action::{Action, ActionKind, make_action};
+    use crate::organize::AssociatedActionMap;
+    use crate::transaction_id::TransactionId;
+    use crate::verify::conflict_graph::{ConflictGraph, normalize_table_name};
+
+    /// Makes a history of pairs of interleaved transactions. Each pair touches its own tables, and the
+    /// second transaction in a pair reads what the first writes once it's done writing
+    fn make_synthetic_history(action_count: usize) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let mut second = 0i64;
+        let mut pair = 0u32;
+        while actions.len() < action_count {
+            let table = format!("table_{}", pair);
+            let other_table = format!("other_table_{}", pair);
+            let first = pair * 2;
+            let second_txn = pair * 2 + 1;
+
+            for txn in [first, second_txn] {
+                actions.push(make_action(second, txn, ActionKind::BeginTransaction));
+                second += 1;
+            }
+
+            for _ in 0..8 {
+                actions.push(make_action(second, first, ActionKind::Query { read_set: HashSet::new(), write_set: HashSet::from([table.clone()]) }));
+                second += 1;
+                actions.push(make_action(second, second_txn, ActionKind::Query { read_set: HashSet::from([other_table.clone()]), write_set: HashSet::new() }));
+                second += 1;
+            }
+
+            actions.push(make_action(second, second_txn, ActionKind::Query { read_set: HashSet::from([table.clone()]), write_set: HashSet::new() }));
+            second += 1;
+
+            for txn in [first, second_txn] {
+                actions.push(make_action(second, txn, ActionKind::CommitTransaction));
+                second += 1;
+            }
+
+            pair += 1;
+        }
+
+        actions
+    }
+
+    #[test]
+    fn build_handles_large_history() {
+        let action_map = AssociatedActionMap::new().build(make_synthetic_history(10_000));
+        let graph = ConflictGraph::new(action_map.get_all_transaction_ids())
+            .build(&action_map);
+
+        // every pair only conflicts in one direction, so there are edges but no cycles
+        let pair_ids = action_map.get_all_transaction_ids();
+        let first = pair_ids.iter().find(|id| id.2 == 0).unwrap();
+        let second = pair_ids.iter().find(|id| id.2 == 1).unwrap();
+        assert!(!graph.get_conflict_vec(first, second).unwrap().is_empty());
+        assert!(graph.get_conflict_vec(second, first).unwrap().is_empty());
+        assert!(graph.detect_cycles().is_empty());
+    }
+
+    #[test]
+    fn normalize_table_name_unquotes_and_lowercases() {
+        assert_eq!(normalize_table_name("Students"), "students");
+        assert_eq!(normalize_table_name("\"Students\""), "students");
+        assert_eq!(normalize_table_name("`STUDENTS`"), "students");
+        assert_eq!(normalize_table_name("[students]"), "students");
+    }
+
+    #[test]
+    fn case_differing_tables_conflict() {
+        let actions = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 2, ActionKind::BeginTransaction),
+            make_action(2, 1, ActionKind::Query { read_set: HashSet::from([String::from("Students")]), write_set: HashSet::new() }),
+            make_action(3, 2, ActionKind::Query { read_set: HashSet::new(), write_set: HashSet::from([String::from("\"students\"")]) }),
+            make_action(4, 1, ActionKind::CommitTransaction),
+            make_action(5, 2, ActionKind::CommitTransaction),
+        ];
+        let action_map = AssociatedActionMap::new().build(actions);
+        let graph = ConflictGraph::new(action_map.get_all_transaction_ids())
+            .build(&action_map);
+
+        let conflicts = graph.get_conflict_vec(&TransactionId(1, 1, 1), &TransactionId(1, 2, 2)).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].edge().conflicting_tables(), &HashSet::from([&String::from("Students")]));
+    }
+
+    #[test]
+    fn serial_order_follows_read_then_write() {
+        // transaction 0 has the lower id, but transaction 1 reads before it writes, so 1 has to come first
+        let actions = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 0, ActionKind::BeginTransaction),
+            make_action(2, 1, ActionKind::Query { read_set: HashSet::from([String::from("flights")]), write_set: HashSet::new() }),
+            make_action(3, 0, ActionKind::Query { read_set: HashSet::new(), write_set: HashSet::from([String::from("flights")]) }),
+            make_action(4, 1, ActionKind::CommitTransaction),
+            make_action(5, 0, ActionKind::CommitTransaction),
+            make_action(6, 2, ActionKind::BeginTransaction),
+            make_action(7, 2, ActionKind::Query { read_set: HashSet::from([String::from("seats")]), write_set: HashSet::new() }),
+            make_action(8, 2, ActionKind::CommitTransaction),
+        ];
+        let action_map = AssociatedActionMap::new().build(actions);
+        let graph = ConflictGraph::new(action_map.get_all_transaction_ids())
+            .build(&action_map);
+
+        assert_eq!(graph.serial_order(), Some(vec![TransactionId(1, 1, 1), TransactionId(1, 0, 0), TransactionId(1, 2, 2)]));
+    }
+
+    #[test]
+    fn cycles_are_found_once() {
+        // 2 writes flights between 1 reading and writing it
+        let actions = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 2, ActionKind::BeginTransaction),
+            make_action(2, 1, ActionKind::Query { read_set: HashSet::from([String::from("flights")]), write_set: HashSet::new() }),
+            make_action(3, 2, ActionKind::Query { read_set: HashSet::new(), write_set: HashSet::from([String::from("flights")]) }),
+            make_action(4, 1, ActionKind::Query { read_set: HashSet::new(), write_set: HashSet::from([String::from("flights")]) }),
+            make_action(5, 1, ActionKind::CommitTransaction),
+            make_action(6, 2, ActionKind::CommitTransaction),
+        ];
+        let action_map = AssociatedActionMap::new().build(actions);
+        let graph = ConflictGraph::new(action_map.get_all_transaction_ids())
+            .build(&action_map);
+
+        let cycles = graph.detect_cycles();
+        assert_eq!(cycles.len(), 1);
+        assert!(std::ptr::eq(cycles, graph.detect_cycles()));
     }
 }
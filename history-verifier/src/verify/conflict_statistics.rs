@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use crate::organize::AssociatedActionMap;
+use crate::verify::conflict_graph::ConflictGraph;
+use crate::verify::conflict_type::ConflictType;
+
+/// Summary of the conflicts found over a history
+#[derive(Debug, Default, PartialEq)]
+pub struct ConflictStatistics {
+    /// the number of transactions in the history
+    pub transaction_count: usize,
+    /// the number of transactions that are part of at least one cycle
+    pub cycle_transaction_count: usize,
+    pub read_write_count: usize,
+    pub write_read_count: usize,
+    pub write_write_count: usize,
+}
+
+impl ConflictStatistics {
+    pub fn new(conflict_graph: &ConflictGraph, associated_action_map: &AssociatedActionMap) -> Self {
+        let mut statistics = Self {
+            transaction_count: associated_action_map.get_all_transaction_ids().len(),
+            cycle_transaction_count: conflict_graph.detect_cycles().iter()
+                .flatten()
+                .collect::<HashSet<_>>()
+                .len(),
+            ..Default::default()
+        };
+
+        for conflict in conflict_graph.conflicts() {
+            match conflict {
+                ConflictType::ReadWrite(_) => statistics.read_write_count += 1,
+                ConflictType::WriteRead(_) => statistics.write_read_count += 1,
+                ConflictType::WriteWrite(_) => statistics.write_write_count += 1,
+            }
+        }
+
+        statistics
+    }
+}
+
+impl Display for ConflictStatistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Conflict Statistics:")?;
+        writeln!(f, "transactions: {}", self.transaction_count)?;
+        writeln!(f, "transactions in cycles: {}", self.cycle_transaction_count)?;
+        writeln!(f, "read-write conflicts: {}", self.read_write_count)?;
+        writeln!(f, "write-read conflicts: {}", self.write_read_count)?;
+        write!(f, "write-write conflicts: {}", self.write_write_count)
+    }
+}
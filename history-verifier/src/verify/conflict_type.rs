@@ -1,9 +1,10 @@
 use std::collections::HashSet;
 use std::fmt::{Display, Formatter, write};
 use colored::Colorize;
+use serde::Serialize;
 use crate::history_file_parser::action::Action;
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Serialize)]
 pub struct ConflictEdge<'action> {
     /// The action of the transaction causing the conflict
     pub causing_action: &'action Action,
@@ -41,13 +42,34 @@ impl<'action> Display for ConflictEdge<'action> {
     }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
 pub enum ConflictType<'action> {
     ReadWrite(ConflictEdge<'action>),
     WriteRead(ConflictEdge<'action>),
     WriteWrite(ConflictEdge<'action>),
 }
 
+impl<'action> ConflictType<'action> {
+    /// the edge that's in conflict
+    pub fn edge(&self) -> &ConflictEdge<'action> {
+        match self {
+            ConflictType::ReadWrite(edge) => edge,
+            ConflictType::WriteRead(edge) => edge,
+            ConflictType::WriteWrite(edge) => edge,
+        }
+    }
+
+    /// abbreviated name of the kind of conflict, like RW
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            ConflictType::ReadWrite(_) => "RW",
+            ConflictType::WriteRead(_) => "WR",
+            ConflictType::WriteWrite(_) => "WW",
+        }
+    }
+}
+
 impl<'action> Display for ConflictType<'action> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let (msg, edge) = match self {
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use time::OffsetDateTime;
+use time::format_description::well_known::Iso8601;
+use crate::history_file_parser::action::Action;
+use crate::transaction_id::TransactionId;
+
+/// Parses a window bound given on the command line
+pub fn parse_timestamp(value: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(value, &Iso8601::DEFAULT)
+        .map_err(|err| format!("invalid ISO-8601 timestamp: {}", err))
+}
+
+/// Only keeps transactions that overlap the window from `since` to `until`. A transaction that overlaps
+/// the window is kept whole, so transactions are never cut in half
+pub fn filter_to_window(actions: Vec<Action>, since: Option<OffsetDateTime>, until: Option<OffsetDateTime>) -> Vec<Action> {
+    if since.is_none() && until.is_none() {
+        return actions;
+    }
+
+    // find when each transaction starts and ends
+    let mut spans: HashMap<TransactionId, (OffsetDateTime, OffsetDateTime)> = HashMap::new();
+    for action in &actions {
+        let span = spans.entry(TransactionId::from(action))
+            .or_insert((action.instant, action.instant));
+        span.0 = span.0.min(action.instant);
+        span.1 = span.1.max(action.instant);
+    }
+
+    actions.into_iter()
+        .filter(|action| {
+            let (start, end) = spans[&TransactionId::from(action)];
+            let starts_before_until = match until {
+                Some(until) => start <= until,
+                None => true,
+            };
+            let ends_after_since = match since {
+                Some(since) => end >= since,
+                None => true,
+            };
+            starts_before_until && ends_after_since
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use time::OffsetDateTime;
+    use crate::history_file_parser::action::{ActionKind, make_action};
+    use crate::window::filter_to_window;
+
+    fn at(second: u64) -> OffsetDateTime {
+        OffsetDateTime::UNIX_EPOCH + Duration::from_secs(second)
+    }
+
+    #[test]
+    fn actions_outside_window_are_excluded() {
+        let actions = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 1, ActionKind::CommitTransaction),
+            make_action(5, 2, ActionKind::BeginTransaction),
+            make_action(10, 2, ActionKind::CommitTransaction),
+            make_action(20, 3, ActionKind::BeginTransaction),
+            make_action(21, 3, ActionKind::CommitTransaction),
+        ];
+
+        let filtered = filter_to_window(actions, Some(at(8)), Some(at(15)));
+
+        // transaction 2 overlaps the window, so all of it is kept
+        assert_eq!(filtered.len(), 2);
+        assert!(filtered.iter().all(|action| action.transaction_id == 2));
+    }
+
+    #[test]
+    fn open_ended_window() {
+        let actions = vec![
+            make_action(0, 1, ActionKind::BeginTransaction),
+            make_action(1, 1, ActionKind::CommitTransaction),
+            make_action(20, 3, ActionKind::BeginTransaction),
+            make_action(21, 3, ActionKind::CommitTransaction),
+        ];
+
+        let filtered = filter_to_window(actions, Some(at(10)), None);
+        assert!(filtered.iter().all(|action| action.transaction_id == 3));
+        assert_eq!(filtered.len(), 2);
+    }
+}
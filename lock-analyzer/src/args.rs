@@ -0,0 +1,16 @@
+use std::path::PathBuf;
+use clap::Parser;
+use sddms_shared::sql_metadata::LockGranularity;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// How finely to lock the data statements touch, either table or column
+    #[arg(long, default_value = "table")]
+    pub lock_granularity: LockGranularity,
+    /// Only list the conflicts, not every transaction's lock set
+    #[arg(long, default_value = "false")]
+    pub conflicts_only: bool,
+    /// Path to a transaction file, like the ones sql-trans-gen generates
+    pub workload_path: PathBuf,
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use sddms_shared::error::SddmsError;
+use sddms_shared::sql_metadata::{combine_metadata, LockGranularity, parse_lock_table_stmt, parse_statements, parse_transaction_stmt, split_sql_statements, split_stmts_into_transactions};
+
+/// Splits a transaction file into its statements. Lines that are only comments, like the `--txn N--`
+/// markers sql-trans-gen writes, are dropped so they don't end up as statements of their own
+pub fn split_workload(workload: &str) -> Result<Vec<String>, SddmsError> {
+    let buffer = workload.lines()
+        .filter(|line| !line.trim_start().starts_with("--"))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    split_sql_statements(&buffer)
+}
+
+/// Every lock a transaction takes over the course of running, as if it took them all up front. A resource
+/// locked both ways is only listed as exclusive
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransactionLockSet {
+    /// where the transaction is in the workload, starting from 0
+    pub index: usize,
+    pub shared: BTreeSet<String>,
+    pub exclusive: BTreeSet<String>,
+}
+
+impl TransactionLockSet {
+    pub fn new(index: usize, stmts: &[String], granularity: LockGranularity) -> Result<Self, SddmsError> {
+        let mut shared = BTreeSet::new();
+        let mut exclusive = BTreeSet::new();
+        let mut metadata = Vec::with_capacity(stmts.len());
+        for stmt in stmts {
+            if let Some(lock_table_stmt) = parse_lock_table_stmt(stmt) {
+                let lock_table_stmt = lock_table_stmt?;
+                if lock_table_stmt.exclusive {
+                    exclusive.extend(lock_table_stmt.tables);
+                } else {
+                    shared.extend(lock_table_stmt.tables);
+                }
+                continue;
+            }
+
+            // begin, commit, and rollback don't lock anything
+            if parse_transaction_stmt(stmt)?.is_some() {
+                continue;
+            }
+
+            let stmt_metadata = parse_statements(stmt)
+                .map_err(|err| SddmsError::general(format!("Failed to parse statement in transaction {}", index)).with_cause(err))?;
+            metadata.extend(stmt_metadata);
+        }
+
+        let lock_resources = combine_metadata(metadata).lock_resources(granularity);
+        shared.extend(lock_resources.shared);
+        exclusive.extend(lock_resources.exclusive);
+        shared.retain(|resource| !exclusive.contains(resource));
+
+        Ok(Self {
+            index,
+            shared,
+            exclusive,
+        })
+    }
+
+    fn locks(&self, resource: &String) -> bool {
+        self.shared.contains(resource) || self.exclusive.contains(resource)
+    }
+
+    /// The resources this transaction and the other can't both hold at once, because at least one of them
+    /// locks the resource exclusively
+    pub fn conflicting_resources(&self, other: &TransactionLockSet) -> BTreeSet<String> {
+        self.exclusive.iter()
+            .filter(|resource| other.locks(resource))
+            .chain(other.exclusive.iter().filter(|resource| self.locks(resource)))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Display for TransactionLockSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join = |resources: &BTreeSet<String>| resources.iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "txn {}: shared [{}] exclusive [{}]", self.index, join(&self.shared), join(&self.exclusive))
+    }
+}
+
+/// Two transactions in a workload that would contend for locks if they ran at the same time
+#[derive(Debug, PartialEq, Eq)]
+pub struct LockConflict {
+    pub first: usize,
+    pub second: usize,
+    /// the resources they contend for
+    pub resources: BTreeSet<String>,
+}
+
+impl Display for LockConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let resources = self.resources.iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "txn {} <-> txn {} on {}", self.first, self.second, resources)
+    }
+}
+
+/// Works out the lock set of every transaction in a workload
+pub fn workload_lock_sets(stmts: Vec<String>, granularity: LockGranularity) -> Result<Vec<TransactionLockSet>, SddmsError> {
+    split_stmts_into_transactions(stmts)?
+        .iter()
+        .enumerate()
+        .map(|(index, stmts)| TransactionLockSet::new(index, stmts, granularity))
+        .collect()
+}
+
+/// Finds every pair of transactions that would contend for locks, in workload order. Nothing is known
+/// about when transactions actually run, so any two that conflict are reported
+pub fn find_conflicts(lock_sets: &[TransactionLockSet]) -> Vec<LockConflict> {
+    let mut conflicts = Vec::new();
+    for (position, first) in lock_sets.iter().enumerate() {
+        for second in &lock_sets[position + 1..] {
+            let resources = first.conflicting_resources(second);
+            if !resources.is_empty() {
+                conflicts.push(LockConflict {
+                    first: first.index,
+                    second: second.index,
+                    resources,
+                });
+            }
+        }
+    }
+
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use sddms_shared::sql_metadata::LockGranularity;
+    use crate::lock_set::{find_conflicts, LockConflict, split_workload, workload_lock_sets};
+
+    #[test]
+    fn transactions_writing_the_same_table_conflict() {
+        let workload = "--txn 0--\nBEGIN TRANSACTION;\nSELECT * FROM bookings;\nUPDATE flights SET seats = 2;\nCOMMIT;\n\
+            --txn 1--\nINSERT INTO flights VALUES (1, 3);\n\
+            --txn 2--\nSELECT * FROM bookings;\n";
+        let lock_sets = workload_lock_sets(split_workload(workload).unwrap(), LockGranularity::Table).unwrap();
+        assert_eq!(lock_sets.len(), 3);
+        assert_eq!(lock_sets[0].shared, BTreeSet::from([String::from("bookings")]));
+        assert_eq!(lock_sets[0].exclusive, BTreeSet::from([String::from("flights")]));
+
+        // the two readers of bookings don't conflict with each other
+        assert_eq!(find_conflicts(&lock_sets), vec![LockConflict {
+            first: 0,
+            second: 1,
+            resources: BTreeSet::from([String::from("flights")]),
+        }]);
+    }
+
+    #[test]
+    fn lock_table_statements_add_to_the_lock_set() {
+        let workload = "BEGIN;\nLOCK TABLE flights IN SHARE MODE;\nSELECT * FROM flights;\nCOMMIT;\n\
+            BEGIN;\nLOCK TABLE flights;\nSELECT * FROM flights;\nCOMMIT;\n";
+        let lock_sets = workload_lock_sets(split_workload(workload).unwrap(), LockGranularity::Table).unwrap();
+        assert!(lock_sets[1].shared.is_empty());
+        assert_eq!(find_conflicts(&lock_sets).len(), 1);
+    }
+
+    #[test]
+    fn semicolons_in_strings_do_not_split_statements() {
+        let workload = "--txn 0--\nINSERT INTO notes VALUES ('a;b');\n--txn 1--\nSELECT * FROM notes WHERE body = 'a;b';\n";
+        assert_eq!(split_workload(workload).unwrap(), vec![
+            String::from("INSERT INTO notes VALUES ('a;b');"),
+            String::from("SELECT * FROM notes WHERE body = 'a;b';"),
+        ]);
+
+        let lock_sets = workload_lock_sets(split_workload(workload).unwrap(), LockGranularity::Table).unwrap();
+        assert_eq!(lock_sets.len(), 2);
+        assert_eq!(find_conflicts(&lock_sets).len(), 1);
+    }
+}
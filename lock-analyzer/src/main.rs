@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use clap::Parser;
+use crate::args::Args;
+use crate::lock_set::{find_conflicts, split_workload, workload_lock_sets};
+
+mod args;
+mod lock_set;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let workload = fs::read_to_string(&args.workload_path)?;
+    let lock_sets = workload_lock_sets(split_workload(&workload)?, args.lock_granularity)?;
+    let conflicts = find_conflicts(&lock_sets);
+
+    if !args.conflicts_only {
+        println!("Lock sets at {} granularity:", args.lock_granularity);
+        for lock_set in &lock_sets {
+            println!("{}", lock_set);
+        }
+        println!();
+    }
+
+    println!("Potential conflicts:");
+    for conflict in &conflicts {
+        println!("{}", conflict);
+    }
+    println!();
+
+    // the resources in the most conflicts are the likeliest to be contended when the workload runs
+    let mut resource_counts: BTreeMap<&String, usize> = BTreeMap::new();
+    for conflict in &conflicts {
+        for resource in &conflict.resources {
+            *resource_counts.entry(resource).or_default() += 1;
+        }
+    }
+    let mut resource_counts = resource_counts.into_iter().collect::<Vec<_>>();
+    resource_counts.sort_by(|(_, left_count), (_, right_count)| right_count.cmp(left_count));
+
+    println!("{} of {} transaction pairs may conflict", conflicts.len(), lock_sets.len() * lock_sets.len().saturating_sub(1) / 2);
+    for (resource, count) in resource_counts {
+        println!("{}: {} conflicts", resource, count);
+    }
+
+    Ok(())
+}
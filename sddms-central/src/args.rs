@@ -1,4 +1,8 @@
+use std::path::PathBuf;
+use std::time::Duration;
 use clap::Parser;
+use sddms_services::transport::TlsOptions;
+use sddms_central::connection_pool::DEFAULT_REPLICATION_PARALLELISM;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -6,4 +10,50 @@ pub struct Args {
     /// the port to host on
     #[arg(short, long, default_value = "50051")]
     pub port: u16,
+    /// if set, serve Prometheus metrics over HTTP at /metrics on this port
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+    /// how many times to retry replicating to a site before giving up on it
+    #[arg(long, default_value = "3")]
+    pub replication_retries: u32,
+    /// how many milliseconds a site gets to take a transaction's updates before it's treated as failed
+    #[arg(long, default_value = "10000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub replication_timeout_ms: u64,
+    /// how many sites to replicate to at once. 1 replicates to one site at a time
+    #[arg(long, default_value_t = DEFAULT_REPLICATION_PARALLELISM, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub replication_parallelism: usize,
+    /// if set, wait this many milliseconds for an apparent deadlock to clear before failing the transaction
+    #[arg(long)]
+    pub deadlock_grace_ms: Option<u64>,
+    /// file to persist transaction id counters in, so that ids aren't reused after a restart
+    #[arg(long)]
+    pub trans_id_file: Option<PathBuf>,
+    /// PEM certificate to serve with. Also used to replicate to sites over TLS
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM key for the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// PEM certificate of the CA that sites' certificates are checked against
+    #[arg(long, requires = "tls_cert")]
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn deadlock_grace(&self) -> Option<Duration> {
+        self.deadlock_grace_ms.map(Duration::from_millis)
+    }
+
+    pub fn replication_timeout(&self) -> Duration {
+        Duration::from_millis(self.replication_timeout_ms)
+    }
+
+    /// TLS is used when there's a certificate to serve with
+    pub fn tls_options(&self) -> Option<TlsOptions> {
+        let identity = self.tls_cert.clone().zip(self.tls_key.clone())?;
+        Some(TlsOptions {
+            identity: Some(identity),
+            ca_cert: self.ca_cert.clone(),
+        })
+    }
 }
@@ -1,28 +1,85 @@
-use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info, warn};
 use tonic::{Request, Response, Status};
 use sddms_services::central_controller::concurrency_controller_service_server::ConcurrencyControllerService;
-use sddms_services::central_controller::{AcquireLockRequest, AcquireLockResponse, AcquireLockResults, FinalizeTransactionRequest, FinalizeTransactionResponse, RegisterSiteRequest, RegisterSiteResponse, RegisterSiteResults, RegisterTransactionRequest, RegisterTransactionResponse, RegisterTransactionResults, ReleaseLockRequest, ReleaseLockResponse, ReleaseLockResults};
+use sddms_services::central_controller::{AcquireLockRequest, AcquireLockResponse, AcquireLockResults, FinalizeTransactionRequest, FinalizeTransactionResponse, RegisterSiteRequest, RegisterSiteResponse, RegisterSiteResults, RegisterTransactionRequest, RegisterTransactionResponse, RegisterTransactionResults, ReleaseLockRequest, ReleaseLockResponse, ReleaseLockResults, SubscribeEventsRequest, TransactionEventKind, UnregisterSiteRequest, UnregisterSiteResponse};
 use sddms_services::central_controller::acquire_lock_response::AcquireLockPayload;
 use sddms_services::central_controller::register_site_response::RegisterSitePayload;
 use sddms_services::central_controller::register_transaction_response::RegisterTransactionPayload;
 use sddms_services::central_controller::release_lock_response::ReleaseLockPayload;
-use sddms_services::shared::{ApiError, ReturnStatus};
+use sddms_services::shared::{ApiError, LockMode, LockRequest, ReturnStatus};
+use sddms_services::transport::TlsOptions;
+use sddms_shared::error::SddmsError;
 use crate::connection_pool::ConnectionPool;
-use crate::lock_table::{LockRequestResult, LockTable};
+use crate::lock_table::{LockRequestResult, LockTable, transaction_not_found};
+use crate::metrics::Metrics;
+use crate::transaction_events::{TransactionEvents, TransactionEventStream};
 use crate::transaction_id::{TransactionId, TransactionIdGenerator};
 
 pub struct CentralService {
     lock_tab: LockTable,
     connections: ConnectionPool,
     trans_id_gen: TransactionIdGenerator,
+    events: TransactionEvents,
+    metrics: Arc<Metrics>,
 }
 
 impl CentralService {
-    pub fn new() -> Self {
+    pub fn new(replication_retries: u32, trans_id_gen: TransactionIdGenerator, tls: Option<TlsOptions>) -> Self {
+        let metrics = Arc::new(Metrics::default());
         Self {
-            lock_tab: LockTable::new(),
-            connections: ConnectionPool::new(),
-            trans_id_gen: TransactionIdGenerator::new(),
+            lock_tab: LockTable::with_metrics(metrics.clone()),
+            connections: ConnectionPool::new(replication_retries, tls),
+            trans_id_gen,
+            events: TransactionEvents::new(),
+            metrics,
+        }
+    }
+
+    /// Sites that don't take a transaction's updates within the timeout are treated as failed
+    pub fn with_replication_timeout(mut self, timeout: Duration) -> Self {
+        self.connections = self.connections.with_replication_timeout(timeout);
+        self
+    }
+
+    /// Gives apparent deadlocks the grace period to clear up before a transaction is failed for one
+    pub fn with_deadlock_grace(mut self, grace: Duration) -> Self {
+        self.lock_tab = self.lock_tab.with_deadlock_grace(grace);
+        self
+    }
+
+    /// How many sites a transaction's updates are sent to at once
+    pub fn with_replication_parallelism(mut self, parallelism: usize) -> Self {
+        self.connections = self.connections.with_replication_parallelism(parallelism);
+        self
+    }
+
+    /// The counters this service keeps, for serving to a scraper
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Releases the locks of every transaction a site left behind, returning how many there were
+    async fn release_site_transactions(&self, site_id: u32) -> Result<usize, SddmsError> {
+        let abandoned_transactions = self.lock_tab.site_transactions(site_id).await;
+        for trans_id in &abandoned_transactions {
+            warn!("Releasing locks for transaction {} abandoned by site {}", trans_id, site_id);
+            self.lock_tab.release_all_locks(trans_id).await?;
+            self.lock_tab.remove_all_pending_requests(trans_id).await;
+            self.lock_tab.finalize_transaction(*trans_id).await?;
+            self.events.publish(TransactionEventKind::Finalized, *trans_id, format!("abandoned by site {}", site_id));
+        }
+
+        Ok(abandoned_transactions.len())
+    }
+
+    /// Lock modes come off the wire, so a request that never set one is rejected here before it reaches
+    /// the lock table, which assumes every mode is shared or exclusive
+    fn validate_lock_requests(lock_requests: &[LockRequest]) -> Result<(), SddmsError> {
+        match lock_requests.iter().find(|request| request.mode() == LockMode::Unspecified) {
+            Some(request) => Err(SddmsError::central(format!("Lock request for {} has no lock mode", request.record))),
+            None => Ok(()),
         }
     }
 
@@ -61,6 +118,7 @@ impl ConcurrencyControllerService for CentralService {
                 });
                 response.set_ret(ReturnStatus::Ok);
                 response.register_site_payload = Some(results);
+                self.metrics.site_registered();
                 info!("Successfully registered site {}:{} with id {}", register_site_request.host, register_site_request.port, site_id);
                 response
             }
@@ -76,7 +134,13 @@ impl ConcurrencyControllerService for CentralService {
     async fn register_transaction(&self, request: Request<RegisterTransactionRequest>) -> Result<Response<RegisterTransactionResponse>, Status> {
         let register_transaction_request = request.into_inner();
         info!("Registering transaction for site {}", register_transaction_request.site_id);
-        let trans_id = self.trans_id_gen.next_trans_id(register_transaction_request.site_id);
+        let trans_id = match self.trans_id_gen.next_trans_id(register_transaction_request.site_id) {
+            Ok(trans_id) => trans_id,
+            Err(err) => {
+                error!("Failed to allocate transaction id for site {}: {}", register_transaction_request.site_id, err);
+                return Ok(Response::new(RegisterTransactionResponse::from(err)));
+            }
+        };
 
         let register_transaction_result = self.lock_tab.register_transaction(trans_id)
             .await
@@ -95,6 +159,7 @@ impl ConcurrencyControllerService for CentralService {
         let mut response = RegisterTransactionResponse::default();
         response.set_ret(ReturnStatus::Ok);
         response.register_transaction_payload = Some(RegisterTransactionPayload::Results(results));
+        self.events.publish(TransactionEventKind::Registered, trans_id, "");
         info!("Successfully registered transaction for site {} with id {}", register_transaction_request.site_id, trans_id);
         Ok(Response::new(response))
     }
@@ -104,23 +169,55 @@ impl ConcurrencyControllerService for CentralService {
         let trans_id = TransactionId::new(acquire_lock_request.site_id, acquire_lock_request.transaction_id);
         info!("Transaction {} is trying to acquire locks: {:?}", trans_id, &acquire_lock_request.lock_requests);
 
-        let lock_result = self.lock_tab.acquire_locks(trans_id, acquire_lock_request.lock_requests.clone()).await;
+        if let Err(err) = Self::validate_lock_requests(&acquire_lock_request.lock_requests) {
+            error!("Rejecting lock request from {}: {}", trans_id, err);
+            return Ok(Response::new(AcquireLockResponse::from(err)));
+        }
+
+        let lock_requests = acquire_lock_request.lock_requests.clone();
+        let lock_result = if acquire_lock_request.ordered {
+            self.lock_tab.acquire_locks_in_order(trans_id, lock_requests, !acquire_lock_request.no_wait).await
+        } else if acquire_lock_request.no_wait {
+            self.lock_tab.try_acquire_locks(trans_id, lock_requests).await
+        } else {
+            self.lock_tab.acquire_locks(trans_id, lock_requests).await
+        };
 
         let response = match lock_result {
             Ok(result) => {
                 match result {
                     LockRequestResult::Deadlocked(cause) => {
                         info!("{} deadlocked: {}", trans_id, cause);
+                        self.events.publish(TransactionEventKind::Deadlocked, trans_id, cause.to_string());
                         let mut acquire_lock_response = AcquireLockResponse::default();
                         acquire_lock_response.set_ret(ReturnStatus::Deadlocked);
                         acquire_lock_response.acquire_lock_payload = Some(AcquireLockPayload::Error(ApiError::from(cause)));
                         acquire_lock_response
                     }
+                    LockRequestResult::TransactionNotFound => {
+                        let err = transaction_not_found(&trans_id);
+                        error!("Rejecting lock request: {}", err);
+                        let mut acquire_lock_response = AcquireLockResponse::from(err);
+                        acquire_lock_response.set_ret(ReturnStatus::TransactionNotFound);
+                        acquire_lock_response
+                    }
+                    LockRequestResult::WouldBlock => {
+                        info!("{} did not wait for locks {:?}", trans_id, &acquire_lock_request.lock_requests);
+                        let err = SddmsError::central(format!("Transaction {} would have to wait for locks {:?}", trans_id, &acquire_lock_request.lock_requests));
+                        let mut acquire_lock_response = AcquireLockResponse::from(err);
+                        acquire_lock_response.set_ret(ReturnStatus::WouldBlock);
+                        acquire_lock_response
+                    }
                     success => {
+                        let queue_position = match &success {
+                            LockRequestResult::AcquiredLock { queue_position } => *queue_position,
+                            _ => 0,
+                        };
                         let mut acquire_lock_response = AcquireLockResponse::default();
                         acquire_lock_response.set_ret(ReturnStatus::Ok);
-                        acquire_lock_response.acquire_lock_payload = Some(AcquireLockPayload::Results(AcquireLockResults { acquired: true }));
+                        acquire_lock_response.acquire_lock_payload = Some(AcquireLockPayload::Results(AcquireLockResults { acquired: true, queue_position }));
                         info!("{} successfully locked {:?} :: {}", trans_id, &acquire_lock_request.lock_requests, success);
+                        self.events.publish(TransactionEventKind::LockAcquired, trans_id, format!("{:?}", &acquire_lock_request.lock_requests));
                         acquire_lock_response
                     }
                 }
@@ -160,16 +257,23 @@ impl ConcurrencyControllerService for CentralService {
         let trans_id = TransactionId::new(finalize_request.site_id, finalize_request.transaction_id);
         info!("Transaction {} is finalizing itself", trans_id);
 
-        // send replication message to all sites
-        // TODO pull this block into its own function
-        let replication_error = self.connections.replicate_sites(&finalize_request.update_history, finalize_request.site_id)
+        // an unknown transaction has nothing to replicate or release, so stop before touching the lock table
+        if !self.lock_tab.transaction_exists(&trans_id).await {
+            let err = SddmsError::central(format!("Transaction {} is not live, so it cannot be finalized", trans_id));
+            error!("{}", err);
+            let mut response = FinalizeTransactionResponse::from(err);
+            response.set_ret(ReturnStatus::TransactionNotFound);
+            return Ok(Response::new(response));
+        }
+
+        // send replication message to all sites. The transaction is already committed at its own site, so
+        // it is still finalized if some sites can't be reached, but the failure is reported afterwards
+        let replication_failure = self.connections.replicate_sites(&finalize_request.update_history, trans_id, finalize_request.commit_timestamp_us)
             .await
             .err();
 
-        if let Some(rep_err) = replication_error {
-            error!("Error while replicating transaction: {}", rep_err);
-            let response = FinalizeTransactionResponse::from(rep_err);
-            return Ok(Response::new(response));
+        if let Some(rep_failure) = &replication_failure {
+            error!("Error while replicating transaction {}: {}", trans_id, rep_failure);
         }
 
         // Release all locks that this transaction currently holds
@@ -181,9 +285,19 @@ impl ConcurrencyControllerService for CentralService {
         let finalize_result = self.lock_tab.finalize_transaction(trans_id).await;
         match finalize_result {
             Ok(_) => {
+                if let Some(rep_failure) = replication_failure {
+                    info!("Finalized transaction {}, but it was not replicated everywhere", trans_id);
+                    self.events.publish(TransactionEventKind::Finalized, trans_id, format!("{}, not replicated everywhere", finalize_request.finalize_mode().as_str_name()));
+                    // the transaction is committed either way, so the site must not treat this as a failure
+                    let mut response = FinalizeTransactionResponse::from(SddmsError::from(rep_failure));
+                    response.set_ret(ReturnStatus::PartiallyReplicated);
+                    return Ok(Response::new(response));
+                }
+
                 let mut response = FinalizeTransactionResponse::default();
                 response.set_ret(ReturnStatus::Ok);
                 info!("Successfully finalized transaction {}", trans_id);
+                self.events.publish(TransactionEventKind::Finalized, trans_id, finalize_request.finalize_mode().as_str_name());
                 Ok(Response::new(response))
             }
             Err(err) => {
@@ -193,4 +307,230 @@ impl ConcurrencyControllerService for CentralService {
             }
         }
     }
+
+    async fn unregister_site(&self, request: Request<UnregisterSiteRequest>) -> Result<Response<UnregisterSiteResponse>, Status> {
+        let site_id = request.into_inner().site_id;
+        info!("Site {} is shutting down", site_id);
+
+        if !self.connections.unregister_site(site_id).await {
+            let err = SddmsError::central(format!("Site {} is not registered", site_id));
+            return Ok(Response::new(UnregisterSiteResponse::from(err)));
+        }
+        self.metrics.site_unregistered();
+
+        match self.release_site_transactions(site_id).await {
+            Ok(released_transactions) => {
+                let mut response = UnregisterSiteResponse::default();
+                response.set_ret(ReturnStatus::Ok);
+                response.released_transactions = released_transactions as u32;
+                info!("Unregistered site {}, releasing {} abandoned transactions", site_id, released_transactions);
+                Ok(Response::new(response))
+            }
+            Err(err) => {
+                error!("Error while releasing transactions for site {}: {}", site_id, err);
+                Ok(Response::new(UnregisterSiteResponse::from(err)))
+            }
+        }
+    }
+
+    type SubscribeEventsStream = TransactionEventStream;
+
+    async fn subscribe_events(&self, _request: Request<SubscribeEventsRequest>) -> Result<Response<Self::SubscribeEventsStream>, Status> {
+        info!("New transaction event subscriber");
+        Ok(Response::new(self.events.subscribe()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Request;
+    use tokio_stream::StreamExt;
+    use sddms_services::central_controller::{AcquireLockRequest, FinalizeTransactionRequest, RegisterTransactionRequest, SubscribeEventsRequest, TransactionEventKind};
+    use sddms_services::central_controller::acquire_lock_response::AcquireLockPayload;
+    use sddms_services::central_controller::register_transaction_response::RegisterTransactionPayload;
+    use sddms_services::central_controller::concurrency_controller_service_server::ConcurrencyControllerService;
+    use sddms_services::shared::{FinalizeMode, LockMode, LockRequest, ReturnStatus};
+    use sddms_services::central_controller::concurrency_controller_service_client::ConcurrencyControllerServiceClient;
+    use sddms_services::central_controller::concurrency_controller_service_server::ConcurrencyControllerServiceServer;
+    use sddms_services::transport;
+    use sddms_services::transport::TlsOptions;
+    use crate::central_service::CentralService;
+    use crate::transaction_id::TransactionIdGenerator;
+
+    /// Serves a central controller on a free local port, giving back its address
+    fn serve(tls: Option<TlsOptions>) -> String {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap()
+            .local_addr().unwrap()
+            .port();
+        let service = CentralService::new(0, TransactionIdGenerator::new(None).unwrap(), tls.clone());
+        let server = transport::server_builder(tls.as_ref()).unwrap()
+            .add_service(ConcurrencyControllerServiceServer::new(service));
+        tokio::spawn(server.serve(format!("127.0.0.1:{}", port).parse().unwrap()));
+        format!("localhost:{}", port)
+    }
+
+    /// Registers a transaction with the central controller at the given address, waiting for it to come up
+    async fn register_remote_transaction(addr: &str, tls: Option<&TlsOptions>) -> RegisterTransactionPayload {
+        let mut last_err = None;
+        for _ in 0..50 {
+            match transport::connect(addr, tls).await {
+                Ok(channel) => {
+                    let response = ConcurrencyControllerServiceClient::new(channel)
+                        .register_transaction(RegisterTransactionRequest { site_id: 1, ..Default::default() })
+                        .await
+                        .unwrap()
+                        .into_inner();
+                    return response.register_transaction_payload.unwrap();
+                }
+                Err(err) => last_err = Some(err.to_string()),
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        panic!("Could not connect to {}: {:?}", addr, last_err)
+    }
+
+    #[tokio::test]
+    async fn serves_over_plaintext_without_tls_options() {
+        let addr = serve(None);
+        let payload = register_remote_transaction(&addr, None).await;
+        assert!(matches!(payload, RegisterTransactionPayload::Results(_)));
+    }
+
+    #[test]
+    fn serving_over_tls_needs_a_certificate() {
+        let tls = TlsOptions::default();
+        assert!(transport::server_builder(Some(&tls)).is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_over_tls_with_self_signed_certs() {
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../sddms-services/fixtures/tls");
+        let tls = TlsOptions {
+            identity: Some((fixtures.join("node.pem"), fixtures.join("node.key"))),
+            ca_cert: Some(fixtures.join("ca.pem")),
+        };
+        let addr = serve(Some(tls.clone()));
+
+        let payload = register_remote_transaction(&addr, Some(&tls)).await;
+        assert!(matches!(payload, RegisterTransactionPayload::Results(_)));
+
+        // a plaintext client can't talk to it
+        let plaintext = transport::connect(&addr, None).await;
+        if let Ok(channel) = plaintext {
+            let response = ConcurrencyControllerServiceClient::new(channel)
+                .register_transaction(RegisterTransactionRequest { site_id: 1, ..Default::default() })
+                .await;
+            assert!(response.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn unspecified_lock_mode_is_rejected() {
+        let service = CentralService::new(0, TransactionIdGenerator::new(None).unwrap(), None);
+        let registration = service.register_transaction(Request::new(RegisterTransactionRequest { site_id: 1, ..Default::default() }))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(RegisterTransactionPayload::Results(results)) = registration.register_transaction_payload else {
+            panic!("expected the transaction to be registered");
+        };
+        let trans_id = results.trans_id;
+
+        let unspecified = LockRequest { record: String::from("flights"), mode: LockMode::Unspecified as i32 };
+        let response = service.acquire_lock(Request::new(AcquireLockRequest {
+            site_id: 1,
+            transaction_id: trans_id,
+            lock_requests: vec![LockRequest::new("airports", LockMode::Shared), unspecified],
+            ..Default::default()
+        })).await.unwrap().into_inner();
+
+        assert_eq!(response.ret(), ReturnStatus::Error);
+        let Some(AcquireLockPayload::Error(err)) = response.acquire_lock_payload else {
+            panic!("expected an error payload");
+        };
+        assert!(err.message.contains("flights"));
+
+        // nothing was locked, so the transaction can still lock normally
+        let response = service.acquire_lock(Request::new(AcquireLockRequest {
+            site_id: 1,
+            transaction_id: trans_id,
+            lock_requests: vec![LockRequest::new("flights", LockMode::Exclusive)],
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Ok);
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_registered_transactions() {
+        let service = CentralService::new(0, TransactionIdGenerator::new(None).unwrap(), None);
+        let mut events = service.subscribe_events(Request::new(SubscribeEventsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let registration = service.register_transaction(Request::new(RegisterTransactionRequest { site_id: 3, ..Default::default() }))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(RegisterTransactionPayload::Results(results)) = registration.register_transaction_payload else {
+            panic!("expected the transaction to be registered");
+        };
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), TransactionEventKind::Registered);
+        assert_eq!((event.site_id, event.transaction_id), (3, results.trans_id));
+
+        service.acquire_lock(Request::new(AcquireLockRequest {
+            site_id: 3,
+            transaction_id: results.trans_id,
+            lock_requests: vec![LockRequest::new("flights", LockMode::Shared)],
+            ..Default::default()
+        })).await.unwrap();
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), TransactionEventKind::LockAcquired);
+        assert!(event.detail.contains("flights"));
+    }
+
+    #[tokio::test]
+    async fn finalizing_an_unknown_transaction_is_rejected() {
+        let service = CentralService::new(0, TransactionIdGenerator::new(None).unwrap(), None);
+        let mut request = FinalizeTransactionRequest {
+            site_id: 2,
+            transaction_id: 42,
+            ..Default::default()
+        };
+        request.set_finalize_mode(FinalizeMode::Commit);
+
+        let response = service.finalize_transaction(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(response.ret(), ReturnStatus::TransactionNotFound);
+        let err = response.error.unwrap();
+        assert!(err.message.contains("Transaction 2:42 is not live"), "{}", err.message);
+    }
+
+    #[tokio::test]
+    async fn locking_for_an_unknown_transaction_reports_not_found() {
+        let service = CentralService::new(0, TransactionIdGenerator::new(None).unwrap(), None);
+        let response = service.acquire_lock(Request::new(AcquireLockRequest {
+            site_id: 2,
+            transaction_id: 42,
+            lock_requests: vec![LockRequest::new("flights", LockMode::Shared)],
+            ..Default::default()
+        }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // distinguishable from every other failure, which is reported as a plain error
+        assert_eq!(response.ret(), ReturnStatus::TransactionNotFound);
+        let Some(AcquireLockPayload::Error(err)) = response.acquire_lock_payload else {
+            panic!("expected an error payload");
+        };
+        assert!(err.message.contains("Transaction 2:42 doesn't exist"), "{}", err.message);
+    }
 }
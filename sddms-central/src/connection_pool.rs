@@ -1,26 +1,115 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 use std::sync::{Arc};
 use std::sync::atomic::{AtomicU32, Ordering};
-use sddms_shared::error::{SddmsError, SddmsTermError};
+use std::time::Duration;
+use futures::StreamExt;
+use log::warn;
+use sddms_services::transport::TlsOptions;
+use sddms_shared::error::SddmsError;
 use crate::site_client::SiteClient;
+use crate::transaction_id::TransactionId;
 
-pub struct ConnectionPool {
+/// How long to wait before the first replication retry. Each retry after that waits twice as long
+const INITIAL_REPLICATION_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How many sites are replicated to at once unless configured otherwise
+pub const DEFAULT_REPLICATION_PARALLELISM: usize = 8;
+
+/// Sends replication updates to a site
+#[tonic::async_trait]
+pub trait SiteReplicator: Send + Sync {
+    /// Sends the updates made by the given transaction, which ran at the site in its id and committed there
+    /// at the given time
+    async fn replicate(&self, connection_string: &str, update_history: &[String], trans_id: TransactionId, commit_timestamp_us: u64) -> Result<(), SddmsError>;
+}
+
+/// Replicates to sites over gRPC, using TLS if there are options for it
+pub struct GrpcSiteReplicator {
+    tls: Option<TlsOptions>,
+}
+
+#[tonic::async_trait]
+impl SiteReplicator for GrpcSiteReplicator {
+    async fn replicate(&self, connection_string: &str, update_history: &[String], trans_id: TransactionId, commit_timestamp_us: u64) -> Result<(), SddmsError> {
+        let mut connection = SiteClient::connect(connection_string, self.tls.as_ref())
+            .await?;
+
+        connection.replicate_updates(update_history, trans_id, commit_timestamp_us).await
+    }
+}
+
+/// The sites that still failed to replicate after every retry, with the last error from each. Errors are
+/// kept as messages so that the failure can be held across awaits
+#[derive(Debug)]
+pub struct ReplicationFailure {
+    pub failed_sites: HashMap<u32, String>,
+}
+
+impl Display for ReplicationFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut site_ids = self.failed_sites.keys().collect::<Vec<_>>();
+        site_ids.sort();
+        write!(f, "Failed to replicate to sites {:?}", site_ids)?;
+        for site_id in site_ids {
+            write!(f, "\n  site {}: {}", site_id, self.failed_sites[site_id])?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<ReplicationFailure> for SddmsError {
+    fn from(value: ReplicationFailure) -> Self {
+        SddmsError::central(value.to_string())
+    }
+}
+
+pub struct ConnectionPool<ReplicatorT: SiteReplicator = GrpcSiteReplicator> {
     /// map of connections
     connections: tokio::sync::Mutex<HashMap<u32, String>>,
     /// keep track of site ids
     site_ids: Arc<AtomicU32>,
+    /// how many times to retry replicating to a site before giving up on it
+    replication_retries: u32,
+    /// how long a site gets to take the updates, retries included, before it's treated as failed
+    replication_timeout: Option<Duration>,
+    /// how many sites are replicated to at once. 1 replicates to one site at a time, in site id order
+    replication_parallelism: usize,
+    /// sends the actual replication requests
+    replicator: ReplicatorT,
 }
 
 impl ConnectionPool {
-    pub fn new() -> Self {
+    pub fn new(replication_retries: u32, tls: Option<TlsOptions>) -> Self {
+        Self::with_replicator(replication_retries, GrpcSiteReplicator { tls })
+    }
+}
+
+impl<ReplicatorT: SiteReplicator> ConnectionPool<ReplicatorT> {
+    pub fn with_replicator(replication_retries: u32, replicator: ReplicatorT) -> Self {
         Self {
             connections: tokio::sync::Mutex::new(HashMap::new()),
             site_ids: Arc::new(AtomicU32::new(0)),
+            replication_retries,
+            replication_timeout: None,
+            replication_parallelism: DEFAULT_REPLICATION_PARALLELISM,
+            replicator,
         }
     }
 
+    pub fn with_replication_timeout(mut self, timeout: Duration) -> Self {
+        self.replication_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_replication_parallelism(mut self, parallelism: usize) -> Self {
+        self.replication_parallelism = parallelism.max(1);
+        self
+    }
+
     pub async fn register_site(&self, host: &str, port: u16) -> Result<u32, SddmsError> {
-        let conn_str = format!("http://{}:{}", host, port);
+        let conn_str = format!("{}:{}", host, port);
 
         let site_id = self.site_ids.fetch_add(1, Ordering::AcqRel);
         let mut conn_map = self.connections.lock().await;
@@ -28,19 +117,187 @@ impl ConnectionPool {
         Ok(site_id)
     }
 
-    pub async fn replicate_sites(&self, update_history: &[String], originating_site: u32) -> Result<(), SddmsTermError> {
-        let connection_pool = self.connections.lock().await;
-        for (site_id, connection_string) in connection_pool.iter() {
-            if site_id == &originating_site {
-                continue;
+    /// Stops replicating to the given site
+    pub async fn unregister_site(&self, site_id: u32) -> bool {
+        self.connections.lock().await.remove(&site_id).is_some()
+    }
+
+    /// Replicates the updates made by a transaction to every site besides the one it ran at, up to the configured number
+    /// of sites at a time. Each site is retried with backoff, and every site that still fails or runs
+    /// out of time is reported so the caller can decide what to do
+    pub async fn replicate_sites(&self, update_history: &[String], trans_id: TransactionId, commit_timestamp_us: u64) -> Result<(), ReplicationFailure> {
+        // don't hold the lock while backing off
+        let mut connections = self.connections.lock().await.iter()
+            .filter(|(site_id, _)| **site_id != trans_id.site_id)
+            .map(|(site_id, connection_string)| (*site_id, connection_string.clone()))
+            .collect::<Vec<_>>();
+        connections.sort();
+
+        let failed_sites = futures::stream::iter(connections)
+            .map(|(site_id, connection_string)| async move {
+                let replication = self.replicate_with_retries(site_id, &connection_string, update_history, trans_id, commit_timestamp_us);
+                let result = match self.replication_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, replication).await
+                        .unwrap_or_else(|_| Err(SddmsError::central(format!("Site did not take the updates within {:?}", timeout)))),
+                    None => replication.await,
+                };
+
+                result.err().map(|err| (site_id, err.to_string()))
+            })
+            .buffer_unordered(self.replication_parallelism)
+            .filter_map(|failure| async move { failure })
+            .collect::<HashMap<_, _>>()
+            .await;
+
+        if failed_sites.is_empty() {
+            Ok(())
+        } else {
+            Err(ReplicationFailure { failed_sites })
+        }
+    }
+
+    async fn replicate_with_retries(&self, site_id: u32, connection_string: &str, update_history: &[String], trans_id: TransactionId, commit_timestamp_us: u64) -> Result<(), SddmsError> {
+        let mut backoff = INITIAL_REPLICATION_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match self.replicator.replicate(connection_string, update_history, trans_id, commit_timestamp_us).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < self.replication_retries => {
+                    attempt += 1;
+                    warn!("Replicating to site {} failed, retrying in {:?} ({}/{}): {}", site_id, backoff, attempt, self.replication_retries, err);
+                }
+                Err(err) => return Err(err),
             }
 
-            let mut connection = SiteClient::connect(connection_string)
-                .await?;
+            // the error isn't Send, so it has to be dropped before waiting
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
 
-            connection.replicate_updates(update_history, originating_site).await?;
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use sddms_shared::error::SddmsError;
+    use crate::connection_pool::{ConnectionPool, SiteReplicator};
+    use crate::transaction_id::TransactionId;
+
+    /// Fails a set number of times for each site before succeeding
+    struct FlakySiteReplicator {
+        remaining_failures: Mutex<HashMap<String, u32>>,
+        attempts: Mutex<HashMap<String, u32>>,
+    }
+
+    impl FlakySiteReplicator {
+        fn new(failures: &[(&str, u32)]) -> Self {
+            Self {
+                remaining_failures: Mutex::new(failures.iter().map(|(host, count)| (host.to_string(), *count)).collect()),
+                attempts: Mutex::default(),
+            }
         }
+    }
 
-        Ok(())
+    #[tonic::async_trait]
+    impl SiteReplicator for FlakySiteReplicator {
+        async fn replicate(&self, connection_string: &str, _update_history: &[String], _trans_id: TransactionId, _commit_timestamp_us: u64) -> Result<(), SddmsError> {
+            *self.attempts.lock().unwrap().entry(connection_string.to_string()).or_default() += 1;
+            let mut remaining_failures = self.remaining_failures.lock().unwrap();
+            match remaining_failures.get_mut(connection_string) {
+                Some(remaining) if *remaining > 0 => {
+                    *remaining -= 1;
+                    Err(SddmsError::central("site is unreachable"))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn flaky_site_is_retried() {
+        let replicator = FlakySiteReplicator::new(&[("flaky:1", 2), ("down:2", 10)]);
+        let pool = ConnectionPool::with_replicator(3, replicator);
+        let origin = pool.register_site("origin", 0).await.unwrap();
+        pool.register_site("flaky", 1).await.unwrap();
+        let down = pool.register_site("down", 2).await.unwrap();
+
+        let failure = pool.replicate_sites(&[String::from("DELETE FROM flights;")], TransactionId::new(origin, 1), 0).await.unwrap_err();
+
+        // the flaky site eventually succeeds, but the one that is down runs out of retries
+        assert_eq!(failure.failed_sites.keys().collect::<Vec<_>>(), vec![&down]);
+        let attempts = pool.replicator.attempts.lock().unwrap();
+        assert_eq!(attempts["flaky:1"], 3);
+        assert_eq!(attempts["down:2"], 4);
+        assert!(!attempts.contains_key("origin:0"));
+    }
+
+    /// Never answers sites listed as hung, and takes the updates everywhere else
+    struct HungSiteReplicator {
+        hung: Vec<String>,
+    }
+
+    #[tonic::async_trait]
+    impl SiteReplicator for HungSiteReplicator {
+        async fn replicate(&self, connection_string: &str, _update_history: &[String], _trans_id: TransactionId, _commit_timestamp_us: u64) -> Result<(), SddmsError> {
+            if self.hung.iter().any(|host| host == connection_string) {
+                std::future::pending::<()>().await;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn hung_site_times_out() {
+        let replicator = HungSiteReplicator { hung: vec![String::from("hung:1")] };
+        let pool = ConnectionPool::with_replicator(3, replicator)
+            .with_replication_timeout(Duration::from_millis(50));
+        let origin = pool.register_site("origin", 0).await.unwrap();
+        let hung = pool.register_site("hung", 1).await.unwrap();
+        pool.register_site("healthy", 2).await.unwrap();
+
+        let updates = [String::from("UPDATE flights SET seats = 1;")];
+        let replication = pool.replicate_sites(&updates, TransactionId::new(origin, 1), 0);
+        let failure = tokio::time::timeout(Duration::from_secs(5), replication).await
+            .expect("replication blocked on the hung site")
+            .unwrap_err();
+
+        assert_eq!(failure.failed_sites.keys().collect::<Vec<_>>(), vec![&hung]);
+        assert!(failure.failed_sites[&hung].contains("within"));
+    }
+
+    /// Takes a while to answer every site
+    struct SlowSiteReplicator {
+        delay: Duration,
+    }
+
+    #[tonic::async_trait]
+    impl SiteReplicator for SlowSiteReplicator {
+        async fn replicate(&self, _connection_string: &str, _update_history: &[String], _trans_id: TransactionId, _commit_timestamp_us: u64) -> Result<(), SddmsError> {
+            tokio::time::sleep(self.delay).await;
+            Ok(())
+        }
+    }
+
+    async fn time_replication(parallelism: usize) -> Duration {
+        let pool = ConnectionPool::with_replicator(0, SlowSiteReplicator { delay: Duration::from_millis(200) })
+            .with_replication_parallelism(parallelism);
+        let origin = pool.register_site("origin", 0).await.unwrap();
+        for port in 1..=3 {
+            pool.register_site("site", port).await.unwrap();
+        }
+
+        let started = std::time::Instant::now();
+        pool.replicate_sites(&[String::from("UPDATE flights SET seats = 1;")], TransactionId::new(origin, 1), 0).await.unwrap();
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn sites_are_replicated_to_concurrently() {
+        // three sites at once take about as long as the slowest one
+        assert!(time_replication(3).await < Duration::from_millis(500));
+        // one at a time takes as long as all of them together
+        assert!(time_replication(1).await >= Duration::from_millis(600));
     }
 }
@@ -0,0 +1,8 @@
+pub mod central_service;
+pub mod lock_table;
+pub mod connection_pool;
+pub mod transaction_id;
+pub mod live_transaction_set;
+pub mod metrics;
+pub mod site_client;
+pub mod transaction_events;
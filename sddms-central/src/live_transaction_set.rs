@@ -42,6 +42,27 @@ impl LiveTransactionSet {
         Ok(())
     }
 
+    /// Gets every live transaction belonging to the given site
+    pub async fn site_transactions(&self, site_id: u32) -> Vec<TransactionId> {
+        let growing = self.growing.read().await;
+        let shrinking = self.shrinking.read().await;
+        growing.iter()
+            .chain(shrinking.iter())
+            .filter(|trans| trans.site_id == site_id)
+            .cloned()
+            .collect()
+    }
+
+    /// How many transactions are live, growing or shrinking
+    pub async fn len(&self) -> usize {
+        self.growing.read().await.len() + self.shrinking.read().await.len()
+    }
+
+    /// Whether no transactions are live
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
     pub async fn is_growing(&self, trans: &TransactionId) -> bool {
         self.growing.read().await.contains(trans)
     }
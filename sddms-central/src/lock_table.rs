@@ -4,7 +4,9 @@ mod deadlock_graph;
 
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{Display, Formatter};
-use log::{debug, info};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, info, warn};
 use tokio::sync::MutexGuard;
 use tokio::task::yield_now;
 use sddms_services::shared::{LockMode, LockRequest};
@@ -12,44 +14,84 @@ use sddms_shared::error::{SddmsError, SddmsTermError};
 use crate::live_transaction_set::LiveTransactionSet;
 use crate::lock_table::deadlock_graph::DeadlockGraph;
 use crate::lock_table::lock_queue_opt::optimize_lock_queue;
-use crate::lock_table::resource_lock::{ResourceLock};
+use crate::lock_table::resource_lock::{DisplayLockQueue, ResourceLock};
+use crate::metrics::Metrics;
 use crate::transaction_id::TransactionId;
 
+/// The error for a transaction the lock table doesn't know about
+pub fn transaction_not_found(transaction_id: &TransactionId) -> SddmsError {
+    SddmsError::central(format!("Transaction {} doesn't exist", transaction_id))
+}
+
 #[derive(Debug)]
 pub enum LockRequestResult {
     HadLock,
-    AcquiredLock,
+    /// the locks were acquired after waiting behind `queue_position` other locks at most
+    AcquiredLock { queue_position: u32 },
     PromotedLock,
     Deadlocked(SddmsTermError),
+    /// the transaction isn't live, so there's nothing to acquire locks for
+    TransactionNotFound,
+    /// the locks weren't free and the caller didn't want to wait for them. Nothing was acquired
+    WouldBlock,
 }
 
 impl Display for LockRequestResult {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             LockRequestResult::HadLock => f.write_str("already had lock"),
-            LockRequestResult::AcquiredLock => f.write_str("acquired lock"),
+            LockRequestResult::AcquiredLock { queue_position: 0 } => f.write_str("acquired lock"),
+            LockRequestResult::AcquiredLock { queue_position } => write!(f, "acquired lock after waiting behind {} locks", queue_position),
             LockRequestResult::PromotedLock => f.write_str("promoted lock to exclusive"),
             LockRequestResult::Deadlocked(deadlock_error) => write!(f, "{}", deadlock_error),
+            LockRequestResult::TransactionNotFound => f.write_str("transaction not found"),
+            LockRequestResult::WouldBlock => f.write_str("locks are held by another transaction"),
         }
     }
 }
 
+/// What a single call to `acquire_locks` has done so far, so that it can be undone
+#[derive(Default)]
+struct PartialAcquisition<'request> {
+    /// resources that were queued up for, and whether the transaction already held them beforehand
+    enqueued: Vec<(&'request str, bool)>,
+    /// resources whose shared lock was promoted to exclusive
+    promoted: Vec<&'request str>,
+}
+
 #[derive(Debug)]
 pub struct LockTable {
     /// table of resources to be locked
     resources: tokio::sync::Mutex<HashMap<String, VecDeque<ResourceLock>>>,
     /// set of transactions that are currently live
     live_transactions: LiveTransactionSet,
+    /// where lock and transaction activity is counted
+    metrics: Arc<Metrics>,
+    /// if set, how long to wait for an apparent deadlock to clear up before giving up on it
+    deadlock_grace: Option<Duration>,
 }
 
 impl LockTable {
     pub fn new() -> Self {
+        Self::with_metrics(Arc::default())
+    }
+
+    pub fn with_metrics(metrics: Arc<Metrics>) -> Self {
         Self {
             resources: tokio::sync::Mutex::default(),
             live_transactions: LiveTransactionSet::new(),
+            metrics,
+            deadlock_grace: None,
         }
     }
 
+    /// Instead of failing as soon as a lock request would cause a deadlock, waits for the given
+    /// grace period and checks again, in case the other transactions finish in the meantime
+    pub fn with_deadlock_grace(mut self, grace: Duration) -> Self {
+        self.deadlock_grace = Some(grace);
+        self
+    }
+
     async fn add_new_resource(&self, resource_name: &str) {
         let mut resources = self.resources.lock().await;
         if !resources.contains_key(resource_name) {
@@ -64,12 +106,21 @@ impl LockTable {
     }
     
     pub async fn register_transaction(&self, transaction_id: TransactionId) -> Result<(), SddmsError> {
-        self.live_transactions.register_transaction(transaction_id).await
+        self.live_transactions.register_transaction(transaction_id).await?;
+        self.metrics.set_live_transactions(self.live_transactions.len().await);
+        Ok(())
     }
 
     // removes any pending lock requests and remove the transaction from the live transaction set
     pub async fn finalize_transaction(&self, transaction_id: TransactionId) -> Result<(), SddmsError> {
-        self.live_transactions.remove(&transaction_id).await
+        self.live_transactions.remove(&transaction_id).await?;
+        self.metrics.set_live_transactions(self.live_transactions.len().await);
+        Ok(())
+    }
+
+    /// Gets the live transactions belonging to the given site
+    pub async fn site_transactions(&self, site_id: u32) -> Vec<TransactionId> {
+        self.live_transactions.site_transactions(site_id).await
     }
 
     pub async fn transaction_exists(&self, transaction_id: &TransactionId) -> bool {
@@ -79,7 +130,7 @@ impl LockTable {
     pub async fn lock_set(&self, transaction_id: &TransactionId) -> Result<HashSet<String>, SddmsError> {
         
         if !self.live_transactions.transaction_exists(&transaction_id).await {
-            return Err(SddmsError::central(format!("Transaction {} doesn't exist", transaction_id)))
+            return Err(transaction_not_found(transaction_id))
         }
         
         let resources = self.resources.lock().await;
@@ -125,7 +176,7 @@ impl LockTable {
     async fn attempt_lock_promotion(&self, transaction_id: &TransactionId, resource: &str, mode: LockMode) -> bool {
         let mut resources = self.resources.lock().await;
         let resource_queue = resources.get_mut(resource).unwrap();
-        debug!("{} queue before promotion: {:?}", resource, resource_queue);
+        debug!("{} queue before promotion: {}", resource, DisplayLockQueue(resource_queue));
         let front_lock = resource_queue.pop_front();
 
         match front_lock {
@@ -134,13 +185,21 @@ impl LockTable {
             }
             Some(front_lock) => {
                 if mode == LockMode::Exclusive && front_lock.is_locked_by_shared(transaction_id) {
-                    let (exclusive_lock, shared_lock) = front_lock.to_exclusive(transaction_id);
-                    if shared_lock.is_some() {
-                        resource_queue.push_front(shared_lock.unwrap());
+                    match front_lock.into_exclusive(transaction_id) {
+                        Ok((exclusive_lock, shared_lock)) => {
+                            if let Some(shared_lock) = shared_lock {
+                                resource_queue.push_front(shared_lock);
+                            }
+                            resource_queue.push_front(exclusive_lock);
+                            debug!("{} queue after promotion: {}", resource, DisplayLockQueue(resource_queue));
+                            true
+                        }
+                        Err((front_lock, err)) => {
+                            warn!("Failed to promote lock on {}: {}", resource, err);
+                            resource_queue.push_front(front_lock);
+                            false
+                        }
                     }
-                    resource_queue.push_front(exclusive_lock);
-                    debug!("{} queue after promotion: {:?}", resource, resource_queue);
-                    true
                 } else {
                     resource_queue.push_front(front_lock);
                     false
@@ -149,13 +208,64 @@ impl LockTable {
         }
     }
 
-    pub async fn acquire_locks(&self, transaction_id: TransactionId, mut requests: Vec<LockRequest>) -> Result<LockRequestResult, SddmsTermError> {
+    pub async fn acquire_locks(&self, transaction_id: TransactionId, requests: Vec<LockRequest>) -> Result<LockRequestResult, SddmsTermError> {
+        self.acquire_locks_with(transaction_id, requests, true, false).await
+    }
+
+    /// Acquires the locks only if they can all be had right away. Otherwise, nothing is held and
+    /// `WouldBlock` is returned
+    pub async fn try_acquire_locks(&self, transaction_id: TransactionId, requests: Vec<LockRequest>) -> Result<LockRequestResult, SddmsTermError> {
+        self.acquire_locks_with(transaction_id, requests, false, false).await
+    }
+
+    /// Acquires the locks one at a time in the order they're given, waiting for each before asking for
+    /// the next, instead of asking for them all at once in sorted order. Transactions that all lock in
+    /// the same order can't deadlock with each other
+    pub async fn acquire_locks_in_order(&self, transaction_id: TransactionId, requests: Vec<LockRequest>, wait: bool) -> Result<LockRequestResult, SddmsTermError> {
+        self.acquire_locks_with(transaction_id, requests, wait, true).await
+    }
+
+    /// Waits until the transaction is at the front of the queue of every requested resource. If `wait`
+    /// isn't set, this gives up and returns false unless it already is
+    async fn wait_for_locks(&self, transaction_id: &TransactionId, requests: &[LockRequest], wait: bool) -> bool {
+        loop {
+            let resources = self.resources.lock().await;
+            let holds_all = requests.iter()
+                .all(|request| resources.get(&request.record)
+                    .and_then(VecDeque::front)
+                    .is_some_and(|front_lock| front_lock.is_locked_by(transaction_id)));
+
+            if holds_all || !wait {
+                return holds_all;
+            }
+
+            // we are missing a lock, go back around again
+            drop(resources);
+            yield_now().await;
+        }
+    }
+
+    async fn acquire_locks_with(&self, transaction_id: TransactionId, mut requests: Vec<LockRequest>, wait: bool, ordered: bool) -> Result<LockRequestResult, SddmsTermError> {
+        if !self.live_transactions.transaction_exists(&transaction_id).await {
+            return Ok(LockRequestResult::TransactionNotFound)
+        }
+
         if !self.live_transactions.is_growing(&transaction_id).await {
             return Err(SddmsError::central(format!("Transaction {} is not growing, so it cannot acquire locks", transaction_id)).into())
         }
 
-        // sort from lowest to greatest, which means shared requests go first
-        requests.sort();
+        // sort from lowest to greatest, which means shared requests go first. Ordered requests are left as
+        // the caller gave them
+        if !ordered {
+            requests.sort();
+        }
+
+        // what this call has acquired so far. If we fail partway through, it's all backed out so
+        // that the transaction doesn't sit on a partial set of locks
+        let mut partial = PartialAcquisition::default();
+
+        // the furthest back in any resource's queue this transaction had to wait
+        let mut queue_position = 0;
 
         // for each lock request
         for request in &requests {
@@ -177,6 +287,7 @@ impl LockTable {
             // attempt promoting the lock
             let lock_promoted = self.attempt_lock_promotion(&transaction_id, resource, mode).await;
             if lock_promoted {
+                partial.promoted.push(resource);
                 info!("{} promoted its shared lock on {} to exclusive", transaction_id, resource);
                 // return Ok(LockRequestResult::PromotedLock)
                 continue
@@ -192,50 +303,51 @@ impl LockTable {
             //
             // In either of these cases, we need to enqueue our locking request.
 
-            // check if this will cause deadlock
-            let caused_deadlock = self.detect_deadlock(transaction_id, &resource).await;
+            // check if this will cause deadlock. Some cycles are broken quickly by another transaction
+            // finishing, so give those a chance to clear before failing
+            let mut caused_deadlock = self.detect_deadlock(transaction_id, resource).await;
+            if let (Some(_), Some(grace)) = (&caused_deadlock, self.deadlock_grace) {
+                info!("{}'s attempt to acquire {} lock on {} looks like a deadlock. Checking again in {:?}", transaction_id, mode, resource, grace);
+                tokio::time::sleep(grace).await;
+                caused_deadlock = self.detect_deadlock(transaction_id, resource).await;
+            }
             if let Some(deadlock_cause) = caused_deadlock {
                 info!("{}'s attempt to acquire {} lock on {} will cause deadlocking. Failing.", transaction_id, mode, resource);
+                self.metrics.deadlock_detected();
+                self.release_partial_acquisition(&transaction_id, &partial).await;
                 return Ok(LockRequestResult::Deadlocked(deadlock_cause));
             }
 
             // get in the queue for the given resource
-            self.enqueue_resource(transaction_id, resource, mode).await?;
+            let held_before = self.has_lock_already(&transaction_id, resource, LockMode::Shared).await;
+            let enqueue_result = self.enqueue_resource(transaction_id, resource, mode).await
+                .map_err(SddmsTermError::from);
+            if let Err(err) = enqueue_result {
+                self.release_partial_acquisition(&transaction_id, &partial).await;
+                return Err(err);
+            }
+            partial.enqueued.push((resource, held_before));
+            queue_position = queue_position.max(self.queue_position(&transaction_id, resource).await);
             info!("Transaction {} enqueued {:?} lock request for {}", transaction_id, mode, resource);
+
+            // in order, so this lock has to be held before the next one is asked for
+            if ordered && !self.wait_for_locks(&transaction_id, std::slice::from_ref(request), wait).await {
+                info!("{} would have to wait for its lock on {}, so it is giving up", transaction_id, resource);
+                self.release_partial_acquisition(&transaction_id, &partial).await;
+                return Ok(LockRequestResult::WouldBlock);
+            }
         }
 
         // wait until we are at the front of the queue for the given resource
-        let lock_result = loop {
-            let resources = self.resources.lock().await;
-
-            // check if we acquired all locks
-
-            let mut request_iter = requests.iter();
-            let lock_acquisition_attempt = 'check_loop: loop {
-                let request = request_iter.next();
-                if request.is_none() {
-                    // if we are out of requests to check, then we acquired all locks!
-                    break 'check_loop true;
-                }
-                let request = request.unwrap();
-                let resource = &request.record;
-
-                let resource_queue = resources.get(resource).unwrap();
-                let front_lock = resource_queue.front().unwrap();
-
-                // if we don't have one of the locks we want, fail now. Yield and continue
-                if !front_lock.is_locked_by(&transaction_id) {
-                    break 'check_loop false;
-                }
-            };
-
-            if lock_acquisition_attempt {
-                // we successfully acquired the lock, so we're done!
-                break LockRequestResult::AcquiredLock;
-            } else {
-                // we are missing a lock, go back around again
-                yield_now().await;
-            }
+        let lock_result = if self.wait_for_locks(&transaction_id, &requests, wait).await {
+            // we successfully acquired the lock, so we're done!
+            self.metrics.locks_acquired(requests.len());
+            LockRequestResult::AcquiredLock { queue_position }
+        } else {
+            // back out of every queue rather than wait our turn
+            info!("{} would have to wait for its locks, so it is giving up", transaction_id);
+            self.release_partial_acquisition(&transaction_id, &partial).await;
+            LockRequestResult::WouldBlock
         };
 
         // we got it finally
@@ -248,7 +360,7 @@ impl LockTable {
             let resource_vec = resources_table.get_mut(resource).unwrap();
 
             let resource_lock = resource_vec.front_mut();
-            // debug!("{} starting lock queue: {:?}", resource, resource_vec);
+            // debug!("{} starting lock queue: {}", resource, DisplayLockQueue(resource_vec));
 
             let lock = match resource_lock {
                 None => {
@@ -274,6 +386,7 @@ impl LockTable {
                     true
                 }
             };
+            lock.assert_consistent();
 
             if remove_lock {
                 resource_vec.pop_front();
@@ -315,6 +428,41 @@ impl LockTable {
         }
     }
 
+    /// Backs out whatever a single call to `acquire_locks` managed to do before it failed. Every queue
+    /// entry it made is removed, except for a lock the transaction already held at the front of the
+    /// queue before the call started, and every lock it promoted is demoted back to shared.
+    async fn release_partial_acquisition(&self, transaction_id: &TransactionId, partial: &PartialAcquisition<'_>) {
+        let mut resource_table = self.resources.lock().await;
+
+        for (resource, held_before) in &partial.enqueued {
+            let Some(lock_queue) = resource_table.get_mut(*resource) else {
+                continue;
+            };
+
+            let mut position = 0;
+            lock_queue.retain_mut(|resource_lock| {
+                let keep = (position == 0 && *held_before) || Self::remove_request_from_lock(resource_lock, transaction_id);
+                position += 1;
+                keep
+            });
+            debug!("{} lock queue after withdrawing {}: {}", resource, transaction_id, DisplayLockQueue(lock_queue));
+        }
+
+        for resource in &partial.promoted {
+            let Some((resource_name, mut lock_queue)) = resource_table.remove_entry(*resource) else {
+                continue;
+            };
+
+            if lock_queue.front().is_some_and(|front_lock| front_lock.is_locked_by_exclusive(transaction_id)) {
+                lock_queue.pop_front();
+                lock_queue.push_front(ResourceLock::shared(*transaction_id));
+                lock_queue = optimize_lock_queue(lock_queue);
+            }
+            debug!("{} lock queue after demoting {}: {}", resource, transaction_id, DisplayLockQueue(&lock_queue));
+            resource_table.insert(resource_name, lock_queue);
+        }
+    }
+
     // return true if should be retained, false otherwise
     fn remove_request_from_lock(lock: &mut ResourceLock, transaction_id: &TransactionId) -> bool {
         if lock.is_locked_by(transaction_id) {
@@ -332,6 +480,7 @@ impl LockTable {
                     if let Some(to_remove_idx) = order.iter().position(|id| id == transaction_id) {
                         order.remove(to_remove_idx);
                     }
+                    lock.assert_consistent();
 
                     // not ready to be deleted
                     true
@@ -359,14 +508,22 @@ impl LockTable {
         };
 
         resource_queue.push_back(lock);
-        debug!("{} lock queue after enqueueing: {:?}", resource, resource_queue);
+        debug!("{} lock queue after enqueueing: {}", resource, DisplayLockQueue(&resource_queue));
         resource_queue = optimize_lock_queue(resource_queue);
-        debug!("{} lock queue after optimizing: {:?}", resource, resource_queue);
+        debug!("{} lock queue after optimizing: {}", resource, DisplayLockQueue(&resource_queue));
         resource_table.insert(resource_name, resource_queue);
 
         Ok(())
     }
 
+    /// How many locks are ahead of the transaction's first entry in the resource's queue
+    async fn queue_position(&self, transaction_id: &TransactionId, resource: &str) -> u32 {
+        let resources = self.resources.lock().await;
+        resources.get(resource)
+            .and_then(|resource_queue| resource_queue.iter().position(|lock| lock.is_locked_by(transaction_id)))
+            .unwrap_or(0) as u32
+    }
+
     pub async fn detect_deadlock(&self, transaction_id: TransactionId, resource: &str) -> Option<SddmsTermError> {
         let resource_map = self.resources.lock().await;
 
@@ -407,3 +564,220 @@ impl LockTable {
         waiting_transactions
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use sddms_services::shared::{LockMode, LockRequest};
+    use crate::lock_table::{LockRequestResult, LockTable};
+    use crate::transaction_id::TransactionId;
+
+    fn exclusive(record: &str) -> LockRequest {
+        LockRequest { record: record.to_string(), mode: LockMode::Exclusive as i32 }
+    }
+
+    fn shared(record: &str) -> LockRequest {
+        LockRequest { record: record.to_string(), mode: LockMode::Shared as i32 }
+    }
+
+    async fn wait_until_queued(lock_table: &LockTable, transaction_id: &TransactionId, resource: &str) {
+        while !lock_table.resources.lock().await.get(resource)
+            .is_some_and(|queue| queue.iter().any(|lock| lock.is_locked_by(transaction_id))) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    async fn wait_until_holding(lock_table: &LockTable, transaction_id: &TransactionId, resource: &str) {
+        while !lock_table.has_resource(transaction_id, resource).await.unwrap() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn deadlock_during_multi_resource_acquisition_leaves_no_partial_locks() {
+        let lock_table = Arc::new(LockTable::new());
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(2, 1);
+        lock_table.register_transaction(first).await.unwrap();
+        lock_table.register_transaction(second).await.unwrap();
+
+        lock_table.acquire_locks(first, vec![exclusive("b")]).await.unwrap();
+        lock_table.acquire_locks(second, vec![exclusive("c")]).await.unwrap();
+
+        // first now waits on c, which second holds
+        let waiting_table = lock_table.clone();
+        tokio::spawn(async move {
+            let _ = waiting_table.acquire_locks(first, vec![exclusive("c")]).await;
+        });
+        wait_until_queued(&lock_table, &first, "c").await;
+
+        // a is free, but waiting on b closes the cycle
+        let result = lock_table.acquire_locks(second, vec![exclusive("a"), exclusive("b")]).await.unwrap();
+        assert!(matches!(result, LockRequestResult::Deadlocked(_)));
+
+        assert_eq!(lock_table.lock_set(&second).await.unwrap(), HashSet::from([String::from("c")]));
+        assert!(!lock_table.has_resource(&first, "a").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn deadlock_after_promotion_demotes_the_lock_again() {
+        let lock_table = Arc::new(LockTable::new());
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(2, 1);
+        lock_table.register_transaction(first).await.unwrap();
+        lock_table.register_transaction(second).await.unwrap();
+
+        lock_table.acquire_locks(first, vec![exclusive("b")]).await.unwrap();
+        lock_table.acquire_locks(second, vec![shared("a"), exclusive("c")]).await.unwrap();
+
+        let waiting_table = lock_table.clone();
+        tokio::spawn(async move {
+            let _ = waiting_table.acquire_locks(first, vec![exclusive("c")]).await;
+        });
+        wait_until_queued(&lock_table, &first, "c").await;
+
+        // a gets promoted before b deadlocks
+        let result = lock_table.acquire_locks(second, vec![exclusive("a"), exclusive("b")]).await.unwrap();
+        assert!(matches!(result, LockRequestResult::Deadlocked(_)));
+
+        assert_eq!(lock_table.lock_set(&second).await.unwrap(), HashSet::from([String::from("a"), String::from("c")]));
+        assert!(lock_table.has_lock_already(&second, "a", LockMode::Shared).await);
+        assert!(!lock_table.has_lock_already(&second, "a", LockMode::Exclusive).await);
+    }
+
+    #[tokio::test]
+    async fn acquiring_reports_the_furthest_queue_position() {
+        let lock_table = Arc::new(LockTable::new());
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(2, 1);
+        let third = TransactionId::new(3, 1);
+        for transaction in [first, second, third] {
+            lock_table.register_transaction(transaction).await.unwrap();
+        }
+
+        let result = lock_table.acquire_locks(first, vec![exclusive("a")]).await.unwrap();
+        assert!(matches!(result, LockRequestResult::AcquiredLock { queue_position: 0 }));
+
+        // second waits behind first on a
+        let waiting_table = lock_table.clone();
+        tokio::spawn(async move {
+            let _ = waiting_table.acquire_locks(second, vec![exclusive("a")]).await;
+        });
+        wait_until_queued(&lock_table, &second, "a").await;
+
+        // third is free to take b, but is two back on a
+        let waiting_table = lock_table.clone();
+        let third_acquisition = tokio::spawn(async move {
+            match waiting_table.acquire_locks(third, vec![exclusive("b"), exclusive("a")]).await {
+                Ok(LockRequestResult::AcquiredLock { queue_position }) => Some(queue_position),
+                _ => None,
+            }
+        });
+        wait_until_queued(&lock_table, &third, "a").await;
+
+        lock_table.release_all_locks(&first).await.unwrap();
+        wait_until_holding(&lock_table, &second, "a").await;
+        lock_table.release_all_locks(&second).await.unwrap();
+
+        let queue_position = tokio::time::timeout(std::time::Duration::from_secs(5), third_acquisition).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(queue_position, Some(2));
+    }
+
+    #[tokio::test]
+    async fn conflicting_try_lock_would_block() {
+        let lock_table = LockTable::new();
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(2, 1);
+        lock_table.register_transaction(first).await.unwrap();
+        lock_table.register_transaction(second).await.unwrap();
+
+        lock_table.acquire_locks(first, vec![exclusive("b")]).await.unwrap();
+
+        // a is free but b isn't, so neither is taken
+        let attempt = lock_table.try_acquire_locks(second, vec![exclusive("a"), shared("b")]);
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), attempt).await
+            .expect("try-lock waited for the lock")
+            .unwrap();
+        assert!(matches!(result, LockRequestResult::WouldBlock));
+        assert!(lock_table.lock_set(&second).await.unwrap().is_empty());
+
+        // with nothing in the way, it acquires like normal
+        lock_table.release_all_locks(&first).await.unwrap();
+        let result = lock_table.try_acquire_locks(second, vec![exclusive("a"), shared("b")]).await.unwrap();
+        assert!(matches!(result, LockRequestResult::AcquiredLock { .. }));
+    }
+
+    #[tokio::test]
+    async fn ordered_acquisition_waits_before_asking_for_the_next_lock() {
+        let lock_table = Arc::new(LockTable::new());
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(2, 1);
+        let third = TransactionId::new(3, 1);
+        for transaction_id in [first, second, third] {
+            lock_table.register_transaction(transaction_id).await.unwrap();
+        }
+
+        lock_table.acquire_locks(first, vec![exclusive("b")]).await.unwrap();
+
+        // asking for both at once, second would queue on a too. In order, it waits on b before it asks for a
+        let waiting_table = lock_table.clone();
+        let waiting = tokio::spawn(async move {
+            waiting_table.acquire_locks_in_order(second, vec![exclusive("b"), exclusive("a")], true).await.unwrap()
+        });
+        wait_until_queued(&lock_table, &second, "b").await;
+
+        // so a is still free for anyone else
+        let result = lock_table.try_acquire_locks(third, vec![exclusive("a")]).await.unwrap();
+        assert!(matches!(result, LockRequestResult::AcquiredLock { .. }));
+        assert!(!lock_table.has_resource(&second, "a").await.unwrap());
+
+        lock_table.release_all_locks(&first).await.unwrap();
+        lock_table.release_all_locks(&third).await.unwrap();
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), waiting).await
+            .expect("ordered acquisition never finished")
+            .unwrap();
+        assert!(matches!(result, LockRequestResult::AcquiredLock { .. }));
+        assert!(lock_table.has_resource(&second, "a").await.unwrap());
+        assert!(lock_table.has_resource(&second, "b").await.unwrap());
+    }
+
+    /// Sets up a cycle where `first` holds b and waits on c while `second` holds c, then has `second`
+    /// ask for b. `first` gives up everything shortly after
+    async fn transient_cycle(lock_table: LockTable) -> LockRequestResult {
+        let lock_table = Arc::new(lock_table);
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(2, 1);
+        lock_table.register_transaction(first).await.unwrap();
+        lock_table.register_transaction(second).await.unwrap();
+
+        lock_table.acquire_locks(first, vec![exclusive("b")]).await.unwrap();
+        lock_table.acquire_locks(second, vec![exclusive("c")]).await.unwrap();
+
+        let waiting_table = lock_table.clone();
+        tokio::spawn(async move {
+            let _ = waiting_table.acquire_locks(first, vec![exclusive("c")]).await;
+        });
+        wait_until_queued(&lock_table, &first, "c").await;
+
+        let aborting_table = lock_table.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            aborting_table.remove_all_pending_requests(&first).await;
+            aborting_table.release_all_locks(&first).await.unwrap();
+        });
+
+        lock_table.acquire_locks(second, vec![exclusive("b")]).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn cycle_resolved_within_grace_period_is_not_a_deadlock() {
+        let result = transient_cycle(LockTable::new()).await;
+        assert!(matches!(result, LockRequestResult::Deadlocked(_)));
+
+        let result = transient_cycle(LockTable::new().with_deadlock_grace(std::time::Duration::from_millis(500))).await;
+        assert!(matches!(result, LockRequestResult::AcquiredLock { .. }), "{}", result);
+    }
+}
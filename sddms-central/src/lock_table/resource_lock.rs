@@ -1,7 +1,14 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use log::warn;
 use sddms_services::shared::LockMode;
+use sddms_shared::error::SddmsError;
 use crate::transaction_id::TransactionId;
 
+/// The result of promoting a lock to exclusive. On failure, the lock is given back untouched along with
+/// why it couldn't be promoted
+pub type PromotionResult = Result<(ResourceLock, Option<ResourceLock>), (ResourceLock, SddmsError)>;
+
 pub enum AcquireLockMode {
     /// the resource currently has a shared lock, but the given transaction can make it exclusive
     CanPromoteToExclusive,
@@ -51,6 +58,15 @@ impl ResourceLock {
         }
     }
 
+    /// Checks that a shared lock's owners and order hold exactly the same transactions, each once. Only
+    /// checked in debug builds, so it's cheap to call after every mutation
+    pub fn assert_consistent(&self) {
+        if let ResourceLock::Shared { owners, order } = self {
+            debug_assert_eq!(owners.len(), order.len(), "shared lock owners and order differ in size: {:?}", self);
+            debug_assert!(order.iter().all(|id| owners.contains(id)), "shared lock order has a non-owner: {:?}", self);
+        }
+    }
+
     /// We can easily join two shared locks. Joins the current lock as the left lock with other as
     /// the right lock. The order between the two is preserved
     fn join_two_shared(self, other: Self) -> (Self, Option<Self>) {
@@ -68,7 +84,9 @@ impl ResourceLock {
 
         self_order.append(&mut other_order);
 
-        (Self::Shared { owners: self_owners, order: self_order }, None)
+        let joined = Self::Shared { owners: self_owners, order: self_order };
+        joined.assert_consistent();
+        (joined, None)
     }
 
     /// Try upgrading the left lock into an exclusive lock if the right lock is an exclusive lock
@@ -83,7 +101,13 @@ impl ResourceLock {
 
         // the shared lock can be split
         if self.is_first_locked_by(&owner) {
-            self.to_exclusive(&owner)
+            match self.into_exclusive(&owner) {
+                Ok(promoted) => promoted,
+                Err((lock, err)) => {
+                    warn!("Not upgrading enqueued lock: {}", err);
+                    (lock, Some(other))
+                }
+            }
         } else {
             (self, Some(other))
         }
@@ -102,26 +126,46 @@ impl ResourceLock {
         }
     }
 
-    pub fn to_exclusive(self, owner: &TransactionId) -> (Self, Option<Self>) {
+    /// Promotes the given owner's lock to exclusive. A shared lock is split into the exclusive lock and a
+    /// shared lock for whichever owners remain, which should come after it. Fails if the transaction doesn't
+    /// hold this lock
+    pub fn into_exclusive(self, owner: &TransactionId) -> PromotionResult {
         match self {
             ResourceLock::Shared {
                 mut owners,
                 mut order
             } => {
-                owners.remove(&owner);
-                let remove_idx = order.iter().position(|tid| tid == owner).unwrap();
-                order.remove(remove_idx);
+                if !owners.remove(owner) {
+                    let err = SddmsError::central(format!("Transaction {} cannot promote a shared lock it does not hold", owner));
+                    return Err((Self::Shared { owners, order }, err));
+                }
+
+                // owners and order should always agree, but the owner is already gone from the set so
+                // there's nothing to fix if it's missing from the order
+                match order.iter().position(|tid| tid == owner) {
+                    Some(remove_idx) => {
+                        order.remove(remove_idx);
+                    }
+                    None => warn!("Transaction {} owned a shared lock but was not in its lock order", owner),
+                }
 
                 let right = if !owners.is_empty() {
-                    Some(Self::Shared { order, owners })
+                    let right = Self::Shared { order, owners };
+                    right.assert_consistent();
+                    Some(right)
                 } else {
                     None
                 };
 
-                (Self::Exclusive { owner: *owner }, right)
+                Ok((Self::Exclusive { owner: *owner }, right))
             }
-            ResourceLock::Exclusive { owner } => {
-                (ResourceLock::Exclusive {owner}, None)
+            ResourceLock::Exclusive { owner: current_owner } => {
+                if current_owner == *owner {
+                    Ok((ResourceLock::Exclusive { owner: current_owner }, None))
+                } else {
+                    let err = SddmsError::central(format!("Transaction {} cannot promote a lock exclusively held by {}", owner, current_owner));
+                    Err((ResourceLock::Exclusive { owner: current_owner }, err))
+                }
             }
         }
     }
@@ -189,3 +233,155 @@ impl ResourceLock {
         }
     }
 }
+
+/// Shows the lock as `Excl(owner)` or `Shared[owners...]`, with shared owners in the order they locked
+impl Display for ResourceLock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceLock::Shared { order, .. } => {
+                let owners = order.iter()
+                    .map(|owner| owner.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                write!(f, "Shared[{}]", owners)
+            }
+            ResourceLock::Exclusive { owner } => write!(f, "Excl({})", owner),
+        }
+    }
+}
+
+/// Displays a resource's lock queue front to back
+pub struct DisplayLockQueue<'queue>(pub &'queue VecDeque<ResourceLock>);
+
+impl<'queue> Display for DisplayLockQueue<'queue> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let locks = self.0.iter()
+            .map(|lock| lock.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "[{}]", locks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashSet, VecDeque};
+    use crate::lock_table::resource_lock::{DisplayLockQueue, ResourceLock};
+    use crate::transaction_id::TransactionId;
+
+    fn shared_by(ids: &[TransactionId]) -> ResourceLock {
+        ResourceLock::Shared {
+            owners: ids.iter().copied().collect::<HashSet<_>>(),
+            order: ids.to_vec(),
+        }
+    }
+
+    #[test]
+    fn promoting_sole_owner_leaves_no_shared_lock() {
+        let owner = TransactionId::new(1, 1);
+        let (exclusive, remaining) = ResourceLock::shared(owner).into_exclusive(&owner).unwrap();
+
+        assert!(exclusive.is_locked_by_exclusive(&owner));
+        assert!(remaining.is_none());
+    }
+
+    #[test]
+    fn promoting_one_of_several_owners_keeps_the_rest_in_order() {
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(1, 2);
+        let third = TransactionId::new(2, 1);
+        let (exclusive, remaining) = shared_by(&[first, second, third]).into_exclusive(&second).unwrap();
+
+        assert!(exclusive.is_locked_by_exclusive(&second));
+        let remaining = remaining.unwrap();
+        assert_eq!(remaining.to_string(), "Shared[1:1,2:1]");
+        assert!(!remaining.is_locked_by(&second));
+    }
+
+    #[test]
+    fn promoting_already_exclusive_lock_keeps_it() {
+        let owner = TransactionId::new(1, 1);
+        let (exclusive, remaining) = ResourceLock::exclusive(owner).into_exclusive(&owner).unwrap();
+
+        assert!(exclusive.is_locked_by_exclusive(&owner));
+        assert!(remaining.is_none());
+    }
+
+    #[test]
+    fn promoting_without_holding_lock_gives_it_back() {
+        let owner = TransactionId::new(1, 1);
+        let stranger = TransactionId::new(3, 3);
+
+        let (shared, _) = shared_by(&[owner]).into_exclusive(&stranger).unwrap_err();
+        assert!(shared.is_locked_by_shared(&owner));
+
+        let (exclusive, _) = ResourceLock::exclusive(owner).into_exclusive(&stranger).unwrap_err();
+        assert!(exclusive.is_locked_by_exclusive(&owner));
+    }
+
+    #[test]
+    fn promoting_tolerates_owner_missing_from_order() {
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(1, 2);
+        let inconsistent = ResourceLock::Shared {
+            owners: HashSet::from([first, second]),
+            order: vec![first],
+        };
+
+        let (exclusive, remaining) = inconsistent.into_exclusive(&second).unwrap();
+        assert!(exclusive.is_locked_by_exclusive(&second));
+        assert_eq!(remaining.unwrap().to_string(), "Shared[1:1]");
+    }
+
+    #[test]
+    fn joining_and_promoting_keeps_shared_locks_consistent() {
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(1, 2);
+        let third = TransactionId::new(2, 1);
+
+        let (joined, rest) = ResourceLock::shared(first).try_join_with(ResourceLock::shared(second));
+        assert!(rest.is_none());
+        joined.assert_consistent();
+
+        let (joined, rest) = joined.try_join_with(ResourceLock::shared(third));
+        assert!(rest.is_none());
+        joined.assert_consistent();
+
+        // the first owner asking for an exclusive lock splits it off the front
+        let (exclusive, remaining) = joined.try_join_with(ResourceLock::exclusive(first));
+        exclusive.assert_consistent();
+        let remaining = remaining.unwrap();
+        remaining.assert_consistent();
+        assert_eq!(remaining.to_string(), "Shared[1:2,2:1]");
+
+        let (exclusive, remaining) = remaining.into_exclusive(&third).unwrap();
+        exclusive.assert_consistent();
+        let remaining = remaining.unwrap();
+        remaining.assert_consistent();
+        assert_eq!(remaining.to_string(), "Shared[1:2]");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "differ in size")]
+    fn inconsistent_shared_lock_is_caught() {
+        let first = TransactionId::new(1, 1);
+        let second = TransactionId::new(1, 2);
+        ResourceLock::Shared {
+            owners: HashSet::from([first, second]),
+            order: vec![first],
+        }.assert_consistent();
+    }
+
+    #[test]
+    fn displays_owners_in_lock_order() {
+        let exclusive = ResourceLock::exclusive(TransactionId::new(3, 1));
+        let (shared, _) = ResourceLock::shared(TransactionId::new(3, 2))
+            .try_join_with(ResourceLock::shared(TransactionId::new(3, 1)));
+
+        assert_eq!(exclusive.to_string(), "Excl(3:1)");
+        assert_eq!(shared.to_string(), "Shared[3:2,3:1]");
+        assert_eq!(DisplayLockQueue(&VecDeque::from([exclusive, shared])).to_string(), "[Excl(3:1), Shared[3:2,3:1]]");
+        assert_eq!(DisplayLockQueue(&VecDeque::new()).to_string(), "[]");
+    }
+}
@@ -1,20 +1,16 @@
 mod args;
-mod central_service;
-mod lock_table;
-mod connection_pool;
-mod transaction_id;
-mod live_transaction_set;
-mod site_client;
 
 use std::error::Error;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use clap::Parser;
-use log::{info, LevelFilter};
-use tonic::transport::Server;
+use log::{error, info, LevelFilter};
 use sddms_services::central_controller::concurrency_controller_service_server::ConcurrencyControllerServiceServer;
+use sddms_services::transport;
 use sddms_shared::error::SddmsError;
+use sddms_central::central_service::CentralService;
+use sddms_central::metrics;
+use sddms_central::transaction_id::TransactionIdGenerator;
 use crate::args::Args;
-use crate::central_service::CentralService;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -27,14 +23,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
     info!("Setting up central controller on 0.0.0.0:{}...", args.port);
-    let service = CentralService::new();
+    let tls = args.tls_options();
+    let trans_id_gen = TransactionIdGenerator::new(args.trans_id_file.clone())?;
+    let mut service = CentralService::new(args.replication_retries, trans_id_gen, tls.clone())
+        .with_replication_timeout(args.replication_timeout())
+        .with_replication_parallelism(args.replication_parallelism);
+    if let Some(deadlock_grace) = args.deadlock_grace() {
+        service = service.with_deadlock_grace(deadlock_grace);
+    }
+    if let Some(metrics_port) = args.metrics_port {
+        let metrics_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0)), metrics_port);
+        let metrics = service.metrics();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr, metrics).await {
+                error!("Metrics server stopped: {}", err);
+            }
+        });
+    }
     let server = ConcurrencyControllerServiceServer::new(service);
     info!("Server is initialized");
 
     let serve_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0)), args.port);
-    Server::builder()
+    if tls.is_some() {
+        info!("Serving over TLS");
+    }
+    transport::server_builder(tls.as_ref())?
         .add_service(server)
-        .serve(serve_addr)
+        .serve_with_shutdown(serve_addr, async {
+            tokio::signal::ctrl_c().await.ok();
+            info!("Shutting down central controller...");
+        })
         .await
         .map_err(|err| SddmsError::site("Error while starting server").with_cause(err))?;
 
@@ -0,0 +1,150 @@
+use std::fmt::Write as FmtWrite;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::{error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use sddms_shared::error::SddmsError;
+
+/// the most of a scrape request that is read before answering it
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Operational counters for the central controller, exposed in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registered_sites: AtomicU64,
+    live_transactions: AtomicU64,
+    deadlocks: AtomicU64,
+    lock_acquisitions: AtomicU64,
+}
+
+impl Metrics {
+    pub fn site_registered(&self) {
+        self.registered_sites.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn site_unregistered(&self) {
+        // a site can only unregister after registering, but never wrap around if that changes
+        let _ = self.registered_sites.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sites| sites.checked_sub(1));
+    }
+
+    pub fn set_live_transactions(&self, count: usize) {
+        self.live_transactions.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn deadlock_detected(&self) {
+        self.deadlocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn locks_acquired(&self, count: usize) {
+        self.lock_acquisitions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("sddms_registered_sites", "gauge", "Sites currently registered with the central controller", &self.registered_sites),
+            ("sddms_live_transactions", "gauge", "Transactions that have not been finalized yet", &self.live_transactions),
+            ("sddms_deadlocks_total", "counter", "Lock requests refused because they would deadlock", &self.deadlocks),
+            ("sddms_lock_acquisitions_total", "counter", "Locks granted to transactions", &self.lock_acquisitions),
+        ];
+
+        let mut rendered = String::new();
+        for (name, kind, help, value) in metrics {
+            // writing to a string never fails
+            let _ = write!(rendered, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {}\n", value.load(Ordering::Relaxed));
+        }
+
+        rendered
+    }
+}
+
+/// Serves the metrics over plain HTTP at `/metrics` until the task is dropped
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), SddmsError> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|err| SddmsError::central(format!("Failed to bind metrics server to {}", addr)).with_cause(err))?;
+    info!("Serving metrics on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept()
+            .await
+            .map_err(|err| SddmsError::central("Failed to accept metrics connection").with_cause(err))?;
+
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &metrics).await {
+                error!("Failed to serve metrics to {}: {}", peer, err);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::metrics::{Metrics, serve};
+
+    #[tokio::test]
+    async fn scrape_returns_every_metric() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.site_registered();
+        metrics.site_registered();
+        metrics.site_unregistered();
+        metrics.set_live_transactions(3);
+        metrics.deadlock_detected();
+        metrics.locks_acquired(4);
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(async move { serve(addr, metrics).await.ok() });
+
+        let mut stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+            }
+        };
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        for line in ["sddms_registered_sites 1", "sddms_live_transactions 3", "sddms_deadlocks_total 1", "sddms_lock_acquisitions_total 4"] {
+            assert!(response.lines().any(|response_line| response_line == line), "missing {} in {}", line, response);
+        }
+        assert!(response.contains("# TYPE sddms_deadlocks_total counter"));
+    }
+}
@@ -1,27 +1,33 @@
 use tonic::transport::Channel;
 use sddms_services::site_controller::ReplicationUpdateRequest;
 use sddms_services::site_controller::site_manager_service_client::SiteManagerServiceClient;
+use sddms_services::transport;
+use sddms_services::transport::TlsOptions;
 use sddms_shared::error::SddmsError;
+use crate::transaction_id::TransactionId;
 
 pub struct SiteClient {
     client: SiteManagerServiceClient<Channel>
 }
 
 impl SiteClient {
-    pub async fn connect<ConnStrT: Into<String>>(connection_str: ConnStrT) -> Result<Self, SddmsError> {
-        let client = SiteManagerServiceClient::connect(connection_str.into())
+    /// Connects to the site at `<host>:<port>`
+    pub async fn connect(connection_str: &str, tls: Option<&TlsOptions>) -> Result<Self, SddmsError> {
+        let channel = transport::connect(connection_str, tls)
             .await
-            .map_err(|err| SddmsError::site("Failed to connect to central site").with_cause(err))?;
+            .map_err(|err| SddmsError::central("Failed to connect to site").with_cause(err))?;
 
         Ok(Self {
-            client
+            client: SiteManagerServiceClient::new(channel)
         })
     }
 
-    pub async fn replicate_updates(&mut self, updates: &[String], originating_site: u32) -> Result<(), SddmsError> {
+    pub async fn replicate_updates(&mut self, updates: &[String], trans_id: TransactionId, commit_timestamp_us: u64) -> Result<(), SddmsError> {
         let replication_update_request = ReplicationUpdateRequest {
             update_statements: updates.clone().to_vec(),
-            originating_site,
+            originating_site: trans_id.site_id,
+            transaction_id: trans_id.transaction_id,
+            commit_timestamp_us,
         };
 
         let response = self.client.replication_update(replication_update_request)
@@ -0,0 +1,83 @@
+use std::pin::Pin;
+use log::warn;
+use tokio::sync::broadcast;
+use tokio_stream::{Stream, StreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use tonic::Status;
+use sddms_services::central_controller::{TransactionEvent, TransactionEventKind};
+use crate::transaction_id::TransactionId;
+
+/// how many events a slow subscriber can fall behind before it starts missing them
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+pub type TransactionEventStream = Pin<Box<dyn Stream<Item = Result<TransactionEvent, Status>> + Send>>;
+
+/// Fans transaction lifecycle events out to everyone subscribed to them. Publishing never blocks, and
+/// events published while no one is subscribed are dropped
+pub struct TransactionEvents {
+    sender: broadcast::Sender<TransactionEvent>,
+}
+
+impl Default for TransactionEvents {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        Self {
+            sender,
+        }
+    }
+}
+
+impl TransactionEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, kind: TransactionEventKind, trans_id: TransactionId, detail: impl Into<String>) {
+        let mut event = TransactionEvent {
+            site_id: trans_id.site_id,
+            transaction_id: trans_id.transaction_id,
+            detail: detail.into(),
+            ..Default::default()
+        };
+        event.set_kind(kind);
+
+        // sending only fails if there are no subscribers, in which case no one cares about the event
+        let _ = self.sender.send(event);
+    }
+
+    /// Streams every event published from now on. Events a subscriber falls too far behind on are skipped
+    pub fn subscribe(&self) -> TransactionEventStream {
+        let events = BroadcastStream::new(self.sender.subscribe())
+            .filter_map(|event| match event {
+                Ok(event) => Some(Ok(event)),
+                Err(lagged) => {
+                    warn!("Event subscriber fell behind: {}", lagged);
+                    None
+                }
+            });
+
+        Box::pin(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+    use sddms_services::central_controller::TransactionEventKind;
+    use crate::transaction_events::TransactionEvents;
+    use crate::transaction_id::TransactionId;
+
+    #[tokio::test]
+    async fn subscribers_only_see_events_published_after_subscribing() {
+        let events = TransactionEvents::new();
+        events.publish(TransactionEventKind::Registered, TransactionId::new(1, 1), "");
+
+        let mut subscription = events.subscribe();
+        events.publish(TransactionEventKind::Finalized, TransactionId::new(1, 2), "committed");
+
+        let event = subscription.next().await.unwrap().unwrap();
+        assert_eq!(event.kind(), TransactionEventKind::Finalized);
+        assert_eq!((event.site_id, event.transaction_id), (1, 2));
+        assert_eq!(event.detail, "committed");
+    }
+}
@@ -1,8 +1,12 @@
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::{RwLock};
-use log::{debug, info};
+use std::sync::{Mutex, RwLock};
+use log::{debug, error, info};
+use sddms_shared::error::SddmsError;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct TransactionId {
@@ -45,18 +49,54 @@ impl Into<u64> for TransactionId {
     }
 }
 
-pub struct TransactionIdGenerator {
-    sites: RwLock<HashMap<u32, AtomicU32>>
+/// How many ids are reserved in the counter file at a time. The file is only written when a site runs past
+/// its reservation, so a restart skips at most this many ids per site instead of reusing any
+const RESERVATION_BLOCK: u32 = 1000;
+
+struct SiteCounter {
+    /// the next id to hand out
+    next: AtomicU32,
+    /// ids below this are recorded in the counter file
+    reserved: AtomicU32,
 }
 
-impl TransactionIdGenerator {
-    pub fn new() -> Self {
+impl SiteCounter {
+    fn starting_at(trans_id: u32) -> Self {
         Self {
-            sites: RwLock::new(HashMap::new())
+            next: AtomicU32::new(trans_id),
+            reserved: AtomicU32::new(trans_id),
         }
     }
+}
+
+pub struct TransactionIdGenerator {
+    sites: RwLock<HashMap<u32, SiteCounter>>,
+    /// where reservations are persisted. Writes are serialized by the lock
+    counter_file: Option<Mutex<PathBuf>>,
+}
+
+impl TransactionIdGenerator {
+    /// Creates a generator, restoring the counters from the given file if there is one. A missing file
+    /// starts every site at 0
+    pub fn new(counter_path: Option<PathBuf>) -> Result<Self, SddmsError> {
+        let reservations = match &counter_path {
+            Some(path) => Self::load_reservations(path)?,
+            None => HashMap::new(),
+        };
+
+        let sites = reservations.into_iter()
+            .map(|(site_id, reserved)| (site_id, SiteCounter::starting_at(reserved)))
+            .collect();
+
+        Ok(Self {
+            sites: RwLock::new(sites),
+            counter_file: counter_path.map(Mutex::new),
+        })
+    }
 
-    pub fn next_trans_id(&self, site_id: u32) -> TransactionId {
+    /// Hands out the next id for the site. Fails without handing out anything if the id is past what the
+    /// counter file records and a new reservation can't be written, since a restart could reuse the id
+    pub fn next_trans_id(&self, site_id: u32) -> Result<TransactionId, SddmsError> {
         debug!("Getting next transaction id for site {}", site_id);
 
         // potentially insert site if it doesn't exist yet
@@ -65,20 +105,139 @@ impl TransactionIdGenerator {
         // Acquire the site transaction counter
         let sites_read_lock = self.sites.read().unwrap();
         let existing_counter = sites_read_lock.get(&site_id).unwrap();
-        debug!("Got transaction counter for site {}. Currently has value {}", site_id, existing_counter.load(Ordering::Acquire));
+        debug!("Got transaction counter for site {}. Currently has value {}", site_id, existing_counter.next.load(Ordering::Acquire));
 
         // get next transaction
-        let next_trans_id = existing_counter.fetch_add(1, Ordering::SeqCst);
+        let next_trans_id = existing_counter.next.fetch_add(1, Ordering::SeqCst);
         debug!("Allocated new transaction {} for site {}", next_trans_id, site_id);
-        debug!("After allocating, counter has value {}", existing_counter.load(Ordering::Acquire));
-        TransactionId::new(site_id, next_trans_id)
+        debug!("After allocating, counter has value {}", existing_counter.next.load(Ordering::Acquire));
+
+        if next_trans_id >= existing_counter.reserved.load(Ordering::Acquire) {
+            self.reserve_past(&sites_read_lock, site_id, next_trans_id)?;
+        }
+
+        Ok(TransactionId::new(site_id, next_trans_id))
     }
 
     fn add_new_site(&self, site_id: u32) {
         let exists = self.sites.read().unwrap().contains_key(&site_id);
         if !exists {
             debug!("Site {} does not exist. Inserting...", site_id);
-            self.sites.write().unwrap().insert(site_id, AtomicU32::new(0));
+            self.sites.write().unwrap().entry(site_id).or_insert_with(|| SiteCounter::starting_at(0));
         }
     }
+
+    /// Records a new reservation block for the site before the given id is handed out. If it can't be
+    /// written, the reservation stays where it was and every allocation past it fails until a write succeeds
+    fn reserve_past(&self, sites: &HashMap<u32, SiteCounter>, site_id: u32, trans_id: u32) -> Result<(), SddmsError> {
+        let Some(counter_file) = &self.counter_file else {
+            return Ok(());
+        };
+
+        let path = counter_file.lock().unwrap();
+        let counter = &sites[&site_id];
+        // another allocation may have already reserved past this id while we waited
+        if trans_id < counter.reserved.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let reserved = trans_id.saturating_add(RESERVATION_BLOCK);
+        let mut reservations = sites.iter()
+            .map(|(site_id, counter)| (*site_id, counter.reserved.load(Ordering::Acquire)))
+            .collect::<HashMap<_, _>>();
+        reservations.insert(site_id, reserved);
+
+        // only publish the reservation once it's on disk, so nothing is handed out that a restart could reuse
+        if let Err(err) = Self::store_reservations(&path, &reservations) {
+            error!("Failed to persist transaction counters, not handing out transaction {}: {}", trans_id, err);
+            return Err(err);
+        }
+        counter.reserved.store(reserved, Ordering::Release);
+        Ok(())
+    }
+
+    fn load_reservations(path: &Path) -> Result<HashMap<u32, u32>, SddmsError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                info!("No transaction counter file at {}, starting every site at 0", path.display());
+                return Ok(HashMap::new());
+            }
+            Err(err) => return Err(SddmsError::central(format!("Failed to read transaction counters from {}", path.display())).with_cause(err)),
+        };
+
+        serde_json::from_str(&contents)
+            .map_err(|err| SddmsError::central(format!("Transaction counter file {} is malformed", path.display())).with_cause(err))
+    }
+
+    fn store_reservations(path: &Path, reservations: &HashMap<u32, u32>) -> Result<(), SddmsError> {
+        let contents = serde_json::to_string(reservations)
+            .map_err(|err| SddmsError::central("Failed to serialize transaction counters").with_cause(err))?;
+
+        // write to the side and rename so a crash never leaves a truncated file
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|err| SddmsError::central(format!("Failed to write transaction counters to {}", path.display())).with_cause(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_id::{RESERVATION_BLOCK, TransactionIdGenerator};
+
+    #[test]
+    fn counters_survive_restart() {
+        let path = std::env::temp_dir().join(format!("sddms-central-counters-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let generator = TransactionIdGenerator::new(Some(path.clone())).unwrap();
+        let last_id = (0..5).map(|_| generator.next_trans_id(1).unwrap().transaction_id).last().unwrap();
+        generator.next_trans_id(2).unwrap();
+        drop(generator);
+
+        let restarted = TransactionIdGenerator::new(Some(path.clone())).unwrap();
+        let next_id = restarted.next_trans_id(1).unwrap().transaction_id;
+        assert!(next_id > last_id);
+        assert_eq!(next_id, RESERVATION_BLOCK);
+        assert!(restarted.next_trans_id(2).unwrap().transaction_id > 0);
+        // a site the file has never seen still starts at 0
+        assert_eq!(restarted.next_trans_id(3).unwrap().transaction_id, 0);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn missing_counter_file_starts_at_zero() {
+        let path = std::env::temp_dir().join(format!("sddms-central-missing-counters-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let generator = TransactionIdGenerator::new(Some(path.clone())).unwrap();
+        assert_eq!(generator.next_trans_id(0).unwrap().transaction_id, 0);
+        assert_eq!(generator.next_trans_id(0).unwrap().transaction_id, 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn nothing_is_handed_out_past_an_unwritten_reservation() {
+        let dir = std::env::temp_dir().join(format!("sddms-central-counter-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("counters.json");
+
+        // the directory doesn't exist yet, so the reservation can't be written
+        let generator = TransactionIdGenerator::new(Some(path.clone())).unwrap();
+        assert!(generator.next_trans_id(0).is_err());
+        assert!(generator.next_trans_id(0).is_err());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let trans_id = generator.next_trans_id(0).unwrap().transaction_id;
+        drop(generator);
+
+        // whatever was handed out is covered by what's on disk
+        let restarted = TransactionIdGenerator::new(Some(path.clone())).unwrap();
+        assert!(restarted.next_trans_id(0).unwrap().transaction_id > trans_id);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -1,5 +1,11 @@
 use std::path::PathBuf;
+use clap::builder::RangedU64ValueParser;
 use clap::Parser;
+use sddms_services::transport::TlsOptions;
+use sddms_shared::sql_metadata::LockGranularity;
+use crate::contention::ContentionAdvisor;
+use crate::dsn::Dsn;
+use crate::query_results::{DEFAULT_NULL_DISPLAY, DisplayOptions, OutputFormat};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -9,6 +15,90 @@ pub struct Args {
     /// if set, read sql statements from the given path and execute them one by one
     #[arg(short, long)]
     pub input: Option<PathBuf>,
-    /// The host string of the site controller to connect to, <ip_addr>:<port>
-    pub connect_host: String
+    /// if set, write query results to the given path instead of stdout. Only used with `--input`
+    #[arg(short, long, requires = "input")]
+    pub output_file: Option<PathBuf>,
+    /// the prompt shown when no transaction is in progress
+    #[arg(long, default_value = "sddms>> ")]
+    pub prompt: String,
+    /// the prompt shown while a transaction is in progress. `{txn}` is replaced with the transaction's id
+    #[arg(long, default_value = "sddms(txn #{txn})>> ")]
+    pub transaction_prompt: String,
+    /// how finely queries lock data, either `table` or `column`
+    #[arg(long, default_value = "table")]
+    pub lock_granularity: LockGranularity,
+    /// fail a query right away instead of waiting when another transaction holds the locks it needs
+    #[arg(long, default_value = "false")]
+    pub no_wait: bool,
+    /// run reads outside of a transaction without a transaction or locks of their own. This saves trips to
+    /// the central controller, but those reads can see another transaction's updates partway through being
+    /// replicated, so they're no longer serializable
+    #[arg(long, default_value = "false")]
+    pub no_autocommit_reads: bool,
+    /// warn before running statements that write to tables that often deadlock, learning which ones do
+    /// over the session
+    #[arg(long, default_value = "false")]
+    pub warn_contention: bool,
+    /// comma separated tables that are known to be contended. Implies `--warn-contention`
+    #[arg(long, value_delimiter = ',')]
+    pub hot_tables: Vec<String>,
+    /// get query results back in batches, writing each one as it arrives
+    #[arg(long, default_value = "false")]
+    pub stream: bool,
+    /// truncate result table cells to at most this many characters
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_col_width: Option<usize>,
+    /// only print the first this many rows of each result table
+    #[arg(long)]
+    pub max_rows: Option<usize>,
+    /// show each result row as a list of `column: value` lines instead of a table. Toggled with `\x`
+    #[arg(long, default_value = "false")]
+    pub expanded: bool,
+    /// what NULL values are shown as in result tables
+    #[arg(long, default_value = DEFAULT_NULL_DISPLAY)]
+    pub null_display: String,
+    /// connect to the site over TLS. Also set by the DSN's `tls` option
+    #[arg(long, default_value = "false")]
+    pub tls: bool,
+    /// PEM certificate of the CA that the site's certificate is checked against
+    #[arg(long, requires = "tls")]
+    pub ca_cert: Option<PathBuf>,
+    /// PEM certificate to present to a site that checks its clients
+    #[arg(long, requires_all = ["tls", "tls_key"])]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM key for the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// The site controller to connect to, either <ip_addr>:<port> or a DSN such as
+    /// sddms://<ip_addr>:<port>/?timeout=5s&format=json&tls=true
+    pub dsn: Dsn,
+}
+
+impl Args {
+    pub fn tls_options(&self) -> Option<TlsOptions> {
+        (self.tls || self.dsn.tls).then(|| TlsOptions {
+            identity: self.tls_cert.clone().zip(self.tls_key.clone()),
+            ca_cert: self.ca_cert.clone(),
+        })
+    }
+
+    pub fn output_format(&self) -> OutputFormat {
+        self.dsn.format.unwrap_or_default()
+    }
+
+    /// The contention advisor for the session, if contention warnings are on
+    pub fn contention_advisor(&self) -> Option<ContentionAdvisor> {
+        (self.warn_contention || !self.hot_tables.is_empty())
+            .then(|| ContentionAdvisor::new(self.hot_tables.iter().cloned()))
+    }
+
+    pub fn display_options(&self) -> DisplayOptions {
+        DisplayOptions {
+            format: self.output_format(),
+            max_col_width: self.max_col_width,
+            max_rows: self.max_rows,
+            null_display: self.null_display.clone(),
+            expanded: self.expanded,
+        }
+    }
 }
@@ -0,0 +1,83 @@
+use std::collections::{BTreeSet, HashSet};
+use sddms_shared::sql_metadata::{parse_statements, parse_transaction_stmt, SqlMetadata};
+
+/// The tables touched by a statement, or nothing if it's a transaction statement or can't be parsed
+fn statement_metadata(statement: &str) -> Vec<SqlMetadata> {
+    match parse_transaction_stmt(statement) {
+        Ok(None) => parse_statements(statement).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Warns before a transaction runs if it writes to tables that other transactions often contend over. Hot
+/// tables are either given up front or learned from the statements that deadlock over the session. This
+/// is only a heuristic: it says nothing about what other sites are running right now
+#[derive(Debug, Default)]
+pub struct ContentionAdvisor {
+    hot_tables: HashSet<String>,
+}
+
+impl ContentionAdvisor {
+    pub fn new<TablesT: IntoIterator<Item=String>>(hot_tables: TablesT) -> Self {
+        Self {
+            hot_tables: hot_tables.into_iter().collect(),
+        }
+    }
+
+    /// Treats every table the deadlocked statement touched as hot from now on
+    pub fn record_deadlock(&mut self, statement: &str) {
+        for metadata in statement_metadata(statement) {
+            self.hot_tables.extend(metadata.read_tables().iter().cloned());
+            self.hot_tables.extend(metadata.write_tables().iter().cloned());
+        }
+    }
+
+    /// An advisory for the given statements if they write to any hot tables
+    pub fn advise(&self, statements: &[String]) -> Option<String> {
+        let contended = statements.iter()
+            .flat_map(|statement| statement_metadata(statement))
+            .flat_map(|metadata| metadata.write_tables().clone())
+            .filter(|table| self.hot_tables.contains(table))
+            .collect::<BTreeSet<_>>();
+
+        if contended.is_empty() {
+            return None;
+        }
+
+        let contended = contended.into_iter().collect::<Vec<_>>().join(", ");
+        Some(format!("Transaction writes to frequently contended tables ({}). Writing to them last, and in the same order in every transaction, makes deadlocks less likely", contended))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contention::ContentionAdvisor;
+
+    #[test]
+    fn writing_a_hot_table_is_advised_against() {
+        let advisor = ContentionAdvisor::new([String::from("flights")]);
+        let transaction = vec![
+            String::from("BEGIN;"),
+            String::from("SELECT * FROM flights;"),
+            String::from("UPDATE flights SET seats = seats - 1 WHERE id = 1;"),
+            String::from("INSERT INTO bookings VALUES (1, 1);"),
+            String::from("COMMIT;"),
+        ];
+
+        let advisory = advisor.advise(&transaction).expect("expected an advisory");
+        assert!(advisory.contains("(flights)"), "{}", advisory);
+
+        // only reading a hot table is fine
+        assert!(advisor.advise(&transaction[..2]).is_none());
+    }
+
+    #[test]
+    fn deadlocked_tables_become_hot() {
+        let mut advisor = ContentionAdvisor::default();
+        let transaction = vec![String::from("INSERT INTO bookings VALUES (1, 1);")];
+        assert!(advisor.advise(&transaction).is_none());
+
+        advisor.record_deadlock("UPDATE bookings SET seat = 2;");
+        assert!(advisor.advise(&transaction).is_some());
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use crate::query_results::OutputFormat;
+
+const DSN_SCHEME: &str = "sddms://";
+
+/// Where and how to connect to a site controller. Either a bare `<host>:<port>` or a DSN of the form
+/// `sddms://<host>:<port>/?timeout=5s&format=json&tls=true`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dsn {
+    /// the `<host>:<port>` of the site controller
+    pub host: String,
+    /// how long to wait for the connection before giving up
+    pub timeout: Option<Duration>,
+    /// how query results are written
+    pub format: Option<OutputFormat>,
+    /// whether to connect over TLS
+    pub tls: bool,
+}
+
+impl Dsn {
+    fn with_host(host: &str) -> Result<Self, String> {
+        if host.is_empty() {
+            return Err("DSN is missing a host".to_string());
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            timeout: None,
+            format: None,
+            tls: false,
+        })
+    }
+
+    fn apply_option(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "timeout" => self.timeout = Some(parse_duration(value)?),
+            "format" => self.format = Some(value.parse()?),
+            "tls" => self.tls = value.parse()
+                .map_err(|_| format!("invalid tls option '{}', expected 'true' or 'false'", value))?,
+            other => return Err(format!("unknown DSN option '{}'", other)),
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Dsn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix(DSN_SCHEME) else {
+            return Self::with_host(s);
+        };
+
+        let (host, query) = match rest.split_once('?') {
+            Some((host, query)) => (host, query),
+            None => (rest, ""),
+        };
+
+        let mut dsn = Self::with_host(host.trim_end_matches('/'))?;
+        for option in query.split('&').filter(|option| !option.is_empty()) {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| format!("DSN option '{}' is missing a value", option))?;
+            dsn.apply_option(key, value)?;
+        }
+
+        Ok(dsn)
+    }
+}
+
+impl Display for Dsn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.host)
+    }
+}
+
+/// parses durations like `500ms`, `5s` or `2m`
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let unit_start = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(unit_start);
+    let amount: u64 = amount.parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "s" | "" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount * 60)),
+        other => Err(format!("unknown duration unit '{}' in '{}', expected 'ms', 's' or 'm'", other, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use clap::Parser;
+    use crate::args::Args;
+    use crate::dsn::Dsn;
+    use crate::query_results::OutputFormat;
+
+    #[test]
+    fn bare_host_is_a_dsn_without_options() {
+        let dsn: Dsn = "127.0.0.1:50052".parse().unwrap();
+        assert_eq!(dsn.host, "127.0.0.1:50052");
+        assert_eq!(dsn.timeout, None);
+        assert_eq!(dsn.format, None);
+        assert!(!dsn.tls);
+    }
+
+    #[test]
+    fn dsn_options_are_applied_to_args() {
+        let args = Args::try_parse_from(["sddms-client", "sddms://localhost:50052/?timeout=5s&format=json&tls=true"]).unwrap();
+        assert_eq!(args.dsn.host, "localhost:50052");
+        assert_eq!(args.dsn.timeout, Some(Duration::from_secs(5)));
+        assert_eq!(args.output_format(), OutputFormat::Json);
+        assert!(args.tls_options().is_some());
+
+        let args = Args::try_parse_from(["sddms-client", "localhost:50052"]).unwrap();
+        assert_eq!(args.output_format(), OutputFormat::Table);
+        assert!(args.tls_options().is_none());
+    }
+
+    #[test]
+    fn bad_dsn_options_are_rejected() {
+        assert!("sddms://localhost:50052/?timeout=soon".parse::<Dsn>().is_err());
+        assert!("sddms://localhost:50052/?colour=blue".parse::<Dsn>().is_err());
+        assert!("sddms://localhost:50052/?format".parse::<Dsn>().is_err());
+        assert!("sddms:///?format=json".parse::<Dsn>().is_err());
+    }
+}
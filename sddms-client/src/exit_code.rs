@@ -0,0 +1,127 @@
+use std::fmt::{Display, Formatter};
+use std::process::ExitCode;
+
+/// Exit codes reported by the client so that scripts can tell why a session failed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClientExitCode {
+    Success = 0,
+    /// an unexpected error ended the session
+    Failure = 1,
+    /// could not connect to or register with the site controller
+    ConnectionFailure = 2,
+    /// a statement could not be parsed
+    ParseError = 3,
+    /// a statement failed while being invoked
+    QueryError = 4,
+    /// a transaction was aborted because it deadlocked
+    DeadlockAbort = 5,
+}
+
+impl From<ClientExitCode> for ExitCode {
+    fn from(value: ClientExitCode) -> Self {
+        ExitCode::from(value as u8)
+    }
+}
+
+/// Records what happened over the course of a session, including any problems that came up
+#[derive(Debug, Default)]
+pub struct SessionOutcome {
+    deadlocked: bool,
+    parse_failed: bool,
+    query_failed: bool,
+    /// statements that ran successfully, not counting BEGIN, COMMIT or ROLLBACK
+    statements_executed: u64,
+    transactions_committed: u64,
+    transactions_rolled_back: u64,
+    deadlocks: u64,
+}
+
+impl SessionOutcome {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_deadlock(&mut self) {
+        self.deadlocked = true;
+        self.deadlocks += 1;
+    }
+
+    pub fn record_statement(&mut self) {
+        self.statements_executed += 1;
+    }
+
+    pub fn record_commit(&mut self) {
+        self.transactions_committed += 1;
+    }
+
+    pub fn record_rollback(&mut self) {
+        self.transactions_rolled_back += 1;
+    }
+
+    pub fn record_parse_error(&mut self) {
+        self.parse_failed = true;
+    }
+
+    pub fn record_query_error(&mut self) {
+        self.query_failed = true;
+    }
+
+    /// The exit code for this session. If several problems came up, deadlocks take precedence over
+    /// query failures, which take precedence over parse errors
+    pub fn exit_code(&self) -> ClientExitCode {
+        if self.deadlocked {
+            ClientExitCode::DeadlockAbort
+        } else if self.query_failed {
+            ClientExitCode::QueryError
+        } else if self.parse_failed {
+            ClientExitCode::ParseError
+        } else {
+            ClientExitCode::Success
+        }
+    }
+}
+
+/// A one line summary of the session
+impl Display for SessionOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} statements executed, {} transactions committed, {} rolled back, {} deadlocks",
+               self.statements_executed, self.transactions_committed, self.transactions_rolled_back, self.deadlocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::exit_code::{ClientExitCode, SessionOutcome};
+
+    #[test]
+    fn clean_session_succeeds() {
+        let outcome = SessionOutcome::new();
+        assert_eq!(outcome.exit_code(), ClientExitCode::Success);
+    }
+
+    #[test]
+    fn deadlock_takes_precedence() {
+        let mut outcome = SessionOutcome::new();
+        outcome.record_parse_error();
+        assert_eq!(outcome.exit_code(), ClientExitCode::ParseError);
+
+        outcome.record_deadlock();
+        outcome.record_query_error();
+        assert_eq!(outcome.exit_code(), ClientExitCode::DeadlockAbort);
+        assert_eq!(outcome.exit_code() as u8, 5);
+    }
+
+    #[test]
+    fn summary_counts_everything() {
+        let mut outcome = SessionOutcome::new();
+        for _ in 0..3 {
+            outcome.record_statement();
+        }
+        outcome.record_commit();
+        outcome.record_deadlock();
+        outcome.record_rollback();
+
+        assert_eq!(outcome.to_string(), "3 statements executed, 1 transactions committed, 1 rolled back, 1 deadlocks");
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+use tabled::builder::Builder;
+use tabled::Table;
+use sddms_shared::error::SddmsError;
+use sddms_shared::sql_metadata::{LockGranularity, parse_statements};
+
+/// Joins a set of names in a stable order so the same query always explains the same way
+fn format_names<'name, NamesT: IntoIterator<Item=&'name String>>(names: NamesT) -> String {
+    let mut names = names.into_iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    names.sort();
+    names.join(", ")
+}
+
+/// Shows what the client would send to the site for each statement in the query, without sending it:
+/// whether it modifies anything or has results, which tables it reads and writes, and which locks it
+/// takes at the given granularity
+pub fn explain_query(query: &str, lock_granularity: LockGranularity) -> Result<Table, SddmsError> {
+    let statements = parse_statements(query)
+        .map_err(|err| SddmsError::client("Failed to parse SQL query").with_cause(err))?;
+
+    if statements.is_empty() {
+        return Err(SddmsError::client("Nothing to explain"));
+    }
+
+    let mut builder = Builder::new();
+    builder.set_header(["modifiable", "has_results", "read_tables", "write_tables", "shared_locks", "exclusive_locks"]);
+    for metadata in statements {
+        let lock_resources = metadata.lock_resources(lock_granularity);
+        builder.push_record([
+            metadata.modifiable().to_string(),
+            metadata.has_results().to_string(),
+            format_names(metadata.read_tables()),
+            format_names(metadata.write_tables()),
+            format_names(&lock_resources.shared.into_iter().collect::<HashSet<_>>()),
+            format_names(&lock_resources.exclusive.into_iter().collect::<HashSet<_>>()),
+        ]);
+    }
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use sddms_shared::sql_metadata::LockGranularity;
+    use crate::explain::explain_query;
+
+    #[test]
+    fn explains_read_and_write_sets() {
+        let table = explain_query("INSERT INTO students SELECT * FROM applicants;", LockGranularity::Table)
+            .unwrap()
+            .to_string();
+
+        let rows = table.lines()
+            .filter(|line| line.starts_with('|'))
+            .map(|line| line.split('|').map(str::trim).filter(|cell| !cell.is_empty()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows[0], vec!["modifiable", "has_results", "read_tables", "write_tables", "shared_locks", "exclusive_locks"]);
+        assert_eq!(rows[1], vec!["true", "false", "applicants", "students", "applicants", "students"]);
+    }
+
+    #[test]
+    fn parse_errors_are_reported() {
+        let err = explain_query("SELEC * FROM students;", LockGranularity::Table).unwrap_err();
+        assert!(err.to_string().contains("Failed to parse SQL query"));
+    }
+}
@@ -1,49 +1,140 @@
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::{Path};
+use std::process::ExitCode;
 use clap::Parser;
 use log::{error, info, LevelFilter, warn};
-use rustyline::{DefaultEditor};
-use tabled::Table;
+use rustyline::Editor;
+use rustyline::history::DefaultHistory;
 use sddms_shared::error::SddmsError;
-use sddms_shared::sql_metadata::{parse_transaction_stmt, split_stmts_into_transactions, TransactionStmt};
+use sddms_shared::sql_metadata::{parse_lock_table_stmt, parse_transaction_stmt, split_stmts_into_transactions, TransactionStmt};
 use crate::args::Args;
-use crate::query_results::QueryResults;
+use crate::contention::ContentionAdvisor;
+use crate::exit_code::{ClientExitCode, SessionOutcome};
+use crate::explain::explain_query;
+use crate::prompt::Prompt;
+use crate::query_results::DisplayOptions;
 use crate::reader::{Command, MetaCommand, read_next_command, split_statements};
 use crate::site_client::SddmsSiteClient;
+use crate::sql_helper::SqlHelper;
 use crate::transaction_state::TransactionState;
+use crate::variables::SessionVariables;
+use crate::watch::watch_query;
 
 mod args;
+mod contention;
+mod dsn;
+mod exit_code;
+mod explain;
+mod prompt;
 mod reader;
 mod site_client;
+mod sql_helper;
 mod query_results;
 mod transaction_state;
+mod variables;
+mod watch;
 
-async fn invoke_query(client: &mut SddmsSiteClient, transaction_state: &TransactionState, query: &str) -> Result<bool, SddmsError> {
+async fn invoke_query(client: &mut SddmsSiteClient, transaction_state: &TransactionState, query: &str, stream: bool, display_options: &DisplayOptions, output: &mut dyn Write) -> Result<bool, SddmsError> {
     let trans_id = transaction_state.transaction_id().ok();
 
+    if stream {
+        // write each batch as soon as it arrives instead of waiting on the whole result set
+        let mut batches = client.invoke_query_streaming(trans_id, query).await?;
+        while let Some(results) = batches.next().await? {
+            if let Some(deadlock_err) = results.write_to(output, display_options)? {
+                error!("{}", deadlock_err);
+                return Ok(true);
+            }
+        }
+
+        return Ok(false);
+    }
+
     let results = client.invoke_query(trans_id, query).await?;
 
-    match results {
-        QueryResults::AffectedRows(row_count) => println!("Affected {} rows", row_count),
-        QueryResults::Results(results) => {
-            let table: Table = results.into();
-            println!("{}", table);
-        }
-        QueryResults::DeadLock(deadlock_err) => {
-            error!("{}", deadlock_err);
-            return Ok(true);
-        }
-    };
+    if let Some(deadlock_err) = results.write_to(output, display_options)? {
+        error!("{}", deadlock_err);
+        return Ok(true);
+    }
 
     Ok(false)
 }
 
-async fn handle_lines(next_statements: &[String], args: &Args, client: &mut SddmsSiteClient, transaction_state: &mut TransactionState) -> Result<(), Box<dyn Error>> {
+/// finalizes the transaction in progress, failing if there isn't one
+async fn finalize_current_transaction(client: &mut SddmsSiteClient, transaction_state: &mut TransactionState, finalize_cmd: TransactionStmt, outcome: &mut SessionOutcome) -> Result<(), SddmsError> {
+    let transaction_id = transaction_state.transaction_id()?;
+    let committing = matches!(finalize_cmd, TransactionStmt::Commit);
+    client.finalize_transaction(transaction_id, finalize_cmd).await?;
+    transaction_state.clear();
+    if committing {
+        outcome.record_commit();
+    } else {
+        outcome.record_rollback();
+    }
+    Ok(())
+}
+
+/// Everything that's kept track of over the course of a session
+struct Session {
+    transaction_state: TransactionState,
+    outcome: SessionOutcome,
+    contention_advisor: Option<ContentionAdvisor>,
+    /// variables defined with `\set`
+    variables: SessionVariables,
+    /// how results are shown, which `\x` can change
+    display_options: DisplayOptions,
+}
+
+impl Session {
+    fn new(args: &Args, transaction_state: TransactionState) -> Self {
+        Self {
+            transaction_state,
+            outcome: SessionOutcome::new(),
+            contention_advisor: args.contention_advisor(),
+            variables: SessionVariables::new(),
+            display_options: args.display_options(),
+        }
+    }
+}
+
+async fn handle_lines(next_statements: &[String], args: &Args, client: &mut SddmsSiteClient, session: &mut Session, output: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let Session { transaction_state, outcome, contention_advisor, variables, display_options } = session;
+    if let Some(advisory) = contention_advisor.as_ref().and_then(|advisor| advisor.advise(next_statements)) {
+        warn!("{}", advisory);
+    }
+
     for stmt in next_statements {
-        let parse_attempt = parse_transaction_stmt(stmt);
+        let stmt = match variables.substitute(stmt) {
+            Ok(stmt) => stmt,
+            Err(err) => {
+                outcome.record_parse_error();
+                eprintln!("{err}");
+                continue;
+            }
+        };
+
+        // explicit locks are taken along with the transaction's next statement
+        if let Some(lock_table_stmt) = parse_lock_table_stmt(&stmt) {
+            let queued = lock_table_stmt.and_then(|lock_table_stmt| {
+                if !transaction_state.has_transaction() {
+                    return Err(SddmsError::client("LOCK TABLE only works inside a transaction"));
+                }
+                client.queue_ordered_locks(lock_table_stmt);
+                Ok(())
+            });
+            if let Err(err) = queued {
+                outcome.record_query_error();
+                eprintln!("{err}");
+            }
+            continue;
+        }
+
+        let parse_attempt = parse_transaction_stmt(&stmt);
         let Ok(transaction_stmt_opt) = parse_attempt else {
+            outcome.record_parse_error();
             eprintln!("{}", parse_attempt.unwrap_err());
             continue;
         };
@@ -58,27 +149,36 @@ async fn handle_lines(next_statements: &[String], args: &Args, client: &mut Sddm
                         })
                 }
                 finalize_cmd => {
-                    let transaction_id = transaction_state.transaction_id()?;
-                    client.finalize_transaction(transaction_id, finalize_cmd).await?;
-                    transaction_state.clear();
-                    Ok(())
+                    finalize_current_transaction(client, transaction_state, finalize_cmd, outcome).await
                 }
             }
         } else {
-            let dead_locked = invoke_query(client, &transaction_state, stmt).await?;
-            if dead_locked && args.rollback_on_deadlock {
-                warn!("Automatically rolling back transaction");
-                let transaction_id = transaction_state.transaction_id()?;
-                client.finalize_transaction(transaction_id, TransactionStmt::Rollback).await?;
-                transaction_state.clear();
-                // just go ahead and bail
-                return Ok(());
+            match invoke_query(client, transaction_state, &stmt, args.stream, display_options, output).await {
+                Ok(true) => {
+                    outcome.record_deadlock();
+                    if let Some(advisor) = contention_advisor {
+                        advisor.record_deadlock(&stmt);
+                    }
+                    if args.rollback_on_deadlock {
+                        warn!("Automatically rolling back transaction");
+                        finalize_current_transaction(client, transaction_state, TransactionStmt::Rollback, outcome).await?;
+                        // just go ahead and bail
+                        return Ok(());
+                    }
+                    Ok(())
+                }
+                Ok(false) => {
+                    outcome.record_statement();
+                    Ok(())
+                }
+                // a failed query is reported like a failed BEGIN or COMMIT, and the session goes on
+                Err(err) => Err(err),
             }
-            Ok(())
         };
 
         if invoke_stmt_result.is_err() {
             let err = invoke_stmt_result.unwrap_err();
+            outcome.record_query_error();
             eprintln!("{err}");
         }
     }
@@ -86,11 +186,20 @@ async fn handle_lines(next_statements: &[String], args: &Args, client: &mut Sddm
     Ok(())
 }
 
-async fn interactive_mode(client_id: u32, args: &Args, mut client: SddmsSiteClient, mut transaction_state: TransactionState) -> Result<(), Box<dyn Error>> {
-    let mut line_reader = DefaultEditor::new()?;
+async fn interactive_mode(client_id: u32, args: &Args, client: &mut SddmsSiteClient, transaction_state: TransactionState) -> Result<SessionOutcome, Box<dyn Error>> {
+    let mut session = Session::new(args, transaction_state);
+    let table_names = client.fetch_table_names().await
+        .unwrap_or_else(|err| {
+            warn!("Could not fetch table names for completion: {}", err);
+            Vec::new()
+        });
+
+    let mut line_reader: Editor<SqlHelper, DefaultHistory> = Editor::new()?;
+    line_reader.set_helper(Some(SqlHelper::new(table_names)));
+    let prompt = Prompt::new(&args.prompt, &args.transaction_prompt);
 
     loop {
-        let next_lines = read_next_command(&mut line_reader);
+        let next_lines = read_next_command(&mut line_reader, &prompt, &session.transaction_state);
         if next_lines.is_err() {
             let err = next_lines.unwrap_err();
             let err = SddmsError::client("Error while reading line")
@@ -106,26 +215,61 @@ async fn interactive_mode(client_id: u32, args: &Args, mut client: SddmsSiteClie
                 match meta_command {
                     MetaCommand::Quit => break,
                     MetaCommand::PrintTransactionInfo => {
-                        if transaction_state.has_transaction() {
+                        if session.transaction_state.has_transaction() {
                             println!("client_id={}", client_id);
-                            println!("transaction_id={}", transaction_state.transaction_id().unwrap());
+                            println!("transaction_id={}", session.transaction_state.transaction_id().unwrap());
                         } else {
                             println!("No transaction in progress");
                         }
                     }
                     MetaCommand::CancelLine => { /* NOP */ }
+                    MetaCommand::Explain(query) => {
+                        match explain_query(&query, args.lock_granularity) {
+                            Ok(explanation) => println!("{}", explanation),
+                            Err(err) => eprintln!("{}", err),
+                        }
+                    }
+                    MetaCommand::Watch { interval, query } => {
+                        let watched = match session.variables.substitute(&query) {
+                            Ok(query) => watch_query(client, interval, &query, &session.display_options).await,
+                            Err(err) => Err(err),
+                        };
+                        if let Err(err) = watched {
+                            eprintln!("{}", err);
+                        }
+                    }
+                    MetaCommand::Set { name, value } => session.variables.set(name, value),
+                    MetaCommand::ToggleExpanded => {
+                        session.display_options.expanded = !session.display_options.expanded;
+                        println!("Expanded display is {}", if session.display_options.expanded { "on" } else { "off" });
+                    }
+                    MetaCommand::Commit | MetaCommand::Rollback => {
+                        let finalize_cmd = if matches!(meta_command, MetaCommand::Commit) {
+                            TransactionStmt::Commit
+                        } else {
+                            TransactionStmt::Rollback
+                        };
+
+                        if let Err(err) = finalize_current_transaction(client, &mut session.transaction_state, finalize_cmd, &mut session.outcome).await {
+                            session.outcome.record_query_error();
+                            eprintln!("{}", err);
+                        }
+                    }
                 }
             }
             Command::Lines(next_statements) => {
-                handle_lines(&next_statements, args, &mut client, &mut transaction_state).await?
+                handle_lines(&next_statements, args, client, &mut session, &mut io::stdout()).await?
             }
         }
     }
 
-    Ok(())
+    eprintln!("{}", session.outcome);
+    Ok(session.outcome)
 }
 
-async fn input_file_mode(input_file_path: &Path, args: &Args, mut client: SddmsSiteClient, mut transaction_state: TransactionState) -> Result<(), Box<dyn Error>> {
+async fn input_file_mode(input_file_path: &Path, args: &Args, client: &mut SddmsSiteClient, transaction_state: TransactionState) -> Result<SessionOutcome, Box<dyn Error>> {
+    // there's no way to \set a variable or toggle expanded display in an input file
+    let mut session = Session::new(args, transaction_state);
     let input_file = File::open(input_file_path)?;
     let input_file_reader = BufReader::new(input_file);
     let all_lines = input_file_reader.lines()
@@ -137,18 +281,32 @@ async fn input_file_mode(input_file_path: &Path, args: &Args, mut client: SddmsS
     // split all statements into transactions
     let transactions = split_stmts_into_transactions(all_statements)?;
 
+    // results go to the output file if there is one. Logging stays on stderr either way
+    let mut output: Box<dyn Write> = match &args.output_file {
+        Some(output_file_path) => {
+            let output_file = File::create(output_file_path)
+                .map_err(|err| SddmsError::client("Failed to create output file").with_cause(err))?;
+            Box::new(BufWriter::new(output_file))
+        }
+        None => Box::new(io::stdout()),
+    };
+
     // if a transaction gets auto roll-backed, then it'll skip the remainder of the transaction
     // and carry on to the next
     // TODO implement auto-retry
     for transaction in &transactions {
-        handle_lines(transaction, &args, &mut client, &mut transaction_state).await?;
+        handle_lines(transaction, args, client, &mut session, output.as_mut()).await?;
     }
 
-    Ok(())
+    output.flush()
+        .map_err(|err| SddmsError::client("Failed to flush query results").with_cause(err))?;
+
+    eprintln!("{}", session.outcome);
+    Ok(session.outcome)
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
     env_logger::builder()
         .filter_level(LevelFilter::Info)
         .init();
@@ -168,23 +326,75 @@ async fn main() -> Result<(), Box<dyn Error>> {
         args
     };
 
-    info!("Connecting to {}", args.connect_host);
+    info!("Connecting to {}", args.dsn);
 
     // configure connection to site controller
-    let mut client = SddmsSiteClient::connect(&args.connect_host).await?;
-    info!("Connected to site client at {}", args.connect_host);
-    let client_id = client.register_self().await?;
+    let mut client = match SddmsSiteClient::connect(&args.dsn, args.tls_options().as_ref()).await {
+        Ok(client) => client,
+        Err(err) => {
+            error!("{}", err);
+            return Ok(ClientExitCode::ConnectionFailure.into());
+        }
+    };
+    info!("Connected to site client at {}", args.dsn);
+    let client_id = match client.register_self().await {
+        Ok(client_id) => client_id,
+        Err(err) => {
+            error!("{}", err);
+            return Ok(ClientExitCode::ConnectionFailure.into());
+        }
+    };
     client.set_client_id(client_id);
+    client.set_lock_granularity(args.lock_granularity);
+    client.set_no_wait(args.no_wait);
+    client.set_autocommit_reads(!args.no_autocommit_reads);
     info!("Client successfully registered at site with id {}", client_id);
 
     let transaction_state = TransactionState::new();
 
-    if let Some(input_file_path) = &args.input {
-        input_file_mode(input_file_path, &args, client, transaction_state).await?;
+    let session_result = if let Some(input_file_path) = &args.input {
+        input_file_mode(input_file_path, &args, &mut client, transaction_state).await
     } else {
-        interactive_mode(client_id, &args, client, transaction_state).await?;
+        interactive_mode(client_id, &args, &mut client, transaction_state).await
+    };
+
+    // let the site clean up after us, even if the session failed. Anything still in progress gets rolled back
+    match client.unregister_self().await {
+        Ok(rolled_back) => {
+            for trans_id in rolled_back {
+                warn!("Transaction {} was still in progress and was rolled back", trans_id);
+            }
+        }
+        Err(err) => error!("{}", err),
     }
 
+    let outcome = session_result?;
     info!("Done!");
-    Ok(())
+    Ok(outcome.exit_code().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use crate::args::Args;
+    use crate::exit_code::ClientExitCode;
+    use crate::site_client::SddmsSiteClient;
+    use crate::transaction_state::TransactionState;
+    use crate::{handle_lines, Session};
+
+    #[tokio::test]
+    async fn failed_query_is_recorded_without_ending_the_session() {
+        let args = Args::parse_from(["sddms-client", "127.0.0.1:1"]);
+        // nothing listens here, so every query fails
+        let mut client = SddmsSiteClient::lazy("127.0.0.1:1").unwrap();
+        client.set_client_id(0);
+        let mut session = Session::new(&args, TransactionState::new());
+
+        let statements = vec![String::from("SELECT * FROM flights;"), String::from("SELECT * FROM seats;")];
+        handle_lines(&statements, &args, &mut client, &mut session, &mut Vec::new()).await
+            .unwrap();
+
+        assert_eq!(session.outcome.exit_code(), ClientExitCode::QueryError);
+        assert_eq!(session.outcome.to_string(), "0 statements executed, 0 transactions committed, 0 rolled back, 0 deadlocks");
+    }
 }
@@ -0,0 +1,78 @@
+use crate::transaction_state::TransactionState;
+
+/// Stands in for the current transaction's id in the in-transaction prompt
+pub const TRANSACTION_ID_PLACEHOLDER: &str = "{txn}";
+
+/// The prompts shown by the interactive line reader. Which one is shown depends on whether a
+/// transaction is currently in progress
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    /// shown when no transaction is in progress
+    idle: String,
+    /// shown while a transaction is in progress, with `{txn}` replaced by the transaction id
+    in_transaction: String,
+}
+
+impl Prompt {
+    pub fn new<IdleT: Into<String>, InTransactionT: Into<String>>(idle: IdleT, in_transaction: InTransactionT) -> Self {
+        Self {
+            idle: idle.into(),
+            in_transaction: in_transaction.into(),
+        }
+    }
+
+    /// The prompt shown at the start of a new statement
+    pub fn primary(&self, transaction_state: &TransactionState) -> String {
+        match transaction_state.transaction_id() {
+            Ok(transaction_id) => self.in_transaction.replace(TRANSACTION_ID_PLACEHOLDER, &transaction_id.to_string()),
+            Err(_) => self.idle.clone(),
+        }
+    }
+
+    /// The prompt shown when a statement continues onto another line. It's right-aligned with the
+    /// primary prompt so that multi-line statements line up
+    pub fn continuation(&self, transaction_state: &TransactionState) -> String {
+        let width = self.primary(transaction_state).chars().count();
+        format!("{:>width$}", "> ", width = width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prompt::Prompt;
+    use crate::transaction_state::TransactionState;
+
+    #[test]
+    fn primary_changes_when_transaction_active() {
+        let prompt = Prompt::new(">> ", "txn> ");
+        let mut transaction_state = TransactionState::new();
+        assert_eq!(prompt.primary(&transaction_state), ">> ");
+
+        transaction_state.push(4).unwrap();
+        assert_eq!(prompt.primary(&transaction_state), "txn> ");
+
+        transaction_state.clear();
+        assert_eq!(prompt.primary(&transaction_state), ">> ");
+    }
+
+    #[test]
+    fn primary_shows_transaction_id() {
+        let prompt = Prompt::new("sddms>> ", "sddms(txn #{txn})>> ");
+        let mut transaction_state = TransactionState::new();
+        assert_eq!(prompt.primary(&transaction_state), "sddms>> ");
+
+        transaction_state.push(42).unwrap();
+        assert_eq!(prompt.primary(&transaction_state), "sddms(txn #42)>> ");
+        assert_eq!(prompt.continuation(&transaction_state), format!("{}> ", " ".repeat(15)));
+    }
+
+    #[test]
+    fn continuation_aligns_with_primary() {
+        let prompt = Prompt::new(">> ", "txn> ");
+        let mut transaction_state = TransactionState::new();
+        assert_eq!(prompt.continuation(&transaction_state), " > ");
+
+        transaction_state.push(4).unwrap();
+        assert_eq!(prompt.continuation(&transaction_state), "   > ");
+    }
+}
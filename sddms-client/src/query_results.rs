@@ -1,31 +1,157 @@
-use serde_json::{Map, Value};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::str::FromStr;
+use serde_json::{json, Map, Value};
 use tabled::builder::Builder;
 use tabled::Table;
 use sddms_shared::error::SddmsError;
+use sddms_shared::sql_metadata::SchemaChange;
+
+/// How query results are written
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// a table per result set
+    #[default]
+    Table,
+    /// a JSON object per query
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format!("unknown output format '{}', expected 'table' or 'json'", other)),
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Table => f.write_str("table"),
+            OutputFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// What NULL values are shown as in a result table unless told otherwise
+pub const DEFAULT_NULL_DISPLAY: &str = "NULL";
+
+/// How query results are written out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayOptions {
+    pub format: OutputFormat,
+    /// truncate table cells longer than this many characters
+    pub max_col_width: Option<usize>,
+    /// only write this many rows of a result table
+    pub max_rows: Option<usize>,
+    /// what NULL values are shown as in a result table, so they can be told apart from empty strings
+    pub null_display: String,
+    /// write each row as a list of `column: value` lines instead of as a table
+    pub expanded: bool,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            max_col_width: None,
+            max_rows: None,
+            null_display: String::from(DEFAULT_NULL_DISPLAY),
+            expanded: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ResultsInfo {
     pub columns: Vec<String>,
     pub results: Vec<Map<String, Value>>,
+    /// how many rows the query modified, if it modified any while returning rows
+    pub affected_rows: Option<u32>,
 }
 
 #[derive(Debug)]
 pub enum QueryResults {
     AffectedRows(u32),
     Results(ResultsInfo),
+    SchemaChanged(SchemaChange),
     DeadLock(SddmsError),
 }
 
-impl Into<Table> for ResultsInfo {
-    fn into(self) -> Table {
+impl QueryResults {
+    /// Writes the affected row count or result table to the given output. Deadlocks aren't results,
+    /// so they are left for the caller to report
+    pub fn write_to(self, output: &mut dyn Write, options: &DisplayOptions) -> Result<Option<SddmsError>, SddmsError> {
+        if options.format == OutputFormat::Json {
+            return self.write_json_to(output);
+        }
+
+        let write_result = match self {
+            QueryResults::AffectedRows(row_count) => writeln!(output, "Affected {} rows", row_count),
+            QueryResults::SchemaChanged(schema_change) => writeln!(output, "{}", schema_change),
+            QueryResults::Results(results) => {
+                let affected_rows = results.affected_rows;
+                let (rendered, hidden_rows) = if options.expanded {
+                    results.build_expanded(options)
+                } else {
+                    let (table, hidden_rows) = results.build_table(options);
+                    (table.to_string(), hidden_rows)
+                };
+                writeln!(output, "{}", rendered)
+                    .and_then(|_| match hidden_rows {
+                        0 => Ok(()),
+                        hidden_rows => writeln!(output, "... ({} more rows)", hidden_rows),
+                    })
+                    .and_then(|_| match affected_rows {
+                        Some(row_count) => writeln!(output, "Affected {} rows", row_count),
+                        None => Ok(()),
+                    })
+            }
+            QueryResults::DeadLock(deadlock_err) => return Ok(Some(deadlock_err)),
+        };
+
+        write_result
+            .map(|_| None)
+            .map_err(|err| SddmsError::client("Failed to write query results").with_cause(err))
+    }
+
+    fn write_json_to(self, output: &mut dyn Write) -> Result<Option<SddmsError>, SddmsError> {
+        let object = match self {
+            QueryResults::AffectedRows(row_count) => json!({ "affected_rows": row_count }),
+            QueryResults::SchemaChanged(schema_change) => json!({ "schema_change": schema_change.to_string() }),
+            QueryResults::Results(results) => json!({
+                "columns": results.columns,
+                "rows": results.results,
+                "affected_rows": results.affected_rows,
+            }),
+            QueryResults::DeadLock(deadlock_err) => return Ok(Some(deadlock_err)),
+        };
+
+        writeln!(output, "{}", object)
+            .map(|_| None)
+            .map_err(|err| SddmsError::client("Failed to write query results").with_cause(err))
+    }
+}
+
+impl ResultsInfo {
+    /// Builds a table of at most `max_rows` rows with cells cut down to `max_col_width` characters.
+    /// Also gives how many rows were left out
+    pub fn build_table(self, options: &DisplayOptions) -> (Table, usize) {
 
         let columns = self.columns;
+        let row_count = self.results.len();
+        let shown_rows = options.max_rows.map_or(row_count, |max_rows| max_rows.min(row_count));
         let mut rows: Vec<Vec<String>> = Vec::new();
-        for record in self.results {
+        for record in self.results.into_iter().take(shown_rows) {
             let mut row: Vec<String> = Vec::new();
             for column_name in &columns {
-                let column_value = record.get(column_name).unwrap();
-                row.push(column_value.to_string())
+                let column_value = cell_text(record.get(column_name).unwrap(), &options.null_display);
+                row.push(truncate_cell(column_value, options.max_col_width))
             }
             rows.push(row);
         }
@@ -36,6 +162,189 @@ impl Into<Table> for ResultsInfo {
             builder.push_record(row);
         }
 
-        builder.build()
+        (builder.build(), row_count - shown_rows)
+    }
+
+    /// Like `build_table`, but writes each row as a numbered record of `column: value` lines, which is
+    /// easier to read than a table when there are many columns
+    pub fn build_expanded(self, options: &DisplayOptions) -> (String, usize) {
+        let row_count = self.results.len();
+        let shown_rows = options.max_rows.map_or(row_count, |max_rows| max_rows.min(row_count));
+        let column_width = self.columns.iter()
+            .map(|column| column.chars().count())
+            .max()
+            .unwrap_or(0);
+
+        let mut records: Vec<String> = Vec::new();
+        for (index, record) in self.results.into_iter().take(shown_rows).enumerate() {
+            let mut lines = vec![format!("-[ RECORD {} ]", index + 1)];
+            for column_name in &self.columns {
+                let column_value = cell_text(record.get(column_name).unwrap(), &options.null_display);
+                lines.push(format!("{:width$}: {}", column_name, truncate_cell(column_value, options.max_col_width), width = column_width));
+            }
+            records.push(lines.join("\n"));
+        }
+
+        if records.is_empty() {
+            records.push(String::from("(0 rows)"));
+        }
+
+        (records.join("\n"), row_count - shown_rows)
+    }
+}
+
+impl From<ResultsInfo> for Table {
+    fn from(value: ResultsInfo) -> Self {
+        value.build_table(&DisplayOptions::default()).0
+    }
+}
+
+/// The text shown for a value in a result table. Strings are shown without quotes, so NULL is shown as
+/// `null_display` to tell it apart from a string
+fn cell_text(value: &Value, null_display: &str) -> String {
+    match value {
+        Value::Null => null_display.to_string(),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// cuts a serialized cell value down to `max_width` characters, ending it with an ellipsis
+fn truncate_cell(value: String, max_width: Option<usize>) -> String {
+    match max_width {
+        Some(max_width) if value.chars().count() > max_width => {
+            let mut truncated = value.chars()
+                .take(max_width.saturating_sub(1))
+                .collect::<String>();
+            truncated.push('…');
+            truncated
+        }
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value};
+    use crate::query_results::{DisplayOptions, OutputFormat, QueryResults, ResultsInfo};
+
+    fn select_results(value: i64) -> QueryResults {
+        let mut record: Map<String, Value> = Map::new();
+        record.insert(String::from("id"), json!(value));
+        QueryResults::Results(ResultsInfo {
+            columns: vec![String::from("id")],
+            results: vec![record],
+            affected_rows: None,
+        })
+    }
+
+    #[test]
+    fn script_of_selects_writes_every_table() {
+        let path = std::env::temp_dir().join(format!("sddms-client-output-{}.txt", std::process::id()));
+        let mut output = std::fs::File::create(&path).unwrap();
+        for value in [1, 2, 3] {
+            assert!(select_results(value).write_to(&mut output, &DisplayOptions::default()).unwrap().is_none());
+        }
+        drop(output);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.matches("| id |").count(), 3);
+        for value in ["| 1  |", "| 2  |", "| 3  |"] {
+            assert!(contents.contains(value), "missing {} in {}", value, contents);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn deadlock_is_not_written() {
+        let mut output: Vec<u8> = Vec::new();
+        let deadlock = QueryResults::DeadLock(sddms_shared::error::SddmsError::client("deadlocked"));
+        assert!(deadlock.write_to(&mut output, &DisplayOptions::default()).unwrap().is_some());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn json_format_writes_an_object_per_query() {
+        let mut output: Vec<u8> = Vec::new();
+        let json_options = DisplayOptions { format: OutputFormat::Json, ..DisplayOptions::default() };
+        assert!(select_results(7).write_to(&mut output, &json_options).unwrap().is_none());
+        assert!(QueryResults::AffectedRows(2).write_to(&mut output, &json_options).unwrap().is_none());
+
+        let lines = String::from_utf8(output).unwrap();
+        let objects = lines.lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(objects, vec![
+            json!({ "columns": ["id"], "rows": [{ "id": 7 }], "affected_rows": null }),
+            json!({ "affected_rows": 2 }),
+        ]);
+    }
+
+    #[test]
+    fn wide_cells_and_long_results_are_truncated() {
+        let records = ["a", "abcdefghij", "second", "third"].into_iter()
+            .map(|name| {
+                let mut record: Map<String, Value> = Map::new();
+                record.insert(String::from("name"), json!(name));
+                record
+            })
+            .collect();
+        let results = QueryResults::Results(ResultsInfo {
+            columns: vec![String::from("name")],
+            results: records,
+            affected_rows: None,
+        });
+        let options = DisplayOptions { max_col_width: Some(6), max_rows: Some(2), ..DisplayOptions::default() };
+
+        let mut output: Vec<u8> = Vec::new();
+        results.write_to(&mut output, &options).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("| a "), "{}", output);
+        assert!(output.contains("| abcde… |"), "{}", output);
+        assert!(!output.contains("second"), "{}", output);
+        assert!(output.trim_end().ends_with("... (2 more rows)"), "{}", output);
+    }
+
+    #[test]
+    fn nulls_are_told_apart_from_empty_strings() {
+        let records = [json!(null), json!("")].into_iter()
+            .map(|name| {
+                let mut record: Map<String, Value> = Map::new();
+                record.insert(String::from("name"), name);
+                record
+            })
+            .collect();
+        let results = ResultsInfo {
+            columns: vec![String::from("name")],
+            results: records,
+            affected_rows: None,
+        };
+        let options = DisplayOptions { null_display: String::from("<null>"), ..DisplayOptions::default() };
+
+        let (table, _) = results.build_table(&options);
+        let output = table.to_string();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert!(lines.iter().any(|line| line.starts_with("| <null> |")), "{}", output);
+        assert!(lines.iter().any(|line| line.starts_with("|        |")), "{}", output);
+        assert!(!output.contains("\"\""), "{}", output);
+    }
+
+    #[test]
+    fn expanded_mode_writes_column_value_pairs() {
+        let mut record: Map<String, Value> = Map::new();
+        record.insert(String::from("id"), json!(7));
+        record.insert(String::from("name"), json!("pat"));
+        record.insert(String::from("nickname"), json!(null));
+        let results = QueryResults::Results(ResultsInfo {
+            columns: vec![String::from("id"), String::from("name"), String::from("nickname")],
+            results: vec![record],
+            affected_rows: None,
+        });
+        let options = DisplayOptions { expanded: true, ..DisplayOptions::default() };
+
+        let mut output: Vec<u8> = Vec::new();
+        results.write_to(&mut output, &options).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "-[ RECORD 1 ]\nid      : 7\nname    : pat\nnickname: NULL\n");
     }
 }
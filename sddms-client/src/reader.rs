@@ -1,35 +1,109 @@
+use std::time::Duration;
 use rustyline::history::History;
 use regex::{RegexSet};
-use rustyline::{Editor, Helper};
+use rustyline::Editor;
 use sddms_shared::error::{SddmsError, SddmsResult};
+use crate::prompt::Prompt;
+use crate::sql_helper::SqlHelper;
+use crate::transaction_state::TransactionState;
 
 #[derive(Debug, Clone)]
 pub enum MetaCommand {
     Quit,
     PrintTransactionInfo,
     CancelLine,
+    /// show the metadata computed for the query without running it
+    Explain(String),
+    /// commit the current transaction without typing out `COMMIT;`
+    Commit,
+    /// roll back the current transaction without typing out `ROLLBACK;`
+    Rollback,
+    /// re-run a read every so often until interrupted
+    Watch {
+        interval: Duration,
+        query: String,
+    },
+    /// switch between showing results as tables and as a list of column/value pairs per row
+    ToggleExpanded,
+    /// define a variable that later statements can refer to as `:name`
+    Set {
+        name: String,
+        value: String,
+    },
 }
 
 impl MetaCommand {
     fn looks_like_meta_command(line: &str) -> bool {
         line.starts_with("\\")
     }
+
+    /// Gives the arguments after the command name, if the line is that command. The name has to be
+    /// followed by whitespace or nothing, so `\setx` isn't taken as `\set`
+    fn strip_command<'line>(line: &'line str, command: &str) -> Option<&'line str> {
+        line.strip_prefix(command)
+            .filter(|args| args.is_empty() || args.starts_with(char::is_whitespace))
+    }
 }
 
 impl TryFrom<&str> for MetaCommand {
     type Error = SddmsError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if let Some(query) = MetaCommand::strip_command(value, "\\explain") {
+            let query = query.trim();
+            if query.is_empty() {
+                return Err(SddmsError::client("\\explain needs a query to explain"));
+            }
+            return Ok(MetaCommand::Explain(query.to_string()));
+        }
+
+        if let Some(args) = MetaCommand::strip_command(value, "\\watch") {
+            let Some((interval, query)) = args.trim().split_once(char::is_whitespace) else {
+                return Err(SddmsError::client("\\watch needs an interval in seconds and a query to run"));
+            };
+            let interval = interval.parse::<f64>()
+                .ok()
+                .filter(|seconds| seconds.is_finite() && *seconds > 0f64)
+                .ok_or(SddmsError::client(format!("Invalid \\watch interval '{}'", interval)))?;
+            return Ok(MetaCommand::Watch {
+                interval: Duration::from_secs_f64(interval),
+                query: query.trim().to_string(),
+            });
+        }
+
+        if let Some(args) = MetaCommand::strip_command(value, "\\set") {
+            let args = args.trim();
+            let (name, value) = args.split_once(char::is_whitespace)
+                .unwrap_or((args, ""));
+            let mut name_chars = name.chars();
+            let valid_name = name_chars.next().is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+                && name_chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+            if !valid_name {
+                return Err(SddmsError::client("\\set needs a variable name made of letters, digits and underscores"));
+            }
+            return Ok(MetaCommand::Set {
+                name: name.to_string(),
+                value: value.trim().to_string(),
+            });
+        }
+
         let meta_command = RegexSet::new([
             r#"\\q(uit)?"#,
             r#"\\txn"#,
+            // these come before cancel so that \commit doesn't match as \c
+            r#"^\\commit$"#,
+            r#"^\\rollback$"#,
             r#"\\c(ancel)?"#,
+            r#"^\\x$"#,
         ]).unwrap();
 
         let commands = vec![
             MetaCommand::Quit,
             MetaCommand::PrintTransactionInfo,
+            MetaCommand::Commit,
+            MetaCommand::Rollback,
             MetaCommand::CancelLine,
+            MetaCommand::ToggleExpanded,
         ];
 
         let result = meta_command.matches(value).iter()
@@ -45,16 +119,21 @@ pub enum Command {
     Lines(Vec<String>)
 }
 
-pub fn read_next_command<HelperT: Helper, HistoryT: History>(reader: &mut Editor<HelperT, HistoryT>) -> SddmsResult<Command> {
+pub fn read_next_command<HistoryT: History>(reader: &mut Editor<SqlHelper, HistoryT>, prompt: &Prompt, transaction_state: &TransactionState) -> SddmsResult<Command> {
 
     let mut lines = Vec::new();
     let mut multiline = false;
     loop {
+        // let the helper see the rest of the statement so it can be validated once it's complete
+        if let Some(helper) = reader.helper_mut() {
+            helper.set_pending(&lines);
+        }
+
         // read the line given line
         let line = if !multiline {
-            reader.readline(">> ")
+            reader.readline(&prompt.primary(transaction_state))
         } else {
-            reader.readline(" > ")
+            reader.readline(&prompt.continuation(transaction_state))
         };
 
         if line.is_err() {
@@ -99,7 +178,8 @@ pub fn split_statements(lines: Vec<String>) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
-    use crate::reader::split_statements;
+    use std::time::Duration;
+    use crate::reader::{MetaCommand, split_statements};
 
     #[test]
     fn split_statements__works() {
@@ -115,4 +195,56 @@ mod tests {
         assert_eq!(actual[1], "how\nare you doing;");
         assert_eq!(actual[2], "I'm doing really\nwell;");
     }
+
+    #[test]
+    fn explain_takes_the_rest_of_the_line() {
+        let MetaCommand::Explain(query) = MetaCommand::try_from("\\explain SELECT * FROM students;").unwrap() else {
+            panic!("expected an explain command");
+        };
+        assert_eq!(query, "SELECT * FROM students;");
+
+        assert!(MetaCommand::try_from("\\explain").is_err());
+    }
+
+    #[test]
+    fn watch_takes_an_interval_and_query() {
+        let MetaCommand::Watch { interval, query } = MetaCommand::try_from("\\watch 2.5 SELECT * FROM students;").unwrap() else {
+            panic!("expected a watch command");
+        };
+        assert_eq!(interval, Duration::from_millis(2500));
+        assert_eq!(query, "SELECT * FROM students;");
+
+        assert!(MetaCommand::try_from("\\watch 2").is_err());
+        assert!(MetaCommand::try_from("\\watch 0 SELECT * FROM students;").is_err());
+        assert!(MetaCommand::try_from("\\watch soon SELECT * FROM students;").is_err());
+    }
+
+    #[test]
+    fn set_takes_a_name_and_value() {
+        let MetaCommand::Set { name, value } = MetaCommand::try_from("\\set min_id  10").unwrap() else {
+            panic!("expected a set command");
+        };
+        assert_eq!((name.as_str(), value.as_str()), ("min_id", "10"));
+
+        assert!(matches!(MetaCommand::try_from("\\set empty").unwrap(), MetaCommand::Set { value, .. } if value.is_empty()));
+        assert!(MetaCommand::try_from("\\set").is_err());
+        assert!(MetaCommand::try_from("\\set 1st value").is_err());
+    }
+
+    #[test]
+    fn command_names_must_end_at_whitespace() {
+        assert!(MetaCommand::try_from("\\setx name value").is_err());
+        assert!(MetaCommand::try_from("\\explained SELECT * FROM students;").is_err());
+        assert!(MetaCommand::try_from("\\watch2 SELECT * FROM students;").is_err());
+        assert!(matches!(MetaCommand::try_from("\\set\tname value").unwrap(), MetaCommand::Set { name, .. } if name == "name"));
+    }
+
+    #[test]
+    fn finalize_shortcuts_are_recognized() {
+        assert!(matches!(MetaCommand::try_from("\\commit").unwrap(), MetaCommand::Commit));
+        assert!(matches!(MetaCommand::try_from("\\rollback").unwrap(), MetaCommand::Rollback));
+        assert!(matches!(MetaCommand::try_from("\\c").unwrap(), MetaCommand::CancelLine));
+        assert!(matches!(MetaCommand::try_from("\\cancel").unwrap(), MetaCommand::CancelLine));
+        assert!(matches!(MetaCommand::try_from("\\x").unwrap(), MetaCommand::ToggleExpanded));
+    }
 }
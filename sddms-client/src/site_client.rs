@@ -1,14 +1,20 @@
+use log::{info, warn};
 use serde_json::{Map, Value};
+use tonic::Streaming;
 use tonic::transport::Channel;
-use sddms_services::shared::{FinalizeMode, ReturnStatus};
+use sddms_services::shared::{FinalizeMode, LockMode, LockRequest, ReturnStatus};
 use sddms_services::site_controller::invoke_query_response::InvokeQueryPayload;
-use sddms_services::site_controller::{BeginTransactionRequest, FinalizeTransactionRequest, InvokeQueryRequest, RegisterClientRequest};
+use sddms_services::site_controller::{BeginTransactionRequest, FinalizeTransactionRequest, InvokeQueryRequest, InvokeQueryResponse, RegisterClientRequest, UnregisterClientRequest};
 use sddms_services::site_controller::begin_transaction_response::BeginTransactionPayload;
 use sddms_services::site_controller::finalize_transaction_response::FinalizeTransactionPayload;
 use sddms_services::site_controller::register_client_response::RegisterClientPayload;
+use sddms_services::site_controller::unregister_client_response::UnregisterClientPayload;
 use sddms_services::site_controller::site_manager_service_client::SiteManagerServiceClient;
+use sddms_services::transport;
+use sddms_services::transport::TlsOptions;
 use sddms_shared::error::SddmsError;
-use sddms_shared::sql_metadata::TransactionStmt;
+use sddms_shared::sql_metadata::{LockGranularity, LockTableStmt, SchemaChange, TransactionStmt};
+use crate::dsn::Dsn;
 use crate::query_results::{QueryResults, ResultsInfo};
 
 pub enum FinalizeResult {
@@ -19,13 +25,24 @@ pub enum FinalizeResult {
 pub struct SddmsSiteClient {
     client: SiteManagerServiceClient<Channel>,
     client_id: Option<u32>,
+    lock_granularity: LockGranularity,
+    /// fail queries right away instead of waiting for locks held by someone else
+    no_wait: bool,
+    /// run reads outside of a transaction in a transaction of their own, so that they're serializable
+    autocommit_reads: bool,
+    /// locks named by `LOCK TABLE` statements, sent in order with the next query of the transaction
+    ordered_locks: Vec<LockRequest>,
 }
 
 impl SddmsSiteClient {
     fn new(inner: SiteManagerServiceClient<Channel>) -> Self {
         Self {
             client: inner,
-            client_id: None
+            client_id: None,
+            lock_granularity: LockGranularity::default(),
+            no_wait: false,
+            autocommit_reads: true,
+            ordered_locks: Vec::new(),
         }
     }
 
@@ -33,18 +50,54 @@ impl SddmsSiteClient {
         self.client_id = Some(id);
     }
 
+    pub fn set_lock_granularity(&mut self, lock_granularity: LockGranularity) {
+        self.lock_granularity = lock_granularity;
+    }
+
+    pub fn set_no_wait(&mut self, no_wait: bool) {
+        self.no_wait = no_wait;
+    }
+
+    pub fn set_autocommit_reads(&mut self, autocommit_reads: bool) {
+        self.autocommit_reads = autocommit_reads;
+    }
+
+    /// Has the next query in the transaction take these locks first, in the order they're named
+    pub fn queue_ordered_locks(&mut self, lock_table_stmt: LockTableStmt) {
+        let mode = if lock_table_stmt.exclusive { LockMode::Exclusive } else { LockMode::Shared };
+        self.ordered_locks.extend(lock_table_stmt.tables.into_iter()
+            .map(|table| LockRequest::new(table, mode)));
+    }
+
     #[inline]
     fn client_id(&self) -> u32 {
         self.client_id.unwrap()
     }
 
-    pub async fn connect<ConnStrT: Into<String>>(conn_str: ConnStrT) -> Result<Self, SddmsError> {
-        let conn_str = conn_str.into();
-        let client = SiteManagerServiceClient::connect(format!("http://{}", conn_str))
-            .await
+    pub async fn connect(dsn: &Dsn, tls: Option<&TlsOptions>) -> Result<Self, SddmsError> {
+        let connection = transport::connect(&dsn.host, tls);
+        let connected = match dsn.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connection)
+                .await
+                .map_err(|err| SddmsError::client(format!("Timed out connecting to site controller after {:?}", timeout)).with_cause(err))?,
+            None => connection.await,
+        };
+
+        let channel = connected
             .map_err(|err| SddmsError::client("Failed to connect to site controller").with_cause(err))?;
 
-        Ok(Self::new(client))
+        Ok(Self::new(SiteManagerServiceClient::new(channel)))
+    }
+
+    /// A client for a site that isn't connected to until the first request, so the client can be tested
+    /// without one
+    #[cfg(test)]
+    pub fn lazy(host: &str) -> Result<Self, SddmsError> {
+        let channel = Channel::from_shared(format!("http://{}", host))
+            .map_err(|err| SddmsError::client("Invalid site address").with_cause(err))?
+            .connect_lazy();
+
+        Ok(Self::new(SiteManagerServiceClient::new(channel)))
     }
 
     pub async fn register_self(&mut self) -> Result<u32, SddmsError> {
@@ -68,6 +121,28 @@ impl SddmsSiteClient {
         }
     }
 
+    /// Tells the site that this client is done. Returns any transactions that were rolled back
+    /// because they were still in progress
+    pub async fn unregister_self(&mut self) -> Result<Vec<u32>, SddmsError> {
+        let request = UnregisterClientRequest {
+            client_id: self.client_id(),
+        };
+
+        let response = self.client.unregister_client(request)
+            .await
+            .map_err(|err| SddmsError::client("Failed to unregister from site controller").with_cause(err))
+            ?.into_inner();
+
+        match response.unregister_client_payload.unwrap() {
+            UnregisterClientPayload::Error(err) => {
+                Err(err.into())
+            }
+            UnregisterClientPayload::Results(results) => {
+                Ok(results.rolled_back_transactions)
+            }
+        }
+    }
+
     pub async fn begin_transaction(&mut self) -> Result<u32, SddmsError> {
         let request = BeginTransactionRequest {
             transaction_name: None,
@@ -89,44 +164,52 @@ impl SddmsSiteClient {
     }
 
     pub async fn invoke_query(&mut self, trans_id: Option<u32>, query: &str) -> Result<QueryResults, SddmsError> {
-        let request = self.configure_request(trans_id, query)?;
+        let (mut request, schema_change) = configure_request(self.client_id(), self.lock_granularity, self.autocommit_reads, trans_id, query)?;
+        request.no_wait = self.no_wait;
+        if trans_id.is_some() {
+            request.ordered_locks = std::mem::take(&mut self.ordered_locks);
+        }
         let response = self.client.invoke_query(request).await
             .map_err(|status| SddmsError::client(format!("Error while sending request: {} {}", status.code(), status.message())))?;
 
-        let invoke_response = response.into_inner();
-        let ret = invoke_response.ret().clone();
-        let result = match invoke_response.invoke_query_payload.unwrap() {
-            InvokeQueryPayload::Error(api_error) => {
-                if let ReturnStatus::Deadlocked = ret {
-                    Ok(QueryResults::DeadLock(api_error.into()))
-                } else {
-                    let sddms_err_cause: SddmsError = api_error.into();
-                    Err(SddmsError::client("Failed to invoke query")
-                        .with_cause(sddms_err_cause))
-                }
-            }
-            InvokeQueryPayload::Results(query_results) => {
-                let results = if let Some(affected_records) = query_results.affected_records {
-                    QueryResults::AffectedRows(affected_records)
-                } else if let Some(payload) = query_results.data_payload {
-                    let objects: Vec<Map<String, Value>> = serde_json::from_slice(&payload)
-                        .map_err(|err| SddmsError::general("Could not deserialize query result").with_cause(err))?;
-                    QueryResults::Results(ResultsInfo {
-                        results: objects,
-                        columns: query_results.column_names
-                    })
-                } else {
-                    panic!("Nothing was specified")
-                };
-                Ok(results)
-            }
-        };
+        read_query_results(response.into_inner(), schema_change)
+    }
 
-        result
+    /// Invokes a query, getting its rows back in batches that can be shown as they arrive
+    pub async fn invoke_query_streaming(&mut self, trans_id: Option<u32>, query: &str) -> Result<QueryResultBatches, SddmsError> {
+        let (mut request, schema_change) = configure_request(self.client_id(), self.lock_granularity, self.autocommit_reads, trans_id, query)?;
+        request.no_wait = self.no_wait;
+        if trans_id.is_some() {
+            request.ordered_locks = std::mem::take(&mut self.ordered_locks);
+        }
+        let batches = self.client.invoke_query_stream(request).await
+            .map_err(|status| SddmsError::client(format!("Error while sending request: {} {}", status.code(), status.message())))?
+            .into_inner();
+
+        Ok(QueryResultBatches { batches, schema_change })
+    }
+
+    /// Gets the names of all tables in the site's database
+    pub async fn fetch_table_names(&mut self) -> Result<Vec<String>, SddmsError> {
+        let results = self.invoke_query(None, "SELECT name FROM sqlite_master WHERE type = 'table';").await?;
+        match results {
+            QueryResults::Results(results) => {
+                let names = results.results.iter()
+                    .filter_map(|row| row.get("name"))
+                    .filter_map(|name| name.as_str())
+                    .map(String::from)
+                    .collect();
+                Ok(names)
+            }
+            QueryResults::DeadLock(err) => Err(SddmsError::client("Deadlocked while fetching table names").with_cause(err)),
+            QueryResults::AffectedRows(_) | QueryResults::SchemaChanged(_) => Err(SddmsError::client("Fetching table names returned no results")),
+        }
     }
 
     pub async fn finalize_transaction(&mut self, id: u32, mode: TransactionStmt) -> Result<(), SddmsError> {
-        let finalize_mode = FinalizeMode::try_from(mode).unwrap();
+        self.ordered_locks.clear();
+        let finalize_mode = FinalizeMode::try_from(mode)
+            .map_err(|err| SddmsError::client(format!("Cannot finalize transaction {}", id)).with_cause(err))?;
         let mut request = FinalizeTransactionRequest {
             mode: 0,
             transaction_id: id,
@@ -144,32 +227,211 @@ impl SddmsSiteClient {
                 Err(SddmsError::client("Failed to finalize transaction")
                     .with_cause(sddms_err_cause))
             }
-            FinalizeTransactionPayload::Results(_) => {
+            FinalizeTransactionPayload::Results(results) => {
+                // the transaction is committed, it just won't show up everywhere until the sites catch up
+                if let Some(warning) = results.replication_warning {
+                    warn!("Transaction {} committed, but was not replicated to every site: {}", id, warning);
+                }
                 Ok(())
             }
         }
     }
+}
+
+/// Builds the request for a query, locking the resources the query touches at the given granularity. Also
+/// gives back the schema change the query makes, if it's DDL. A query outside of a transaction normally runs
+/// in a transaction of its own. Without `autocommit_reads`, reads outside of a transaction skip that and run
+/// without any locks, which saves round trips to the central controller but means they can see other
+/// transactions' updates partway through being replicated
+fn configure_request(client_id: u32, lock_granularity: LockGranularity, autocommit_reads: bool, trans_id: Option<u32>, query: &str) -> Result<(InvokeQueryRequest, Option<SchemaChange>), SddmsError> {
+    let sql_statements = sddms_shared::sql_metadata::parse_statements(query)
+        .map_err(|err| SddmsError::client("Failed to parse SQL query").with_cause(err))?;
+
+    if sql_statements.len() != 1 {
+        panic!("Got {} statements, which is too many", sql_statements.len())
+    }
 
-    fn configure_request(&self, trans_id: Option<u32>, query: &str) -> Result<InvokeQueryRequest, SddmsError> {
-        let sql_statements = sddms_shared::sql_metadata::parse_statements(query)
-            .map_err(|err| SddmsError::client("Failed to parse SQL query").with_cause(err))?;
+    let metadata = sql_statements.first().unwrap();
+    let lock_resources = metadata.lock_resources(lock_granularity);
 
-        if sql_statements.len() != 1 {
-            panic!("Got {} statements, which is too many", sql_statements.len())
+    let unlocked_read = trans_id.is_none() && !autocommit_reads && !metadata.modifiable();
+    let single_stmt_trans = trans_id.is_none() && !unlocked_read;
+
+    let request = InvokeQueryRequest {
+        transaction_id: trans_id.unwrap_or_default(),
+        query: String::from(query),
+        has_results: metadata.has_results(),
+        read_set: lock_resources.shared,
+        write_set: lock_resources.exclusive,
+        single_stmt_transaction: single_stmt_trans,
+        client_id,
+        no_wait: false,
+        unlocked_read,
+        ordered_locks: Vec::new(),
+        read_after: None,
+    };
+
+    Ok((request, metadata.schema_change()))
+}
+
+/// The results of a streamed query, one batch at a time
+pub struct QueryResultBatches {
+    batches: Streaming<InvokeQueryResponse>,
+    schema_change: Option<SchemaChange>,
+}
+
+impl QueryResultBatches {
+    /// Waits for the next batch, giving `None` once every batch has been read
+    pub async fn next(&mut self) -> Result<Option<QueryResults>, SddmsError> {
+        let next_batch = self.batches.message().await
+            .map_err(|status| SddmsError::client(format!("Error while reading results: {} {}", status.code(), status.message())))?;
+
+        next_batch
+            .map(|response| read_query_results(response, self.schema_change))
+            .transpose()
+    }
+}
+
+/// Reads the results out of a query's response. A response that carries neither an affected row count nor
+/// any data is malformed, so it's an error rather than an empty result. DDL reports its schema change
+/// rather than the rows it affected, which is always none
+fn read_query_results(invoke_response: InvokeQueryResponse, schema_change: Option<SchemaChange>) -> Result<QueryResults, SddmsError> {
+    let ret = invoke_response.ret();
+    let Some(payload) = invoke_response.invoke_query_payload else {
+        return Err(SddmsError::client("Query response did not have a payload"));
+    };
+
+    match payload {
+        InvokeQueryPayload::Error(api_error) => {
+            if let ReturnStatus::Deadlocked = ret {
+                Ok(QueryResults::DeadLock(api_error.into()))
+            } else {
+                let sddms_err_cause: SddmsError = api_error.into();
+                Err(SddmsError::client("Failed to invoke query")
+                    .with_cause(sddms_err_cause))
+            }
         }
+        InvokeQueryPayload::Results(query_results) => {
+            if let Some(warning) = &query_results.replication_warning {
+                warn!("Query committed, but was not replicated to every site: {}", warning);
+            }
+            if query_results.lock_queue_position > 0 {
+                info!("Query waited behind {} locks before it ran", query_results.lock_queue_position);
+            }
 
-        let metadata = sql_statements.get(0).unwrap();
+            // rows take precedence, since a statement like `UPDATE ... RETURNING` both modifies rows and
+            // returns them. The affected count is kept alongside them so it isn't lost
+            if let Some(payload) = query_results.data_payload {
+                let objects: Vec<Map<String, Value>> = serde_json::from_slice(&payload)
+                    .map_err(|err| SddmsError::general("Could not deserialize query result").with_cause(err))?;
+                Ok(QueryResults::Results(ResultsInfo {
+                    results: objects,
+                    columns: query_results.column_names,
+                    affected_rows: query_results.affected_records,
+                }))
+            } else if let Some(affected_records) = query_results.affected_records {
+                match schema_change {
+                    Some(schema_change) => Ok(QueryResults::SchemaChanged(schema_change)),
+                    None => Ok(QueryResults::AffectedRows(affected_records)),
+                }
+            } else {
+                Err(SddmsError::client("Query results had neither an affected row count nor any data"))
+            }
+        }
+    }
+}
 
-        let single_stmt_trans = trans_id.is_none();
+#[cfg(test)]
+mod tests {
+    use sddms_services::shared::FinalizeMode;
+    use sddms_shared::sql_metadata::{LockGranularity, TransactionStmt};
+    use sddms_services::site_controller::{InvokeQueryResponse, InvokeQueryResults};
+    use sddms_services::site_controller::invoke_query_response::InvokeQueryPayload;
+    use crate::query_results::{DisplayOptions, QueryResults};
+    use crate::site_client::{configure_request, read_query_results};
 
-        Ok(InvokeQueryRequest {
-            transaction_id: trans_id.unwrap_or_default(),
-            query: String::from(query),
-            has_results: metadata.has_results(),
-            read_set: metadata.read_tables().iter().cloned().collect::<Vec<_>>(),
-            write_set: metadata.write_tables().iter().cloned().collect::<Vec<_>>(),
-            single_stmt_transaction: single_stmt_trans,
-            client_id: self.client_id(),
-        })
+    #[test]
+    fn empty_results_are_an_error() {
+        let response = InvokeQueryResponse {
+            invoke_query_payload: Some(InvokeQueryPayload::Results(InvokeQueryResults::default())),
+            ..Default::default()
+        };
+        assert!(read_query_results(response, None).is_err());
+
+        assert!(read_query_results(InvokeQueryResponse::default(), None).is_err());
+
+        let response = InvokeQueryResponse {
+            invoke_query_payload: Some(InvokeQueryPayload::Results(InvokeQueryResults { affected_records: Some(3), ..Default::default() })),
+            ..Default::default()
+        };
+        assert!(matches!(read_query_results(response, None), Ok(QueryResults::AffectedRows(3))));
+    }
+
+    #[test]
+    fn create_table_reports_schema_change() {
+        let (_, schema_change) = configure_request(1, LockGranularity::Table, true, None, "CREATE TABLE flights (id INTEGER);").unwrap();
+        let mut response = InvokeQueryResponse::default();
+        response.invoke_query_payload = Some(InvokeQueryPayload::Results(InvokeQueryResults { affected_records: Some(0), ..Default::default() }));
+
+        let mut output: Vec<u8> = Vec::new();
+        read_query_results(response, schema_change).unwrap().write_to(&mut output, &DisplayOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "Table created\n");
+    }
+
+    #[test]
+    fn returned_rows_keep_affected_count() {
+        let mut response = InvokeQueryResponse::default();
+        response.invoke_query_payload = Some(InvokeQueryPayload::Results(InvokeQueryResults {
+            data_payload: Some(br#"[{"id":1},{"id":2}]"#.to_vec()),
+            affected_records: Some(2),
+            column_names: vec![String::from("id")],
+            ..Default::default()
+        }));
+
+        let Ok(QueryResults::Results(results)) = read_query_results(response, None) else {
+            panic!("expected the returned rows");
+        };
+        assert_eq!(results.results.len(), 2);
+        assert_eq!(results.affected_rows, Some(2));
+
+        let mut output: Vec<u8> = Vec::new();
+        QueryResults::Results(results).write_to(&mut output, &DisplayOptions::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("| id |"));
+        assert!(output.trim_end().ends_with("Affected 2 rows"));
+    }
+
+    #[test]
+    fn only_commit_and_rollback_finalize() {
+        assert_eq!(FinalizeMode::try_from(TransactionStmt::Commit).unwrap(), FinalizeMode::Commit);
+        assert_eq!(FinalizeMode::try_from(TransactionStmt::Rollback).unwrap(), FinalizeMode::Abort);
+        assert!(FinalizeMode::try_from(TransactionStmt::Begin).is_err());
+    }
+
+    #[test]
+    fn reads_can_opt_out_of_autocommit() {
+        let (request, _) = configure_request(1, LockGranularity::Table, false, None, "SELECT * FROM students;").unwrap();
+        assert!(request.unlocked_read);
+        assert!(!request.single_stmt_transaction);
+
+        // writes and reads inside a transaction still lock as usual
+        let (request, _) = configure_request(1, LockGranularity::Table, false, None, "DELETE FROM students;").unwrap();
+        assert!(!request.unlocked_read);
+        assert!(request.single_stmt_transaction);
+        let (request, _) = configure_request(1, LockGranularity::Table, false, Some(2), "SELECT * FROM students;").unwrap();
+        assert!(!request.unlocked_read);
+    }
+
+    #[test]
+    fn column_granularity_locks_columns() {
+        let query = "UPDATE students SET grade = 90 WHERE id = 1;";
+
+        let (table_request, _) = configure_request(1, LockGranularity::Table, true, Some(2), query).unwrap();
+        assert!(table_request.read_set.is_empty());
+        assert_eq!(table_request.write_set, vec!["students".to_string()]);
+
+        let (column_request, _) = configure_request(1, LockGranularity::Column, true, Some(2), query).unwrap();
+        assert_eq!(column_request.read_set, vec!["students".to_string(), "students.id".to_string()]);
+        assert_eq!(column_request.write_set, vec!["students.grade".to_string()]);
     }
 }
@@ -0,0 +1,190 @@
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+use sddms_shared::sql_metadata::{check_syntax, parse_lock_table_stmt, split_sql_statements};
+
+/// SQL keywords offered as completions alongside table names
+const SQL_KEYWORDS: &[&str] = &[
+    "SELECT", "FROM", "WHERE", "INSERT", "INTO", "VALUES", "UPDATE", "SET", "DELETE",
+    "CREATE", "TABLE", "DROP", "ALTER", "JOIN", "INNER", "LEFT", "OUTER", "ON", "AND", "OR",
+    "NOT", "NULL", "IS", "IN", "LIKE", "BETWEEN", "ORDER", "GROUP", "BY", "HAVING", "LIMIT",
+    "OFFSET", "AS", "DISTINCT", "UNION", "BEGIN", "TRANSACTION", "COMMIT", "ROLLBACK",
+];
+
+/// Line editor helper that completes table names and SQL keywords, and checks that statements parse
+/// before they are sent
+pub struct SqlHelper {
+    /// names of the tables in the site's database
+    table_names: Vec<String>,
+    /// lines of the current statement that have already been read
+    pending: String,
+}
+
+impl SqlHelper {
+    pub fn new(table_names: Vec<String>) -> Self {
+        Self {
+            table_names,
+            pending: String::new(),
+        }
+    }
+
+    /// Sets the lines of a multi-line statement that were read before the current line
+    pub fn set_pending(&mut self, lines: &[String]) {
+        self.pending = lines.join("\n");
+    }
+
+    /// Checks the statement that the given line completes, if any. Gives an error message if the
+    /// statement is not valid SQL. Statements that ended on earlier lines aren't checked again
+    fn check_line(&self, line: &str) -> Option<String> {
+        let line = line.trim();
+        if line.starts_with('\\') || !line.ends_with(';') {
+            return None;
+        }
+
+        let buffered = if self.pending.is_empty() {
+            line.to_string()
+        } else {
+            format!("{}\n{}", self.pending, line)
+        };
+
+        let statement = match split_sql_statements(&buffered) {
+            Ok(mut statements) => statements.pop()?,
+            Err(err) => return Some(format!("\nSyntax error: {}", err)),
+        };
+
+        // SQLite has no LOCK TABLE, so the client checks those itself
+        if let Some(lock_table_stmt) = parse_lock_table_stmt(&statement) {
+            return lock_table_stmt.err()
+                .map(|err| format!("\nSyntax error: {}", err));
+        }
+
+        check_syntax(&statement).err()
+            .map(|err| format!("\nSyntax error: {}", err))
+    }
+
+    /// Gets all of the completions for the given partial word. Table names are matched
+    /// case-sensitively, while keywords are matched regardless of case and take the case of the prefix
+    fn candidates(&self, prefix: &str) -> Vec<String> {
+        let table_candidates = self.table_names.iter()
+            .filter(|table_name| table_name.starts_with(prefix))
+            .cloned();
+
+        let upper_prefix = prefix.to_uppercase();
+        let is_lowercase = prefix.chars().all(|ch| !ch.is_uppercase());
+        let keyword_candidates = SQL_KEYWORDS.iter()
+            .filter(|keyword| keyword.starts_with(&upper_prefix))
+            .map(|keyword| if is_lowercase { keyword.to_lowercase() } else { keyword.to_string() });
+
+        table_candidates.chain(keyword_candidates).collect()
+    }
+}
+
+impl Completer for SqlHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
+        let word_start = line[..pos]
+            .rfind(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+
+        let prefix = &line[word_start..pos];
+        if prefix.is_empty() {
+            return Ok((pos, Vec::new()));
+        }
+
+        Ok((word_start, self.candidates(prefix)))
+    }
+}
+
+impl Hinter for SqlHelper {
+    type Hint = String;
+}
+
+impl Highlighter for SqlHelper {}
+
+impl Validator for SqlHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        match self.check_line(ctx.input()) {
+            Some(msg) => Ok(ValidationResult::Invalid(Some(msg))),
+            None => Ok(ValidationResult::Valid(None)),
+        }
+    }
+}
+
+impl Helper for SqlHelper {}
+
+#[cfg(test)]
+mod tests {
+    use rustyline::completion::Completer;
+    use rustyline::history::DefaultHistory;
+    use rustyline::Context;
+    use crate::sql_helper::SqlHelper;
+
+    #[test]
+    fn complete_returns_matching_table_names() {
+        let helper = SqlHelper::new(vec![
+            String::from("flights"),
+            String::from("flight_seats"),
+            String::from("passengers"),
+        ]);
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        let line = "SELECT * FROM fli";
+        let (start, candidates) = helper.complete(line, line.len(), &ctx).unwrap();
+
+        assert_eq!(start, 14);
+        assert_eq!(candidates, vec![String::from("flights"), String::from("flight_seats")]);
+    }
+
+    #[test]
+    fn complete_matches_keywords_in_prefix_case() {
+        let helper = SqlHelper::new(Vec::new());
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        let (_, candidates) = helper.complete("sel", 3, &ctx).unwrap();
+        assert_eq!(candidates, vec![String::from("select")]);
+
+        let (_, candidates) = helper.complete("SEL", 3, &ctx).unwrap();
+        assert_eq!(candidates, vec![String::from("SELECT")]);
+    }
+
+    #[test]
+    fn check_line_flags_invalid_statement() {
+        let helper = SqlHelper::new(Vec::new());
+        assert!(helper.check_line("SELECT * FRM students;").is_some());
+        assert!(helper.check_line("SELECT * FROM students;").is_none());
+    }
+
+    #[test]
+    fn check_line_waits_for_complete_statement() {
+        let mut helper = SqlHelper::new(Vec::new());
+        assert!(helper.check_line("SELECT *").is_none());
+
+        helper.set_pending(&[String::from("SELECT *")]);
+        assert!(helper.check_line("FROM students;").is_none());
+        assert!(helper.check_line("FROM students WHERE;").is_some());
+    }
+
+    #[test]
+    fn check_line_accepts_lock_table() {
+        let mut helper = SqlHelper::new(Vec::new());
+        assert!(helper.check_line("LOCK TABLE flights IN EXCLUSIVE MODE;").is_none());
+        assert!(helper.check_line("LOCK TABL flights;").is_some());
+
+        helper.set_pending(&[String::from("LOCK TABLES flights,")]);
+        assert!(helper.check_line("seats IN SHARE MODE;").is_none());
+    }
+
+    #[test]
+    fn check_line_only_checks_the_statement_it_completes() {
+        let mut helper = SqlHelper::new(Vec::new());
+        helper.set_pending(&[String::from("SELECT * FRM students; SELECT *")]);
+        assert!(helper.check_line("FROM students;").is_none());
+        assert!(helper.check_line("FROM students WHERE;").is_some());
+    }
+}
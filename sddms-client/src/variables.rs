@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use sddms_shared::error::SddmsError;
+use sddms_shared::sql_metadata::split_sql_statements;
+
+/// Variables defined with `\set` over the course of a session. `:name` references in statements are
+/// replaced with the variable's value before the statement is sent, so a value meant to be a string
+/// should be set with its quotes, e.g. `\set name 'pat'`
+#[derive(Debug, Default)]
+pub struct SessionVariables {
+    values: HashMap<String, String>,
+}
+
+fn is_name_start(ch: char) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_'
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+impl SessionVariables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, name: String, value: String) {
+        self.values.insert(name, value);
+    }
+
+    /// Replaces every `:name` reference in the statement with the value of that variable. References
+    /// inside of string literals are left alone. Fails if a referenced variable was never set, or if a value
+    /// would split the statement into several
+    pub fn substitute(&self, stmt: &str) -> Result<String, SddmsError> {
+        let mut substituted = String::with_capacity(stmt.len());
+        let mut in_string = false;
+        let mut chars = stmt.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch == '\'' {
+                // doubled quotes inside a string toggle twice, so they stay inside the string
+                in_string = !in_string;
+            }
+
+            if in_string || ch != ':' || !chars.peek().is_some_and(|next| is_name_start(*next)) {
+                substituted.push(ch);
+                continue;
+            }
+
+            let mut name = String::new();
+            while let Some(next) = chars.next_if(|next| is_name_char(*next)) {
+                name.push(next);
+            }
+
+            let value = self.values.get(&name)
+                .ok_or_else(|| SddmsError::client(format!("Variable '{}' is not set", name)))?;
+            substituted.push_str(value);
+        }
+
+        // statements were already split up, so a value can't be allowed to add more
+        let statement_count = |sql: &str| split_sql_statements(sql).map(|statements| statements.len()).ok();
+        if statement_count(&substituted) > statement_count(stmt) {
+            return Err(SddmsError::client("Variable values can't end the statement they're used in"));
+        }
+
+        Ok(substituted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sddms_shared::sql_metadata::check_syntax;
+    use crate::reader::MetaCommand;
+    use crate::variables::SessionVariables;
+
+    #[test]
+    fn set_variables_are_substituted() {
+        let mut variables = SessionVariables::new();
+        let MetaCommand::Set { name, value } = MetaCommand::try_from("\\set student_name 'pat'").unwrap() else {
+            panic!("expected a set command");
+        };
+        variables.set(name, value);
+        variables.set(String::from("min_id"), String::from("10"));
+
+        let stmt = "SELECT * FROM students WHERE name = :student_name AND id > :min_id AND note <> ':min_id';";
+        assert!(check_syntax(stmt).is_ok());
+        assert_eq!(variables.substitute(stmt).unwrap(), "SELECT * FROM students WHERE name = 'pat' AND id > 10 AND note <> ':min_id';");
+    }
+
+    #[test]
+    fn unset_variables_are_errors() {
+        let variables = SessionVariables::new();
+        assert!(variables.substitute("SELECT * FROM students WHERE id = :id;").is_err());
+        assert_eq!(variables.substitute("SELECT '10:30';").unwrap(), "SELECT '10:30';");
+    }
+
+    #[test]
+    fn values_cannot_add_statements() {
+        let mut variables = SessionVariables::new();
+        variables.set(String::from("id"), String::from("1; DELETE FROM students"));
+        assert!(variables.substitute("SELECT * FROM students WHERE id = :id;").is_err());
+
+        // a semicolon inside a string is still one statement
+        variables.set(String::from("name"), String::from("'pat;'"));
+        assert_eq!(variables.substitute("SELECT * FROM students WHERE name = :name;").unwrap(), "SELECT * FROM students WHERE name = 'pat;';");
+    }
+}
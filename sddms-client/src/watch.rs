@@ -0,0 +1,83 @@
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+use sddms_shared::error::SddmsError;
+use sddms_shared::sql_metadata::{parse_statements, parse_transaction_stmt};
+use crate::reader::split_statements;
+use crate::query_results::DisplayOptions;
+use crate::site_client::SddmsSiteClient;
+
+/// moves the cursor to the top left corner and clears the screen
+const CLEAR_SCREEN: &str = "\x1B[2J\x1B[1;1H";
+
+/// Only a single read can be watched, since re-running anything else would keep changing the database
+fn check_watchable(query: &str) -> Result<(), SddmsError> {
+    if split_statements(vec![query.to_string()]).len() != 1 {
+        return Err(SddmsError::client("\\watch takes exactly one statement"));
+    }
+
+    if parse_transaction_stmt(query)?.is_some() {
+        return Err(SddmsError::client("Transaction statements cannot be watched"));
+    }
+
+    let metadata = parse_statements(query)
+        .map_err(|err| SddmsError::client("Failed to parse SQL query").with_cause(err))?;
+
+    if metadata.iter().any(|metadata| metadata.modifiable() || !metadata.has_results()) {
+        return Err(SddmsError::client("Only reads can be watched"));
+    }
+
+    Ok(())
+}
+
+/// Re-runs a read every `interval`, clearing the screen and reprinting its results each time, until the
+/// user hits Ctrl-C. Every run is a single-statement read outside of any transaction. A run that's
+/// already under way when Ctrl-C is hit still finishes, so nothing is left half done at the site
+pub async fn watch_query(client: &mut SddmsSiteClient, interval: Duration, query: &str, display_options: &DisplayOptions) -> Result<(), SddmsError> {
+    check_watchable(query)?;
+
+    let (interrupt_sender, mut interrupted) = tokio::sync::oneshot::channel();
+    let interrupt_listener = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = interrupt_sender.send(());
+        }
+    });
+
+    let result = loop {
+        let results = match client.invoke_query(None, query).await {
+            Ok(results) => results,
+            Err(err) => break Err(err),
+        };
+
+        let mut stdout = io::stdout();
+        write!(stdout, "{}Every {}s: {}\n\n", CLEAR_SCREEN, interval.as_secs_f64(), query)
+            .map_err(|err| SddmsError::client("Failed to write watched results").with_cause(err))?;
+        match results.write_to(&mut stdout, display_options) {
+            Ok(None) => {}
+            Ok(Some(deadlock_err)) => break Err(deadlock_err),
+            Err(err) => break Err(err),
+        }
+
+        tokio::select! {
+            Ok(()) = &mut interrupted => break Ok(()),
+            _ = tokio::time::sleep(interval) => {}
+        }
+    };
+
+    interrupt_listener.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watch::check_watchable;
+
+    #[test]
+    fn only_reads_can_be_watched() {
+        assert!(check_watchable("SELECT * FROM students;").is_ok());
+        assert!(check_watchable("UPDATE students SET grade = 90;").is_err());
+        assert!(check_watchable("BEGIN;").is_err());
+        assert!(check_watchable("SELECT * FROM students; SELECT * FROM courses;").is_err());
+        assert!(check_watchable("").is_err());
+    }
+}
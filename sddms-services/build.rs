@@ -6,6 +6,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             &[
                 "proto/site_manager.proto",
                 "proto/finalize_mode.proto",
+                "proto/lock_mode.proto",
                 "proto/api_result.proto",
             ],
             &[
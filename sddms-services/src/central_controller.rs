@@ -15,3 +15,4 @@ response_from_error_for!(RegisterTransactionResponse, RegisterTransactionPayload
 response_from_error_for!(AcquireLockResponse, AcquireLockPayload, acquire_lock_payload);
 response_from_error_for!(ReleaseLockResponse, ReleaseLockPayload, release_lock_payload);
 response_from_error_for!(FinalizeTransactionResponse, error);
+response_from_error_for!(UnregisterSiteResponse, error);
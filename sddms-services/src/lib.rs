@@ -8,4 +8,6 @@ pub mod site_controller;
 #[cfg(feature = "shared")]
 pub mod shared;
 
+pub mod transport;
+
 mod response_from_error;
\ No newline at end of file
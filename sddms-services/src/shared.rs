@@ -1,7 +1,7 @@
 pub mod lock_request;
 
 use tonic::include_proto;
-use sddms_shared::error::{SddmsError, SddmsTermError};
+use sddms_shared::error::{SddmsError, SddmsErrorCategory, SddmsTermError};
 use sddms_shared::sql_metadata::TransactionStmt;
 
 include_proto!("sddms.shared");
@@ -16,6 +16,7 @@ impl From<SddmsError> for ApiError {
 
         api_error.message = message;
         api_error.description = description;
+        api_error.category = value.category_code();
         api_error
     }
 }
@@ -25,13 +26,18 @@ impl From<SddmsTermError> for ApiError {
         let mut err = ApiError::default();
         err.message = value.message().to_string();
         err.description = format!("{}", value);
+        err.category = value.category_code();
         err
     }
 }
 
-impl Into<SddmsError> for ApiError {
-    fn into(self) -> SddmsError {
-        SddmsError::general(format!("ApiError: {} - {}", self.message, self.description))
+/// Restores the error's category if it was sent along with it. Errors from peers that didn't send one are
+/// treated as general errors
+impl From<ApiError> for SddmsError {
+    fn from(value: ApiError) -> Self {
+        let category = SddmsErrorCategory::from_code(value.category)
+            .unwrap_or(SddmsErrorCategory::General);
+        SddmsError::new(category, format!("ApiError: {} - {}", value.message, value.description))
     }
 }
 
@@ -52,3 +58,26 @@ impl TryFrom<TransactionStmt> for FinalizeMode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sddms_shared::error::{SddmsError, SddmsErrorCategory};
+    use crate::shared::ApiError;
+
+    #[test]
+    fn deadlock_keeps_central_category_over_the_wire() {
+        let deadlock = SddmsError::central("Transaction 1:1 would deadlock");
+        let api_error = ApiError::from(deadlock);
+        assert_eq!(api_error.category, SddmsErrorCategory::Central.code());
+
+        let restored = SddmsError::from(api_error);
+        assert_eq!(restored.category(), &SddmsErrorCategory::Central);
+        assert!(restored.message().contains("Transaction 1:1 would deadlock"));
+    }
+
+    #[test]
+    fn missing_category_is_general() {
+        let api_error = ApiError { message: String::from("failed"), description: String::new(), category: 0 };
+        assert_eq!(SddmsError::from(api_error).category(), &SddmsErrorCategory::General);
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig};
+use sddms_shared::error::SddmsError;
+
+/// Certificates for talking to other nodes over TLS. The same files are used whether the node is serving or
+/// connecting, since sites do both. Peers are checked by the host name they're connected to, so certificates
+/// have to cover the addresses nodes reach each other by
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// this node's PEM certificate and key. Servers need one, while clients only send one if given
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// the PEM certificate of the CA that peers are checked against. A server with one requires clients to
+    /// present a certificate signed by it
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    fn read_identity(&self) -> Result<Option<Identity>, SddmsError> {
+        self.identity.as_ref()
+            .map(|(cert, key)| Ok(Identity::from_pem(read_pem(cert)?, read_pem(key)?)))
+            .transpose()
+    }
+
+    fn read_ca_cert(&self) -> Result<Option<Certificate>, SddmsError> {
+        self.ca_cert.as_ref()
+            .map(|ca_cert| Ok(Certificate::from_pem(read_pem(ca_cert)?)))
+            .transpose()
+    }
+
+    fn server_config(&self) -> Result<ServerTlsConfig, SddmsError> {
+        let identity = self.read_identity()?
+            .ok_or(SddmsError::general("Serving over TLS needs a certificate and key"))?;
+
+        let mut tls_config = ServerTlsConfig::new().identity(identity);
+        if let Some(ca_cert) = self.read_ca_cert()? {
+            tls_config = tls_config.client_ca_root(ca_cert);
+        }
+
+        Ok(tls_config)
+    }
+
+    fn client_config(&self) -> Result<ClientTlsConfig, SddmsError> {
+        let mut tls_config = ClientTlsConfig::new();
+        if let Some(ca_cert) = self.read_ca_cert()? {
+            tls_config = tls_config.ca_certificate(ca_cert);
+        }
+        if let Some(identity) = self.read_identity()? {
+            tls_config = tls_config.identity(identity);
+        }
+
+        Ok(tls_config)
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, SddmsError> {
+    std::fs::read(path)
+        .map_err(|err| SddmsError::general(format!("Failed to read {}", path.display())).with_cause(err))
+}
+
+/// Starts building a server, serving over TLS if there are any options for it
+pub fn server_builder(tls: Option<&TlsOptions>) -> Result<Server, SddmsError> {
+    match tls {
+        None => Ok(Server::builder()),
+        Some(tls) => Server::builder()
+            .tls_config(tls.server_config()?)
+            .map_err(|err| SddmsError::general("Failed to configure server TLS").with_cause(err)),
+    }
+}
+
+/// Connects to a node at `<host>:<port>`, over https if there are TLS options and http otherwise
+pub async fn connect(addr: &str, tls: Option<&TlsOptions>) -> Result<Channel, SddmsError> {
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let mut endpoint = Endpoint::from_shared(format!("{}://{}", scheme, addr))
+        .map_err(|err| SddmsError::general(format!("Invalid address {}", addr)).with_cause(err))?;
+
+    if let Some(tls) = tls {
+        endpoint = endpoint.tls_config(tls.client_config()?)
+            .map_err(|err| SddmsError::general("Failed to configure client TLS").with_cause(err))?;
+    }
+
+    endpoint.connect()
+        .await
+        .map_err(|err| SddmsError::general(format!("Failed to connect to {}", addr)).with_cause(err))
+}
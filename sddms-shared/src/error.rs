@@ -1,7 +1,7 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SddmsErrorCategory {
     Client,
     Site,
@@ -9,6 +9,30 @@ pub enum SddmsErrorCategory {
     Central,
 }
 
+impl SddmsErrorCategory {
+    /// A stable code for the category, so it can be sent over the wire. Zero is never used so that an
+    /// unset code can be told apart from a real one
+    pub fn code(&self) -> u32 {
+        match self {
+            SddmsErrorCategory::Client => 1,
+            SddmsErrorCategory::Site => 2,
+            SddmsErrorCategory::General => 3,
+            SddmsErrorCategory::Central => 4,
+        }
+    }
+
+    /// The category with the given code, if there is one
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(SddmsErrorCategory::Client),
+            2 => Some(SddmsErrorCategory::Site),
+            3 => Some(SddmsErrorCategory::General),
+            4 => Some(SddmsErrorCategory::Central),
+            _ => None,
+        }
+    }
+}
+
 impl Display for SddmsErrorCategory {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -31,7 +55,7 @@ pub struct SddmsError {
 }
 
 impl SddmsError {
-    fn new<MsgT: Into<String>>(category: SddmsErrorCategory, message: MsgT) -> Self {
+    pub fn new<MsgT: Into<String>>(category: SddmsErrorCategory, message: MsgT) -> Self {
         Self {
             category,
             message: message.into(),
@@ -64,26 +88,43 @@ impl SddmsError {
     pub fn category(&self) -> &SddmsErrorCategory {
         &self.category
     }
+    pub fn category_code(&self) -> u32 {
+        self.category.code()
+    }
     pub fn message(&self) -> &str {
         &self.message
     }
     pub fn inner_cause(&self) -> &Option<Box<dyn Error>> {
         &self.cause
     }
+
+    /// Walks the chain of causes, starting with the immediate cause and following each one's source
+    pub fn causes(&self) -> impl Iterator<Item = &(dyn Error + 'static)> + '_ {
+        std::iter::successors(self.cause.as_deref(), |&cause| cause.source())
+    }
 }
 
 impl Display for SddmsError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{}: {}", self.category, self.message))?;
-        if let Some(cause) = &self.cause {
-            f.write_fmt(format_args!("\ncaused by: {}", cause))?;
+        for (depth, cause) in self.causes().enumerate() {
+            let indent = "  ".repeat(depth);
+            // a nested sddms error would print its own causes too, so only its headline is shown here
+            match cause.downcast_ref::<SddmsError>() {
+                Some(sddms_cause) => f.write_fmt(format_args!("\n{}caused by: {}: {}", indent, sddms_cause.category, sddms_cause.message))?,
+                None => f.write_fmt(format_args!("\n{}caused by: {}", indent, cause))?,
+            }
         }
 
         Ok(())
     }
 }
 
-impl Error for SddmsError {}
+impl Error for SddmsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_deref()
+    }
+}
 
 pub type SddmsResult<T> = Result<T, SddmsError>;
 
@@ -99,6 +140,9 @@ impl SddmsTermError {
     pub fn category(&self) -> &SddmsErrorCategory {
         &self.category
     }
+    pub fn category_code(&self) -> u32 {
+        self.category.code()
+    }
     pub fn message(&self) -> &str {
         &self.message
     }
@@ -127,3 +171,27 @@ impl Display for SddmsTermError {
 }
 
 impl Error for SddmsTermError {}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use crate::error::SddmsError;
+
+    #[test]
+    fn display_shows_every_cause() {
+        let io_err = io::Error::new(io::ErrorKind::NotFound, "history file is missing");
+        let err = SddmsError::client("Failed to start session")
+            .with_cause(SddmsError::site("Failed to open history").with_cause(io_err));
+
+        let causes = err.causes()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(causes.len(), 2);
+        assert!(causes[1].contains("history file is missing"));
+
+        let displayed = err.to_string();
+        assert_eq!(displayed, "Client Error: Failed to start session\n\
+            caused by: Site Error: Failed to open history\n  \
+            caused by: history file is missing");
+    }
+}
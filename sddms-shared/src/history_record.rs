@@ -0,0 +1,163 @@
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
+
+/// One line of a JSON-lines history file. Sites write these and the history verifier reads them back, so
+/// neither side has to agree on a text layout
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    /// when the event happened, as an ISO 8601 date time with offset
+    pub instant: String,
+    #[serde(flatten)]
+    pub event: HistoryEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HistoryEvent {
+    Begin { site: u32, client: u32, txn: u32 },
+    Commit { site: u32, client: u32, txn: u32 },
+    Rollback { site: u32, client: u32, txn: u32 },
+    Query { site: u32, client: u32, txn: u32, read_set: Vec<String>, write_set: Vec<String> },
+    /// `site` is where the replication was applied, `orig_site` is where the transaction ran
+    Replication { site: u32, orig_site: u32, write_set: Vec<String> },
+}
+
+impl HistoryRecord {
+    pub fn to_json_line(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json_line(line: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+}
+
+/// The current time in the ISO 8601 form every history line is stamped with
+pub fn instant_now() -> Result<String, time::error::Format> {
+    OffsetDateTime::now_utc().format(&Iso8601::DATE_TIME_OFFSET)
+}
+
+/// Formats a set of table names as a JSON array so that names with quotes or other special characters
+/// can be read back by the history verifier
+fn table_set_json(tables: &[String]) -> Result<String, serde_json::Error> {
+    serde_json::to_string(tables)
+}
+
+/// Formats the tables a query read and wrote as the action of a text history line
+pub fn query_text_action(write_set: &[String], read_set: &[String]) -> Result<String, serde_json::Error> {
+    let read_set_string = if !read_set.is_empty() {
+        format!("Read({})", table_set_json(read_set)?)
+    } else {
+        String::default()
+    };
+
+    let write_set_string = if !write_set.is_empty() {
+        format!("Write({})", table_set_json(write_set)?)
+    } else {
+        String::default()
+    };
+
+    let joiner = if !(write_set.is_empty() || read_set.is_empty()) {
+        ","
+    } else {
+        ""
+    };
+
+    // a statement that touches no tables still needs something after the colon, or the line would
+    // end in whitespace that gets trimmed away when it's parsed
+    if read_set.is_empty() && write_set.is_empty() {
+        Ok(String::from("Read([]),Write([])"))
+    } else {
+        Ok(format!("{}{}{}", read_set_string, joiner, write_set_string))
+    }
+}
+
+/// A text history line for something a transaction did, like `<instant> | site=1, client=2, txn=3: COMMIT`
+pub fn action_text_line(instant: &str, site: u32, client: u32, txn: u32, action: &str) -> String {
+    format!("{} | site={}, client={}, txn={}: {}", instant, site, client, txn, action)
+}
+
+/// A text history line for a replication applied at `site` that writes the given tables
+pub fn replication_text_line(instant: &str, site: u32, orig_site: u32, write_set: &[String]) -> Result<String, serde_json::Error> {
+    Ok(format!("{} | replication: site={}, orig_site={}: Write({})", instant, site, orig_site, table_set_json(write_set)?))
+}
+
+/// A history kept in memory instead of a file, so that whatever records a history can hand it straight to
+/// whatever checks it. Clones share the same records
+#[derive(Debug, Clone, Default)]
+pub struct MemoryHistory {
+    records: Arc<Mutex<Vec<HistoryRecord>>>,
+}
+
+impl MemoryHistory {
+    pub fn push(&self, record: HistoryRecord) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    /// Copies out every record so far, in the order they were pushed
+    pub fn records(&self) -> Vec<HistoryRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history_record::{action_text_line, HistoryEvent, HistoryRecord, MemoryHistory, query_text_action, replication_text_line};
+
+    #[test]
+    fn records_round_trip_through_json_lines() {
+        let records = [
+            HistoryRecord {
+                instant: String::from("2023-12-01T10:00:00.000000000Z"),
+                event: HistoryEvent::Begin { site: 1, client: 2, txn: 3 },
+            },
+            HistoryRecord {
+                instant: String::from("2023-12-01T10:00:01.000000000Z"),
+                event: HistoryEvent::Query { site: 1, client: 2, txn: 3, read_set: vec![String::from("odd \"table\"")], write_set: vec![] },
+            },
+            HistoryRecord {
+                instant: String::from("2023-12-01T10:00:02.000000000Z"),
+                event: HistoryEvent::Replication { site: 2, orig_site: 1, write_set: vec![String::from("flights")] },
+            },
+        ];
+
+        for record in records {
+            let line = record.to_json_line().unwrap();
+            assert!(!line.contains('\n'));
+            assert_eq!(HistoryRecord::from_json_line(&line).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn records_are_tagged_with_their_kind() {
+        let record = HistoryRecord {
+            instant: String::from("2023-12-01T10:00:00Z"),
+            event: HistoryEvent::Commit { site: 1, client: 2, txn: 3 },
+        };
+        assert_eq!(record.to_json_line().unwrap(), r#"{"instant":"2023-12-01T10:00:00Z","kind":"commit","site":1,"client":2,"txn":3}"#);
+    }
+
+    #[test]
+    fn memory_history_clones_share_records() {
+        let history = MemoryHistory::default();
+        let writer = history.clone();
+        writer.push(HistoryRecord {
+            instant: String::from("2023-12-01T10:00:00Z"),
+            event: HistoryEvent::Begin { site: 1, client: 2, txn: 3 },
+        });
+
+        assert_eq!(history.records().len(), 1);
+    }
+
+    #[test]
+    fn text_lines_quote_table_sets() {
+        let instant = "2023-12-01T10:00:00.000000000Z";
+        let query = query_text_action(&[String::from("back\\slash")], &[String::from("odd \"table\"")]).unwrap();
+        assert_eq!(action_text_line(instant, 1, 2, 3, &query),
+                   r#"2023-12-01T10:00:00.000000000Z | site=1, client=2, txn=3: Read(["odd \"table\""]),Write(["back\\slash"])"#);
+        assert_eq!(replication_text_line(instant, 4, 1, &[String::from("flights")]).unwrap(),
+                   r#"2023-12-01T10:00:00.000000000Z | replication: site=4, orig_site=1: Write(["flights"])"#);
+    }
+}
@@ -2,3 +2,4 @@
 pub mod sql_metadata;
 pub mod error;
 pub mod host_utils;
+pub mod history_record;
@@ -1,8 +1,83 @@
+mod column_access;
+mod lock_granularity;
+mod row_writes;
+mod schema_change;
+
 use std::collections::{HashMap, HashSet};
-use sqlparser::ast::{Query, SetExpr, Statement, With};
+use sqlparser::ast::{AlterTableOperation, ObjectType, Query, SetExpr, Statement, TableFactor, With};
 use sqlparser::dialect::SQLiteDialect;
+use sqlparser::keywords::Keyword;
 use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Location, Token, Tokenizer};
 use crate::error::SddmsError;
+use crate::sql_metadata::column_access::{collect_expr_columns, select_columns};
+pub use crate::sql_metadata::lock_granularity::LockGranularity;
+pub use crate::sql_metadata::row_writes::{ColumnWrite, key_covers, keys_overlap, parse_row_writes, RowKey, RowWrite, without_assignments};
+pub use crate::sql_metadata::schema_change::SchemaChange;
+
+/// The schemas a table is found in when it isn't qualified with one, which SQLite searches by default
+pub const DEFAULT_SCHEMAS: [&str; 2] = ["main", "temp"];
+
+/// Strips a leading default schema off of a table name, so that `main.students` and `students` lock the
+/// same resource. The schema may be quoted in any of the ways SQLite allows
+fn strip_default_schema(table: &str, default_schemas: &[&str]) -> String {
+    for schema in default_schemas {
+        for (open, close) in [("", ""), ("\"", "\""), ("`", "`"), ("[", "]")] {
+            let prefix = format!("{}{}{}.", open, schema, close);
+            let has_prefix = table.get(..prefix.len())
+                .is_some_and(|table_prefix| table_prefix.eq_ignore_ascii_case(&prefix));
+            if has_prefix && table.len() > prefix.len() {
+                return table[prefix.len()..].to_string();
+            }
+        }
+    }
+
+    table.to_string()
+}
+
+/// The name of the table a relation reads from, without any alias it's given
+fn relation_name(relation: &TableFactor) -> String {
+    match relation {
+        TableFactor::Table { name, .. } => name.to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Columns accessed in each table. A table that's accessed but missing from the map is accessed as a whole
+type ColumnAccess = HashMap<String, HashSet<String>>;
+
+/// Merges the column access of two statements. A table only keeps its columns if neither side accessed
+/// it as a whole
+fn merge_column_access(left_tables: &HashSet<String>, mut left_columns: ColumnAccess, right_tables: &HashSet<String>, mut right_columns: ColumnAccess) -> ColumnAccess {
+    let all_tables = left_tables.iter().chain(right_tables)
+        .chain(left_columns.keys())
+        .chain(right_columns.keys())
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    let take_columns = |tables: &HashSet<String>, columns: &mut ColumnAccess, table: &String| {
+        match columns.remove(table) {
+            Some(columns) => Some(columns),
+            None if tables.contains(table) => None,
+            None => Some(HashSet::new()),
+        }
+    };
+
+    all_tables.into_iter()
+        .filter_map(|table| {
+            let left = take_columns(left_tables, &mut left_columns, &table)?;
+            let right = take_columns(right_tables, &mut right_columns, &table)?;
+            Some((table, left.into_iter().chain(right).collect()))
+        })
+        .collect()
+}
+
+/// The resources a statement needs to lock, by lock mode
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LockResources {
+    pub shared: Vec<String>,
+    pub exclusive: Vec<String>,
+}
 
 #[derive(Debug, Default)]
 pub struct SqlMetadata {
@@ -14,6 +89,12 @@ pub struct SqlMetadata {
     write_tables: HashSet<String>,
     /// the tables that are read from
     read_tables: HashSet<String>,
+    /// the columns read from each table, where they could be worked out
+    read_columns: ColumnAccess,
+    /// the columns written in each table, where they could be worked out
+    write_columns: ColumnAccess,
+    /// the schema change made by a DDL statement
+    schema_change: Option<SchemaChange>,
 }
 
 impl SqlMetadata {
@@ -29,9 +110,69 @@ impl SqlMetadata {
         self.has_results
     }
 
+    pub fn schema_change(&self) -> Option<SchemaChange> {
+        self.schema_change
+    }
+
     pub fn take_write_tables(self) -> HashSet<String> { self.write_tables }
 
+    /// Gets the resources to lock for this statement. Table granularity locks every table read in shared
+    /// mode and every table written in exclusive mode.
+    ///
+    /// Column granularity locks `table.column` resources instead wherever the accessed columns are known, and
+    /// takes the table itself in shared mode. Since column writers only share the table, a statement that
+    /// reads a whole table has to take the table exclusively to be ordered with them
+    pub fn lock_resources(&self, granularity: LockGranularity) -> LockResources {
+        if granularity == LockGranularity::Table {
+            return LockResources {
+                shared: self.read_tables.iter().cloned().collect(),
+                exclusive: self.write_tables.iter().cloned().collect(),
+            };
+        }
+
+        let mut shared = HashSet::new();
+        let mut exclusive = HashSet::new();
+        let column_resource = |table: &String, column: &String| format!("{}.{}", table, column);
+
+        for table in &self.write_tables {
+            match self.write_columns.get(table) {
+                Some(columns) => {
+                    shared.insert(table.clone());
+                    exclusive.extend(columns.iter().map(|column| column_resource(table, column)));
+                }
+                None => {
+                    exclusive.insert(table.clone());
+                }
+            }
+        }
+
+        for (table, columns) in &self.read_columns {
+            // reads are already covered if the whole table is written
+            if self.write_tables.contains(table) && !self.write_columns.contains_key(table) {
+                continue;
+            }
+
+            shared.insert(table.clone());
+            shared.extend(columns.iter().map(|column| column_resource(table, column)));
+        }
+
+        for table in &self.read_tables {
+            if !self.read_columns.contains_key(table) {
+                exclusive.insert(table.clone());
+            }
+        }
+
+        let mut shared = shared.difference(&exclusive).cloned().collect::<Vec<_>>();
+        let mut exclusive = exclusive.into_iter().collect::<Vec<_>>();
+        shared.sort();
+        exclusive.sort();
+        LockResources { shared, exclusive }
+    }
+
     fn merge_override_flags(mut self, mut other: SqlMetadata, modifiable: bool, has_results: bool) -> Self {
+        let read_columns = merge_column_access(&self.read_tables, self.read_columns, &other.read_tables, other.read_columns);
+        let write_columns = merge_column_access(&self.write_tables, self.write_columns, &other.write_tables, other.write_columns);
+
         other.read_tables.drain()
             .for_each(|read_table| {
                 self.read_tables.insert(read_table);
@@ -46,7 +187,10 @@ impl SqlMetadata {
             modifiable,
             has_results,
             read_tables: self.read_tables,
-            write_tables: self.write_tables
+            write_tables: self.write_tables,
+            read_columns,
+            write_columns,
+            schema_change: self.schema_change.or(other.schema_change),
         }
     }
 
@@ -56,10 +200,34 @@ impl SqlMetadata {
         self.merge_override_flags(other, modifiable, has_results)
     }
 
+    /// Strips the default schemas off of every table this statement accesses
+    fn strip_default_schemas(mut self, default_schemas: &[&str]) -> Self {
+        let strip_tables = |tables: HashSet<String>| tables.into_iter()
+            .map(|table| strip_default_schema(&table, default_schemas))
+            .collect::<HashSet<_>>();
+        let strip_columns = |columns: ColumnAccess| {
+            let mut stripped = ColumnAccess::new();
+            for (table, table_columns) in columns {
+                stripped.entry(strip_default_schema(&table, default_schemas))
+                    .or_default()
+                    .extend(table_columns);
+            }
+            stripped
+        };
+
+        self.read_tables = strip_tables(self.read_tables);
+        self.write_tables = strip_tables(self.write_tables);
+        self.read_columns = strip_columns(self.read_columns);
+        self.write_columns = strip_columns(self.write_columns);
+        self
+    }
+
     fn remove_aliases<'items_lifetime, KeySetT: Iterator<Item=&'items_lifetime String>>(&mut self, aliases: KeySetT) {
         for alias in aliases {
             self.read_tables.remove(alias);
             self.write_tables.remove(alias);
+            self.read_columns.remove(alias);
+            self.write_columns.remove(alias);
         }
     }
 
@@ -68,9 +236,13 @@ impl SqlMetadata {
             .cloned()
             .collect::<HashSet<_>>();
 
-        // any table that is both read and write should be just write
+        // any table that is both read and write should be just write. The read columns are kept so they're
+        // still locked, but a table read as a whole has to be written as a whole to cover the read
         for tab in read_and_write {
             self.read_tables.remove(&tab);
+            if !self.read_columns.contains_key(&tab) {
+                self.write_columns.remove(&tab);
+            }
         }
 
         self
@@ -80,30 +252,46 @@ impl SqlMetadata {
 fn extract_ctes_from_with(with: With) -> HashMap<String, SqlMetadata> {
     let mut cte_aliases: HashMap<String, SqlMetadata> = HashMap::new();
     for cte in with.cte_tables {
-        let metadata = extract_metadata_from_query(cte.query);
         let alias_name = cte.alias.name.value.to_string();
+        let metadata = if with.recursive {
+            extract_metadata_from_recursive_cte(cte.query, &alias_name)
+        } else {
+            extract_metadata_from_query(cte.query)
+        };
         cte_aliases.insert(alias_name, metadata);
     }
 
     cte_aliases
 }
 
-fn extract_metadata_from_query(query: Box<Query>) -> SqlMetadata {
+/// A recursive CTE is an anchor term and a recursive term joined by a UNION. The recursive term reads from
+/// the CTE itself, which isn't a real table, so the CTE's own alias is removed from what both terms access
+fn extract_metadata_from_recursive_cte(query: Box<Query>, alias_name: &str) -> SqlMetadata {
+    let mut metadata = extract_metadata_from_set_expr(*query.body, &HashMap::new());
 
-    let with_cte_aliases = if let Some(with) = query.with {
-        extract_ctes_from_with(with)
-    } else {
-        HashMap::new()
-    };
+    metadata.remove_aliases(std::iter::once(&alias_name.to_string()));
+    metadata
+}
 
-    let query_body = query.body;
-    let mut body_metadata = match *query_body {
+fn extract_metadata_from_set_expr(set_expr: SetExpr, with_cte_aliases: &HashMap<String, SqlMetadata>) -> SqlMetadata {
+    match set_expr {
         SetExpr::Select(select) => {
+            // columns can only be attributed when there's a single table
+            let single_table = match select.from.as_slice() {
+                [table] if table.joins.is_empty() => Some(relation_name(&table.relation))
+                    .filter(|table| !with_cte_aliases.contains_key(table)),
+                _ => None,
+            };
+            let read_columns = single_table
+                .and_then(|table| select_columns(&select).map(|columns| (table, columns)))
+                .into_iter()
+                .collect::<ColumnAccess>();
+
             let read_tables = select.from.into_iter()
                 .flat_map(|table| {
-                    let relation_table = table.relation.to_string();
+                    let relation_table = relation_name(&table.relation);
                     let mut join_tables = table.joins.iter()
-                        .map(|join_tab| join_tab.relation.to_string())
+                        .map(|join_tab| relation_name(&join_tab.relation))
                         .collect::<Vec<_>>();
 
                     join_tables.insert(0, relation_table);
@@ -118,13 +306,21 @@ fn extract_metadata_from_query(query: Box<Query>) -> SqlMetadata {
                 has_results: true,
                 write_tables: Default::default(),
                 read_tables: read_tables.into_iter().collect::<HashSet<_>>(),
+                read_columns,
+                write_columns: Default::default(),
+                schema_change: None,
             }
         }
         SetExpr::Query(query) => {
             // TODO recursive might be bad...
             extract_metadata_from_query(query)
         }
-        SetExpr::SetOperation { .. } => { SqlMetadata::default() }
+        SetExpr::SetOperation { left, right, .. } => {
+            // every operand is read, no matter how the results are combined
+            let left_metadata = extract_metadata_from_set_expr(*left, with_cte_aliases);
+            let right_metadata = extract_metadata_from_set_expr(*right, with_cte_aliases);
+            left_metadata.merge(right_metadata)
+        }
         SetExpr::Values(_) => {  SqlMetadata::default()  }
         SetExpr::Insert(insert_stmt) => {
             SqlMetadata::from(insert_stmt)
@@ -133,8 +329,29 @@ fn extract_metadata_from_query(query: Box<Query>) -> SqlMetadata {
         SetExpr::Table(_table) => {
             todo!()
         }
+    }
+}
+
+fn extract_metadata_from_query(query: Box<Query>) -> SqlMetadata {
+
+    let with_cte_aliases = if let Some(with) = query.with {
+        extract_ctes_from_with(with)
+    } else {
+        HashMap::new()
     };
 
+    let mut body_metadata = extract_metadata_from_set_expr(*query.body, &with_cte_aliases);
+
+    // ORDER BY reads columns too, but they can't be attributed if they aren't simple
+    let mut order_columns = HashSet::new();
+    if query.order_by.iter().all(|order_by| collect_expr_columns(&order_by.expr, &mut order_columns)) {
+        for columns in body_metadata.read_columns.values_mut() {
+            columns.extend(order_columns.iter().cloned());
+        }
+    } else {
+        body_metadata.read_columns.clear();
+    }
+
     // remove any aliases from the body
     body_metadata.remove_aliases(with_cte_aliases.keys());
 
@@ -154,6 +371,8 @@ fn extract_metadata_from_query(query: Box<Query>) -> SqlMetadata {
 impl From<Statement> for SqlMetadata {
     fn from(value: Statement) -> Self {
         let metadata = match value {
+            // `INSERT OR REPLACE` and `REPLACE` delete conflicting rows before inserting while `INSERT OR IGNORE`
+            // skips them, but whatever the conflict clause, only the target table is written
             Statement::Insert { table_name, source, .. } => {
 
                 // read any metadata from source query
@@ -167,39 +386,98 @@ impl From<Statement> for SqlMetadata {
                 let insert_metadata = SqlMetadata {
                     modifiable: true,
                     write_tables: HashSet::from([table_name.to_string()]),
-                    read_tables: HashSet::default(),
-                    has_results: false
+                    has_results: false,
+                    ..SqlMetadata::default()
                 };
 
                 // merge the two
                 insert_metadata.merge_override_flags(source_metadata, true, false)
             }
-            Statement::Update { table, .. } => {
+            Statement::Update { table, assignments, from, selection, .. } => {
+                let table_name = relation_name(&table.relation);
+
+                // the assigned columns are written, and whatever the new values and filter use is read
+                let mut read_columns = HashSet::new();
+                let reads_known = table.joins.is_empty()
+                    && from.is_none()
+                    && assignments.iter().all(|assignment| collect_expr_columns(&assignment.value, &mut read_columns))
+                    && selection.iter().all(|selection| collect_expr_columns(selection, &mut read_columns));
+
+                let (read_columns, write_columns) = if reads_known {
+                    let write_columns = assignments.iter()
+                        .filter_map(|assignment| assignment.id.last())
+                        .map(|column| column.value.clone())
+                        .collect::<HashSet<_>>();
+                    (ColumnAccess::from([(table_name.clone(), read_columns)]), ColumnAccess::from([(table_name.clone(), write_columns)]))
+                } else {
+                    (ColumnAccess::default(), ColumnAccess::default())
+                };
+
                 SqlMetadata {
                     modifiable: true,
-                    write_tables: HashSet::from([table.relation.to_string()]),
+                    write_tables: HashSet::from([table_name]),
                     read_tables: HashSet::default(),
                     has_results: false,
+                    read_columns,
+                    write_columns,
+                    schema_change: None,
                 }
             }
             Statement::Delete { tables, .. } => {
                 SqlMetadata {
                     modifiable: true,
                     write_tables: HashSet::from_iter(tables.into_iter().map(|item| item.to_string())),
-                    read_tables: HashSet::new(),
                     has_results: false,
+                    ..SqlMetadata::default()
                 }
             }
             Statement::Query(query) => {
                 extract_metadata_from_query(query)
             }
 
-            // TODO lock the table that's created too
-            Statement::CreateTable { query , .. } => {
+            Statement::CreateTable { name, query, .. } => {
+                // the created table is written so that creating it is serialized with anything else using it
+                let create_metadata = SqlMetadata {
+                    modifiable: true,
+                    write_tables: HashSet::from([name.to_string()]),
+                    has_results: false,
+                    schema_change: Some(SchemaChange::CreateTable),
+                    ..SqlMetadata::default()
+                };
+
                 if let Some(query) = query {
-                    extract_metadata_from_query(query)
+                    create_metadata.merge_override_flags(extract_metadata_from_query(query), true, false)
                 } else {
-                    SqlMetadata::default()
+                    create_metadata
+                }
+            }
+
+            Statement::AlterTable { name, operations, .. } => {
+                // the altered table is taken exclusively so that the change is serialized with any DML on
+                // it. A rename also takes the new name, since that table comes into existence
+                let mut write_tables = HashSet::from([name.to_string()]);
+                for operation in operations {
+                    if let AlterTableOperation::RenameTable { table_name } = operation {
+                        write_tables.insert(table_name.to_string());
+                    }
+                }
+
+                SqlMetadata {
+                    modifiable: true,
+                    write_tables,
+                    has_results: false,
+                    schema_change: Some(SchemaChange::AlterTable),
+                    ..SqlMetadata::default()
+                }
+            }
+
+            Statement::Drop { object_type: ObjectType::Table, names, .. } => {
+                SqlMetadata {
+                    modifiable: true,
+                    write_tables: names.iter().map(|name| name.to_string()).collect(),
+                    has_results: false,
+                    schema_change: Some(SchemaChange::DropTable),
+                    ..SqlMetadata::default()
                 }
             }
 
@@ -212,16 +490,66 @@ impl From<Statement> for SqlMetadata {
     }
 }
 
-pub fn parse_statements(sql: &str) -> Result<Vec<SqlMetadata>, ParserError> {
+/// Parses sql the same way as `Parser::parse_sql`, except that SQLite's bare `REPLACE INTO` statements are
+/// understood as well. The parser can read them as inserts but never dispatches to them on its own
+fn parse_sqlite(sql: &str) -> Result<Vec<Statement>, ParserError> {
     let dialect = SQLiteDialect {};
-    let statements = Parser::parse_sql(&dialect, sql)?;
+    let mut parser = Parser::new(&dialect).try_with_sql(sql)?;
+    let mut statements = Vec::new();
+    let mut expecting_statement_delimiter = false;
+    loop {
+        while parser.consume_token(&Token::SemiColon) {
+            expecting_statement_delimiter = false;
+        }
+
+        let next_token = parser.peek_token();
+        if next_token.token == Token::EOF {
+            break;
+        }
+
+        if expecting_statement_delimiter {
+            return parser.expected("end of statement", next_token);
+        }
+
+        let statement = match next_token.token {
+            Token::Word(word) if word.keyword == Keyword::REPLACE => parser.parse_insert()?,
+            _ => parser.parse_statement()?,
+        };
+        statements.push(statement);
+        expecting_statement_delimiter = true;
+    }
+
+    Ok(statements)
+}
+
+pub fn parse_statements(sql: &str) -> Result<Vec<SqlMetadata>, ParserError> {
+    parse_statements_with_default_schemas(sql, &DEFAULT_SCHEMAS)
+}
+
+/// Parses statements, treating tables qualified with any of the given schemas the same as unqualified ones
+pub fn parse_statements_with_default_schemas(sql: &str, default_schemas: &[&str]) -> Result<Vec<SqlMetadata>, ParserError> {
+    let statements = parse_sqlite(sql)?;
     let metadata = statements.into_iter()
-        .map(|item| SqlMetadata::from(item))
+        .map(|item| SqlMetadata::from(item).strip_default_schemas(default_schemas))
         .collect::<Vec<_>>();
 
     Ok(metadata)
 }
 
+/// Checks that the given sql is syntactically valid without extracting any metadata from it
+pub fn check_syntax(sql: &str) -> Result<(), ParserError> {
+    parse_sqlite(sql).map(|_| ())
+}
+
+/// Combines the metadata of several statements into the metadata of running all of them, like a
+/// transaction does. A table read by one statement and written by another is only written, so it's locked
+/// once in exclusive mode
+pub fn combine_metadata<MetadataT: IntoIterator<Item=SqlMetadata>>(metadata: MetadataT) -> SqlMetadata {
+    metadata.into_iter()
+        .fold(SqlMetadata::default(), SqlMetadata::merge)
+        .consolidate_tables()
+}
+
 #[derive(Debug)]
 pub enum TransactionStmt {
     Begin,
@@ -230,8 +558,7 @@ pub enum TransactionStmt {
 }
 
 pub fn parse_transaction_stmt(sql: &str) -> Result<Option<TransactionStmt>, SddmsError> {
-    let dialect = SQLiteDialect {};
-    let mut statements = Parser::parse_sql(&dialect, sql)
+    let mut statements = parse_sqlite(sql)
         .map_err(|err| SddmsError::client("Failed to parse sql").with_cause(err))?;
 
     if statements.len() != 1 {
@@ -250,6 +577,53 @@ pub fn parse_transaction_stmt(sql: &str) -> Result<Option<TransactionStmt>, Sddm
     Ok(transaction_kind)
 }
 
+/// An explicit `LOCK TABLE a, b [IN SHARE MODE | IN EXCLUSIVE MODE];` statement, which names the locks a
+/// transaction wants and the order to take them in
+#[derive(Debug, PartialEq, Eq)]
+pub struct LockTableStmt {
+    /// the tables to lock, in order
+    pub tables: Vec<String>,
+    /// if the tables are locked exclusively, which they are unless share mode is given
+    pub exclusive: bool,
+}
+
+/// Reads a `LOCK TABLE` statement, if `sql` is one. SQLite doesn't have these, so they can't go through
+/// the parser
+pub fn parse_lock_table_stmt(sql: &str) -> Option<Result<LockTableStmt, SddmsError>> {
+    let sql = sql.trim().trim_end_matches(';').trim_end();
+    let (keyword, rest) = sql.split_once(char::is_whitespace)?;
+    if !keyword.eq_ignore_ascii_case("LOCK") {
+        return None;
+    }
+
+    let rest = rest.trim_start();
+    let (table_keyword, tables) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    if !table_keyword.eq_ignore_ascii_case("TABLE") && !table_keyword.eq_ignore_ascii_case("TABLES") {
+        return Some(Err(SddmsError::client("Expected LOCK TABLE")));
+    }
+
+    let words = tables.split_whitespace().collect::<Vec<_>>();
+    let mode_clause = words.len().checked_sub(3)
+        .map(|start| &words[start..])
+        .filter(|clause| clause[0].eq_ignore_ascii_case("IN") && clause[2].eq_ignore_ascii_case("MODE"));
+    let (tables, exclusive) = match mode_clause {
+        Some(clause) if clause[1].eq_ignore_ascii_case("SHARE") => (&words[..words.len() - 3], false),
+        Some(clause) if clause[1].eq_ignore_ascii_case("EXCLUSIVE") => (&words[..words.len() - 3], true),
+        Some(clause) => return Some(Err(SddmsError::client(format!("Unknown lock mode {}", clause[1])))),
+        None => (&words[..], true),
+    };
+
+    let tables = tables.join(" ")
+        .split(',')
+        .map(|table| table.trim().to_string())
+        .collect::<Vec<_>>();
+    if tables.iter().any(|table| table.is_empty() || table.contains(char::is_whitespace)) {
+        return Some(Err(SddmsError::client("LOCK TABLE needs a comma separated list of tables")));
+    }
+
+    Some(Ok(LockTableStmt { tables, exclusive }))
+}
+
 enum TransactionStatementMode {
     Open,
     Close,
@@ -257,6 +631,10 @@ enum TransactionStatementMode {
 }
 
 fn classify_transaction_stmt(sql: &str) -> Result<TransactionStatementMode, SddmsError> {
+    if parse_lock_table_stmt(sql).is_some() {
+        return Ok(TransactionStatementMode::Normal);
+    }
+
     let trans_stmt = parse_transaction_stmt(sql)?;
     if trans_stmt.is_none() {
         return Ok(TransactionStatementMode::Normal);
@@ -268,6 +646,41 @@ fn classify_transaction_stmt(sql: &str) -> Result<TransactionStatementMode, Sddm
     })
 }
 
+/// Splits SQL into its statements, each ending in a semicolon. Semicolons in string literals, quoted names,
+/// and comments don't end a statement. Statements are cut out of `sql` as written rather than parsed, so
+/// ones SQLite doesn't have, like `LOCK TABLE`, come through too
+pub fn split_sql_statements(sql: &str) -> Result<Vec<String>, SddmsError> {
+    let dialect = SQLiteDialect {};
+    let tokens = Tokenizer::new(&dialect, sql).tokenize_with_location()
+        .map_err(|err| SddmsError::general("Failed to tokenize sql").with_cause(err))?;
+
+    // tokens are located by line and column, so work out where in sql each line starts
+    let line_starts = std::iter::once(0)
+        .chain(sql.match_indices('\n').map(|(index, _)| index + 1))
+        .collect::<Vec<_>>();
+    let byte_offset = |location: &Location| {
+        let line_start = line_starts[location.line as usize - 1];
+        sql[line_start..].char_indices()
+            .nth(location.column as usize - 1)
+            .map_or(sql.len(), |(offset, _)| line_start + offset)
+    };
+
+    let mut statements = Vec::new();
+    let mut start = 0;
+    for semicolon in tokens.iter().filter(|token| token.token == Token::SemiColon) {
+        let end = byte_offset(&semicolon.location);
+        statements.push(&sql[start..end]);
+        start = end + 1;
+    }
+    statements.push(&sql[start..]);
+
+    Ok(statements.into_iter()
+        .map(str::trim)
+        .filter(|stmt| !stmt.is_empty())
+        .map(|stmt| format!("{};", stmt))
+        .collect())
+}
+
 pub fn split_stmts_into_transactions(stmts: Vec<String>) -> Result<Vec<Vec<String>>, SddmsError> {
     let mut transactions: Vec<Vec<String>> = Vec::new();
     let mut has_transaction = false;
@@ -298,7 +711,18 @@ pub fn split_stmts_into_transactions(stmts: Vec<String>) -> Result<Vec<Vec<Strin
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
-    use crate::sql_metadata::{parse_statements, split_stmts_into_transactions};
+    use crate::sql_metadata::{check_syntax, combine_metadata, LockGranularity, LockResources, parse_statements, parse_lock_table_stmt, parse_statements_with_default_schemas, LockTableStmt, SchemaChange, split_sql_statements, split_stmts_into_transactions};
+
+    fn lock_resources(sql: &str, granularity: LockGranularity) -> LockResources {
+        parse_statements(sql).unwrap().first().unwrap().lock_resources(granularity)
+    }
+
+    /// Two statements conflict if either locks exclusively something the other locks at all
+    fn resources_conflict(left: &LockResources, right: &LockResources) -> bool {
+        let touches = |resources: &LockResources, resource: &String| resources.shared.contains(resource) || resources.exclusive.contains(resource);
+        left.exclusive.iter().any(|resource| touches(right, resource))
+            || right.exclusive.iter().any(|resource| touches(left, resource))
+    }
 
     #[test]
     fn parses_select() {
@@ -324,6 +748,143 @@ VALUES_CTE(class_name,enroll_count) AS (VALUES ('P3is',79),('hriWO9kPBr',81),('I
         assert_eq!(metadata.read_tables(), &HashSet::from(["professors".to_string()]));
     }
 
+    #[test]
+    fn recursive_cte_does_not_read_itself() {
+        let sql = "WITH RECURSIVE nums(n) AS (SELECT 1 UNION ALL SELECT n+1 FROM nums WHERE n < 10) SELECT * FROM nums";
+        let metadata = parse_statements(sql).unwrap();
+        let metadata = metadata.first().unwrap();
+        assert!(metadata.has_results);
+        assert!(!metadata.modifiable);
+        assert!(!metadata.read_tables().contains("nums"));
+        assert!(metadata.read_tables().is_empty());
+    }
+
+    #[test]
+    fn recursive_cte_reads_tables_from_both_terms() {
+        let sql = "WITH RECURSIVE chain(id) AS (SELECT id FROM professors WHERE id = 1 UNION ALL SELECT students.id FROM students JOIN chain ON students.advisor = chain.id) SELECT * FROM chain";
+        let metadata = parse_statements(sql).unwrap();
+        let metadata = metadata.first().unwrap();
+        assert_eq!(metadata.read_tables(), &HashSet::from(["professors".to_string(), "students".to_string()]));
+    }
+
+    #[test]
+    fn parses_union_correctly() {
+        let sql = "SELECT name FROM students UNION SELECT name FROM professors;";
+        let metadata = parse_statements(sql).unwrap();
+        let metadata = metadata.first().unwrap();
+        assert!(metadata.has_results);
+        assert!(!metadata.modifiable);
+        assert_eq!(metadata.read_tables(), &HashSet::from(["students".to_string(), "professors".to_string()]));
+    }
+
+    #[test]
+    fn parses_nested_union_all_correctly() {
+        let sql = "SELECT id FROM students UNION ALL SELECT id FROM professors UNION ALL SELECT id FROM classes;";
+        let metadata = parse_statements(sql).unwrap();
+        let metadata = metadata.first().unwrap();
+        assert_eq!(metadata.read_tables(), &HashSet::from(["students".to_string(), "professors".to_string(), "classes".to_string()]));
+    }
+
+    #[test]
+    fn parses_create_table_as_correctly() {
+        let sql = "CREATE TABLE summary AS SELECT count(*) FROM students;";
+        let metadata = parse_statements(sql).unwrap();
+        let metadata = metadata.first().unwrap();
+        assert!(!metadata.has_results);
+        assert!(metadata.modifiable);
+        assert_eq!(metadata.write_tables(), &HashSet::from(["summary".to_string()]));
+        assert_eq!(metadata.read_tables(), &HashSet::from(["students".to_string()]));
+        assert_eq!(metadata.schema_change(), Some(SchemaChange::CreateTable));
+        assert_eq!(parse_statements("SELECT * FROM summary;").unwrap()[0].schema_change(), None);
+    }
+
+    #[test]
+    fn alter_table_locks_the_table_exclusively() {
+        let metadata = parse_statements("ALTER TABLE students ADD COLUMN gpa REAL;").unwrap();
+        assert!(metadata[0].modifiable());
+        assert_eq!(metadata[0].write_tables(), &HashSet::from(["students".to_string()]));
+        assert!(metadata[0].read_tables().is_empty());
+        assert_eq!(metadata[0].schema_change(), Some(SchemaChange::AlterTable));
+
+        for granularity in [LockGranularity::Table, LockGranularity::Column] {
+            let resources = metadata[0].lock_resources(granularity);
+            assert_eq!(resources.exclusive, vec!["students".to_string()]);
+            assert!(resources.shared.is_empty());
+        }
+
+        let metadata = parse_statements("ALTER TABLE main.students RENAME TO pupils;").unwrap();
+        assert_eq!(metadata[0].write_tables(), &HashSet::from(["students".to_string(), "pupils".to_string()]));
+    }
+
+    #[test]
+    fn drop_table_locks_every_dropped_table_exclusively() {
+        let metadata = parse_statements("DROP TABLE students;").unwrap();
+        assert!(metadata[0].modifiable());
+        assert_eq!(metadata[0].write_tables(), &HashSet::from(["students".to_string()]));
+        assert_eq!(metadata[0].schema_change(), Some(SchemaChange::DropTable));
+
+        let metadata = parse_statements("DROP TABLE IF EXISTS students, professors;").unwrap();
+        assert_eq!(metadata[0].write_tables(), &HashSet::from(["students".to_string(), "professors".to_string()]));
+    }
+
+    #[test]
+    fn default_schema_is_stripped_from_table_names() {
+        let metadata = parse_statements("SELECT * FROM main.students s JOIN \"temp\".professors ON s.id = professors.id;").unwrap();
+        assert_eq!(metadata[0].read_tables(), &HashSet::from(["students".to_string(), "professors".to_string()]));
+
+        let metadata = parse_statements("INSERT INTO main.classes SELECT * FROM archive.classes;").unwrap();
+        assert_eq!(metadata[0].write_tables(), &HashSet::from(["classes".to_string()]));
+        assert_eq!(metadata[0].read_tables(), &HashSet::from(["archive.classes".to_string()]));
+
+        let metadata = parse_statements_with_default_schemas("SELECT * FROM archive.classes;", &["archive"]).unwrap();
+        assert_eq!(metadata[0].read_tables(), &HashSet::from(["classes".to_string()]));
+    }
+
+    #[test]
+    fn qualified_and_unqualified_tables_collide() {
+        for granularity in [LockGranularity::Table, LockGranularity::Column] {
+            let qualified_write = lock_resources("UPDATE main.students SET grade = 90 WHERE id = 1;", granularity);
+            let unqualified_read = lock_resources("SELECT grade FROM students WHERE id = 2;", granularity);
+            assert!(resources_conflict(&qualified_write, &unqualified_read), "{:?} and {:?}", qualified_write, unqualified_read);
+
+            let unqualified_write = lock_resources("UPDATE students SET grade = 0;", granularity);
+            let qualified_read = lock_resources("SELECT * FROM MAIN.students;", granularity);
+            assert!(resources_conflict(&unqualified_write, &qualified_read), "{:?} and {:?}", unqualified_write, qualified_read);
+        }
+    }
+
+    #[test]
+    fn column_disjoint_updates_do_not_conflict_with_column_granularity() {
+        let grade_update = "UPDATE students SET grade = grade + 1 WHERE id = 1;";
+        let name_update = "UPDATE students SET name = 'Ada' WHERE id = 2;";
+
+        let table_grade = lock_resources(grade_update, LockGranularity::Table);
+        let table_name = lock_resources(name_update, LockGranularity::Table);
+        assert!(resources_conflict(&table_grade, &table_name));
+
+        let column_grade = lock_resources(grade_update, LockGranularity::Column);
+        let column_name = lock_resources(name_update, LockGranularity::Column);
+        assert_eq!(column_grade, LockResources {
+            shared: vec!["students".to_string(), "students.id".to_string()],
+            exclusive: vec!["students.grade".to_string()],
+        });
+        assert!(!resources_conflict(&column_grade, &column_name));
+    }
+
+    #[test]
+    fn column_granularity_still_conflicts_on_shared_columns() {
+        let grade_update = lock_resources("UPDATE students SET grade = 90 WHERE id = 1;", LockGranularity::Column);
+        let grade_read = lock_resources("SELECT name, grade FROM students WHERE id = 3;", LockGranularity::Column);
+        let name_read = lock_resources("SELECT name FROM students;", LockGranularity::Column);
+        let whole_read = lock_resources("SELECT * FROM students;", LockGranularity::Column);
+
+        assert!(resources_conflict(&grade_update, &grade_read));
+        assert!(!resources_conflict(&grade_update, &name_read));
+        // a whole-table read can't tell which columns it needs, so it conflicts with every column writer
+        assert_eq!(whole_read.exclusive, vec!["students".to_string()]);
+        assert!(resources_conflict(&grade_update, &whole_read));
+    }
+
     #[test]
     fn parses_insert_correctly() {
         let sql = "INSERT INTO students (column1, column2) VALUES ('value1', 'value2'),('value2', 'value3');";
@@ -335,6 +896,35 @@ VALUES_CTE(class_name,enroll_count) AS (VALUES ('P3is',79),('hriWO9kPBr',81),('I
         assert!(metadata.read_tables().is_empty());
     }
 
+    #[test]
+    fn parses_conflict_clause_inserts_correctly() {
+        let statements = [
+            "INSERT OR REPLACE INTO students (id, name) VALUES (1, 'a');",
+            "INSERT OR IGNORE INTO students (id, name) VALUES (1, 'a');",
+            "REPLACE INTO students (id, name) VALUES (1, 'a');",
+        ];
+        for sql in statements {
+            let metadata = parse_statements(sql).unwrap();
+            assert_eq!(metadata.len(), 1, "{}", sql);
+            let metadata = metadata.first().unwrap();
+            assert!(!metadata.has_results);
+            assert!(metadata.modifiable, "{}", sql);
+            assert_eq!(metadata.write_tables(), &HashSet::from(["students".to_string()]));
+            assert!(metadata.read_tables().is_empty());
+        }
+    }
+
+    #[test]
+    fn parses_replace_among_other_statements() {
+        let sql = "SELECT * FROM courses; REPLACE INTO students SELECT * FROM applicants; DELETE FROM courses;";
+        let metadata = parse_statements(sql).unwrap();
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata[1].write_tables(), &HashSet::from(["students".to_string()]));
+        assert_eq!(metadata[1].read_tables(), &HashSet::from(["applicants".to_string()]));
+        assert!(check_syntax("REPLACE INTO students VALUES (1)").is_ok());
+        assert!(check_syntax("REPLACE INTO students VALUES (1) SELECT").is_err());
+    }
+
     #[test]
     fn parses_update_correctly() {
         let sql = "UPDATE students SET column1=column1 + 1 WHERE students=1;";
@@ -363,4 +953,46 @@ VALUES_CTE(class_name,enroll_count) AS (VALUES ('P3is',79),('hriWO9kPBr',81),('I
             .map(|str_ref| str_ref.to_string())
             .collect::<Vec<_>>());
     }
+
+    #[test]
+    fn lock_table_statements_keep_their_order() {
+        let stmt = parse_lock_table_stmt("lock table flights, bookings IN SHARE MODE;").unwrap().unwrap();
+        assert_eq!(stmt, LockTableStmt { tables: vec![String::from("flights"), String::from("bookings")], exclusive: false });
+
+        let stmt = parse_lock_table_stmt("LOCK TABLES bookings,flights;").unwrap().unwrap();
+        assert_eq!(stmt, LockTableStmt { tables: vec![String::from("bookings"), String::from("flights")], exclusive: true });
+
+        assert!(parse_lock_table_stmt("LOCK TABLE flights IN ROW MODE;").unwrap().is_err());
+        assert!(parse_lock_table_stmt("LOCK TABLE ;").unwrap().is_err());
+        assert!(parse_lock_table_stmt("SELECT * FROM flights;").is_none());
+
+        // they stay part of the transaction they're in
+        let stmts = ["BEGIN;", "LOCK TABLE flights;", "SELECT * FROM flights;", "COMMIT;"].iter()
+            .map(|str_ref| str_ref.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(split_stmts_into_transactions(stmts).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn combined_metadata_locks_each_table_once() {
+        let metadata = parse_statements("SELECT * FROM flights; UPDATE flights SET seats = 2; SELECT * FROM bookings;").unwrap();
+        let combined = combine_metadata(metadata);
+        assert!(combined.modifiable());
+        assert!(combined.has_results());
+        assert_eq!(combined.lock_resources(LockGranularity::Table), LockResources {
+            shared: vec![String::from("bookings")],
+            exclusive: vec![String::from("flights")],
+        });
+    }
+
+    #[test]
+    fn split_sql_statements_skips_quoted_semicolons() {
+        let sql = "INSERT INTO notes VALUES ('a;b');\n-- not; a statement\nSELECT \"odd;name\" FROM notes; LOCK TABLE notes IN SHARE MODE;";
+        assert_eq!(split_sql_statements(sql).unwrap(), vec![
+            String::from("INSERT INTO notes VALUES ('a;b');"),
+            String::from("-- not; a statement\nSELECT \"odd;name\" FROM notes;"),
+            String::from("LOCK TABLE notes IN SHARE MODE;"),
+        ]);
+        assert!(split_sql_statements("  ;\n").unwrap().is_empty());
+    }
 }
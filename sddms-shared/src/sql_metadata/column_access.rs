@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+use sqlparser::ast::{Expr, FunctionArg, FunctionArgExpr, GroupByExpr, Select, SelectItem};
+
+/// Collects the columns an expression reads into `columns`. Gives false if the expression is too complex to
+/// tell, such as one with a subquery or a wildcard, in which case the whole table has to be treated as read
+pub(super) fn collect_expr_columns(expr: &Expr, columns: &mut HashSet<String>) -> bool {
+    match expr {
+        Expr::Identifier(ident) => {
+            columns.insert(ident.value.clone());
+            true
+        }
+        // the table qualifier is redundant when there's only one table
+        Expr::CompoundIdentifier(idents) => {
+            match idents.last() {
+                Some(ident) => {
+                    columns.insert(ident.value.clone());
+                    true
+                }
+                None => false,
+            }
+        }
+        Expr::Value(_) => true,
+        Expr::BinaryOp { left, right, .. } => {
+            collect_expr_columns(left, columns) && collect_expr_columns(right, columns)
+        }
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr)
+        | Expr::Cast { expr, .. } => collect_expr_columns(expr, columns),
+        Expr::InList { expr, list, .. } => {
+            collect_expr_columns(expr, columns) && list.iter().all(|item| collect_expr_columns(item, columns))
+        }
+        Expr::Between { expr, low, high, .. } => {
+            collect_expr_columns(expr, columns) && collect_expr_columns(low, columns) && collect_expr_columns(high, columns)
+        }
+        Expr::Like { expr, pattern, .. } => {
+            collect_expr_columns(expr, columns) && collect_expr_columns(pattern, columns)
+        }
+        Expr::Function(function) => {
+            function.args.iter().all(|arg| {
+                let (FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg)) = arg;
+                match arg {
+                    FunctionArgExpr::Expr(expr) => collect_expr_columns(expr, columns),
+                    // count(*) and the like read every column
+                    FunctionArgExpr::Wildcard | FunctionArgExpr::QualifiedWildcard(_) => false,
+                }
+            })
+        }
+        _ => false,
+    }
+}
+
+/// Gets the columns a single-table select reads, or nothing if it reads the whole table
+pub(super) fn select_columns(select: &Select) -> Option<HashSet<String>> {
+    let mut columns = HashSet::new();
+
+    let projection_known = select.projection.iter().all(|item| match item {
+        SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => collect_expr_columns(expr, &mut columns),
+        SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => false,
+    });
+
+    let group_by_known = match &select.group_by {
+        GroupByExpr::All => false,
+        GroupByExpr::Expressions(exprs) => exprs.iter().all(|expr| collect_expr_columns(expr, &mut columns)),
+    };
+
+    let filters_known = [&select.selection, &select.having].into_iter()
+        .flatten()
+        .all(|expr| collect_expr_columns(expr, &mut columns));
+
+    if projection_known && group_by_known && filters_known {
+        Some(columns)
+    } else {
+        None
+    }
+}
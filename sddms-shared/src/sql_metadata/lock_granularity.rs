@@ -0,0 +1,33 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// How finely statements lock the data they touch
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LockGranularity {
+    /// lock whole tables
+    #[default]
+    Table,
+    /// lock the individual columns of a table when it's clear which columns a statement touches
+    Column,
+}
+
+impl FromStr for LockGranularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" => Ok(LockGranularity::Table),
+            "column" => Ok(LockGranularity::Column),
+            other => Err(format!("unknown lock granularity '{}', expected 'table' or 'column'", other)),
+        }
+    }
+}
+
+impl Display for LockGranularity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockGranularity::Table => f.write_str("table"),
+            LockGranularity::Column => f.write_str("column"),
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, HashSet};
+use sqlparser::ast::{BinaryOperator, Expr, SetExpr, Statement, TableFactor, TableWithJoins, Value};
+use sqlparser::parser::ParserError;
+use crate::sql_metadata::{DEFAULT_SCHEMAS, parse_sqlite, relation_name, SqlMetadata, strip_default_schema};
+
+/// Picks out rows by the literal value some of their columns have. A key without any columns could be any
+/// row of the table
+pub type RowKey = BTreeMap<String, String>;
+
+/// A column a statement writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnWrite {
+    /// the column written, or `None` if the statement writes whole rows, like an insert or delete does
+    pub column: Option<String>,
+    /// true if the column is set to a constant, so the write doesn't depend on what was there before
+    pub overwrite: bool,
+}
+
+/// The rows and columns of a table that a statement writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowWrite {
+    pub table: String,
+    /// the rows written
+    pub rows: Vec<RowKey>,
+    /// true if exactly the rows picked out by `rows` are written. Otherwise the statement filters on more
+    /// than equalities, so it may write fewer of them
+    pub exact: bool,
+    pub columns: Vec<ColumnWrite>,
+}
+
+impl RowWrite {
+    /// Writes to whole rows of the table that can't be told apart, which is assumed of any statement
+    /// that isn't understood
+    fn whole_table(table: String) -> Self {
+        Self {
+            table,
+            rows: vec![RowKey::new()],
+            exact: false,
+            columns: vec![ColumnWrite { column: None, overwrite: false }],
+        }
+    }
+}
+
+/// True if some row could be picked out by both keys, which is the case unless they need a column to
+/// have different values
+pub fn keys_overlap(left: &RowKey, right: &RowKey) -> bool {
+    left.iter()
+        .all(|(column, value)| right.get(column).iter().all(|other_value| *other_value == value))
+}
+
+/// True if every row picked out by `specific` is also picked out by `general`
+pub fn key_covers(general: &RowKey, specific: &RowKey) -> bool {
+    general.iter()
+        .all(|(column, value)| specific.get(column) == Some(value))
+}
+
+/// The text of a literal, so that `1` and `'1'` compare the same like they do in SQLite
+fn literal_text(value: &Value) -> String {
+    match value {
+        Value::Number(number, _) => number.to_string(),
+        Value::SingleQuotedString(text) | Value::DoubleQuotedString(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn column_name(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Identifier(ident) => Some(ident.value.clone()),
+        Expr::CompoundIdentifier(idents) => idents.last().map(|ident| ident.value.clone()),
+        _ => None,
+    }
+}
+
+/// Collects the `column = literal` equalities that a filter ANDs together into `key`. Gives false if the
+/// filter has anything else in it, since then it may match fewer rows than the key does
+fn collect_key(expr: &Expr, key: &mut RowKey) -> bool {
+    match expr {
+        Expr::BinaryOp { left, op: BinaryOperator::And, right } => {
+            // both sides have to be collected, even if the left one isn't exact
+            let left_exact = collect_key(left, key);
+            collect_key(right, key) && left_exact
+        }
+        Expr::BinaryOp { left, op: BinaryOperator::Eq, right } => {
+            let equality = match (left.as_ref(), right.as_ref()) {
+                (column, Expr::Value(value)) | (Expr::Value(value), column) => column_name(column)
+                    .map(|column| (column, literal_text(value))),
+                _ => None,
+            };
+
+            match equality {
+                Some((column, value)) => {
+                    key.insert(column, value);
+                    true
+                }
+                None => false,
+            }
+        }
+        Expr::Nested(expr) => collect_key(expr, key),
+        _ => false,
+    }
+}
+
+/// True if an expression always has the same value, no matter what row it's evaluated on
+fn is_constant(expr: &Expr) -> bool {
+    match expr {
+        Expr::Value(_) => true,
+        Expr::UnaryOp { expr, .. } | Expr::Nested(expr) => is_constant(expr),
+        Expr::BinaryOp { left, right, .. } => is_constant(left) && is_constant(right),
+        _ => false,
+    }
+}
+
+/// The table written by a single, unjoined table
+fn single_table(tables: &[TableWithJoins]) -> Option<String> {
+    match tables {
+        [TableWithJoins { relation: relation @ TableFactor::Table { .. }, joins }] if joins.is_empty() => Some(relation_name(relation)),
+        _ => None,
+    }
+}
+
+fn statement_row_writes(statement: Statement) -> Vec<RowWrite> {
+    let strip = |table: String| strip_default_schema(&table, &DEFAULT_SCHEMAS);
+    match statement {
+        Statement::Insert { table_name, columns, source, .. } => {
+            let table = strip(table_name.to_string());
+            // only literal values say anything about which rows are inserted
+            let rows = match source.as_deref().map(|query| query.body.as_ref()) {
+                Some(SetExpr::Values(values)) if !columns.is_empty() => values.rows.iter()
+                    .map(|row| columns.iter()
+                        .zip(row)
+                        .filter_map(|(column, value)| match value {
+                            Expr::Value(value) => Some((column.value.clone(), literal_text(value))),
+                            _ => None,
+                        })
+                        .collect::<RowKey>())
+                    .collect(),
+                _ => vec![RowKey::new()],
+            };
+
+            vec![RowWrite { rows, ..RowWrite::whole_table(table) }]
+        }
+        Statement::Update { table, assignments, from, selection, .. } => {
+            let Some(table_name) = single_table(std::slice::from_ref(&table)).filter(|_| from.is_none()) else {
+                return vec![RowWrite::whole_table(strip(relation_name(&table.relation)))];
+            };
+
+            let mut key = RowKey::new();
+            let exact = selection.iter().all(|selection| collect_key(selection, &mut key));
+            let columns = assignments.iter()
+                .filter_map(|assignment| assignment.id.last().map(|column| ColumnWrite {
+                    column: Some(column.value.clone()),
+                    overwrite: is_constant(&assignment.value),
+                }))
+                .collect();
+
+            vec![RowWrite { table: strip(table_name), rows: vec![key], exact, columns }]
+        }
+        Statement::Delete { tables, from, using, selection, .. } => {
+            let table = match single_table(&from).filter(|_| tables.is_empty() && using.is_none()) {
+                Some(table) => table,
+                None => return tables.iter().map(ToString::to_string)
+                    .chain(from.iter().map(|from| relation_name(&from.relation)))
+                    .map(|table| RowWrite::whole_table(strip(table)))
+                    .collect(),
+            };
+
+            let mut key = RowKey::new();
+            let exact = selection.iter().all(|selection| collect_key(selection, &mut key));
+            // a deleted row ends up the same no matter what was written to it before
+            let columns = vec![ColumnWrite { column: None, overwrite: true }];
+            vec![RowWrite { table: strip(table), rows: vec![key], exact, columns }]
+        }
+        other => SqlMetadata::from(other)
+            .strip_default_schemas(&DEFAULT_SCHEMAS)
+            .take_write_tables()
+            .into_iter()
+            .map(RowWrite::whole_table)
+            .collect(),
+    }
+}
+
+/// Works out which rows and columns the statements in `sql` write. Rows are told apart by the literal
+/// values a statement's filter or inserted values give their columns, so a statement that doesn't give
+/// any is taken to write every row of its table
+pub fn parse_row_writes(sql: &str) -> Result<Vec<RowWrite>, ParserError> {
+    let statements = parse_sqlite(sql)?;
+    Ok(statements.into_iter()
+        .flat_map(statement_row_writes)
+        .collect())
+}
+
+/// Removes the assignments to the given columns from the updates in `sql`. Updates left without any
+/// assignments are removed altogether, and `None` is given back if nothing is left
+pub fn without_assignments(sql: &str, columns: &HashSet<String>) -> Result<Option<String>, ParserError> {
+    let remaining = parse_sqlite(sql)?.into_iter()
+        .filter_map(|statement| match statement {
+            Statement::Update { table, assignments, from, selection, returning } => {
+                let assignments = assignments.into_iter()
+                    .filter(|assignment| !assignment.id.last().is_some_and(|column| columns.contains(&column.value)))
+                    .collect::<Vec<_>>();
+                (!assignments.is_empty())
+                    .then_some(Statement::Update { table, assignments, from, selection, returning })
+            }
+            other => Some(other),
+        })
+        .map(|statement| format!("{};", statement))
+        .collect::<Vec<_>>();
+
+    Ok((!remaining.is_empty()).then(|| remaining.join(" ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::sql_metadata::row_writes::{ColumnWrite, key_covers, keys_overlap, parse_row_writes, RowKey, RowWrite, without_assignments};
+
+    fn key(pairs: &[(&str, &str)]) -> RowKey {
+        pairs.iter()
+            .map(|(column, value)| (column.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn updates_write_the_rows_they_filter_to() {
+        let writes = parse_row_writes("UPDATE main.flights SET seats = 3, price = price + 1 WHERE id = 1 AND origin = 'SEA';").unwrap();
+        assert_eq!(writes, vec![RowWrite {
+            table: String::from("flights"),
+            rows: vec![key(&[("id", "1"), ("origin", "SEA")])],
+            exact: true,
+            columns: vec![
+                ColumnWrite { column: Some(String::from("seats")), overwrite: true },
+                ColumnWrite { column: Some(String::from("price")), overwrite: false },
+            ],
+        }]);
+
+        // anything besides equalities might match fewer rows, and OR might match more
+        let writes = parse_row_writes("UPDATE flights SET seats = 3 WHERE id = 1 AND seats > 0;").unwrap();
+        assert_eq!((writes[0].rows.clone(), writes[0].exact), (vec![key(&[("id", "1")])], false));
+        let writes = parse_row_writes("UPDATE flights SET seats = 3 WHERE id = 1 OR id = 2;").unwrap();
+        assert_eq!((writes[0].rows.clone(), writes[0].exact), (vec![RowKey::new()], false));
+    }
+
+    #[test]
+    fn inserts_and_deletes_write_whole_rows() {
+        let writes = parse_row_writes("INSERT INTO flights (id, seats) VALUES (1, 3), ('2', seats);").unwrap();
+        assert_eq!(writes[0].rows, vec![key(&[("id", "1"), ("seats", "3")]), key(&[("id", "2")])]);
+        assert_eq!(writes[0].columns, vec![ColumnWrite { column: None, overwrite: false }]);
+
+        // without the columns named, the inserted rows could be anything
+        let writes = parse_row_writes("INSERT INTO flights VALUES (1, 3);").unwrap();
+        assert_eq!(writes[0].rows, vec![RowKey::new()]);
+
+        let writes = parse_row_writes("DELETE FROM flights WHERE id = 4;").unwrap();
+        assert_eq!(writes, vec![RowWrite {
+            table: String::from("flights"),
+            rows: vec![key(&[("id", "4")])],
+            exact: true,
+            columns: vec![ColumnWrite { column: None, overwrite: true }],
+        }]);
+
+        assert!(parse_row_writes("SELECT * FROM flights;").unwrap().is_empty());
+    }
+
+    #[test]
+    fn keys_compare_by_shared_columns() {
+        assert!(keys_overlap(&key(&[("id", "1")]), &key(&[("id", "1"), ("seats", "3")])));
+        assert!(keys_overlap(&key(&[("id", "1")]), &key(&[("seats", "3")])));
+        assert!(!keys_overlap(&key(&[("id", "1")]), &key(&[("id", "2"), ("seats", "3")])));
+
+        assert!(key_covers(&RowKey::new(), &key(&[("id", "1")])));
+        assert!(key_covers(&key(&[("id", "1")]), &key(&[("id", "1"), ("seats", "3")])));
+        assert!(!key_covers(&key(&[("id", "1"), ("seats", "3")]), &key(&[("id", "1")])));
+    }
+
+    #[test]
+    fn assignments_can_be_removed() {
+        let columns = HashSet::from([String::from("seats")]);
+        assert_eq!(without_assignments("UPDATE flights SET seats = 3, price = 4 WHERE id = 1;", &columns).unwrap(),
+                   Some(String::from("UPDATE flights SET price = 4 WHERE id = 1;")));
+        assert_eq!(without_assignments("UPDATE flights SET seats = 3 WHERE id = 1;", &columns).unwrap(), None);
+    }
+}
@@ -0,0 +1,20 @@
+use std::fmt::{Display, Formatter};
+
+/// The change a DDL statement makes to the schema. DDL doesn't affect any rows, so this is reported instead
+/// of a row count
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    CreateTable,
+    AlterTable,
+    DropTable,
+}
+
+impl Display for SchemaChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaChange::CreateTable => f.write_str("Table created"),
+            SchemaChange::AlterTable => f.write_str("Table altered"),
+            SchemaChange::DropTable => f.write_str("Table dropped"),
+        }
+    }
+}
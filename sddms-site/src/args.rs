@@ -1,5 +1,9 @@
 use std::path::PathBuf;
 use clap::Parser;
+use sddms_services::transport::TlsOptions;
+use sddms_site::history_logger::HistoryFormat;
+use sddms_site::journal_mode::JournalMode;
+use sddms_site::replication_conflicts::ReplicationConflictPolicy;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -13,12 +17,88 @@ pub struct Args {
     #[arg(short, long)]
     pub init_file: Option<PathBuf>,
 
-    /// Path to write the operation history to
+    /// Path to write the operation history to. History is added to the end of the file if it already exists
     #[arg(long)]
     pub history_file: Option<PathBuf>,
 
+    /// How history lines are written, either `text` or `jsonl`
+    #[arg(long, default_value = "text", requires = "history_file")]
+    pub history_format: HistoryFormat,
+
+    /// Roll the history file over to <history_file>.1 once it would grow past this many megabytes
+    #[arg(long, requires = "history_file")]
+    pub history_rotate_mb: Option<u64>,
+
+    /// Discard any history already in the history file instead of adding to it
+    #[arg(long, default_value = "false", requires = "history_file")]
+    pub truncate_history: bool,
+
+    /// The SQLite journal mode to use for the disk database
+    #[arg(long, value_enum)]
+    pub journal_mode: Option<JournalMode>,
+
+    /// The most clients that can be connected at once. Unlimited if not given
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+
+    /// An SQLite extension library to load into every connection. Can be given more than once
+    #[arg(long = "extension")]
+    pub extensions: Vec<PathBuf>,
+
+    /// Reject statements that modify the database, serving only reads and replicated updates
+    #[arg(long, default_value = "false")]
+    pub read_only: bool,
+
+    /// How many milliseconds a query waits for this site to apply the replication it asked to read after
+    #[arg(long, default_value = "5000")]
+    pub catchup_timeout_ms: u64,
+
+    /// How to settle replicated writes to the same table that arrive out of order. With last-writer-wins, a
+    /// replicated statement is skipped if a later transaction already wrote its table
+    #[arg(long, value_enum, default_value_t = ReplicationConflictPolicy::ArrivalOrder)]
+    pub replication_conflicts: ReplicationConflictPolicy,
+
+    /// How many seconds to wait for transactions in progress to finalize when shutting down
+    #[arg(long, default_value = "10")]
+    pub shutdown_timeout: u64,
+
+    /// PEM certificate to serve with, which is also presented to the central controller when given
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM key for the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM certificate of the CA that the central controller's and clients' certificates are checked against
+    #[arg(long)]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Connect to the central controller over TLS
+    #[arg(long, default_value = "false")]
+    pub tls: bool,
+
     /// path to the sqlite db to open. Creates if it doesn't exist
     pub db_path: PathBuf,
     /// the address of the central controller, <ip_addr>:<port>
     pub cc_addr: String,
 }
+
+impl Args {
+    fn tls_files(&self) -> TlsOptions {
+        TlsOptions {
+            identity: self.tls_cert.clone().zip(self.tls_key.clone()),
+            ca_cert: self.ca_cert.clone(),
+        }
+    }
+
+    /// The site serves over TLS when it has a certificate to serve with
+    pub fn server_tls_options(&self) -> Option<TlsOptions> {
+        self.tls_cert.as_ref().map(|_| self.tls_files())
+    }
+
+    /// The site connects to the central controller over TLS if asked to
+    pub fn central_tls_options(&self) -> Option<TlsOptions> {
+        self.tls.then(|| self.tls_files())
+    }
+}
@@ -2,15 +2,31 @@ use log::debug;
 use tonic::transport::Channel;
 use sddms_services::central_controller::concurrency_controller_service_client::ConcurrencyControllerServiceClient;
 use sddms_services::central_controller::register_site_response::RegisterSitePayload;
-use sddms_services::central_controller::{AcquireLockRequest, FinalizeTransactionRequest, RegisterSiteRequest, RegisterTransactionRequest};
+use sddms_services::central_controller::{AcquireLockRequest, FinalizeTransactionRequest, RegisterSiteRequest, RegisterTransactionRequest, UnregisterSiteRequest};
 use sddms_services::central_controller::acquire_lock_response::AcquireLockPayload;
 use sddms_services::central_controller::register_transaction_response::RegisterTransactionPayload;
 use sddms_services::shared::{FinalizeMode, LockRequest, ReturnStatus};
+use sddms_services::transport;
+use sddms_services::transport::TlsOptions;
 use sddms_shared::error::{SddmsError, SddmsTermError};
 
 pub enum AcquireLockRet {
+    /// the locks were acquired after waiting behind `queue_position` other locks at most
+    Ok { queue_position: u32 },
+    Deadlock(SddmsTermError),
+    /// the central controller doesn't know the transaction, so it has to be registered again or aborted
+    TransactionNotFound(SddmsError),
+    /// the locks were held by someone else and the request asked not to wait
+    WouldBlock(SddmsError),
+}
+
+/// How finalizing a transaction with the central controller went
+pub enum FinalizeRet {
     Ok,
-    Deadlock(SddmsTermError)
+    /// the central controller doesn't know the transaction, so it holds no locks there
+    TransactionNotFound(SddmsError),
+    /// the transaction was finalized, but some sites couldn't be replicated to
+    PartiallyReplicated(SddmsError),
 }
 
 pub struct CentralClient {
@@ -18,14 +34,26 @@ pub struct CentralClient {
 }
 
 impl CentralClient {
-    pub async fn new(conn_str: &str) -> Result<Self, SddmsError> {
-        let conn_str = format!("http://{}", conn_str);
-        let client = ConcurrencyControllerServiceClient::connect(conn_str)
+    pub async fn new(conn_str: &str, tls: Option<&TlsOptions>) -> Result<Self, SddmsError> {
+        let channel = transport::connect(conn_str, tls)
             .await
             .map_err(|err| SddmsError::site("Failed to connect to central site").with_cause(err))?;
 
         Ok(Self {
-            client
+            client: ConcurrencyControllerServiceClient::new(channel)
+        })
+    }
+
+    /// A client for a central controller that isn't connected to until the first request, so a site can
+    /// be tested without one as long as it never needs to reach it
+    #[cfg(test)]
+    pub fn lazy(conn_str: &str) -> Result<Self, SddmsError> {
+        let channel = Channel::from_shared(format!("http://{}", conn_str))
+            .map_err(|err| SddmsError::site("Invalid central site address").with_cause(err))?
+            .connect_lazy();
+
+        Ok(Self {
+            client: ConcurrencyControllerServiceClient::new(channel)
         })
     }
 
@@ -50,6 +78,28 @@ impl CentralClient {
         }
     }
 
+    /// Tells the central controller this site is shutting down. Returns how many of the site's
+    /// transactions were still live and had their locks released
+    pub async fn unregister_self(&self, site_id: u32) -> Result<u32, SddmsError> {
+        let request = UnregisterSiteRequest {
+            site_id,
+        };
+
+        let response = self.client.clone().unregister_site(request)
+            .await
+            .map_err(|err| SddmsError::site("Failed to transport unregister site request").with_cause(err))
+            ?.into_inner();
+
+        match response.error {
+            Some(api_err) => {
+                Err(api_err.into())
+            }
+            None => {
+                Ok(response.released_transactions)
+            }
+        }
+    }
+
     pub async fn register_transaction(&self, site_id: u32) -> Result<u32, SddmsError> {
         let request = RegisterTransactionRequest {
             site_id,
@@ -71,13 +121,29 @@ impl CentralClient {
         }
     }
 
-    pub async fn acquire_table_lock(&self, site_id: u32, transaction_id: u32, lock_requests: Vec<LockRequest>) -> Result<AcquireLockRet, SddmsError> {
-        let request = AcquireLockRequest {
+    pub async fn acquire_table_lock(&self, site_id: u32, transaction_id: u32, lock_requests: Vec<LockRequest>, no_wait: bool) -> Result<AcquireLockRet, SddmsError> {
+        self.send_acquire_lock(AcquireLockRequest {
             site_id,
             transaction_id,
-            lock_requests: lock_requests.clone()
-        };
+            lock_requests,
+            no_wait,
+            ordered: false,
+        }).await
+    }
 
+    /// Acquires the locks one at a time in exactly the given order, rather than all at once
+    pub async fn acquire_table_lock_in_order(&self, site_id: u32, transaction_id: u32, lock_requests: Vec<LockRequest>, no_wait: bool) -> Result<AcquireLockRet, SddmsError> {
+        self.send_acquire_lock(AcquireLockRequest {
+            site_id,
+            transaction_id,
+            lock_requests,
+            no_wait,
+            ordered: true,
+        }).await
+    }
+
+    async fn send_acquire_lock(&self, request: AcquireLockRequest) -> Result<AcquireLockRet, SddmsError> {
+        let lock_requests = request.lock_requests.clone();
         let response = self.client.clone().acquire_lock(request)
             .await
             .map_err(|err| SddmsError::site("Failed to transport acquire lock request").with_cause(err))
@@ -90,24 +156,29 @@ impl CentralClient {
 
                 if let ReturnStatus::Deadlocked = ret {
                     Ok(AcquireLockRet::Deadlock(SddmsTermError::from(SddmsError::central("Acquiring locks failed due to deadlock"))))
+                } else if let ReturnStatus::TransactionNotFound = ret {
+                    Ok(AcquireLockRet::TransactionNotFound(api_err.into()))
+                } else if let ReturnStatus::WouldBlock = ret {
+                    Ok(AcquireLockRet::WouldBlock(api_err.into()))
                 } else {
                     let err: SddmsError = api_err.into();
                     Err(SddmsError::site(format!("Failed to acquire locks {:?}", lock_requests))
                         .with_cause(err))
                 }
             }
-            AcquireLockPayload::Results(_) => {
-                Ok(AcquireLockRet::Ok)
+            AcquireLockPayload::Results(results) => {
+                Ok(AcquireLockRet::Ok { queue_position: results.queue_position })
             }
         }
     }
 
-     pub async fn finalize_transaction(&self, site_id: u32, trans_id: u32, mode: FinalizeMode, update_commands: &[String]) -> Result<(), SddmsError> {
+     pub async fn finalize_transaction(&self, site_id: u32, trans_id: u32, mode: FinalizeMode, update_commands: &[String], commit_timestamp_us: u64) -> Result<FinalizeRet, SddmsError> {
         let mut request = FinalizeTransactionRequest {
             site_id,
             transaction_id: trans_id,
             finalize_mode: 0,
-            update_history: update_commands.to_vec()
+            update_history: update_commands.to_vec(),
+            commit_timestamp_us,
         };
         request.set_finalize_mode(mode);
 
@@ -118,14 +189,21 @@ impl CentralClient {
             ?.into_inner();
         debug!("Received finalize response");
 
+        let ret = response.ret();
         match response.error {
+            Some(api_err) if ret == ReturnStatus::TransactionNotFound => {
+                Ok(FinalizeRet::TransactionNotFound(api_err.into()))
+            }
+            Some(api_err) if ret == ReturnStatus::PartiallyReplicated => {
+                Ok(FinalizeRet::PartiallyReplicated(api_err.into()))
+            }
             Some(api_err) => {
                 let err: SddmsError = api_err.into();
                 Err(SddmsError::site(format!("Failed to finalize transaction {}", trans_id))
                     .with_cause(err))
             }
             None => {
-                Ok(())
+                Ok(FinalizeRet::Ok)
             }
         }
     }
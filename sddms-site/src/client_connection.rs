@@ -1,162 +1,607 @@
 use std::collections::HashMap;
 use std::path::Path;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::time::Duration;
-use log::info;
+use log::{debug, info};
 use rusqlite::{Connection, OpenFlags};
 use rusqlite::backup::Backup;
+use serde_json::{Map, Value};
+use tokio::sync::{Mutex, MutexGuard};
 use sddms_services::site_controller::InvokeQueryResults;
 use sddms_shared::error::{SddmsError, SddmsTermError};
+use crate::sqlite_extensions::SqliteExtensions;
 use crate::sqlite_row_serializer::serialize_row;
 
+/// Takes each full batch of rows while a read is still stepping through the rest
+pub type BatchHandler<'a> = &'a mut (dyn FnMut(InvokeQueryResults) + Send);
+
+/// The database shared by every client on the site. Reads take the read lock, so they can run side by side,
+/// while writes take the write lock
+type SharedConnection = Arc<tokio::sync::RwLock<ConnectionPool>>;
+
+/// how many reads can run against the shared database at once
+const READER_COUNT: usize = 4;
+
+/// used to give every site's shared database its own name
+static DATABASE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Connections to one in-memory database. rusqlite connections aren't `Sync`, so each read needs a
+/// connection to itself, which comes from the readers
+struct ConnectionPool {
+    /// only used under the write lock
+    writer: Mutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    /// which reader to wait for when they're all busy
+    next_reader: AtomicUsize,
+}
+
+impl ConnectionPool {
+    /// Copies the database into a fresh in-memory database, which every connection in the pool shares
+    fn copy_of(source: &Connection) -> Result<Self, SddmsError> {
+        let name = format!("file:sddms-site-{}-{}?mode=memory&cache=shared", std::process::id(), DATABASE_COUNTER.fetch_add(1, Ordering::SeqCst));
+        let open = || Connection::open_with_flags(&name, OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI)
+            .map_err(|err| SddmsError::site("Could not open memory database").with_cause(err));
+
+        let mut writer = open()?;
+        // do this in a smaller scope so that the writer borrow drops
+        {
+            let backup = Backup::new(source, &mut writer)
+                .map_err(|err| SddmsError::site("Failed to create backup").with_cause(err))?;
+
+            backup.run_to_completion(5, Duration::from_millis(500), None)
+                .map_err(|err| SddmsError::site("Error while backing up").with_cause(err))?;
+        }
+
+        let readers = (0..READER_COUNT)
+            .map(|_| open().map(Mutex::new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            readers,
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    fn load_extensions(&mut self, extensions: &SqliteExtensions) -> Result<(), SddmsError> {
+        extensions.load_into(self.writer.get_mut())?;
+        for reader in &mut self.readers {
+            extensions.load_into(reader.get_mut())?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes whichever reader is free, or waits for one if they're all busy
+    async fn reader(&self) -> MutexGuard<'_, Connection> {
+        for reader in &self.readers {
+            if let Ok(reader) = reader.try_lock() {
+                return reader;
+            }
+        }
+
+        let index = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        self.readers[index].lock().await
+    }
+}
+
+/// Runs a query on top of a transaction's writes so far, then throws the writes away again. A transaction
+/// sees its own writes this way, while every other client only sees them once it commits
+fn with_pending_writes<T, E: From<SddmsError>>(connection: &mut Connection, pending_writes: &[String], query: impl FnOnce(&Connection) -> Result<T, E>) -> Result<T, E> {
+    // dropping the savepoint rolls it back
+    let savepoint = connection.savepoint()
+        .map_err(|err| SddmsError::site("Failed to open savepoint for transaction").with_cause(err))?;
+
+    for stmt in pending_writes {
+        savepoint.execute(stmt, [])
+            .map_err(|err| SddmsError::site("Failed to apply the transaction's earlier writes").with_cause(err))?;
+    }
+
+    query(&savepoint)
+}
+
+/// Serializes a batch of rows read by a query
+fn batch_results(rows: &[Map<String, Value>], col_names: &[String]) -> Result<InvokeQueryResults, SddmsError> {
+    let payload_results = serde_json::to_vec(rows)
+        .map_err(|err| SddmsError::general("Failed to serialize record payload").with_cause(err))?;
+
+    Ok(InvokeQueryResults {
+        data_payload: Some(payload_results),
+        column_names: col_names.to_vec(),
+        ..Default::default()
+    })
+}
+
+/// Steps through the rows of a query, handing off every batch of `batch_size` rows as soon as it fills.
+/// Gives back the rows left after the last full batch, which is all of them if no batch filled up
+fn read_query(connection: &Connection, query_text: &str, batch_size: usize, on_batch: BatchHandler) -> Result<InvokeQueryResults, SddmsError> {
+    let sliced_query_text = if query_text.ends_with(";") {
+        &query_text[0..query_text.len()-1]
+    } else {
+        query_text
+    };
+
+    let mut statement = connection.prepare(sliced_query_text)
+        .map_err(|err| SddmsError::general("Failed to prepare query").with_cause(err))?;
+
+    let col_names = statement.column_names().iter()
+        .map(|col_name| String::from(*col_name))
+        .collect::<Vec<_>>();
+
+    let mut rows = statement.query([])
+        .map_err(|err| SddmsError::site("Error while executing query").with_cause(err))?;
+
+    let mut batch = Vec::new();
+    let mut row_count = 0usize;
+    while let Some(row) = rows.next().map_err(|err| SddmsError::site("Error while reading query results").with_cause(err))? {
+        batch.push(serialize_row(row, &col_names));
+        row_count += 1;
+        if batch.len() >= batch_size {
+            on_batch(batch_results(&batch, &col_names)?);
+            batch.clear();
+        }
+    }
+
+    info!("Read {} rows", row_count);
+    batch_results(&batch, &col_names)
+}
+
+fn modify_query(connection: &Connection, query_text: &str) -> Result<InvokeQueryResults, SddmsTermError> {
+    let mut results = InvokeQueryResults::default();
+    connection.execute(query_text, ())
+        .map_err(|err| SddmsError::general("Failed to invoke SQL query").with_cause(err))?;
+
+    let affected_rows = connection.changes() as u32;
+    results.affected_records = Some(affected_rows);
+    info!("Updated {} rows", affected_rows);
+    Ok(results)
+}
+
+fn perform_update_transaction(stmts: &[String], connection: &Connection) -> Result<(), SddmsError> {
+    // apply the whole batch or none of it, so a failure can't leave the connection half-updated. Client
+    // transactions only hold a savepoint while they query, so there is never another transaction open here
+    let transaction = connection.unchecked_transaction()
+        .map_err(|err| SddmsError::site("Failed to open update transaction").with_cause(err))?;
+
+    for stmt in stmts {
+        let execute_result = transaction.execute(stmt, []);
+        if let Err(error) = execute_result {
+            let err = SddmsError::site("Failed to execute update statement")
+                .with_cause(error);
+            return Err(err);
+        }
+    }
+
+    transaction.commit()
+        .map_err(|err| SddmsError::site("Failed to commit update transaction").with_cause(err))
+}
+
+enum ConnectionState {
+    /// not in a transaction, so everything runs on the shared database
+    Idle,
+    /// in a transaction, holding the writes it has made so far
+    Transaction(Vec<String>),
+}
+
 pub struct ClientConnection {
-    connection: tokio::sync::Mutex<Connection>,
+    shared: SharedConnection,
+    state: tokio::sync::Mutex<ConnectionState>,
     id: u32,
 }
 
 impl ClientConnection {
-    fn new(connection: Connection, id: u32) -> Self {
+    fn new(shared: SharedConnection, id: u32) -> Self {
         Self {
-            connection: tokio::sync::Mutex::new(connection),
+            shared,
+            state: tokio::sync::Mutex::new(ConnectionState::Idle),
             id,
         }
     }
 
     pub async fn invoke_read_query(&self, query_text: &str) -> Result<InvokeQueryResults, SddmsError> {
+        self.invoke_batched_read_query(query_text, usize::MAX, &mut |_| {}).await
+    }
 
-        let sliced_query_text = if query_text.ends_with(";") {
-            &query_text[0..query_text.len()-1]
-        } else {
-            query_text
+    /// Runs a read, handing off each batch of `batch_size` rows as soon as it's read. Gives back the rows
+    /// left after the last full batch
+    pub async fn invoke_batched_read_query(&self, query_text: &str, batch_size: usize, on_batch: BatchHandler<'_>) -> Result<InvokeQueryResults, SddmsError> {
+        // a transaction that has written reads on top of its writes, which needs the writer
+        let pending_writes = match &*self.state.lock().await {
+            ConnectionState::Transaction(pending_writes) if !pending_writes.is_empty() => Some(pending_writes.clone()),
+            _ => None,
         };
 
-        let mut results = InvokeQueryResults::default();
-        let connection = self.connection.lock().await;
-        let mut statement = connection.prepare(sliced_query_text)
-            .map_err(|err| SddmsError::general("Failed to prepare query").with_cause(err))?;
-
-        let col_names = statement.column_names().iter()
-            .map(|col_name| String::from(*col_name))
-            .collect::<Vec<_>>();
-
-        let serialized_rows = statement
-            .query_map([], |row| {
-                Ok(serialize_row(&row, &col_names))
-            })
-            .map_err(|err| SddmsError::site("Error while executing query").with_cause(err))
-            ?.filter_map(|result| result.ok())
-            .collect::<Vec<_>>();
+        match pending_writes {
+            Some(pending_writes) => {
+                let shared = self.shared.write().await;
+                let mut writer = shared.writer.lock().await;
+                with_pending_writes(&mut writer, &pending_writes, |connection| read_query(connection, query_text, batch_size, on_batch))
+            }
+            None => {
+                let shared = self.shared.read().await;
+                let reader = shared.reader().await;
+                read_query(&reader, query_text, batch_size, on_batch)
+            }
+        }
+    }
 
-        info!("Read {} rows", serialized_rows.len());
+    pub async fn invoke_modify_query(&self, query_text: &str) -> Result<InvokeQueryResults, SddmsTermError> {
+        // always lock shared before state so that replication can't deadlock with us
+        let shared = self.shared.write().await;
+        let mut writer = shared.writer.lock().await;
+        let mut state = self.state.lock().await;
+        match &mut *state {
+            ConnectionState::Idle => modify_query(&writer, query_text),
+            ConnectionState::Transaction(pending_writes) => {
+                // the write is only checked here. It's applied for real when the transaction commits
+                debug!("Client {} is writing in a transaction", self.id);
+                let results = with_pending_writes(&mut writer, pending_writes, |connection| modify_query(connection, query_text))?;
+                pending_writes.push(String::from(query_text));
+                Ok(results)
+            }
+        }
+    }
 
-        let payload_results = serde_json::to_vec(&serialized_rows)
-            .map_err(|err| SddmsError::general("Failed to serialize record payload").with_cause(err))?;
+    pub async fn begin_transaction(&self) -> Result<(), SddmsTermError> {
+        let mut state = self.state.lock().await;
+        if let ConnectionState::Transaction(_) = &*state {
+            return Err(SddmsError::site(format!("Client {} already has a transaction in progress", self.id)).into());
+        }
 
-        results.data_payload = Some(payload_results);
-        results.column_names = col_names.into_iter().map(|column| String::from(column)).collect();
-        Ok(results)
+        *state = ConnectionState::Transaction(Vec::new());
+        Ok(())
     }
 
-    pub async fn invoke_modify_query(&self, query_text: &str) -> Result<InvokeQueryResults, SddmsTermError> {
-        let mut results = InvokeQueryResults::default();
-        let connection = self.connection.lock().await;
-        connection.execute(query_text, ())
-            .map_err(|err| SddmsError::general("Failed to invoke SQL query").with_cause(err))?;
-
-        let affected_rows = connection.changes() as u32;
-        results.affected_records = Some(affected_rows);
-        info!("Updated {} rows", affected_rows);
-        Ok(results)
+    /// Throws away anything the current transaction wrote
+    pub async fn rollback_transaction(&self) {
+        let mut state = self.state.lock().await;
+        *state = ConnectionState::Idle;
     }
 
-    pub async fn invoke_one_off_stmt(&self, query_text: &str) -> Result<usize, SddmsTermError> {
-        let connection = self.connection.lock().await;
-        connection.execute(query_text, ())
-            .map_err(|err| SddmsError::general("Failed to execute one off SQL statement").with_cause(err))
-            .map_err(|sddms_err| SddmsTermError::from(sddms_err))
+    /// Ends the current transaction. Returns true if the transaction wrote anything, meaning its updates
+    /// still need to be applied to the shared database
+    async fn end_transaction(&self) -> bool {
+        let mut state = self.state.lock().await;
+        let previous = std::mem::replace(&mut *state, ConnectionState::Idle);
+        matches!(previous, ConnectionState::Transaction(pending_writes) if !pending_writes.is_empty())
     }
 }
 
+/// Connections for every client on the site. Clients share a single in-memory copy of the database,
+/// so their committed writes are visible to each other right away. A transaction's writes are kept
+/// aside and replayed in a savepoint whenever it queries, so no one else sees them until it commits.
 pub struct ClientConnectionMap {
+    /// the database shared by every client
+    shared: SharedConnection,
     /// map of connections
     connections: HashMap<u32, ClientConnection>,
     /// how many clients are registered
     client_counter: AtomicU32,
+    /// the most clients that can be connected at once, if there's a limit
+    max_clients: Option<usize>,
 }
 
 impl ClientConnectionMap {
-    pub fn new() -> Self {
-        Self {
+    pub fn open(db_path: &Path) -> Result<Self, SddmsError> {
+        // the path may be an SQLite URI, such as one for a shared in-memory database
+        let disk_connection = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI)
+            .map_err(|err| SddmsError::site("Could not open disk database").with_cause(err))?;
+
+        let shared = ConnectionPool::copy_of(&disk_connection)?;
+
+        Ok(Self {
+            shared: Arc::new(tokio::sync::RwLock::new(shared)),
             connections: Default::default(),
             client_counter: AtomicU32::new(0),
-        }
+            max_clients: None,
+        })
+    }
+
+    /// Loads the extensions into every connection to the shared database. This has to happen before any
+    /// clients connect
+    pub fn load_extensions(&mut self, extensions: SqliteExtensions) -> Result<(), SddmsError> {
+        let mut shared = self.shared.try_write()
+            .map_err(|err| SddmsError::site("Shared database is in use").with_cause(err))?;
+        shared.load_extensions(&extensions)
     }
 
-    pub fn open_connection(&mut self, db_path: &Path) -> Result<u32, SddmsError> {
-        // open connection to database
-        let db_conn = Self::open_proxy(db_path)?;
+    /// Limits how many clients can be connected at once. Every client's transaction keeps its writes until it
+    /// finishes, so this bounds how much memory they can take
+    pub fn with_max_clients(mut self, max_clients: Option<usize>) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    pub fn open_connection(&mut self) -> Result<u32, SddmsError> {
+        if let Some(max_clients) = self.max_clients {
+            if self.connections.len() >= max_clients {
+                return Err(SddmsError::site(format!("Site already has the maximum of {} clients connected", max_clients)));
+            }
+        }
 
         let next_id = self.next_client_id();
 
-        let connection = ClientConnection::new(db_conn, next_id);
+        let connection = ClientConnection::new(self.shared.clone(), next_id);
 
         self.connections.insert(next_id, connection);
         Ok(next_id)
     }
 
-    fn open_proxy(db_path: &Path) -> Result<Connection, SddmsError> {
-        let disk_connection = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
-            .map_err(|err| SddmsError::site("Could not open disk database").with_cause(err))?;
+    /// Drops a client's connection, throwing away any transaction it had in progress
+    pub fn close_connection(&mut self, client_id: u32) -> Option<ClientConnection> {
+        self.connections.remove(&client_id)
+    }
 
-        let mut memory_connection = Connection::open_in_memory()
-            .map_err(|err| SddmsError::site("Could not open memory database").with_cause(err))?;
+    /// Applies updates from another site to the shared database. Transactions in progress see them the next
+    /// time they query
+    pub async fn replicate_messages(&self, update_stmts: &[String]) -> Result<(), SddmsError> {
+        let shared = self.shared.write().await;
+        let writer = shared.writer.lock().await;
+        perform_update_transaction(update_stmts, &writer)
+    }
 
-        // do this in a smaller scope so that memory_connection borrow drops
-        {
-            let backup = Backup::new(&disk_connection, &mut memory_connection)
-                .map_err(|err| SddmsError::site("Failed to create backup").with_cause(err))?;
+    /// Ends a client's transaction and makes its updates visible to every other client
+    pub async fn commit_transaction(&self, client_id: u32, update_stmts: &[String]) -> Result<(), SddmsError> {
+        let shared = self.shared.write().await;
+        let writer = shared.writer.lock().await;
 
-            backup.run_to_completion(5, Duration::from_millis(500), None)
-                .map_err(|err| SddmsError::site("Error while backing up").with_cause(err))?;
+        let client_connection = self.get_client_connection(client_id)
+            .ok_or_else(|| SddmsError::site(format!("No connection for client {}", client_id)))?;
+
+        // outside of a transaction, the updates already went to the shared database directly
+        if client_connection.end_transaction().await {
+            perform_update_transaction(update_stmts, &writer)?;
         }
 
-        Ok(memory_connection)
+        Ok(())
     }
 
-    pub async fn replicate_messages(&self, update_stmts: &[String], skip_site: Option<u32>) -> Result<(), SddmsError> {
-        for (site_id, connection) in &self.connections {
-            // skip the site we don't want
-            if skip_site.is_some_and(|skipped_id| *site_id == skipped_id) {
-                continue;
-            }
+    pub fn get_client_connection(&self, client_id: u32) -> Option<&ClientConnection> {
+        self.connections.get(&client_id)
+    }
 
-            // invoke the sql text on the
-            Self::perform_update_transaction(update_stmts, connection).await?;
-        }
+    fn next_client_id(&self) -> u32 {
+        self.client_counter.fetch_add(1, Ordering::SeqCst)
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use rusqlite::Connection;
+    use serde_json::{Map, Value};
+    use crate::client_connection::ClientConnectionMap;
+    use crate::sqlite_extensions::SqliteExtensions;
+
+    fn make_test_db(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sddms-site-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch("CREATE TABLE students (name TEXT);").unwrap();
+        path
     }
 
-    async fn perform_update_transaction(stmts: &[String], connection: &ClientConnection) -> Result<(), SddmsError> {
+    async fn count_students(connection_map: &ClientConnectionMap, client_id: u32) -> usize {
+        let results = connection_map.get_client_connection(client_id).unwrap()
+            .invoke_read_query("SELECT * FROM students;").await
+            .unwrap();
+
+        let rows: Vec<Map<String, Value>> = serde_json::from_slice(&results.data_payload.unwrap()).unwrap();
+        rows.len()
+    }
 
-        let connection = connection.connection.lock().await;
+    #[test]
+    fn registration_past_limit_is_rejected() {
+        let db_path = make_test_db("limit");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap()
+            .with_max_clients(Some(2));
+        let first = connection_map.open_connection().unwrap();
+        connection_map.open_connection().unwrap();
 
-        for stmt in stmts {
-            let execute_result = connection.execute(stmt, []);
-            if let Err(error) = execute_result {
-                let err = SddmsError::site("Failed to execute update statement")
-                    .with_cause(error);
-                return Err(err);
-            }
+        let err = connection_map.open_connection().unwrap_err();
+        assert!(err.message().contains("maximum of 2 clients"));
+
+        // a client leaving makes room for another
+        connection_map.close_connection(first).unwrap();
+        connection_map.open_connection().unwrap();
+        assert!(connection_map.open_connection().is_err());
+
+        drop(connection_map);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn registered_functions_work_for_every_client() {
+        let db_path = make_test_db("functions");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        connection_map.load_extensions(SqliteExtensions::default()
+            .with_scalar_function("shout", 1, |context| Ok(rusqlite::types::Value::Text(context.get::<String>(0)?.to_uppercase()))))
+            .unwrap();
+        let writer = connection_map.open_connection().unwrap();
+        let reader = connection_map.open_connection().unwrap();
+
+        // the writer's transaction runs on a different connection than the reads, which needs the function too
+        let writer_connection = connection_map.get_client_connection(writer).unwrap();
+        writer_connection.begin_transaction().await.unwrap();
+        writer_connection.invoke_modify_query("INSERT INTO students VALUES (shout('alice'));").await.unwrap();
+        connection_map.commit_transaction(writer, &[String::from("INSERT INTO students VALUES (shout('alice'));")]).await.unwrap();
+
+        let results = connection_map.get_client_connection(reader).unwrap()
+            .invoke_read_query("SELECT name, shout('bob') AS other FROM students;").await
+            .unwrap();
+        let rows: Vec<Map<String, Value>> = serde_json::from_slice(&results.data_payload.unwrap()).unwrap();
+        assert_eq!(rows[0]["name"], "ALICE");
+        assert_eq!(rows[0]["other"], "BOB");
+
+        drop(connection_map);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_is_visible_to_other_clients() {
+        let db_path = make_test_db("visible");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let writer = connection_map.open_connection().unwrap();
+        let reader = connection_map.open_connection().unwrap();
+
+        connection_map.get_client_connection(writer).unwrap()
+            .invoke_modify_query("INSERT INTO students VALUES ('alice');").await
+            .unwrap();
+
+        assert_eq!(count_students(&connection_map, reader).await, 1);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn transaction_is_visible_after_commit() {
+        let db_path = make_test_db("commit");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let writer = connection_map.open_connection().unwrap();
+        let reader = connection_map.open_connection().unwrap();
+
+        let insert = String::from("INSERT INTO students VALUES ('alice');");
+        let writer_connection = connection_map.get_client_connection(writer).unwrap();
+        writer_connection.begin_transaction().await.unwrap();
+        writer_connection.invoke_modify_query(&insert).await.unwrap();
+
+        assert_eq!(count_students(&connection_map, writer).await, 1);
+        assert_eq!(count_students(&connection_map, reader).await, 0);
+
+        connection_map.commit_transaction(writer, &[insert]).await.unwrap();
+        assert_eq!(count_students(&connection_map, writer).await, 1);
+        assert_eq!(count_students(&connection_map, reader).await, 1);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rolled_back_transaction_is_discarded() {
+        let db_path = make_test_db("rollback");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let writer = connection_map.open_connection().unwrap();
+
+        let writer_connection = connection_map.get_client_connection(writer).unwrap();
+        writer_connection.begin_transaction().await.unwrap();
+        writer_connection.invoke_modify_query("INSERT INTO students VALUES ('alice');").await.unwrap();
+        writer_connection.rollback_transaction().await;
+
+        assert_eq!(count_students(&connection_map, writer).await, 0);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_connection_discards_transaction() {
+        let db_path = make_test_db("close");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let writer = connection_map.open_connection().unwrap();
+        let reader = connection_map.open_connection().unwrap();
+
+        let writer_connection = connection_map.get_client_connection(writer).unwrap();
+        writer_connection.begin_transaction().await.unwrap();
+        writer_connection.invoke_modify_query("INSERT INTO students VALUES ('alice');").await.unwrap();
+
+        assert!(connection_map.close_connection(writer).is_some());
+        assert!(connection_map.get_client_connection(writer).is_none());
+        assert_eq!(count_students(&connection_map, reader).await, 0);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_replication_applies_nothing() {
+        let db_path = make_test_db("atomic");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let reader = connection_map.open_connection().unwrap();
+
+        let stmts = vec![
+            String::from("INSERT INTO students VALUES ('alice');"),
+            String::from("INSERT INTO no_such_table VALUES ('bob');"),
+            String::from("INSERT INTO students VALUES ('carol');"),
+        ];
+
+        assert!(connection_map.replicate_messages(&stmts).await.is_err());
+        assert_eq!(count_students(&connection_map, reader).await, 0);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rows_are_handed_off_as_batches_fill() {
+        let db_path = make_test_db("batches");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let writer = connection_map.open_connection().unwrap();
+        let writer_connection = connection_map.get_client_connection(writer).unwrap();
+        for name in ["alice", "bob", "carol", "dave", "erin"] {
+            writer_connection.invoke_modify_query(&format!("INSERT INTO students VALUES ('{}');", name)).await.unwrap();
         }
-        Ok(())
+
+        let mut full_batches = Vec::new();
+        let rest = writer_connection.invoke_batched_read_query("SELECT * FROM students;", 2, &mut |batch| full_batches.push(batch)).await
+            .unwrap();
+
+        let batch_sizes = full_batches.into_iter().chain([rest])
+            .map(|batch| {
+                assert_eq!(batch.column_names, vec![String::from("name")]);
+                let rows: Vec<Map<String, Value>> = serde_json::from_slice(&batch.data_payload.unwrap()).unwrap();
+                rows.len()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(batch_sizes, vec![2, 2, 1]);
+        std::fs::remove_file(db_path).unwrap();
     }
 
-    pub fn get_client_connection(&self, client_id: u32) -> Option<&ClientConnection> {
-        self.connections.get(&client_id)
+    #[tokio::test]
+    async fn transaction_reads_its_writes_on_top_of_other_commits() {
+        let db_path = make_test_db("layered");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let writer = connection_map.open_connection().unwrap();
+        let other = connection_map.open_connection().unwrap();
+
+        let writer_connection = connection_map.get_client_connection(writer).unwrap();
+        writer_connection.begin_transaction().await.unwrap();
+        writer_connection.invoke_modify_query("INSERT INTO students VALUES ('alice');").await.unwrap();
+
+        connection_map.get_client_connection(other).unwrap()
+            .invoke_modify_query("INSERT INTO students VALUES ('bob');").await
+            .unwrap();
+
+        assert_eq!(count_students(&connection_map, writer).await, 2);
+        assert_eq!(count_students(&connection_map, other).await, 1);
+        std::fs::remove_file(db_path).unwrap();
     }
 
-    fn next_client_id(&self) -> u32 {
-        self.client_counter.fetch_add(1, Ordering::SeqCst)
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reads_run_side_by_side() {
+        let db_path = make_test_db("side-by-side");
+        let mut connection_map = ClientConnectionMap::open(&db_path).unwrap();
+        let first = connection_map.open_connection().unwrap();
+        let second = connection_map.open_connection().unwrap();
+        connection_map.get_client_connection(first).unwrap()
+            .invoke_modify_query("INSERT INTO students VALUES ('alice');").await
+            .unwrap();
+
+        // each read waits partway through for the other one to start, which only works if they overlap
+        let connection_map = std::sync::Arc::new(connection_map);
+        let (first_sender, first_receiver) = std::sync::mpsc::channel();
+        let (second_sender, second_receiver) = std::sync::mpsc::channel();
+        let reads = [(first, first_sender, second_receiver), (second, second_sender, first_receiver)]
+            .map(|(client_id, started, other_started)| {
+                let connection_map = connection_map.clone();
+                tokio::spawn(async move {
+                    let mut overlapped = false;
+                    let overlapped_ref = &mut overlapped;
+                    connection_map.get_client_connection(client_id).unwrap()
+                        .invoke_batched_read_query("SELECT * FROM students;", 1, &mut move |_| {
+                            started.send(()).unwrap();
+                            *overlapped_ref = other_started.recv_timeout(std::time::Duration::from_secs(5)).is_ok();
+                        })
+                        .await
+                        .unwrap();
+                    overlapped
+                })
+            });
+
+        for read in reads {
+            assert!(read.await.unwrap());
+        }
+        std::fs::remove_file(db_path).unwrap();
     }
 }
@@ -1,93 +1,272 @@
 use std::collections::HashSet;
-use std::fs::{File};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::path::Path;
-use time::format_description::well_known::Iso8601;
-use time::OffsetDateTime;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use sddms_shared::error::SddmsError;
+use sddms_shared::history_record::{action_text_line, HistoryEvent, HistoryRecord, instant_now, query_text_action, replication_text_line};
 use sddms_shared::sql_metadata::parse_statements;
 
+/// Gives the structured event for a transaction command
+fn command_event(client_id: u32, site_id: u32, trans_id: u32, cmd: &str) -> Result<HistoryEvent, SddmsError> {
+    let (site, client, txn) = (site_id, client_id, trans_id);
+    match cmd {
+        "Begin Txn" => Ok(HistoryEvent::Begin { site, client, txn }),
+        "COMMIT" => Ok(HistoryEvent::Commit { site, client, txn }),
+        "ROLLBACK" => Ok(HistoryEvent::Rollback { site, client, txn }),
+        other => Err(SddmsError::general(format!("History command '{}' has no history record", other))),
+    }
+}
+
+/// Gets the tables written by a replicated transaction's statements
+fn replication_write_tables(cmds: &[String]) -> Result<Vec<String>, SddmsError> {
+    let mut write_tables = Vec::new();
+    for cmd in cmds {
+        let Ok(stmt_metadatas) = parse_statements(cmd) else {
+            return Err(SddmsError::site("Failed to parse replication statement"));
+        };
+        let unique_write_tables = stmt_metadatas.into_iter()
+            .flat_map(|metadata| metadata.take_write_tables())
+            .collect::<HashSet<_>>();
+
+        write_tables.extend(unique_write_tables);
+    }
+
+    Ok(write_tables)
+}
+
+/// The current time in the ISO-8601 form that the history verifier parses
+fn timestamp_now() -> Result<String, SddmsError> {
+    instant_now()
+        .map_err(|err| SddmsError::general("Failed to format history timestamp").with_cause(err))
+}
+
+/// Formats the tables a query read and wrote as the action of a text history line
+fn query_action(write_set: &[String], read_set: &[String]) -> Result<String, SddmsError> {
+    query_text_action(write_set, read_set)
+        .map_err(|err| SddmsError::general("Failed to serialize table set").with_cause(err))
+}
+
+fn record_now(event: HistoryEvent) -> Result<HistoryRecord, SddmsError> {
+    Ok(HistoryRecord { instant: timestamp_now()?, event })
+}
+
 pub trait HistoryLogger: Send {
     fn log(&mut self, client_id: u32, site_id: u32, trans_id: u32, cmd: &str) -> Result<(), SddmsError>;
-    fn log_replication(&mut self, originating_site: u32, cmds: &[String]) -> Result<(), SddmsError>;
+    fn log_replication(&mut self, site_id: u32, originating_site: u32, cmds: &[String]) -> Result<(), SddmsError>;
 
     fn log_query(&mut self, client_id: u32, site_id: u32, trans_id: u32, write_set: &[String], read_set: &[String]) -> Result<(), SddmsError> {
-        let read_set_string = if !read_set.is_empty() {
-            format!("Read({:?})", read_set)
-        } else {
-            String::default()
-        };
+        self.log(client_id, site_id, trans_id, &query_action(write_set, read_set)?)
+    }
+}
 
-        let write_set_string = if !write_set.is_empty() {
-            format!("Write({:?})", write_set)
-        } else {
-            String::default()
-        };
+/// How history lines are written
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum HistoryFormat {
+    /// `<instant> | site=.., client=.., txn=..: <action>` lines
+    #[default]
+    Text,
+    /// a JSON `HistoryRecord` per line
+    JsonLines,
+}
 
-        let joiner = if !(write_set.is_empty() || read_set.is_empty()) {
-            ","
-        } else {
-            ""
-        };
+impl FromStr for HistoryFormat {
+    type Err = String;
 
-        let total = format!("{}{}{}", read_set_string, joiner, write_set_string);
-        self.log(client_id, site_id, trans_id, &total)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(HistoryFormat::Text),
+            "jsonl" => Ok(HistoryFormat::JsonLines),
+            other => Err(format!("unknown history format '{}', expected 'text' or 'jsonl'", other)),
+        }
+    }
+}
+
+impl Display for HistoryFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryFormat::Text => f.write_str("text"),
+            HistoryFormat::JsonLines => f.write_str("jsonl"),
+        }
     }
 }
 
 pub struct FileHistoryLogger
 {
-    output: BufWriter<File>
+    path: PathBuf,
+    output: BufWriter<File>,
+    /// how many bytes are in the current history file
+    written: u64,
+    /// roll the history file over before it grows past this many bytes
+    max_size: Option<u64>,
+    format: HistoryFormat,
 }
 
 impl FileHistoryLogger {
-    pub fn open(path: &Path) -> Result<Self, SddmsError> {
-        let output = File::options()
+    /// Opens the history file, adding to whatever history it already has unless `truncate` is set
+    pub fn open(path: &Path, truncate: bool) -> Result<Self, SddmsError> {
+        let output = Self::open_file(path, truncate)?;
+        let written = output.metadata()
+            .map_err(|err| SddmsError::general("Failed to read history file size").with_cause(err))?
+            .len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            output: BufWriter::new(output),
+            written,
+            max_size: None,
+            format: HistoryFormat::Text,
+        })
+    }
+
+    pub fn with_format(mut self, format: HistoryFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Rolls the history file over before it grows past `max_size` bytes. The full file is moved to
+    /// `<path>.1`, pushing any older files along to `<path>.2`, `<path>.3` and so on
+    pub fn with_rotation(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    fn open_file(path: &Path, truncate: bool) -> Result<File, SddmsError> {
+        File::options()
             .create(true)
-            .append(false)
+            .append(!truncate)
             .write(true)
+            .truncate(truncate)
             .open(path)
-            .map_err(|err| SddmsError::general("Failed to open history file").with_cause(err))?;
+            .map_err(|err| SddmsError::general("Failed to open history file").with_cause(err))
+    }
 
-        Ok(Self {
-            output: BufWriter::new(output)
-        })
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", index));
+        PathBuf::from(rotated)
     }
-}
 
-impl HistoryLogger for FileHistoryLogger {
-    fn log(&mut self, client_id: u32, site_id: u32, trans_id: u32, cmd: &str) -> Result<(), SddmsError> {
-        let now = OffsetDateTime::now_utc();
+    fn rotate(&mut self) -> Result<(), SddmsError> {
+        self.output.flush()
+            .map_err(|err| SddmsError::general("Failed to flush history").with_cause(err))?;
 
-        let formatted = now.format(&Iso8601::DATE_TIME_OFFSET).unwrap();
+        let mut last_index = 1;
+        while self.rotated_path(last_index).exists() {
+            last_index += 1;
+        }
+
+        for index in (1..last_index).rev() {
+            fs::rename(self.rotated_path(index), self.rotated_path(index + 1))
+                .map_err(|err| SddmsError::general("Failed to rotate history file").with_cause(err))?;
+        }
+
+        fs::rename(&self.path, self.rotated_path(1))
+            .map_err(|err| SddmsError::general("Failed to rotate history file").with_cause(err))?;
+
+        self.output = BufWriter::new(Self::open_file(&self.path, true)?);
+        self.written = 0;
+        Ok(())
+    }
 
-        self.output.write_fmt(format_args!("{} | site={}, client={}, txn={}: {}\n", formatted, site_id, client_id, trans_id, cmd))
+    fn write_line(&mut self, line: &str) -> Result<(), SddmsError> {
+        let line_size = line.len() as u64 + 1;
+        if let Some(max_size) = self.max_size {
+            if self.written > 0 && self.written + line_size > max_size {
+                self.rotate()?;
+            }
+        }
+
+        writeln!(self.output, "{}", line)
             .map_err(|err| SddmsError::general("Failed to log history").with_cause(err))?;
+        self.written += line_size;
         self.output.flush()
             .map_err(|err| SddmsError::general("Failed to flush history").with_cause(err))
     }
 
-    fn log_replication(&mut self, originating_site: u32, cmds: &[String]) -> Result<(), SddmsError> {
-        let now = OffsetDateTime::now_utc();
+    fn write_record(&mut self, event: HistoryEvent) -> Result<(), SddmsError> {
+        let line = record_now(event)?.to_json_line()
+            .map_err(|err| SddmsError::general("Failed to serialize history record").with_cause(err))?;
+        self.write_line(&line)
+    }
+}
 
-        let mut write_tables = Vec::new();
-        for cmd in cmds {
-            let Ok(stmt_metadatas) = parse_statements(cmd) else {
-                return Err(SddmsError::site("Failed to parse replication statement"));
-            };
-            let unique_write_tables = stmt_metadatas.into_iter()
-                .flat_map(|metadata| metadata.take_write_tables())
-                .collect::<HashSet<_>>();
+impl HistoryLogger for FileHistoryLogger {
+    fn log(&mut self, client_id: u32, site_id: u32, trans_id: u32, cmd: &str) -> Result<(), SddmsError> {
+        if self.format == HistoryFormat::JsonLines {
+            return self.write_record(command_event(client_id, site_id, trans_id, cmd)?);
+        }
 
-            write_tables.extend(unique_write_tables.into_iter());
+        self.write_line(&action_text_line(&timestamp_now()?, site_id, client_id, trans_id, cmd))
+    }
+
+    fn log_replication(&mut self, site_id: u32, originating_site: u32, cmds: &[String]) -> Result<(), SddmsError> {
+        let write_tables = replication_write_tables(cmds)?;
+
+        if self.format == HistoryFormat::JsonLines {
+            return self.write_record(HistoryEvent::Replication { site: site_id, orig_site: originating_site, write_set: write_tables });
         }
 
-        let write_info = format!("Write({:?})", write_tables);
+        let line = replication_text_line(&timestamp_now()?, site_id, originating_site, &write_tables)
+            .map_err(|err| SddmsError::general("Failed to serialize table set").with_cause(err))?;
+        self.write_line(&line)
+    }
 
-        self.output.write_fmt(format_args!("{} | replication: orig_site={}: {}\n", now, originating_site, write_info))
-            .map_err(|err| SddmsError::general("Failed to log history").with_cause(err))?;
-        self.output.flush()
-            .map_err(|err| SddmsError::general("Failed to flush history").with_cause(err))
+    fn log_query(&mut self, client_id: u32, site_id: u32, trans_id: u32, write_set: &[String], read_set: &[String]) -> Result<(), SddmsError> {
+        match self.format {
+            HistoryFormat::Text => self.log(client_id, site_id, trans_id, &query_action(write_set, read_set)?),
+            HistoryFormat::JsonLines => self.write_record(HistoryEvent::Query {
+                site: site_id,
+                client: client_id,
+                txn: trans_id,
+                read_set: read_set.to_vec(),
+                write_set: write_set.to_vec(),
+            }),
+        }
+    }
+}
+
+/// Keeps history records in memory so tests can check them without going through a file. Other crates'
+/// tests can use it through the `memory-history` feature
+#[cfg(any(test, feature = "memory-history"))]
+pub struct MemoryHistoryLogger {
+    history: sddms_shared::history_record::MemoryHistory,
+}
+
+#[cfg(any(test, feature = "memory-history"))]
+impl MemoryHistoryLogger {
+    /// Logs into the given history. Keep a clone of it to read the records back
+    pub fn new(history: sddms_shared::history_record::MemoryHistory) -> Self {
+        Self {
+            history,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "memory-history"))]
+impl HistoryLogger for MemoryHistoryLogger {
+    fn log(&mut self, client_id: u32, site_id: u32, trans_id: u32, cmd: &str) -> Result<(), SddmsError> {
+        self.history.push(record_now(command_event(client_id, site_id, trans_id, cmd)?)?);
+        Ok(())
+    }
+
+    fn log_replication(&mut self, site_id: u32, originating_site: u32, cmds: &[String]) -> Result<(), SddmsError> {
+        let write_set = replication_write_tables(cmds)?;
+        self.history.push(record_now(HistoryEvent::Replication { site: site_id, orig_site: originating_site, write_set })?);
+        Ok(())
+    }
+
+    fn log_query(&mut self, client_id: u32, site_id: u32, trans_id: u32, write_set: &[String], read_set: &[String]) -> Result<(), SddmsError> {
+        self.history.push(record_now(HistoryEvent::Query {
+            site: site_id,
+            client: client_id,
+            txn: trans_id,
+            read_set: read_set.to_vec(),
+            write_set: write_set.to_vec(),
+        })?);
+        Ok(())
     }
 }
 
@@ -98,7 +277,144 @@ impl HistoryLogger for NopHistoryLogger {
         Ok(())
     }
 
-    fn log_replication(&mut self, _originating_site: u32, _cmds: &[String]) -> Result<(), SddmsError> {
+    fn log_replication(&mut self, _site_id: u32, _originating_site: u32, _cmds: &[String]) -> Result<(), SddmsError> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sddms_shared::history_record::{HistoryEvent, HistoryRecord};
+    use sddms_shared::history_record::MemoryHistory;
+    use crate::history_logger::{FileHistoryLogger, HistoryFormat, HistoryLogger, MemoryHistoryLogger};
+
+    #[test]
+    fn log_query_writes_json_table_sets() {
+        let path = std::env::temp_dir().join(format!("sddms-site-history-{}.history", std::process::id()));
+        let mut logger = FileHistoryLogger::open(&path, true).unwrap();
+        let read_set = vec![String::from("odd \"table\" (1)")];
+        let write_set = vec![String::from("back\\slash")];
+        logger.log_query(1, 2, 3, &write_set, &read_set).unwrap();
+        drop(logger);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.trim_end().ends_with(r#"site=2, client=1, txn=3: Read(["odd \"table\" (1)"]),Write(["back\\slash"])"#));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn log_query_writes_empty_table_sets() {
+        let path = std::env::temp_dir().join(format!("sddms-site-history-empty-{}.history", std::process::id()));
+        let mut logger = FileHistoryLogger::open(&path, true).unwrap();
+        logger.log_query(1, 2, 3, &[], &[]).unwrap();
+        drop(logger);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.trim_end().ends_with("site=2, client=1, txn=3: Read([]),Write([])"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn log_replication_records_destination_site() {
+        let path = std::env::temp_dir().join(format!("sddms-site-history-replication-{}.history", std::process::id()));
+        let mut logger = FileHistoryLogger::open(&path, true).unwrap();
+        logger.log_replication(4, 2, &[String::from("INSERT INTO flights VALUES (1);")]).unwrap();
+        drop(logger);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.trim_end().ends_with(r#"replication: site=4, orig_site=2: Write(["flights"])"#));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reopening_preserves_existing_history() {
+        let path = std::env::temp_dir().join(format!("sddms-site-history-reopen-{}.history", std::process::id()));
+        let mut logger = FileHistoryLogger::open(&path, true).unwrap();
+        logger.log(1, 2, 3, "BEGIN").unwrap();
+        drop(logger);
+
+        let mut logger = FileHistoryLogger::open(&path, false).unwrap();
+        logger.log(1, 2, 3, "COMMIT").unwrap();
+        drop(logger);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("txn=3: BEGIN"));
+        assert!(lines[1].ends_with("txn=3: COMMIT"));
+
+        // truncating starts the history over
+        let mut logger = FileHistoryLogger::open(&path, true).unwrap();
+        logger.log(1, 2, 4, "BEGIN").unwrap();
+        drop(logger);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn crossing_size_threshold_rolls_the_file() {
+        let dir = std::env::temp_dir().join(format!("sddms-site-history-rotation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history");
+
+        // each line is well over half the limit, so every line after the first rolls the file
+        let mut logger = FileHistoryLogger::open(&path, true).unwrap().with_rotation(80);
+        for trans_id in 1..=3 {
+            logger.log(1, 2, trans_id, "BEGIN").unwrap();
+        }
+        drop(logger);
+
+        let newest = std::fs::read_to_string(&path).unwrap();
+        let rotated = std::fs::read_to_string(dir.join("history.1")).unwrap();
+        let oldest = std::fs::read_to_string(dir.join("history.2")).unwrap();
+        assert!(newest.trim_end().ends_with("txn=3: BEGIN"), "{}", newest);
+        assert!(rotated.trim_end().ends_with("txn=2: BEGIN"), "{}", rotated);
+        assert!(oldest.trim_end().ends_with("txn=1: BEGIN"), "{}", oldest);
+        assert!(!dir.join("history.3").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn json_lines_format_writes_history_records() {
+        let path = std::env::temp_dir().join(format!("sddms-site-history-jsonl-{}.history", std::process::id()));
+        let mut logger = FileHistoryLogger::open(&path, true).unwrap().with_format(HistoryFormat::JsonLines);
+        logger.log(1, 2, 3, "Begin Txn").unwrap();
+        logger.log_query(1, 2, 3, &[String::from("flights")], &[]).unwrap();
+        logger.log(1, 2, 3, "COMMIT").unwrap();
+        logger.log_replication(4, 2, &[String::from("INSERT INTO flights VALUES (1);")]).unwrap();
+        assert!(logger.log(1, 2, 3, "SAVEPOINT").is_err());
+        drop(logger);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let events = contents.lines()
+            .map(|line| HistoryRecord::from_json_line(line).unwrap().event)
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![
+            HistoryEvent::Begin { site: 2, client: 1, txn: 3 },
+            HistoryEvent::Query { site: 2, client: 1, txn: 3, read_set: vec![], write_set: vec![String::from("flights")] },
+            HistoryEvent::Commit { site: 2, client: 1, txn: 3 },
+            HistoryEvent::Replication { site: 4, orig_site: 2, write_set: vec![String::from("flights")] },
+        ]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn memory_logger_shares_records_without_a_file() {
+        let history = MemoryHistory::default();
+        let mut logger: Box<dyn HistoryLogger> = Box::new(MemoryHistoryLogger::new(history.clone()));
+        logger.log(1, 2, 3, "Begin Txn").unwrap();
+        logger.log_query(1, 2, 3, &[String::from("flights")], &[String::from("airports")]).unwrap();
+        logger.log(1, 2, 3, "ROLLBACK").unwrap();
+
+        let events = history.records().into_iter()
+            .map(|record| record.event)
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![
+            HistoryEvent::Begin { site: 2, client: 1, txn: 3 },
+            HistoryEvent::Query { site: 2, client: 1, txn: 3, read_set: vec![String::from("airports")], write_set: vec![String::from("flights")] },
+            HistoryEvent::Rollback { site: 2, client: 1, txn: 3 },
+        ]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt::{Display, Formatter};
+use clap::ValueEnum;
+use rusqlite::Connection;
+use sddms_shared::error::SddmsError;
+
+/// SQLite journal modes that can be used for the site's disk database. In-memory connections always use
+/// the memory journal, so this only applies to the disk connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn pragma_value(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off",
+        }
+    }
+
+    /// Switches the given disk connection to this journal mode. WAL mode also relaxes syncing to NORMAL,
+    /// which is still safe against corruption in WAL mode but avoids syncing on every commit
+    pub fn configure(&self, connection: &Connection) -> Result<(), SddmsError> {
+        let pragma = format!("PRAGMA journal_mode={};", self.pragma_value());
+        let actual_mode: String = connection.query_row(&pragma, [], |row| row.get(0))
+            .map_err(|err| SddmsError::site(format!("Failed to set journal mode to {}", self)).with_cause(err))?;
+
+        // sqlite reports the mode it ended up in instead of failing when it can't switch
+        if !actual_mode.eq_ignore_ascii_case(self.pragma_value()) {
+            return Err(SddmsError::site(format!("Database could not use journal mode {}, it is using {}", self, actual_mode)));
+        }
+
+        if let JournalMode::Wal = self {
+            connection.pragma_update(None, "synchronous", "NORMAL")
+                .map_err(|err| SddmsError::site("Failed to set synchronous mode").with_cause(err))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for JournalMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pragma_value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+    use rusqlite::Connection;
+    use crate::journal_mode::JournalMode;
+
+    #[test]
+    fn wal_mode_is_applied_to_disk_database() {
+        let path = std::env::temp_dir().join(format!("sddms-site-wal-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let connection = Connection::open(&path).unwrap();
+
+        JournalMode::Wal.configure(&connection).unwrap();
+
+        let journal_mode: String = connection.query_row("PRAGMA journal_mode;", [], |row| row.get(0)).unwrap();
+        let synchronous: u32 = connection.query_row("PRAGMA synchronous;", [], |row| row.get(0)).unwrap();
+        assert_eq!(journal_mode, "wal");
+        // NORMAL
+        assert_eq!(synchronous, 1);
+
+        drop(connection);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn wal_mode_is_rejected_for_memory_database() {
+        let connection = Connection::open_in_memory().unwrap();
+        assert!(JournalMode::Wal.configure(&connection).is_err());
+    }
+
+    #[test]
+    fn unknown_journal_mode_is_rejected() {
+        assert_eq!(JournalMode::from_str("WAL", true), Ok(JournalMode::Wal));
+        assert!(JournalMode::from_str("bogus", true).is_err());
+    }
+}
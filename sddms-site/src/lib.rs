@@ -0,0 +1,71 @@
+pub mod site_server;
+pub mod sqlite_row_serializer;
+pub mod central_client;
+pub mod client_connection;
+pub mod transaction_history;
+pub mod history_logger;
+pub mod journal_mode;
+pub mod sqlite_extensions;
+pub mod replication_watermark;
+pub mod replication_conflicts;
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use rusqlite::Connection;
+use sddms_shared::error::SddmsError;
+
+/// Sets up the site's database by running the SQL in the init file on it
+pub fn configure_database(db_path: &Path, init_path: &Path) -> Result<Connection, SddmsError> {
+    let file = File::open(init_path)
+        .map_err(|err| SddmsError::general("Failed to open SQL init file").with_cause(err))?;
+    let mut contents: String = String::new();
+    BufReader::new(file)
+        .read_to_string(&mut contents)
+        .map_err(|err| SddmsError::general("Failed to read SQL contents").with_cause(err))?;
+
+    initialize_database(db_path, &contents)
+}
+
+/// Sets up the site's database by running the given SQL on it. The path can also be an SQLite URI, such as
+/// `file:site?mode=memory&cache=shared` for a database that only lives in memory. An in-memory database
+/// only lasts as long as some connection to it is open, so keep the returned one around while the site runs
+pub fn initialize_database(db_path: &Path, init_sql: &str) -> Result<Connection, SddmsError> {
+    let db = rusqlite::Connection::open(db_path)
+        .map_err(|err| SddmsError::site("Failed to connect to db").with_cause(err))?;
+
+    // init files usually have many statements, which execute would reject
+    db.execute_batch(init_sql)
+        .map_err(|err| SddmsError::client("SQL error while initializing DB").with_cause(err))?;
+
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configure_database;
+
+    #[test]
+    fn configure_database_runs_every_statement() {
+        let dir = std::env::temp_dir();
+        let db_path = dir.join(format!("sddms-site-init-{}.db", std::process::id()));
+        let init_path = dir.join(format!("sddms-site-init-{}.sql", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        std::fs::write(&init_path, "CREATE TABLE students (name TEXT);\n\
+            CREATE TABLE courses (title TEXT);\n\
+            INSERT INTO courses VALUES ('databases');\n").unwrap();
+
+        let db = configure_database(&db_path, &init_path).unwrap();
+        let table_names = db.prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name;").unwrap()
+            .query_map([], |row| row.get::<_, String>(0)).unwrap()
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        let course_count: u32 = db.query_row("SELECT COUNT(*) FROM courses;", [], |row| row.get(0)).unwrap();
+
+        assert_eq!(table_names, vec![String::from("courses"), String::from("students")]);
+        assert_eq!(course_count, 1);
+
+        drop(db);
+        std::fs::remove_file(db_path).unwrap();
+        std::fs::remove_file(init_path).unwrap();
+    }
+}
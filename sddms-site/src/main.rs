@@ -1,44 +1,21 @@
 mod args;
-mod site_server;
-mod sqlite_row_serializer;
-mod central_client;
-mod client_connection;
-mod transaction_history;
-mod history_logger;
 
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, Read};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use clap::Parser;
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 use rusqlite::Connection;
-use tonic::transport::Server;
 use sddms_services::site_controller::site_manager_service_server::SiteManagerServiceServer;
+use sddms_services::transport;
 use sddms_shared::error::SddmsError;
+use sddms_site::configure_database;
+use sddms_site::central_client::CentralClient;
+use sddms_site::history_logger::{FileHistoryLogger, HistoryLogger, NopHistoryLogger};
+use sddms_site::site_server::SddmsSiteManagerService;
+use sddms_site::sqlite_extensions::SqliteExtensions;
 use crate::args::Args;
-use crate::central_client::CentralClient;
-use crate::history_logger::{FileHistoryLogger, HistoryLogger, NopHistoryLogger};
-use crate::site_server::SddmsSiteManagerService;
-
-fn configure_database(db_path: &Path, init_path: &Path) -> Result<Connection, SddmsError> {
-
-    let db = rusqlite::Connection::open(db_path)
-        .map_err(|err| SddmsError::site("Failed to connect to db").with_cause(err))?;
-
-    let file = File::open(init_path)
-        .map_err(|err| SddmsError::general("Failed to open SQL init file").with_cause(err))?;
-    let mut contents: String = String::new();
-    BufReader::new(file)
-        .read_to_string(&mut contents)
-        .map_err(|err| SddmsError::general("Failed to read SQL contents").with_cause(err))?;
-
-    db.execute(&contents, ())
-        .map_err(|err| SddmsError::client("SQL error while initializing DB").with_cause(err))?;
-
-    Ok(db)
-}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -52,14 +29,27 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     info!("Starting up site...");
     {
-        if args.init_file.is_some() {
-            configure_database(&args.db_path, args.init_file.unwrap().as_path())?;
+        if let Some(init_file) = &args.init_file {
+            configure_database(&args.db_path, init_file)?;
             info!("Database configured")
         }
     }
 
+    if let Some(journal_mode) = args.journal_mode {
+        // set this up before any clients connect, since the read-only seed connection can't change it
+        let disk_connection = Connection::open(&args.db_path)
+            .map_err(|err| SddmsError::site("Failed to open disk database").with_cause(err))?;
+        journal_mode.configure(&disk_connection)?;
+        info!("Using journal mode {}", journal_mode);
+    }
+
     let history_logger: Box<dyn HistoryLogger> = if let Some(history_path) = &args.history_file {
-        FileHistoryLogger::open(history_path)
+        FileHistoryLogger::open(history_path, args.truncate_history)
+            .map(|file_logger| file_logger.with_format(args.history_format))
+            .map(|file_logger| match args.history_rotate_mb {
+                Some(max_mb) => file_logger.with_rotation(max_mb * 1024 * 1024),
+                None => file_logger,
+            })
             .map(|file_logger| {
                 let file: Box<dyn HistoryLogger> = Box::new(file_logger);
                 file
@@ -70,25 +60,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }?;
 
     // establish connection with central server
-    let client = CentralClient::new(&args.cc_addr).await?;
+    let client = CentralClient::new(&args.cc_addr, args.central_tls_options().as_ref()).await?;
     let site_id = client.register_self("0.0.0.0", args.port).await?;
 
     info!("Site registered with concurrency controller");
 
     // setup server
-    let service = SddmsSiteManagerService::new(&args.db_path, client, site_id, history_logger);
-    let server = SiteManagerServiceServer::new(service);
+    let service = Arc::new(SddmsSiteManagerService::new(&args.db_path, args.journal_mode, args.max_clients, client, site_id, history_logger)?
+        .with_read_only(args.read_only)
+        .with_catchup_timeout(Duration::from_millis(args.catchup_timeout_ms))
+        .with_replication_conflict_policy(args.replication_conflicts)
+        .with_extensions(SqliteExtensions::new(args.extensions.clone()))?);
+    let server = SiteManagerServiceServer::from_arc(service.clone());
 
     info!("Site configured");
 
     // start up the local site controller service
     let serve_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0)), args.port);
-    Server::builder()
+    let server_tls = args.server_tls_options();
+    if server_tls.is_some() {
+        info!("Serving over TLS");
+    }
+    transport::server_builder(server_tls.as_ref())?
         .add_service(server)
-        .serve(serve_addr)
+        .serve_with_shutdown(serve_addr, async {
+            tokio::signal::ctrl_c().await.ok();
+            info!("Shutting down site...");
+            // give transactions in progress a chance to finish before the server stops
+            service.drain(Duration::from_secs(args.shutdown_timeout)).await;
+        })
         .await
         .map_err(|err| SddmsError::site("Error while starting server").with_cause(err))?;
 
+    if let Err(err) = service.unregister_from_central().await {
+        error!("Failed to unregister from central controller: {}", err);
+    }
+
     info!("Done");
     Ok(())
 }
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::ValueEnum;
+use log::warn;
+use sddms_shared::error::SddmsError;
+use sddms_shared::sql_metadata::{ColumnWrite, key_covers, keys_overlap, parse_row_writes, RowKey, RowWrite, without_assignments};
+
+/// How a site settles replicated writes to the same rows that arrive out of order
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ReplicationConflictPolicy {
+    /// apply every replication in the order it arrives
+    #[default]
+    ArrivalOrder,
+    /// skip a replicated write to columns that a later transaction already set to a constant
+    LastWriterWins,
+}
+
+impl Display for ReplicationConflictPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicationConflictPolicy::ArrivalOrder => f.write_str("arrival-order"),
+            ReplicationConflictPolicy::LastWriterWins => f.write_str("last-writer-wins"),
+        }
+    }
+}
+
+/// Orders committed transactions for last-writer-wins. Commit times come from the clock of the site each
+/// transaction ran at, and ties are broken by the site and transaction ids, so every site puts the same
+/// transactions in the same order
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TransactionVersion {
+    pub commit_timestamp_us: u64,
+    pub site_id: u32,
+    pub transaction_id: u32,
+}
+
+impl TransactionVersion {
+    pub fn new(commit_timestamp_us: u64, site_id: u32, transaction_id: u32) -> Self {
+        Self {
+            commit_timestamp_us,
+            site_id,
+            transaction_id,
+        }
+    }
+}
+
+impl Display for TransactionVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{} (committed at {}us)", self.site_id, self.transaction_id, self.commit_timestamp_us)
+    }
+}
+
+/// The current time in microseconds since the epoch, for stamping a commit
+pub fn commit_timestamp_now() -> Result<u64, SddmsError> {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_micros() as u64)
+        .map_err(|err| SddmsError::site("System clock is before the epoch").with_cause(err))
+}
+
+/// The column of some rows of a table, or the whole rows if there's no column
+type Cell = (RowKey, Option<String>);
+
+/// The transactions that last wrote a cell
+#[derive(Debug, Copy, Clone)]
+struct CellVersions {
+    /// the latest transaction to write it at all
+    latest: TransactionVersion,
+    /// the latest transaction to set it to a constant, which makes whatever was written before it irrelevant
+    latest_overwrite: Option<TransactionVersion>,
+}
+
+/// How a replicated write to a cell relates to what later transactions already wrote
+enum CellOutcome {
+    /// no later transaction wrote it
+    Clear,
+    /// a later transaction set it to a constant, so the write would have been overwritten anyway
+    Superseded,
+    /// a later transaction wrote it in a way that depends on what was there before, so the write can't be
+    /// put back in its place
+    Conflict(TransactionVersion),
+}
+
+fn columns_overlap(left: &Option<String>, right: &Option<String>) -> bool {
+    left.is_none() || right.is_none() || left == right
+}
+
+fn describe_column(column: &Option<String>) -> &str {
+    column.as_deref().unwrap_or("whole rows")
+}
+
+/// Remembers the latest transactions to write each cell, so that writes from older transactions that arrive
+/// late can be skipped. Rows are told apart by the literal values statements give their columns, so
+/// statements that don't give any are taken to write every row of their table.
+///
+/// A late write is only skipped if a later transaction set the same column of the same rows to a
+/// constant, since then it makes no difference. A late write to a cell that a later transaction changed
+/// relative to what was there before, like `seats = seats - 1`, can't be skipped or applied in its place,
+/// so it's applied in arrival order and flagged as a conflict instead. A cell is remembered for every
+/// distinct row key and column written
+#[derive(Debug, Default)]
+pub struct ReplicationConflictResolver {
+    /// the cells written in each table
+    cells: HashMap<String, HashMap<Cell, CellVersions>>,
+}
+
+impl ReplicationConflictResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn outcome(&self, version: TransactionVersion, table: &str, row: &RowKey, column: &Option<String>) -> CellOutcome {
+        let Some(cells) = self.cells.get(table) else {
+            return CellOutcome::Clear;
+        };
+
+        let later_write = cells.iter()
+            .filter(|((key, cell_column), versions)| versions.latest > version && keys_overlap(row, key) && columns_overlap(column, cell_column))
+            .map(|(_, versions)| versions.latest)
+            .max();
+        let Some(later_write) = later_write else {
+            return CellOutcome::Clear;
+        };
+
+        let superseded = cells.iter()
+            .any(|((key, cell_column), versions)| versions.latest_overwrite.is_some_and(|overwrite| overwrite > version)
+                && key_covers(key, row)
+                && (cell_column.is_none() || cell_column == column));
+        if superseded {
+            CellOutcome::Superseded
+        } else {
+            CellOutcome::Conflict(later_write)
+        }
+    }
+
+    fn record(&mut self, version: TransactionVersion, write: &RowWrite, column: &ColumnWrite) {
+        let cells = self.cells.entry(write.table.clone()).or_default();
+        for row in &write.rows {
+            let versions = cells.entry((row.clone(), column.column.clone()))
+                .or_insert(CellVersions { latest: version, latest_overwrite: None });
+            versions.latest = version.max(versions.latest);
+            // only a constant written to exactly these rows is known to replace what they had
+            if column.overwrite && write.exact {
+                versions.latest_overwrite = Some(versions.latest_overwrite.map_or(version, |overwrite| version.max(overwrite)));
+            }
+        }
+    }
+
+    /// Gives the statements of a replicated transaction that should be applied, and records the transaction
+    /// as the latest writer of what they write. Assignments to columns that a later transaction already
+    /// overwrote are taken out of updates, and statements left with nothing to write are skipped
+    pub fn resolve(&mut self, version: TransactionVersion, stmts: &[String]) -> Result<Vec<String>, SddmsError> {
+        let mut applied = Vec::with_capacity(stmts.len());
+        for stmt in stmts {
+            let writes = statement_row_writes(stmt)?;
+            let mut superseded_columns = HashSet::new();
+            let mut kept = Vec::new();
+            for write in &writes {
+                for column in &write.columns {
+                    let outcomes = write.rows.iter()
+                        .map(|row| self.outcome(version, &write.table, row, &column.column))
+                        .collect::<Vec<_>>();
+
+                    if outcomes.iter().all(|outcome| matches!(outcome, CellOutcome::Superseded)) {
+                        // only an update's assignments can be taken out of a statement. Whole rows are only
+                        // written by statements that write nothing else, so those are skipped altogether
+                        if let Some(column) = &column.column {
+                            superseded_columns.insert(column.clone());
+                        }
+                        continue;
+                    }
+
+                    if let Some(later) = outcomes.iter().find_map(|outcome| match outcome {
+                        CellOutcome::Conflict(later) => Some(*later),
+                        _ => None,
+                    }) {
+                        warn!("Replication of {} conflicts with {}, which already wrote {} of {}, so it is applied in arrival order", version, later, describe_column(&column.column), write.table);
+                    }
+                    kept.push((write, column));
+                }
+            }
+
+            if kept.is_empty() && !writes.is_empty() {
+                warn!("Replication of {} arrived after later transactions overwrote everything it writes, so it is skipped: {}", version, stmt);
+                continue;
+            }
+
+            let stmt = if superseded_columns.is_empty() {
+                stmt.clone()
+            } else {
+                warn!("Replication of {} arrived after later transactions overwrote {:?}, so those assignments are skipped", version, superseded_columns);
+                let remaining = without_assignments(stmt, &superseded_columns)
+                    .map_err(|err| SddmsError::site("Failed to remove overwritten assignments").with_cause(err))?;
+                match remaining {
+                    Some(remaining) => remaining,
+                    None => continue,
+                }
+            };
+
+            for (write, column) in kept {
+                self.record(version, write, column);
+            }
+            applied.push(stmt);
+        }
+
+        Ok(applied)
+    }
+
+    /// Records a transaction that committed at this site as the latest writer of what it wrote, so older
+    /// replications don't overwrite it
+    pub fn record_local(&mut self, version: TransactionVersion, stmts: &[String]) -> Result<(), SddmsError> {
+        for stmt in stmts {
+            for write in statement_row_writes(stmt)? {
+                for column in &write.columns {
+                    self.record(version, &write, column);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn statement_row_writes(stmt: &str) -> Result<Vec<RowWrite>, SddmsError> {
+    parse_row_writes(stmt)
+        .map_err(|err| SddmsError::site("Failed to parse replicated statement").with_cause(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replication_conflicts::{ReplicationConflictResolver, TransactionVersion};
+
+    fn stmts(stmts: &[&str]) -> Vec<String> {
+        stmts.iter().map(|stmt| stmt.to_string()).collect()
+    }
+
+    #[test]
+    fn later_writes_win_regardless_of_arrival() {
+        let older = TransactionVersion::new(100, 2, 9);
+        // same commit time, so the site id breaks the tie
+        let newer = TransactionVersion::new(100, 3, 1);
+        let newer_stmts = stmts(&["UPDATE flights SET seats = 3;"]);
+        let older_stmts = stmts(&["UPDATE flights SET seats = 2;", "INSERT INTO bookings VALUES (1);"]);
+
+        let mut resolver = ReplicationConflictResolver::new();
+        resolver.record_local(TransactionVersion::new(50, 1, 1), &stmts(&["INSERT INTO bookings VALUES (2);"])).unwrap();
+        assert_eq!(resolver.resolve(newer, &newer_stmts).unwrap(), newer_stmts);
+        // only the write to flights lost out, since bookings was last written by an even older transaction
+        assert_eq!(resolver.resolve(older, &older_stmts).unwrap(), stmts(&["INSERT INTO bookings VALUES (1);"]));
+    }
+
+    #[test]
+    fn only_overwritten_cells_are_skipped() {
+        let older = TransactionVersion::new(100, 2, 1);
+        let newer = TransactionVersion::new(200, 3, 1);
+
+        let mut resolver = ReplicationConflictResolver::new();
+        resolver.resolve(newer, &stmts(&["UPDATE flights SET seats = 3 WHERE id = 1;"])).unwrap();
+
+        // other rows and other columns of the same row still get written
+        let older_stmts = stmts(&[
+            "UPDATE flights SET seats = 5, price = 10 WHERE id = 1;",
+            "UPDATE flights SET seats = seats - 1 WHERE id = 2;",
+            "INSERT INTO flights (id, seats) VALUES (3, 7);",
+        ]);
+        assert_eq!(resolver.resolve(older, &older_stmts).unwrap(), stmts(&[
+            "UPDATE flights SET price = 10 WHERE id = 1;",
+            "UPDATE flights SET seats = seats - 1 WHERE id = 2;",
+            "INSERT INTO flights (id, seats) VALUES (3, 7);",
+        ]));
+
+        // a later write that depends on what was there before can't be put in order, so the late write is
+        // applied rather than dropped
+        let mut resolver = ReplicationConflictResolver::new();
+        resolver.resolve(newer, &stmts(&["UPDATE flights SET seats = seats - 1 WHERE id = 1;"])).unwrap();
+        let older_stmts = stmts(&["UPDATE flights SET seats = 5 WHERE id = 1;"]);
+        assert_eq!(resolver.resolve(older, &older_stmts).unwrap(), older_stmts);
+    }
+}
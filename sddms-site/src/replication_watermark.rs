@@ -0,0 +1,106 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::sync::watch;
+use sddms_shared::error::SddmsError;
+
+/// How long a query waits for a replication to be applied unless configured otherwise
+pub const DEFAULT_CATCHUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tracks which transactions replicated from each other site this site has applied, so that queries can
+/// wait for a particular transaction to show up. Replications can be applied out of order, so every applied
+/// id is kept rather than just the highest. The watermark reported for a site is the highest id applied
+#[derive(Debug)]
+pub struct ReplicationWatermarks {
+    applied: watch::Sender<HashMap<u32, BTreeSet<u32>>>,
+}
+
+impl Default for ReplicationWatermarks {
+    fn default() -> Self {
+        // values can be sent without any receivers, which are only subscribed while waiting
+        let (applied, _) = watch::channel(HashMap::new());
+        Self {
+            applied,
+        }
+    }
+}
+
+impl ReplicationWatermarks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks a transaction from the given site as applied, waking anything waiting for it
+    pub fn record_applied(&self, site_id: u32, transaction_id: u32) {
+        self.applied.send_if_modified(|applied| applied.entry(site_id).or_default().insert(transaction_id));
+    }
+
+    /// The highest transaction id applied from the given site, if any have been
+    pub fn watermark(&self, site_id: u32) -> Option<u32> {
+        self.applied.borrow().get(&site_id).and_then(|transaction_ids| transaction_ids.last().copied())
+    }
+
+    /// Every site's watermark as `(site_id, transaction_id)` pairs, ordered by site
+    pub fn all(&self) -> Vec<(u32, u32)> {
+        let mut watermarks = self.applied.borrow().iter()
+            .filter_map(|(site_id, transaction_ids)| transaction_ids.last().map(|transaction_id| (*site_id, *transaction_id)))
+            .collect::<Vec<_>>();
+        watermarks.sort();
+        watermarks
+    }
+
+    /// Waits until a transaction from the given site has been applied, failing if that takes longer than the
+    /// timeout
+    pub async fn wait_for(&self, site_id: u32, transaction_id: u32, timeout: Duration) -> Result<(), SddmsError> {
+        let mut receiver = self.applied.subscribe();
+        let caught_up = async {
+            // the borrowed value has to be let go before the receiver is
+            receiver.wait_for(|applied| applied.get(&site_id).is_some_and(|transaction_ids| transaction_ids.contains(&transaction_id))).await
+                .map(|_| ())
+        };
+        match tokio::time::timeout(timeout, caught_up).await {
+            Ok(Ok(())) => Ok(()),
+            // the sender lives as long as self, so it can't be closed while waiting
+            Ok(Err(_)) => Err(SddmsError::site("Replication watermarks were closed while waiting")),
+            Err(_) => Err(SddmsError::site(format!("Site did not apply transaction {}:{} within {:?}", site_id, transaction_id, timeout))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::replication_watermark::ReplicationWatermarks;
+
+    #[tokio::test]
+    async fn watermarks_are_the_highest_applied_id() {
+        let watermarks = ReplicationWatermarks::new();
+        assert_eq!(watermarks.watermark(2), None);
+
+        watermarks.record_applied(2, 5);
+        watermarks.record_applied(2, 3);
+        assert_eq!(watermarks.watermark(2), Some(5));
+        watermarks.record_applied(1, 8);
+        assert_eq!(watermarks.all(), vec![(1, 8), (2, 5)]);
+
+        assert!(watermarks.wait_for(2, 3, Duration::from_millis(10)).await.is_ok());
+        assert!(watermarks.wait_for(2, 6, Duration::from_millis(10)).await.is_err());
+        assert!(watermarks.wait_for(3, 1, Duration::from_millis(10)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_transaction_itself_when_applied_out_of_order() {
+        let watermarks = ReplicationWatermarks::new();
+        watermarks.record_applied(2, 5);
+        // 5 landing first says nothing about 4
+        assert!(watermarks.wait_for(2, 4, Duration::from_millis(10)).await.is_err());
+
+        let wait = watermarks.wait_for(2, 4, Duration::from_secs(1));
+        let apply = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            watermarks.record_applied(2, 4);
+        };
+        let (waited, ()) = tokio::join!(wait, apply);
+        assert!(waited.is_ok());
+        assert_eq!(watermarks.watermark(2), Some(5));
+    }
+}
@@ -1,41 +1,230 @@
 use std::fmt::{Debug, Formatter};
 use std::path::{Path, PathBuf};
-use log::{debug, error, info};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{debug, error, info, warn};
 use rusqlite::Connection;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::UnboundedReceiverStream;
 use tonic::{Request, Response, Status};
 use sddms_services::shared::{ApiError, FinalizeMode, LockMode, LockRequest, ReturnStatus};
-use sddms_services::site_controller::{BeginTransactionRequest, BeginTransactionResponse, BeginTransactionResults, FinalizeTransactionRequest, FinalizeTransactionResponse, FinalizeTransactionResults, InvokeQueryRequest, InvokeQueryResponse, InvokeQueryResults, RegisterClientRequest, RegisterClientResponse, RegisterClientResults, ReplicationUpdateRequest, ReplicationUpdateResponse};
+use sddms_services::site_controller::{BeginTransactionRequest, BeginTransactionResponse, BeginTransactionResults, FinalizeTransactionRequest, FinalizeTransactionResponse, FinalizeTransactionResults, GetReplicationWatermarksRequest, GetReplicationWatermarksResponse, InvokeQueryRequest, InvokeQueryResponse, InvokeQueryResults, RegisterClientRequest, RegisterClientResponse, RegisterClientResults, ReplicationUpdateRequest, ReplicationUpdateResponse, ReplicationWatermark, UnregisterClientRequest, UnregisterClientResponse, UnregisterClientResults};
 use sddms_services::site_controller::begin_transaction_response::BeginTransactionPayload;
 use sddms_services::site_controller::finalize_transaction_response::FinalizeTransactionPayload;
 use sddms_services::site_controller::invoke_query_response::InvokeQueryPayload;
 use sddms_services::site_controller::register_client_response::RegisterClientPayload;
+use sddms_services::site_controller::unregister_client_response::UnregisterClientPayload;
 use sddms_services::site_controller::site_manager_service_server::SiteManagerService;
 use sddms_shared::error::{SddmsError, SddmsTermError};
-use crate::central_client::{AcquireLockRet, CentralClient};
-use crate::client_connection::{ClientConnectionMap};
+use sddms_shared::sql_metadata::parse_statements;
+use crate::central_client::{AcquireLockRet, CentralClient, FinalizeRet};
+use crate::client_connection::{ClientConnection, ClientConnectionMap};
 use crate::history_logger::HistoryLogger;
+use crate::journal_mode::JournalMode;
+use crate::replication_conflicts::{commit_timestamp_now, ReplicationConflictPolicy, ReplicationConflictResolver, TransactionVersion};
+use crate::replication_watermark::{ReplicationWatermarks, DEFAULT_CATCHUP_TIMEOUT};
+use crate::sqlite_extensions::SqliteExtensions;
 use crate::transaction_history::{TransactionHistoryMap};
 
+/// how many rows are sent in each response of a streamed query
+const RESULT_BATCH_SIZE: usize = 256;
+
+type InvokeQueryResponseStream = Pin<Box<dyn Stream<Item = Result<InvokeQueryResponse, Status>> + Send>>;
+
+/// Where a streamed query's batches of rows are sent as they're read. Sending never waits, so rows can be
+/// sent while the query is still stepping through the database
+type ResultSender = tokio::sync::mpsc::UnboundedSender<Result<InvokeQueryResponse, Status>>;
+
+/// Reads a query's rows, sending every full batch down the sender as soon as it fills when there is one.
+/// Gives back the rows that are left, which is all of them when nothing is streamed
+async fn read_rows(client_connection: &ClientConnection, query: &str, batch_sender: Option<&ResultSender>) -> Result<InvokeQueryResults, SddmsError> {
+    let Some(batch_sender) = batch_sender else {
+        return client_connection.invoke_read_query(query).await;
+    };
+
+    let mut send_batch = |results: InvokeQueryResults| {
+        let mut batch_response = InvokeQueryResponse::default();
+        batch_response.set_ret(ReturnStatus::Ok);
+        batch_response.invoke_query_payload = Some(InvokeQueryPayload::Results(results));
+        // if the client hung up, there's no one left to tell
+        let _ = batch_sender.send(Ok(batch_response));
+    };
+    client_connection.invoke_batched_read_query(query, RESULT_BATCH_SIZE, &mut send_batch).await
+}
+
 pub struct SddmsSiteManagerService {
     db_path: PathBuf,
-    // TODO make this a RW lock -- 80% of time we're reading, and underlying connections
-    // are managed by mutexes as well
-    client_connections: tokio::sync::Mutex<ClientConnectionMap>,
+    /// journal mode for connections to the disk database, if not the default
+    journal_mode: Option<JournalMode>,
+    // most requests only read the map, and the shared database has a read-write lock of its own
+    client_connections: tokio::sync::RwLock<ClientConnectionMap>,
     cc_client: CentralClient,
     transaction_history: tokio::sync::Mutex<TransactionHistoryMap>,
     site_id: u32,
     history_logger: tokio::sync::Mutex<Box<dyn HistoryLogger>>,
+    /// set once the site starts shutting down, after which no new transactions are started
+    draining: AtomicBool,
+    /// if set, clients may only read. Replicated updates are still applied
+    read_only: bool,
+    /// loaded into every connection, including the ones that write to disk
+    extensions: SqliteExtensions,
+    /// how far replication from each other site has been applied here
+    replication_watermarks: ReplicationWatermarks,
+    /// how long a query waits for a replication it asked to read after
+    catchup_timeout: Duration,
+    /// settles replicated writes that arrive out of order, unless they're applied in arrival order.
+    /// Replications hold it while they're applied, so they're applied in the order they were resolved
+    replication_conflicts: Option<tokio::sync::Mutex<ReplicationConflictResolver>>,
 }
 
 impl SddmsSiteManagerService {
-    pub fn new<LoggerT: Into<Box<dyn HistoryLogger>>>(path: &Path, cc_client: CentralClient, site_id: u32, logger: LoggerT) -> Self {
-        Self {
+    pub fn new<LoggerT: Into<Box<dyn HistoryLogger>>>(path: &Path, journal_mode: Option<JournalMode>, max_clients: Option<usize>, cc_client: CentralClient, site_id: u32, logger: LoggerT) -> Result<Self, SddmsError> {
+        let client_connections = ClientConnectionMap::open(path)?
+            .with_max_clients(max_clients);
+
+        Ok(Self {
             db_path: PathBuf::from(path),
-            client_connections: tokio::sync::Mutex::new(ClientConnectionMap::new()),
+            journal_mode,
+            client_connections: tokio::sync::RwLock::new(client_connections),
             cc_client,
             transaction_history: tokio::sync::Mutex::default(),
             site_id,
             history_logger: tokio::sync::Mutex::new(logger.into()),
+            draining: AtomicBool::new(false),
+            read_only: false,
+            extensions: SqliteExtensions::default(),
+            replication_watermarks: ReplicationWatermarks::new(),
+            catchup_timeout: DEFAULT_CATCHUP_TIMEOUT,
+            replication_conflicts: None,
+        })
+    }
+
+    /// Only lets clients read, so the site can serve as a query-only replica
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Loads SQLite extensions into every connection, so their functions can be used in queries
+    pub fn with_extensions(mut self, extensions: SqliteExtensions) -> Result<Self, SddmsError> {
+        self.client_connections.get_mut().load_extensions(extensions.clone())?;
+        self.extensions = extensions;
+        Ok(self)
+    }
+
+    /// How long a query waits for this site to apply the replication it asked to read after before failing
+    pub fn with_catchup_timeout(mut self, catchup_timeout: Duration) -> Self {
+        self.catchup_timeout = catchup_timeout;
+        self
+    }
+
+    /// How to settle replicated writes to the same rows that arrive out of order
+    pub fn with_replication_conflict_policy(mut self, policy: ReplicationConflictPolicy) -> Self {
+        self.replication_conflicts = match policy {
+            ReplicationConflictPolicy::ArrivalOrder => None,
+            ReplicationConflictPolicy::LastWriterWins => Some(tokio::sync::Mutex::new(ReplicationConflictResolver::new())),
+        };
+        self
+    }
+
+    /// Waits until the replication the query asked to read after has been applied. Transactions from this
+    /// site are already applied by the time their ids are handed out, so there is nothing to wait for
+    async fn wait_for_replication(&self, invoke_request: &InvokeQueryRequest) -> Result<(), SddmsError> {
+        let Some(read_after) = &invoke_request.read_after else {
+            return Ok(());
+        };
+
+        if read_after.site_id == self.site_id {
+            return Ok(());
+        }
+
+        debug!("Client {} is waiting for transaction {}:{} to be replicated", invoke_request.client_id, read_after.site_id, read_after.transaction_id);
+        self.replication_watermarks.wait_for(read_after.site_id, read_after.transaction_id, self.catchup_timeout).await
+    }
+
+    /// Rejects queries that would modify a read-only site
+    fn check_writable(&self, query: &str) -> Result<(), SddmsError> {
+        if !self.read_only {
+            return Ok(());
+        }
+
+        let modifiable = parse_statements(query)
+            .map_err(|err| SddmsError::site("Failed to parse query on read-only site").with_cause(err))?
+            .iter()
+            .any(|metadata| metadata.modifiable());
+
+        if modifiable {
+            Err(SddmsError::site("Site is read-only, so it does not accept statements that modify the database"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs a read outside of any transaction without taking locks. Nothing that modifies the database
+    /// can run this way, and since it's not part of any transaction it isn't written to the history
+    async fn run_unlocked_read(&self, invoke_request: &InvokeQueryRequest, batch_sender: Option<&ResultSender>) -> InvokeQueryResponse {
+        let modifiable = match parse_statements(&invoke_request.query) {
+            Ok(statements) => statements.iter().any(|metadata| metadata.modifiable()),
+            Err(err) => return InvokeQueryResponse::from(SddmsError::site("Failed to parse unlocked read").with_cause(err)),
+        };
+
+        if modifiable || !invoke_request.has_results {
+            return InvokeQueryResponse::from(SddmsError::site("Only reads can run without a transaction and locks"));
+        }
+
+        let connection_map = self.client_connections.read().await;
+        let Some(client_connection) = connection_map.get_client_connection(invoke_request.client_id) else {
+            return InvokeQueryResponse::from(SddmsError::site(format!("No connection for client {}", invoke_request.client_id)));
+        };
+
+        match read_rows(client_connection, &invoke_request.query, batch_sender).await {
+            Ok(results) => {
+                let mut response = InvokeQueryResponse::default();
+                response.set_ret(ReturnStatus::Ok);
+                response.invoke_query_payload = Some(InvokeQueryPayload::Results(results));
+                response
+            }
+            Err(err) => InvokeQueryResponse::from(err),
+        }
+    }
+
+    /// Stops new transactions from starting and waits for the ones in progress to finalize, giving up
+    /// after the timeout. Returns how many transactions were still outstanding when the wait ended
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+
+        let outstanding = self.transaction_history.lock().await.len();
+        info!("Waiting on {} outstanding transactions to finalize...", outstanding);
+
+        let wait_for_finalize = async {
+            while !self.transaction_history.lock().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        if tokio::time::timeout(timeout, wait_for_finalize).await.is_err() {
+            warn!("Timed out waiting for transactions to finalize");
+        }
+
+        let remaining = self.transaction_history.lock().await.len();
+        info!("Waited on {} transactions, {} finalized and {} were abandoned", outstanding, outstanding.saturating_sub(remaining), remaining);
+        remaining
+    }
+
+    /// Unregisters this site from the central controller, which releases the locks of any transactions
+    /// that were abandoned while shutting down
+    pub async fn unregister_from_central(&self) -> Result<(), SddmsError> {
+        let released_transactions = self.cc_client.unregister_self(self.site_id).await?;
+        info!("Central controller released {} abandoned transactions", released_transactions);
+        Ok(())
+    }
+
+    fn check_not_draining(&self) -> Result<(), SddmsError> {
+        if self.draining.load(Ordering::SeqCst) {
+            Err(SddmsError::site("Site is shutting down, so no new transactions can be started"))
+        } else {
+            Ok(())
         }
     }
 
@@ -45,7 +234,21 @@ impl SddmsSiteManagerService {
             .map_err(|err| err.into())
     }
 
-    async fn acquire_locks_for_txn(&self, trans_id: u32, read_set: &[String], write_set: &[String]) -> Result<(), InvokeQueryResponse> {
+    /// Acquires every lock the query needs, giving back how many locks it waited behind at most
+    async fn acquire_locks_for_txn(&self, trans_id: u32, ordered_locks: &[LockRequest], read_set: &[String], write_set: &[String], no_wait: bool) -> Result<u32, InvokeQueryResponse> {
+        // the locks the client asked for by name come first, in its order
+        let mut queue_position = 0;
+        if !ordered_locks.is_empty() {
+            info!("Acquiring locks in order: {:?}", ordered_locks);
+            let lock_result = self.cc_client.acquire_table_lock_in_order(self.site_id, trans_id, ordered_locks.to_vec(), no_wait)
+                .await
+                .map_err(|err| {
+                    error!("Error while trying to acquire lock: {}", err);
+                    InvokeQueryResponse::from(err)
+                })?;
+            queue_position = Self::check_lock_result(trans_id, ordered_locks, lock_result)?;
+        }
+
         let lock_requests = {
             let mut lock_requests = read_set.into_iter()
                 .map(|table| LockRequest::new(table, LockMode::Shared))
@@ -60,28 +263,50 @@ impl SddmsSiteManagerService {
 
         info!("Acquiring locks: {:?}", lock_requests);
 
-        let lock_result = self.cc_client.acquire_table_lock(self.site_id, trans_id, lock_requests.clone())
+        let lock_result = self.cc_client.acquire_table_lock(self.site_id, trans_id, lock_requests.clone(), no_wait)
             .await
             .map_err(|err| {
                 error!("Error while trying to acquire lock: {}", err);
                 InvokeQueryResponse::from(err)
             })?;
 
+        let queue_position = queue_position.max(Self::check_lock_result(trans_id, &lock_requests, lock_result)?);
+        Ok(queue_position)
+    }
+
+    /// Turns anything but successfully acquiring the locks into the response for the query. Gives back
+    /// how many locks the transaction waited behind if it got them
+    fn check_lock_result(trans_id: u32, lock_requests: &[LockRequest], lock_result: AcquireLockRet) -> Result<u32, InvokeQueryResponse> {
         match lock_result {
-            AcquireLockRet::Ok => {
+            AcquireLockRet::Ok { queue_position } => {
                 info!("Successfully acquired locks: {:?}", lock_requests);
-                Ok(())
+                if queue_position > 0 {
+                    info!("Transaction {} waited behind {} locks for {:?}", trans_id, queue_position, lock_requests);
+                }
+                Ok(queue_position)
             }
             AcquireLockRet::Deadlock(deadlock_err) => {
                 let mut response = InvokeQueryResponse::from(deadlock_err);
                 response.set_ret(ReturnStatus::Deadlocked);
                 Err(response)
             }
+            AcquireLockRet::TransactionNotFound(err) => {
+                error!("Central controller doesn't know transaction {}: {}", trans_id, err);
+                let mut response = InvokeQueryResponse::from(err);
+                response.set_ret(ReturnStatus::TransactionNotFound);
+                Err(response)
+            }
+            AcquireLockRet::WouldBlock(err) => {
+                info!("Locks {:?} for transaction {} are held elsewhere, not waiting", lock_requests, trans_id);
+                let mut response = InvokeQueryResponse::from(err);
+                response.set_ret(ReturnStatus::WouldBlock);
+                Err(response)
+            }
         }
     }
 
     async fn acquire_table_lock(&self, trans_id: u32, lock_requests: Vec<LockRequest>) -> Result<AcquireLockRet, InvokeQueryResponse> {
-        self.cc_client.acquire_table_lock(self.site_id, trans_id, lock_requests)
+        self.cc_client.acquire_table_lock(self.site_id, trans_id, lock_requests, false)
             .await
             .map_err(|err| {
                 error!("Error while trying to acquire lock: {}", err);
@@ -100,15 +325,15 @@ impl SddmsSiteManagerService {
             .push(cmd)
     }
 
-    async fn execute_query_on_db(&self, client_id: u32, transaction_id: u32, invoke_request: &InvokeQueryRequest) -> Result<InvokeQueryResults, SddmsTermError> {
+    async fn execute_query_on_db(&self, client_id: u32, transaction_id: u32, invoke_request: &InvokeQueryRequest, batch_sender: Option<&ResultSender>) -> Result<InvokeQueryResults, SddmsTermError> {
         // get the connection for the given client
-        let connection_map_lock = self.client_connections.lock().await;
+        let connection_map_lock = self.client_connections.read().await;
         let client_connection = connection_map_lock
             .get_client_connection(client_id)
             .unwrap();
 
         if invoke_request.has_results {
-            client_connection.invoke_read_query(&invoke_request.query).await
+            read_rows(client_connection, &invoke_request.query, batch_sender).await
                 .map_err(|err| SddmsTermError::from(err))
         } else {
             debug!("Saving update command from client_id={}, trans_id={}: {}", client_id, transaction_id, &invoke_request.query);
@@ -125,17 +350,23 @@ impl SddmsSiteManagerService {
         }
     }
 
-    async fn replicate_local_transaction(&self, client_connection_map: &mut ClientConnectionMap, client_id: u32, stmts: &[String]) -> Result<(), SddmsTermError> {
+    async fn replicate_local_transaction(&self, client_connection_map: &ClientConnectionMap, client_id: u32, stmts: &[String]) -> Result<(), SddmsTermError> {
         // apply it to the local database
         self.replicate_on_disk(stmts).await?;
-        // apply it to the connection map
-        self.replicate_to_clients(client_connection_map, stmts, Some(client_id)).await
+        // make it visible to the other clients
+        client_connection_map.commit_transaction(client_id, stmts).await
+            .map_err(|err| SddmsTermError::from(err))
     }
 
     async fn replicate_on_disk(&self, stmts: &[String]) -> Result<(), SddmsTermError> {
         let mut disk_connection = Connection::open(&self.db_path)
             .map_err(|err| SddmsError::site("Failed to open disk database").with_cause(err))?;
 
+        if let Some(journal_mode) = &self.journal_mode {
+            journal_mode.configure(&disk_connection)?;
+        }
+        self.extensions.load_into(&disk_connection)?;
+
         let transaction = disk_connection.transaction()
             .map_err(|err| SddmsError::site("Failed to open replication txn on disk").with_cause(err))?;
 
@@ -149,8 +380,8 @@ impl SddmsSiteManagerService {
             .map_err(|err| SddmsTermError::from(err))
     }
 
-    async fn replicate_to_clients(&self, connection_map: &mut ClientConnectionMap, stmts: &[String], skip: Option<u32>) -> Result<(), SddmsTermError> {
-        connection_map.replicate_messages(stmts, skip).await
+    async fn replicate_to_clients(&self, connection_map: &ClientConnectionMap, stmts: &[String]) -> Result<(), SddmsTermError> {
+        connection_map.replicate_messages(stmts).await
             .map_err(|err| SddmsTermError::from(err))
     }
 
@@ -163,25 +394,181 @@ impl SddmsSiteManagerService {
             })
     }
 
-    async fn replicate_and_finalize(&self, client_id: u32, trans_id: u32, mode: FinalizeMode) -> Result<(), SddmsTermError> {
+    /// Commits or aborts a transaction and finalizes it with the central controller. A commit that some sites
+    /// couldn't be replicated to is still committed, so the replication failure is given back as a warning
+    /// rather than an error
+    async fn replicate_and_finalize(&self, client_id: u32, trans_id: u32, mode: FinalizeMode) -> Result<Option<SddmsError>, SddmsTermError> {
         // Get the history of what to replicate
         let mut history = self.transaction_history.lock().await;
-        let transaction_history = history.remove_transaction(client_id, trans_id).unwrap();
+        let transaction_history = history.remove_transaction(client_id, trans_id)
+            .ok_or_else(|| SddmsError::site(format!("Client {} has no transaction {} to finalize", client_id, trans_id)))?;
 
         // replicate locally if commit
-        if let FinalizeMode::Commit = mode {
+        let commit_timestamp_us = if let FinalizeMode::Commit = mode {
             debug!("Replicating to local transactions...");
-            let mut client_connections = self.client_connections.lock().await;
-            self.replicate_local_transaction(&mut client_connections, client_id, &transaction_history).await?;
+            let commit_timestamp_us = commit_timestamp_now()?;
+            {
+                let client_connections = self.client_connections.read().await;
+                self.replicate_local_transaction(&client_connections, client_id, &transaction_history).await?;
+            }
+            if let Some(replication_conflicts) = &self.replication_conflicts {
+                replication_conflicts.lock().await
+                    .record_local(TransactionVersion::new(commit_timestamp_us, self.site_id, trans_id), &transaction_history)?;
+            }
             debug!("Replicated local transaction");
-        }
+            commit_timestamp_us
+        } else {
+            0
+        };
 
         // finalize with concurrency controller
         debug!("Finalizing transaction with CC...");
-        self.cc_client.finalize_transaction(self.site_id, trans_id, mode, &transaction_history).await?;
+        let replication_warning = match self.cc_client.finalize_transaction(self.site_id, trans_id, mode, &transaction_history, commit_timestamp_us).await? {
+            FinalizeRet::Ok => None,
+            // an abort doesn't change anything, so there was nothing for the other sites to miss
+            FinalizeRet::PartiallyReplicated(_) if mode == FinalizeMode::Abort => None,
+            FinalizeRet::TransactionNotFound(err) => {
+                return Err(SddmsError::site(format!("Central controller doesn't know transaction {}, so it couldn't be finalized there", trans_id))
+                    .with_cause(err)
+                    .into());
+            }
+            FinalizeRet::PartiallyReplicated(err) => {
+                warn!("Transaction {} committed, but was not replicated to every site: {}", trans_id, err);
+                Some(err)
+            }
+        };
         debug!("Transaction finalized with CC");
 
-        Ok(())
+        Ok(replication_warning)
+    }
+
+    /// Aborts a single statement transaction that failed after it began, so its begin in the history gets a
+    /// matching rollback and the central controller releases whatever locks it had already taken
+    async fn abort_single_stmt_transaction(&self, client_id: u32, trans_id: u32) {
+        info!("Aborting single statement transaction {}", trans_id);
+        self.history_logger.lock().await.log(client_id, self.site_id, trans_id, "ROLLBACK")
+            .unwrap();
+        if let Err(err) = self.replicate_and_finalize(client_id, trans_id, FinalizeMode::Abort).await {
+            error!("Failed to abort single statement transaction {}: {}", trans_id, err);
+        }
+    }
+
+    /// Runs a query for a client, taking care of single statement transactions, locking, and logging. When
+    /// streaming, full batches of rows are sent as they're read, and the response that's given back has the
+    /// rest of them along with how the query finished
+    async fn run_query(&self, invoke_request: InvokeQueryRequest, batch_sender: Option<&ResultSender>) -> InvokeQueryResponse {
+        debug!("Got query: {}", invoke_request.query);
+        let client_id = invoke_request.client_id;
+
+        // writes are turned away before they can take any locks
+        if let Err(err) = self.check_writable(&invoke_request.query) {
+            error!("Rejecting query from client {}: {}", client_id, err);
+            return InvokeQueryResponse::from(err);
+        }
+
+        // wait before taking any locks, so a slow replication doesn't hold anyone else up
+        if let Err(err) = self.wait_for_replication(&invoke_request).await {
+            error!("Query from client {} could not catch up: {}", client_id, err);
+            return InvokeQueryResponse::from(err);
+        }
+
+        if invoke_request.unlocked_read {
+            debug!("Running unlocked read for client {}", client_id);
+            return self.run_unlocked_read(&invoke_request, batch_sender).await;
+        }
+
+        // only acquire locks if in a transaction
+        let transaction_id = if invoke_request.single_stmt_transaction {
+            if let Err(err) = self.check_not_draining() {
+                return InvokeQueryResponse::from(err);
+            }
+
+            info!("Provisioning transaction for single stmt");
+            let result = self.provision_single_stmt_transaction().await;
+            match result {
+                Ok(id) => {
+                    info!("Provisioned temporary transaction with id {}", id);
+                    self.push_transaction_for_client(client_id, id).await;
+                    // the client never began this transaction, so log it here to keep the history well-formed
+                    self.history_logger.lock().await.log(client_id, self.site_id, id, "Begin Txn")
+                        .unwrap();
+                    id
+                }
+                Err(response) => {
+                    return response
+                }
+            }
+        } else {
+            invoke_request.transaction_id
+        };
+
+        // try acquiring the lock
+        debug!("Acquiring lock(s) for {:?}...", invoke_request.write_set);
+
+        // attempt acquiring all locks necessary
+        let lock_requests_result = self.acquire_locks_for_txn(transaction_id, &invoke_request.ordered_locks, &invoke_request.read_set, &invoke_request.write_set, invoke_request.no_wait).await;
+        let lock_queue_position = match lock_requests_result {
+            Ok(queue_position) => {
+                debug!("Successfully acquired lock");
+                queue_position
+            }
+            Err(err_response) => {
+                if invoke_request.single_stmt_transaction {
+                    self.abort_single_stmt_transaction(client_id, transaction_id).await;
+                }
+                return err_response
+            }
+        };
+
+        // actually execute the results
+        let invoke_results = self.execute_query_on_db(client_id, transaction_id, &invoke_request, batch_sender).await;
+        // check for failure and return if it did
+        if let Err(err) = invoke_results {
+            if invoke_request.single_stmt_transaction {
+                self.abort_single_stmt_transaction(client_id, transaction_id).await;
+            }
+            let response = InvokeQueryResponse::from(err);
+            return response;
+        }
+
+        let results = InvokeQueryResults { lock_queue_position, ..invoke_results.unwrap() };
+
+        // the query is logged before its transaction is finalized, so it falls inside the begin and commit
+        self.history_logger.lock().await.log_query(client_id, self.site_id, transaction_id, &invoke_request.write_set, &invoke_request.read_set)
+            .unwrap();
+
+        // finalize the transaction as well
+        let (ret, payload) = if invoke_request.single_stmt_transaction {
+            // logged before finalizing, the same as an explicit commit
+            self.history_logger.lock().await.log(client_id, self.site_id, transaction_id, "COMMIT")
+                .unwrap();
+
+            let replication_result = self.replicate_and_finalize(client_id, transaction_id, FinalizeMode::Commit)
+                .await;
+
+            match replication_result {
+                Ok(None) => {
+                    (ReturnStatus::Ok, InvokeQueryPayload::Results(results))
+                }
+                Ok(Some(warning)) => {
+                    let results = InvokeQueryResults { replication_warning: Some(warning.to_string()), ..results };
+                    (ReturnStatus::PartiallyReplicated, InvokeQueryPayload::Results(results))
+                }
+                Err(err) => {
+                    (ReturnStatus::Error, InvokeQueryPayload::Error(ApiError::from(err)))
+                }
+            }
+
+        } else {
+            (ReturnStatus::Ok, InvokeQueryPayload::Results(results))
+        };
+
+        let mut response = InvokeQueryResponse::default();
+        response.set_ret(ret);
+        response.invoke_query_payload = Some(payload);
+        info!("Successfully invoked query");
+
+        response
     }
 }
 
@@ -196,8 +583,8 @@ impl SiteManagerService for SddmsSiteManagerService {
     async fn register_client(&self, _request: Request<RegisterClientRequest>) -> Result<Response<RegisterClientResponse>, Status> {
         info!("Registering new client");
 
-        let mut connection_map = self.client_connections.lock().await;
-        let result = connection_map.open_connection(&self.db_path);
+        let mut connection_map = self.client_connections.write().await;
+        let result = connection_map.open_connection();
 
         let (ret, payload) = match result {
             Ok(client_id) => {
@@ -221,25 +608,85 @@ impl SiteManagerService for SddmsSiteManagerService {
         Ok(Response::new(response))
     }
 
+    async fn unregister_client(&self, request: Request<UnregisterClientRequest>) -> Result<Response<UnregisterClientResponse>, Status> {
+        let client_id = request.into_inner().client_id;
+        info!("Unregistering client {}", client_id);
+
+        // dropping the connection throws away anything the client hadn't committed
+        let closed_connection = self.client_connections.write().await.close_connection(client_id);
+        if closed_connection.is_none() {
+            let err = SddmsError::site(format!("Client {} is not registered", client_id));
+            return Ok(Response::new(UnregisterClientResponse::from(err)));
+        }
+
+        // any transactions still in progress need to release their locks
+        let abandoned_transactions = self.transaction_history.lock().await.remove_client_transactions(client_id);
+        let mut rolled_back_transactions = Vec::new();
+        let mut unreleased_transactions = Vec::new();
+        for transaction in abandoned_transactions {
+            let trans_id = transaction.transaction_id();
+            info!("Rolling back transaction {} abandoned by client {}", trans_id, client_id);
+            // the connection is already gone, so the transaction is rolled back here whatever central says
+            self.history_logger.lock().await.log(client_id, self.site_id, trans_id, "ROLLBACK")
+                .unwrap();
+            match self.cc_client.finalize_transaction(self.site_id, trans_id, FinalizeMode::Abort, &[], 0).await {
+                // an abort has nothing to replicate, so it doesn't matter which sites were reached
+                Ok(FinalizeRet::Ok | FinalizeRet::PartiallyReplicated(_)) => {}
+                // the central controller holds no locks for it, so there's nothing to release
+                Ok(FinalizeRet::TransactionNotFound(err)) => warn!("Abandoned transaction {} was already gone from the central controller: {}", trans_id, err),
+                // keep going, so one failure doesn't leave the rest of the client's locks held too
+                Err(err) => {
+                    error!("Failed to release locks for abandoned transaction {}: {}", trans_id, err);
+                    unreleased_transactions.push(trans_id);
+                    continue;
+                }
+            }
+
+            rolled_back_transactions.push(trans_id);
+        }
+
+        if !unreleased_transactions.is_empty() {
+            unreleased_transactions.sort();
+            let err = SddmsError::site(format!("Failed to release locks for abandoned transactions {:?} of client {}", unreleased_transactions, client_id));
+            return Ok(Response::new(UnregisterClientResponse::from(err)));
+        }
+
+        let mut response = UnregisterClientResponse::default();
+        response.set_ret(ReturnStatus::Ok);
+        response.unregister_client_payload = Some(UnregisterClientPayload::Results(UnregisterClientResults { rolled_back_transactions }));
+        info!("Unregistered client {}", client_id);
+
+        Ok(Response::new(response))
+    }
+
     async fn begin_transaction(&self, request: Request<BeginTransactionRequest>) -> Result<Response<BeginTransactionResponse>, Status> {
         info!("Got begin transaction request: {:?}", request.remote_addr());
         let begin_trans_request = request.into_inner();
         let client_id = begin_trans_request.client_id;
+        if let Err(err) = self.check_not_draining() {
+            return Ok(Response::new(BeginTransactionResponse::from(err)));
+        }
+
+        // the client may have unregistered already, so check before the central controller hands out an id
+        if self.client_connections.read().await.get_client_connection(client_id).is_none() {
+            return Err(Status::not_found(format!("No connection for client {}", client_id)));
+        }
+
         let register_trans_result = self.register_transaction_with_cc().await;
         let Ok(trans_id) = register_trans_result else {
             return Ok(Response::new(register_trans_result.unwrap_err()))
         };
 
+        // get the connection for the given client
+        let connection_map_lock = self.client_connections.read().await;
+        let Some(client_connection) = connection_map_lock.get_client_connection(client_id) else {
+            return Err(Status::not_found(format!("No connection for client {}", client_id)));
+        };
+
         // register that we are starting a new transaction
         self.push_transaction_for_client(client_id, trans_id).await;
 
-        // get the connection for the given client
-        let connection_map_lock = self.client_connections.lock().await;
-        let client_connection = connection_map_lock
-            .get_client_connection(client_id)
-            .unwrap();
-
-        let begin_trans_result = client_connection.invoke_one_off_stmt("BEGIN TRANSACTION").await;
+        let begin_trans_result = client_connection.begin_transaction().await;
         if begin_trans_result.is_err() {
             let err = begin_trans_result.unwrap_err();
             return Ok(Response::new(BeginTransactionResponse::from(err)));
@@ -258,79 +705,20 @@ impl SiteManagerService for SddmsSiteManagerService {
 
     async fn invoke_query(&self, request: Request<InvokeQueryRequest>) -> Result<Response<InvokeQueryResponse>, Status> {
         info!("Got invoke query request: {:?}", request.remote_addr());
-        let invoke_request = request.into_inner();
-        debug!("Got query: {}", invoke_request.query);
-        let client_id = invoke_request.client_id;
-
-        // only acquire locks if in a transaction
-        let transaction_id = if invoke_request.single_stmt_transaction {
-            info!("Provisioning transaction for single stmt");
-            let result = self.provision_single_stmt_transaction().await;
-            match result {
-                Ok(id) => {
-                    info!("Provisioned temporary transaction with id {}", id);
-                    self.push_transaction_for_client(client_id, id).await;
-                    id
-                }
-                Err(response) => {
-                    return Ok(Response::new(response))
-                }
-            }
-        } else {
-            invoke_request.transaction_id
-        };
-
-        // try acquiring the lock
-        debug!("Acquiring lock(s) for {:?}...", invoke_request.write_set);
-
-        // attempt acquiring all locks necessary
-        let lock_requests_result = self.acquire_locks_for_txn(transaction_id, &invoke_request.read_set, &invoke_request.write_set).await;
-        match lock_requests_result {
-            Ok(_) => {
-                debug!("Successfully acquired lock");
-            }
-            Err(err_response) => {
-                return Ok(Response::new(err_response))
-            }
-        }
-
-        // actually execute the results
-        let invoke_results = self.execute_query_on_db(client_id, transaction_id, &invoke_request).await;
-        // check for failure and return if it did
-        if let Err(err) = invoke_results {
-            let response = InvokeQueryResponse::from(err);
-            return Ok(Response::new(response));
-        }
-
-        let results = invoke_results.unwrap();
-
-        // finalize the transaction as well
-        let (ret, payload) = if invoke_request.single_stmt_transaction {
-            let replication_result = self.replicate_and_finalize(client_id, transaction_id, FinalizeMode::Commit)
-                .await;
-
-            match replication_result {
-                Ok(_) => {
-                    (ReturnStatus::Ok, InvokeQueryPayload::Results(results))
-                }
-                Err(err) => {
-                    (ReturnStatus::Error, InvokeQueryPayload::Error(ApiError::from(err)))
-                }
-            }
+        Ok(Response::new(self.run_query(request.into_inner(), None).await))
+    }
 
-        } else {
-            (ReturnStatus::Ok, InvokeQueryPayload::Results(results))
-        };
+    type InvokeQueryStreamStream = InvokeQueryResponseStream;
 
-        let mut response = InvokeQueryResponse::default();
-        response.set_ret(ret);
-        response.invoke_query_payload = Some(payload);
-        info!("Successfully invoked query");
-
-        self.history_logger.lock().await.log_query(client_id, self.site_id, transaction_id, &invoke_request.write_set, &invoke_request.read_set)
-            .unwrap();
+    async fn invoke_query_stream(&self, request: Request<InvokeQueryRequest>) -> Result<Response<Self::InvokeQueryStreamStream>, Status> {
+        info!("Got streaming invoke query request: {:?}", request.remote_addr());
+        let (batch_sender, batch_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let response = self.run_query(request.into_inner(), Some(&batch_sender)).await;
+        // the last response has the rest of the rows and says how the query finished
+        let _ = batch_sender.send(Ok(response));
 
-        Ok(Response::new(response))
+        let stream: InvokeQueryResponseStream = Box::pin(UnboundedReceiverStream::new(batch_receiver));
+        Ok(Response::new(stream))
     }
 
     async fn finalize_transaction(&self, request: Request<FinalizeTransactionRequest>) -> Result<Response<FinalizeTransactionResponse>, Status> {
@@ -339,7 +727,11 @@ impl SiteManagerService for SddmsSiteManagerService {
         let client_id = finalize_request.client_id;
         info!("Finalizing transaction {} with mode {:?}", finalize_request.transaction_id, finalize_request.mode());
         let finalize_query = match finalize_request.mode() {
-            FinalizeMode::Unspecified => panic!("Unspecified commit method"),
+            FinalizeMode::Unspecified => {
+                let err = SddmsError::site(format!("Transaction {} cannot be finalized without a finalize mode", finalize_request.transaction_id));
+                error!("{}", err);
+                return Ok(Response::new(FinalizeTransactionResponse::from(err)));
+            }
             FinalizeMode::Commit => {
                 "COMMIT"
             }
@@ -348,23 +740,27 @@ impl SiteManagerService for SddmsSiteManagerService {
             }
         };
 
+        if self.transaction_history.lock().await.get_transaction_for_client(client_id, finalize_request.transaction_id).is_none() {
+            let err = SddmsError::site(format!("Client {} has no transaction {} to finalize", client_id, finalize_request.transaction_id));
+            error!("{}", err);
+            return Ok(Response::new(FinalizeTransactionResponse::from(err)));
+        }
+
         // get the connection for the given client
         debug!("Acquiring connection pool lock...");
         {
-            let connection_map_lock = self.client_connections.lock().await;
-            let client_connection = connection_map_lock
-                .get_client_connection(client_id)
-                .unwrap();
+            let connection_map_lock = self.client_connections.read().await;
+            let Some(client_connection) = connection_map_lock.get_client_connection(client_id) else {
+                return Err(Status::not_found(format!("No connection for client {}", client_id)));
+            };
             debug!("Acquired");
 
-            debug!("Invoking query finalization statement...");
-            let result = client_connection.invoke_one_off_stmt(finalize_query).await;
-            if let Err(err) = result {
-                error!("Error while finalizing transaction query: {}", err);
-                let response = FinalizeTransactionResponse::from(err);
-                return Ok(Response::new(response));
+            // commits are made visible to other clients once they're replicated
+            if let FinalizeMode::Abort = finalize_request.mode() {
+                debug!("Rolling back client transaction...");
+                client_connection.rollback_transaction().await;
+                debug!("Rolled back");
             }
-            debug!("Invoked");
         }
 
         self.history_logger.lock().await.log(client_id, self.site_id, finalize_request.transaction_id, finalize_query)
@@ -373,9 +769,12 @@ impl SiteManagerService for SddmsSiteManagerService {
         debug!("Starting to replicate and finalize...");
         let result = self.replicate_and_finalize(client_id, finalize_request.transaction_id, finalize_request.mode()).await;
         let (ret, payload) = match result {
-            Ok(_) => {
+            Ok(None) => {
                 info!("Transaction successfully replicated and finalized");
-                (ReturnStatus::Ok, FinalizeTransactionPayload::Results(FinalizeTransactionResults {}))
+                (ReturnStatus::Ok, FinalizeTransactionPayload::Results(FinalizeTransactionResults::default()))
+            }
+            Ok(Some(warning)) => {
+                (ReturnStatus::PartiallyReplicated, FinalizeTransactionPayload::Results(FinalizeTransactionResults { replication_warning: Some(warning.to_string()) }))
             }
             Err(err) => {
                 error!("Error while finalizing and replicating transaction: {}", err);
@@ -393,8 +792,24 @@ impl SiteManagerService for SddmsSiteManagerService {
     async fn replication_update(&self, request: Request<ReplicationUpdateRequest>) -> Result<Response<ReplicationUpdateResponse>, Status> {
         info!("Got replication request");
         let replicate_update_request = request.into_inner();
-        let mut connections = self.client_connections.lock().await;
-        let replication_error = self.replicate_to_clients(&mut connections, &replicate_update_request.update_statements, None)
+        let version = TransactionVersion::new(replicate_update_request.commit_timestamp_us, replicate_update_request.originating_site, replicate_update_request.transaction_id);
+        let mut replication_conflicts = match &self.replication_conflicts {
+            Some(replication_conflicts) => Some(replication_conflicts.lock().await),
+            None => None,
+        };
+        let update_statements = match replication_conflicts.as_mut() {
+            Some(resolver) => match resolver.resolve(version, &replicate_update_request.update_statements) {
+                Ok(update_statements) => update_statements,
+                Err(err) => {
+                    error!("Failed to resolve replication conflicts for {}: {}", version, err);
+                    return Ok(Response::new(ReplicationUpdateResponse::from(err)));
+                }
+            },
+            None => replicate_update_request.update_statements.clone(),
+        };
+
+        let connections = self.client_connections.read().await;
+        let replication_error = self.replicate_to_clients(&connections, &update_statements)
             .await
             .err();
 
@@ -404,7 +819,7 @@ impl SiteManagerService for SddmsSiteManagerService {
             return Ok(Response::new(response));
         }
 
-        let disk_replication_err = self.replicate_on_disk(&replicate_update_request.update_statements)
+        let disk_replication_err = self.replicate_on_disk(&update_statements)
             .await
             .err();
 
@@ -419,12 +834,525 @@ impl SiteManagerService for SddmsSiteManagerService {
             response.set_ret(ReturnStatus::Ok);
             response.error = None;
 
-            self.history_logger.lock().await.log_replication(replicate_update_request.originating_site, &replicate_update_request.update_statements)
+            self.history_logger.lock().await.log_replication(self.site_id, replicate_update_request.originating_site, &update_statements)
                 .unwrap();
+            self.replication_watermarks.record_applied(replicate_update_request.originating_site, replicate_update_request.transaction_id);
 
             response
         };
+        drop(replication_conflicts);
 
         Ok(Response::new(response))
     }
+
+    async fn get_replication_watermarks(&self, _request: Request<GetReplicationWatermarksRequest>) -> Result<Response<GetReplicationWatermarksResponse>, Status> {
+        let watermarks = self.replication_watermarks.all().into_iter()
+            .map(|(site_id, transaction_id)| ReplicationWatermark { site_id, transaction_id })
+            .collect();
+
+        let mut response = GetReplicationWatermarksResponse {
+            watermarks,
+            ..Default::default()
+        };
+        response.set_ret(ReturnStatus::Ok);
+        Ok(Response::new(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tonic::Request;
+    use sddms_services::shared::{FinalizeMode, ReturnStatus};
+    use sddms_services::site_controller::{BeginTransactionRequest, FinalizeTransactionRequest, GetReplicationWatermarksRequest, InvokeQueryRequest, RegisterClientRequest, ReplicationUpdateRequest, ReplicationWatermark, UnregisterClientRequest};
+    use sddms_services::site_controller::invoke_query_response::InvokeQueryPayload;
+    use sddms_services::site_controller::finalize_transaction_response::FinalizeTransactionPayload;
+    use sddms_services::site_controller::register_client_response::RegisterClientPayload;
+    use sddms_services::site_controller::site_manager_service_server::SiteManagerService;
+    use crate::central_client::CentralClient;
+    use sddms_services::site_controller::unregister_client_response::UnregisterClientPayload;
+    use sddms_shared::history_record::{HistoryEvent, MemoryHistory};
+    use crate::history_logger::{HistoryLogger, MemoryHistoryLogger, NopHistoryLogger};
+    use crate::replication_conflicts::ReplicationConflictPolicy;
+    use tokio_stream::StreamExt;
+    use crate::site_server::{SddmsSiteManagerService, RESULT_BATCH_SIZE};
+
+    async fn register_client(site: &SddmsSiteManagerService) -> u32 {
+        let response = site.register_client(Request::new(RegisterClientRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(RegisterClientPayload::Results(results)) = response.register_client_payload else {
+            panic!("expected the client to be registered");
+        };
+        results.client_id
+    }
+
+    #[tokio::test]
+    async fn unspecified_finalize_mode_is_rejected() {
+        let db_path = std::env::temp_dir().join(format!("sddms-site-finalize-{}.db", std::process::id()));
+        rusqlite::Connection::open(&db_path).unwrap()
+            .execute_batch("CREATE TABLE flights (id INTEGER);")
+            .unwrap();
+        let cc_client = CentralClient::lazy("127.0.0.1:1").unwrap();
+        let site = SddmsSiteManagerService::new(&db_path, None, None, cc_client, 1, Box::new(NopHistoryLogger) as Box<dyn HistoryLogger>).unwrap();
+        let client_id = register_client(&site).await;
+
+        let mut request = FinalizeTransactionRequest::default();
+        request.client_id = client_id;
+        request.transaction_id = 1;
+        request.set_mode(FinalizeMode::Unspecified);
+        let response = site.finalize_transaction(Request::new(request)).await.unwrap().into_inner();
+
+        assert_eq!(response.ret(), ReturnStatus::Error);
+        let Some(FinalizeTransactionPayload::Error(err)) = response.finalize_transaction_payload else {
+            panic!("expected an error payload");
+        };
+        assert!(err.message.contains("finalize mode"));
+
+        // the site is still up and taking requests
+        assert_ne!(register_client(&site).await, client_id);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    /// Makes a read-only site over a fresh database with a flights table
+    fn read_only_site(name: &str) -> (SddmsSiteManagerService, std::path::PathBuf) {
+        read_only_site_with_schema(name, "CREATE TABLE flights (id INTEGER);")
+    }
+
+    fn read_only_site_with_schema(name: &str, init_sql: &str) -> (SddmsSiteManagerService, std::path::PathBuf) {
+        let db_path = std::env::temp_dir().join(format!("sddms-site-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        rusqlite::Connection::open(&db_path).unwrap()
+            .execute_batch(init_sql)
+            .unwrap();
+        let cc_client = CentralClient::lazy("127.0.0.1:1").unwrap();
+        let site = SddmsSiteManagerService::new(&db_path, None, None, cc_client, 1, Box::new(NopHistoryLogger) as Box<dyn HistoryLogger>)
+            .unwrap()
+            .with_read_only(true);
+        (site, db_path)
+    }
+
+    #[tokio::test]
+    async fn unregistering_rolls_back_every_abandoned_transaction() {
+        let db_path = std::env::temp_dir().join(format!("sddms-site-unregister-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        rusqlite::Connection::open(&db_path).unwrap();
+        // nothing listens here, so releasing locks fails for every transaction
+        let cc_client = CentralClient::lazy("127.0.0.1:1").unwrap();
+        let history = MemoryHistory::default();
+        let site = SddmsSiteManagerService::new(&db_path, None, None, cc_client, 1, Box::new(MemoryHistoryLogger::new(history.clone())) as Box<dyn HistoryLogger>)
+            .unwrap();
+        let client_id = register_client(&site).await;
+        site.push_transaction_for_client(client_id, 3).await;
+        site.push_transaction_for_client(client_id, 4).await;
+
+        let response = site.unregister_client(Request::new(UnregisterClientRequest { client_id })).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Error);
+        let Some(UnregisterClientPayload::Error(err)) = response.unregister_client_payload else {
+            panic!("expected an error payload");
+        };
+        assert!(err.message.contains("[3, 4]"), "{}", err.message);
+
+        // the first failure didn't stop the second transaction from being rolled back
+        let mut rolled_back = history.records().into_iter()
+            .filter_map(|record| match record.event {
+                HistoryEvent::Rollback { txn, .. } => Some(txn),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        rolled_back.sort();
+        assert_eq!(rolled_back, vec![3, 4]);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn late_requests_from_unregistered_client_are_not_found() {
+        let db_path = std::env::temp_dir().join(format!("sddms-site-late-request-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        rusqlite::Connection::open(&db_path).unwrap();
+        let cc_client = CentralClient::lazy("127.0.0.1:1").unwrap();
+        let site = SddmsSiteManagerService::new(&db_path, None, None, cc_client, 1, Box::new(NopHistoryLogger) as Box<dyn HistoryLogger>).unwrap();
+        let client_id = register_client(&site).await;
+        let response = site.unregister_client(Request::new(UnregisterClientRequest { client_id })).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Ok);
+
+        let status = site.begin_transaction(Request::new(BeginTransactionRequest { client_id, ..Default::default() })).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        // a transaction that was somehow left behind still can't be finalized without a connection
+        site.push_transaction_for_client(client_id, 3).await;
+        let request = FinalizeTransactionRequest {
+            client_id,
+            transaction_id: 3,
+            mode: FinalizeMode::Abort.into(),
+        };
+        let status = site.finalize_transaction(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finalizing_unknown_transaction_logs_nothing() {
+        let db_path = std::env::temp_dir().join(format!("sddms-site-finalize-unknown-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        rusqlite::Connection::open(&db_path).unwrap();
+        let cc_client = CentralClient::lazy("127.0.0.1:1").unwrap();
+        let history = MemoryHistory::default();
+        let site = SddmsSiteManagerService::new(&db_path, None, None, cc_client, 1, Box::new(MemoryHistoryLogger::new(history.clone())) as Box<dyn HistoryLogger>)
+            .unwrap();
+        let client_id = register_client(&site).await;
+
+        let request = FinalizeTransactionRequest {
+            client_id,
+            transaction_id: 7,
+            mode: FinalizeMode::Commit.into(),
+        };
+        let response = site.finalize_transaction(Request::new(request)).await.unwrap().into_inner();
+
+        assert_eq!(response.ret(), ReturnStatus::Error);
+        let Some(FinalizeTransactionPayload::Error(err)) = response.finalize_transaction_payload else {
+            panic!("expected an error payload");
+        };
+        assert!(err.message.contains("no transaction 7"), "{}", err.message);
+        assert!(history.records().is_empty());
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_site_rejects_writes() {
+        let (site, db_path) = read_only_site("read-only-write");
+        let client_id = register_client(&site).await;
+
+        let response = site.invoke_query(Request::new(InvokeQueryRequest {
+            query: String::from("INSERT INTO flights VALUES (1);"),
+            write_set: vec![String::from("flights")],
+            single_stmt_transaction: true,
+            client_id,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+
+        assert_eq!(response.ret(), ReturnStatus::Error);
+        let Some(InvokeQueryPayload::Error(err)) = response.invoke_query_payload else {
+            panic!("expected an error payload");
+        };
+        assert!(err.message.contains("read-only"), "{}", err.message);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unlocked_reads_skip_the_central_controller() {
+        let db_path = std::env::temp_dir().join(format!("sddms-site-unlocked-read-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        rusqlite::Connection::open(&db_path).unwrap()
+            .execute_batch("CREATE TABLE flights (id INTEGER); INSERT INTO flights VALUES (1);")
+            .unwrap();
+        // nothing listens here, so the read would fail if it tried to take locks
+        let cc_client = CentralClient::lazy("127.0.0.1:1").unwrap();
+        let site = SddmsSiteManagerService::new(&db_path, None, None, cc_client, 1, Box::new(NopHistoryLogger) as Box<dyn HistoryLogger>).unwrap();
+        let client_id = register_client(&site).await;
+
+        let response = site.invoke_query(Request::new(InvokeQueryRequest {
+            query: String::from("SELECT id FROM flights;"),
+            read_set: vec![String::from("flights")],
+            has_results: true,
+            unlocked_read: true,
+            client_id,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Ok);
+        let Some(InvokeQueryPayload::Results(results)) = response.invoke_query_payload else {
+            panic!("expected results");
+        };
+        let rows: Vec<serde_json::Value> = serde_json::from_slice(&results.data_payload.unwrap()).unwrap();
+        assert_eq!(rows, vec![serde_json::json!({ "id": 1 })]);
+
+        let response = site.invoke_query(Request::new(InvokeQueryRequest {
+            query: String::from("INSERT INTO flights VALUES (2);"),
+            write_set: vec![String::from("flights")],
+            unlocked_read: true,
+            client_id,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Error);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_rows_arrive_in_batches() {
+        let row_count = RESULT_BATCH_SIZE * 2 + 10;
+        let schema = format!("CREATE TABLE flights (id INTEGER); WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < {}) INSERT INTO flights SELECT id FROM ids;", row_count);
+        let (site, db_path) = read_only_site_with_schema("stream", &schema);
+        let client_id = register_client(&site).await;
+
+        let batches = site.invoke_query_stream(Request::new(InvokeQueryRequest {
+            query: String::from("SELECT id FROM flights;"),
+            read_set: vec![String::from("flights")],
+            has_results: true,
+            unlocked_read: true,
+            client_id,
+            ..Default::default()
+        })).await.unwrap().into_inner()
+            .collect::<Vec<_>>().await;
+
+        let batch_sizes = batches.into_iter()
+            .map(|batch| {
+                let batch = batch.unwrap();
+                assert_eq!(batch.ret(), ReturnStatus::Ok);
+                let Some(InvokeQueryPayload::Results(results)) = batch.invoke_query_payload else {
+                    panic!("expected every batch to have results");
+                };
+                assert_eq!(results.column_names, vec![String::from("id")]);
+                let rows: Vec<serde_json::Value> = serde_json::from_slice(&results.data_payload.unwrap()).unwrap();
+                rows.len()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(batch_sizes, vec![RESULT_BATCH_SIZE, RESULT_BATCH_SIZE, 10]);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_queries_share_the_connection_map() {
+        let (site, db_path) = read_only_site_with_schema("concurrent", "CREATE TABLE flights (id INTEGER); INSERT INTO flights VALUES (1);");
+        let mut client_ids = Vec::new();
+        for _ in 0..16 {
+            client_ids.push(register_client(&site).await);
+        }
+
+        let site = Arc::new(site);
+        let tasks = client_ids.into_iter()
+            .flat_map(|client_id| [client_id; 8])
+            .map(|client_id| {
+                let site = site.clone();
+                tokio::spawn(async move {
+                    site.invoke_query(Request::new(InvokeQueryRequest {
+                        query: String::from("SELECT id FROM flights;"),
+                        read_set: vec![String::from("flights")],
+                        has_results: true,
+                        unlocked_read: true,
+                        client_id,
+                        ..Default::default()
+                    })).await.unwrap().into_inner()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            let response = task.await.unwrap();
+            assert_eq!(response.ret(), ReturnStatus::Ok);
+            let Some(InvokeQueryPayload::Results(results)) = response.invoke_query_payload else {
+                panic!("expected results");
+            };
+            let rows: Vec<serde_json::Value> = serde_json::from_slice(&results.data_payload.unwrap()).unwrap();
+            assert_eq!(rows, vec![serde_json::json!({ "id": 1 })]);
+        }
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_site_applies_replicated_writes() {
+        let (site, db_path) = read_only_site("read-only-replication");
+        register_client(&site).await;
+
+        let response = site.replication_update(Request::new(ReplicationUpdateRequest {
+            update_statements: vec![String::from("INSERT INTO flights VALUES (1);")],
+            originating_site: 2,
+            transaction_id: 1,
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Ok);
+
+        let count: i64 = rusqlite::Connection::open(&db_path).unwrap()
+            .query_row("SELECT COUNT(*) FROM flights", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    /// A read of the flights table that waits for the given transaction from site 2
+    fn read_after_request(client_id: u32, transaction_id: u32) -> InvokeQueryRequest {
+        InvokeQueryRequest {
+            query: String::from("SELECT id FROM flights;"),
+            read_set: vec![String::from("flights")],
+            has_results: true,
+            unlocked_read: true,
+            client_id,
+            read_after: Some(ReplicationWatermark { site_id: 2, transaction_id }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_wait_for_the_replication_watermark() {
+        let (site, db_path) = read_only_site("read-after");
+        let client_id = register_client(&site).await;
+
+        let read = site.invoke_query(Request::new(read_after_request(client_id, 7)));
+        let replicate = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            site.replication_update(Request::new(ReplicationUpdateRequest {
+                update_statements: vec![String::from("INSERT INTO flights VALUES (1);")],
+                originating_site: 2,
+                transaction_id: 7,
+                ..Default::default()
+            })).await.unwrap().into_inner()
+        };
+        let (response, replication) = tokio::join!(read, replicate);
+        assert_eq!(replication.ret(), ReturnStatus::Ok);
+
+        // the read only ran once the replicated row was there
+        let response = response.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Ok);
+        let Some(InvokeQueryPayload::Results(results)) = response.invoke_query_payload else {
+            panic!("expected results");
+        };
+        let rows: Vec<serde_json::Value> = serde_json::from_slice(&results.data_payload.unwrap()).unwrap();
+        assert_eq!(rows, vec![serde_json::json!({ "id": 1 })]);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_give_up_when_the_replication_never_arrives() {
+        let (site, db_path) = read_only_site("read-after-timeout");
+        let site = site.with_catchup_timeout(Duration::from_millis(20));
+        let client_id = register_client(&site).await;
+
+        let response = site.invoke_query(Request::new(read_after_request(client_id, 3))).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Error);
+        let Some(InvokeQueryPayload::Error(err)) = response.invoke_query_payload else {
+            panic!("expected an error payload");
+        };
+        assert!(err.message.contains("2:3"), "{}", err.message);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replications_advance_the_watermark() {
+        let (site, db_path) = read_only_site("watermark");
+        register_client(&site).await;
+
+        for transaction_id in [3, 4] {
+            let response = site.replication_update(Request::new(ReplicationUpdateRequest {
+                update_statements: vec![format!("INSERT INTO flights VALUES ({});", transaction_id)],
+                originating_site: 2,
+                transaction_id,
+                ..Default::default()
+            })).await.unwrap().into_inner();
+            assert_eq!(response.ret(), ReturnStatus::Ok);
+        }
+
+        let response = site.get_replication_watermarks(Request::new(GetReplicationWatermarksRequest::default())).await.unwrap().into_inner();
+        assert_eq!(response.ret(), ReturnStatus::Ok);
+        assert_eq!(response.watermarks, vec![ReplicationWatermark { site_id: 2, transaction_id: 4 }]);
+
+        drop(site);
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    /// Applies replications to a site in the given order, giving back the row left in flights
+    async fn apply_replications(site: &SddmsSiteManagerService, db_path: &std::path::Path, replications: &[ReplicationUpdateRequest]) -> i64 {
+        for replication in replications {
+            let response = site.replication_update(Request::new(replication.clone())).await.unwrap().into_inner();
+            assert_eq!(response.ret(), ReturnStatus::Ok);
+        }
+
+        rusqlite::Connection::open(db_path).unwrap()
+            .query_row("SELECT id FROM flights", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn out_of_order_replications_converge() {
+        let replication = |statement: &str, commit_timestamp_us: u64, originating_site: u32| ReplicationUpdateRequest {
+            update_statements: vec![statement.to_string()],
+            originating_site,
+            transaction_id: 1,
+            commit_timestamp_us,
+        };
+        let insert = replication("INSERT INTO flights VALUES (1);", 100, 2);
+        let older = replication("UPDATE flights SET id = 2;", 200, 3);
+        let newer = replication("UPDATE flights SET id = 3;", 300, 4);
+
+        let (in_order, in_order_path) = read_only_site("lww-in-order");
+        let in_order = in_order.with_replication_conflict_policy(ReplicationConflictPolicy::LastWriterWins);
+        let (reordered, reordered_path) = read_only_site("lww-reordered");
+        let reordered = reordered.with_replication_conflict_policy(ReplicationConflictPolicy::LastWriterWins);
+
+        let in_order_id = apply_replications(&in_order, &in_order_path, &[insert.clone(), older.clone(), newer.clone()]).await;
+        let reordered_id = apply_replications(&reordered, &reordered_path, &[insert.clone(), newer.clone(), older.clone()]).await;
+        assert_eq!((in_order_id, reordered_id), (3, 3));
+
+        // applying in arrival order lets the late write win instead
+        let (arrival, arrival_path) = read_only_site("arrival-order");
+        assert_eq!(apply_replications(&arrival, &arrival_path, &[insert, newer, older]).await, 2);
+
+        drop((in_order, reordered, arrival));
+        for path in [in_order_path, reordered_path, arrival_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn late_writes_to_other_rows_still_land() {
+        let replication = |statements: &[&str], commit_timestamp_us: u64, originating_site: u32| ReplicationUpdateRequest {
+            update_statements: statements.iter().map(|statement| statement.to_string()).collect(),
+            originating_site,
+            transaction_id: 1,
+            commit_timestamp_us,
+        };
+        let seed = replication(&["INSERT INTO flights (id, seats) VALUES (1, 10), (3, 20);"], 100, 2);
+        let older = replication(&["UPDATE flights SET seats = 5 WHERE id = 1;", "INSERT INTO flights (id, seats) VALUES (2, 7);"], 200, 3);
+        let relative = replication(&["UPDATE flights SET seats = seats - 1 WHERE id = 3;"], 250, 5);
+        let newer = replication(&["UPDATE flights SET seats = 3 WHERE id = 1;"], 300, 4);
+
+        let schema = "CREATE TABLE flights (id INTEGER PRIMARY KEY, seats INTEGER);";
+        let mut rows = Vec::new();
+        let mut paths = Vec::new();
+        for (name, order) in [("lww-rows-in-order", [&seed, &older, &relative, &newer]), ("lww-rows-reordered", [&seed, &newer, &relative, &older])] {
+            let (site, db_path) = read_only_site_with_schema(name, schema);
+            let site = site.with_replication_conflict_policy(ReplicationConflictPolicy::LastWriterWins);
+            for replication in order {
+                let response = site.replication_update(Request::new(replication.clone())).await.unwrap().into_inner();
+                assert_eq!(response.ret(), ReturnStatus::Ok);
+            }
+
+            let site_rows = rusqlite::Connection::open(&db_path).unwrap()
+                .prepare("SELECT id, seats FROM flights ORDER BY id").unwrap()
+                .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))).unwrap()
+                .collect::<Result<Vec<_>, _>>().unwrap();
+            rows.push(site_rows);
+            paths.push(db_path);
+        }
+
+        // the late update to flight 1 is skipped, but the insert and the update to flight 3 aren't
+        assert_eq!(rows[0], vec![(1, 3), (2, 7), (3, 19)]);
+        assert_eq!(rows[1], rows[0]);
+
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
 }
@@ -0,0 +1,164 @@
+use std::fmt::{Debug, Formatter};
+use std::panic::RefUnwindSafe;
+use std::path::PathBuf;
+use std::sync::Arc;
+use rusqlite::{Connection, LoadExtensionGuard};
+use rusqlite::functions::{Context, FunctionFlags};
+use rusqlite::types::Value;
+use sddms_shared::error::SddmsError;
+
+type ScalarFunctionImpl = dyn Fn(&Context<'_>) -> rusqlite::Result<Value> + Send + Sync + RefUnwindSafe;
+
+/// A Rust function that queries can call like any other SQL function
+#[derive(Clone)]
+struct ScalarFunction {
+    name: String,
+    /// how many arguments it takes, or -1 for any number
+    arg_count: i32,
+    function: Arc<ScalarFunctionImpl>,
+}
+
+impl Debug for ScalarFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.name, self.arg_count)
+    }
+}
+
+/// SQLite extension libraries and custom functions added to every connection the site opens, so that
+/// they can be used in queries and in replicated updates alike
+#[derive(Debug, Default, Clone)]
+pub struct SqliteExtensions {
+    paths: Vec<PathBuf>,
+    functions: Vec<ScalarFunction>,
+}
+
+impl SqliteExtensions {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        Self {
+            paths,
+            functions: Vec::new(),
+        }
+    }
+
+    /// Adds a scalar function. It has to give the same result for the same arguments, since updates are
+    /// replayed on every site and would otherwise diverge
+    pub fn with_scalar_function<FuncT>(mut self, name: &str, arg_count: i32, function: FuncT) -> Self
+        where FuncT: Fn(&Context<'_>) -> rusqlite::Result<Value> + Send + Sync + RefUnwindSafe + 'static {
+        self.functions.push(ScalarFunction {
+            name: name.to_string(),
+            arg_count,
+            function: Arc::new(function),
+        });
+        self
+    }
+
+    /// Loads the extension libraries into the connection, then registers the custom functions on it
+    pub fn load_into(&self, connection: &Connection) -> Result<(), SddmsError> {
+        self.load_libraries(connection)?;
+
+        for scalar_function in &self.functions {
+            let function = scalar_function.function.clone();
+            connection.create_scalar_function(&scalar_function.name, scalar_function.arg_count, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |context| function(context))
+                .map_err(|err| SddmsError::site(format!("Failed to register SQL function {:?}", scalar_function)).with_cause(err))?;
+        }
+
+        Ok(())
+    }
+
+    fn load_libraries(&self, connection: &Connection) -> Result<(), SddmsError> {
+        if self.paths.is_empty() {
+            return Ok(());
+        }
+
+        // extension loading is only turned on while ours are loaded, so queries can't load their own
+        let _guard = unsafe { LoadExtensionGuard::new(connection) }
+            .map_err(|err| SddmsError::site("Failed to enable loading SQLite extensions").with_cause(err))?;
+
+        for path in &self.paths {
+            unsafe { connection.load_extension(path, None) }
+                .map_err(|err| SddmsError::site(format!("Failed to load SQLite extension {}", path.display())).with_cause(err))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::process::Command;
+    use rusqlite::Connection;
+    use rusqlite::types::Value;
+    use crate::sqlite_extensions::SqliteExtensions;
+
+    /// an extension with a single `half(x)` function
+    const HALF_EXTENSION: &str = r#"
+#include <sqlite3ext.h>
+SQLITE_EXTENSION_INIT1
+
+static void half(sqlite3_context *context, int argc, sqlite3_value **argv) {
+    sqlite3_result_double(context, sqlite3_value_double(argv[0]) / 2.0);
+}
+
+int sqlite3_extension_init(sqlite3 *db, char **err, const sqlite3_api_routines *api) {
+    SQLITE_EXTENSION_INIT2(api);
+    return sqlite3_create_function(db, "half", 1, SQLITE_UTF8 | SQLITE_DETERMINISTIC, 0, half, 0, 0);
+}
+"#;
+
+    /// Builds the test extension, or returns nothing if there's no C compiler to build it with
+    fn build_half_extension() -> Option<PathBuf> {
+        let dir = std::env::temp_dir();
+        let source_path = dir.join(format!("sddms-half-{}.c", std::process::id()));
+        let library_path = dir.join(format!("sddms-half-{}.so", std::process::id()));
+        std::fs::write(&source_path, HALF_EXTENSION).unwrap();
+
+        let built = Command::new("cc")
+            .args(["-shared", "-fPIC", "-o"])
+            .arg(&library_path)
+            .arg(&source_path)
+            .status()
+            .is_ok_and(|status| status.success());
+        std::fs::remove_file(source_path).unwrap();
+
+        built.then_some(library_path)
+    }
+
+    #[test]
+    fn extension_functions_can_be_queried() {
+        let Some(library_path) = build_half_extension() else {
+            eprintln!("Skipping, since the test extension could not be built");
+            return;
+        };
+
+        let connection = Connection::open_in_memory().unwrap();
+        SqliteExtensions::new(vec![library_path.clone()]).load_into(&connection).unwrap();
+        let halved: f64 = connection.query_row("SELECT half(9);", [], |row| row.get(0)).unwrap();
+        assert_eq!(halved, 4.5);
+
+        std::fs::remove_file(library_path).unwrap();
+    }
+
+    #[test]
+    fn scalar_functions_can_be_queried() {
+        let extensions = SqliteExtensions::default()
+            .with_scalar_function("shout", 1, |context| {
+                let text = context.get::<String>(0)?;
+                Ok(Value::Text(text.to_uppercase()))
+            });
+
+        let connection = Connection::open_in_memory().unwrap();
+        extensions.load_into(&connection).unwrap();
+        let shouted: String = connection.query_row("SELECT shout('hello');", [], |row| row.get(0)).unwrap();
+        assert_eq!(shouted, "HELLO");
+    }
+
+    #[test]
+    fn missing_extension_is_a_clear_error() {
+        let connection = Connection::open_in_memory().unwrap();
+        let err = SqliteExtensions::new(vec![PathBuf::from("/nonexistent/sddms-extension.so")])
+            .load_into(&connection)
+            .unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/sddms-extension.so"), "{}", err);
+    }
+}
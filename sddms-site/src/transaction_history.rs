@@ -64,6 +64,16 @@ pub struct TransactionHistoryMap {
 
 impl TransactionHistoryMap {
 
+    /// How many transactions are in progress
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// True if no transactions are in progress
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
     pub fn push_transaction(&mut self, client_id: u32, trans_id: u32) {
         let full_trans_id = TransactionId::new(trans_id, client_id);
         self.transactions.insert(full_trans_id, TransactionHistory::new(client_id, trans_id));
@@ -74,6 +84,18 @@ impl TransactionHistoryMap {
         self.transactions.remove(&trans_id)
     }
     
+    /// Removes every transaction belonging to the given client
+    pub fn remove_client_transactions(&mut self, client_id: u32) -> Vec<TransactionHistory> {
+        let client_trans_ids = self.transactions.keys()
+            .filter(|trans_id| trans_id.client_id == client_id)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        client_trans_ids.into_iter()
+            .filter_map(|trans_id| self.transactions.remove(&trans_id))
+            .collect()
+    }
+
     pub fn get_transaction_for_client(&self, client_id: u32, transaction_id: u32) -> Option<&TransactionHistory> {
         let trans_id = TransactionId::new(transaction_id, client_id);
         self.transactions.get(&trans_id)
@@ -0,0 +1,341 @@
+//! Runs a site against a real central controller served in-process, so that whole transactions can be
+//! followed from the site's client API through the central controller's lock table and back
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use rusqlite::Connection;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::Request;
+use sddms_central::central_service::CentralService;
+use sddms_central::transaction_id::TransactionIdGenerator;
+use sddms_services::central_controller::concurrency_controller_service_server::ConcurrencyControllerServiceServer;
+use sddms_services::shared::{FinalizeMode, ReturnStatus};
+use sddms_services::site_controller::{BeginTransactionRequest, FinalizeTransactionRequest, InvokeQueryRequest, InvokeQueryResponse, RegisterClientRequest, UnregisterClientRequest};
+use sddms_services::site_controller::begin_transaction_response::BeginTransactionPayload;
+use sddms_services::site_controller::finalize_transaction_response::FinalizeTransactionPayload;
+use sddms_services::site_controller::invoke_query_response::InvokeQueryPayload;
+use sddms_services::site_controller::register_client_response::RegisterClientPayload;
+use sddms_services::site_controller::site_manager_service_server::SiteManagerService;
+use sddms_site::central_client::CentralClient;
+use sddms_site::history_logger::{FileHistoryLogger, HistoryLogger, NopHistoryLogger};
+use sddms_site::initialize_database;
+use sddms_site::site_server::SddmsSiteManagerService;
+
+/// Serves a fresh central controller on an ephemeral port
+async fn spawn_central() -> (SocketAddr, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = CentralService::new(0, TransactionIdGenerator::new(None).unwrap(), None);
+    let server = tokio::spawn(async move {
+        tonic::transport::Server::builder()
+            .add_service(ConcurrencyControllerServiceServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+            .unwrap();
+    });
+    (addr, server)
+}
+
+/// A site with an in-memory database, registered with the central controller at `central_addr`. The
+/// returned connection keeps the database alive and can be used to look at what the site wrote
+async fn connect_site(name: &str, central_addr: SocketAddr, init_sql: &str) -> (SddmsSiteManagerService, Connection) {
+    connect_site_with_logger(name, central_addr, init_sql, Box::new(NopHistoryLogger)).await
+}
+
+/// Like `connect_site`, but writes the site's history with the given logger
+async fn connect_site_with_logger(name: &str, central_addr: SocketAddr, init_sql: &str, logger: Box<dyn HistoryLogger>) -> (SddmsSiteManagerService, Connection) {
+    let db_path = PathBuf::from(format!("file:sddms-{}-{}?mode=memory&cache=shared", name, std::process::id()));
+    let keeper = initialize_database(&db_path, init_sql).unwrap();
+
+    let cc_client = CentralClient::new(&central_addr.to_string(), None).await.unwrap();
+    // the site is never served, which is fine as long as no other site replicates to it
+    let site_id = cc_client.register_self("127.0.0.1", 0).await.unwrap();
+    let site = SddmsSiteManagerService::new(&db_path, None, None, cc_client, site_id, logger).unwrap();
+    (site, keeper)
+}
+
+async fn register_client(site: &SddmsSiteManagerService) -> u32 {
+    let response = site.register_client(Request::new(RegisterClientRequest::default())).await.unwrap().into_inner();
+    let Some(RegisterClientPayload::Results(results)) = response.register_client_payload else {
+        panic!("expected the client to be registered");
+    };
+    results.client_id
+}
+
+async fn begin_transaction(site: &SddmsSiteManagerService, client_id: u32) -> u32 {
+    let response = site.begin_transaction(Request::new(BeginTransactionRequest { client_id, ..Default::default() })).await.unwrap().into_inner();
+    let Some(BeginTransactionPayload::Value(results)) = response.begin_transaction_payload else {
+        panic!("expected a transaction to start");
+    };
+    results.transaction_id
+}
+
+async fn insert(site: &SddmsSiteManagerService, client_id: u32, transaction_id: u32, query: &str, no_wait: bool) -> InvokeQueryResponse {
+    site.invoke_query(Request::new(InvokeQueryRequest {
+        query: query.to_string(),
+        write_set: vec![String::from("flights")],
+        client_id,
+        transaction_id,
+        no_wait,
+        ..Default::default()
+    })).await.unwrap().into_inner()
+}
+
+async fn finalize(site: &SddmsSiteManagerService, client_id: u32, transaction_id: u32, mode: FinalizeMode) {
+    let mut request = FinalizeTransactionRequest { client_id, transaction_id, ..Default::default() };
+    request.set_mode(mode);
+    let response = site.finalize_transaction(Request::new(request)).await.unwrap().into_inner();
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+}
+
+#[tokio::test]
+async fn committed_transaction_lands_and_releases_its_locks() {
+    let (central_addr, central) = spawn_central().await;
+    let (site, keeper) = connect_site("round-trip", central_addr, "CREATE TABLE flights (id INTEGER);").await;
+    let writer = register_client(&site).await;
+    let other = register_client(&site).await;
+
+    let transaction_id = begin_transaction(&site, writer).await;
+    let response = insert(&site, writer, transaction_id, "INSERT INTO flights VALUES (1);", false).await;
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+
+    // the writer holds flights exclusively until it finishes
+    let other_transaction = begin_transaction(&site, other).await;
+    let blocked = insert(&site, other, other_transaction, "INSERT INTO flights VALUES (2);", true).await;
+    assert_eq!(blocked.ret(), ReturnStatus::WouldBlock);
+
+    finalize(&site, writer, transaction_id, FinalizeMode::Commit).await;
+    let flight_ids = keeper.prepare("SELECT id FROM flights;").unwrap()
+        .query_map([], |row| row.get::<_, i64>(0)).unwrap()
+        .collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(flight_ids, vec![1]);
+
+    // now that the locks are released the other transaction can go ahead
+    let unblocked = insert(&site, other, other_transaction, "INSERT INTO flights VALUES (2);", true).await;
+    assert_eq!(unblocked.ret(), ReturnStatus::Ok);
+    finalize(&site, other, other_transaction, FinalizeMode::Commit).await;
+
+    let flight_count: i64 = keeper.query_row("SELECT COUNT(*) FROM flights;", [], |row| row.get(0)).unwrap();
+    assert_eq!(flight_count, 2);
+
+    central.abort();
+}
+
+#[tokio::test]
+async fn query_that_waited_reports_its_queue_position() {
+    let (central_addr, central) = spawn_central().await;
+    let (site, _keeper) = connect_site("queue-position", central_addr, "CREATE TABLE flights (id INTEGER);").await;
+    let writer = register_client(&site).await;
+    let waiter = register_client(&site).await;
+
+    let writer_transaction = begin_transaction(&site, writer).await;
+    let response = insert(&site, writer, writer_transaction, "INSERT INTO flights VALUES (1);", false).await;
+    let Some(InvokeQueryPayload::Results(results)) = response.invoke_query_payload else {
+        panic!("expected results");
+    };
+    assert_eq!(results.lock_queue_position, 0);
+
+    // the waiter queues up behind the writer's lock until the writer commits
+    let waiter_transaction = begin_transaction(&site, waiter).await;
+    let (waited, _) = tokio::join!(
+        insert(&site, waiter, waiter_transaction, "INSERT INTO flights VALUES (2);", false),
+        async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            finalize(&site, writer, writer_transaction, FinalizeMode::Commit).await;
+        },
+    );
+    assert_eq!(waited.ret(), ReturnStatus::Ok);
+    let Some(InvokeQueryPayload::Results(results)) = waited.invoke_query_payload else {
+        panic!("expected results");
+    };
+    assert_eq!(results.lock_queue_position, 1);
+    finalize(&site, waiter, waiter_transaction, FinalizeMode::Commit).await;
+
+    central.abort();
+}
+
+#[tokio::test]
+async fn aborted_transaction_leaves_nothing_behind() {
+    let (central_addr, central) = spawn_central().await;
+    let (site, keeper) = connect_site("abort", central_addr, "CREATE TABLE flights (id INTEGER);").await;
+    let client_id = register_client(&site).await;
+
+    let transaction_id = begin_transaction(&site, client_id).await;
+    let response = insert(&site, client_id, transaction_id, "INSERT INTO flights VALUES (1);", false).await;
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+    finalize(&site, client_id, transaction_id, FinalizeMode::Abort).await;
+
+    let flight_count: i64 = keeper.query_row("SELECT COUNT(*) FROM flights;", [], |row| row.get(0)).unwrap();
+    assert_eq!(flight_count, 0);
+
+    // the aborted transaction's locks are gone too
+    let next_transaction = begin_transaction(&site, client_id).await;
+    let response = insert(&site, client_id, next_transaction, "INSERT INTO flights VALUES (2);", true).await;
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+
+    central.abort();
+}
+
+#[tokio::test]
+async fn single_stmt_transaction_is_logged_with_begin_and_commit() {
+    let history_path = std::env::temp_dir().join(format!("sddms-site-single-stmt-{}.history", std::process::id()));
+    let logger = FileHistoryLogger::open(&history_path, true).unwrap();
+    let (central_addr, central) = spawn_central().await;
+    let (site, _keeper) = connect_site_with_logger("single-stmt", central_addr, "CREATE TABLE flights (id INTEGER);", Box::new(logger)).await;
+    let client_id = register_client(&site).await;
+
+    let response = site.invoke_query(Request::new(InvokeQueryRequest {
+        query: String::from("INSERT INTO flights VALUES (1);"),
+        write_set: vec![String::from("flights")],
+        single_stmt_transaction: true,
+        client_id,
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+    drop(site);
+
+    let history = std::fs::read_to_string(&history_path).unwrap();
+    let actions = history.lines()
+        .map(|line| line.split_once(": ").unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(actions, vec!["Begin Txn", "Write([\"flights\"])", "COMMIT"]);
+    // all three lines belong to the same transaction
+    let transactions = history.lines()
+        .map(|line| line.split_once(" | ").unwrap().1.split_once(": ").unwrap().0)
+        .collect::<std::collections::HashSet<_>>();
+    assert_eq!(transactions.len(), 1);
+
+    std::fs::remove_file(history_path).unwrap();
+    central.abort();
+}
+
+/// Reads back the actions a file history logger wrote for one transaction, in order
+fn transaction_actions(history_path: &std::path::Path, transaction_id: u32) -> Vec<String> {
+    let transaction = format!("txn={}", transaction_id);
+    std::fs::read_to_string(history_path).unwrap()
+        .lines()
+        .filter_map(|line| line.split_once(" | ").unwrap().1.split_once(": "))
+        .filter(|(ids, _)| ids.ends_with(&transaction))
+        .map(|(_, action)| action.to_string())
+        .collect()
+}
+
+async fn single_stmt_insert(site: &SddmsSiteManagerService, client_id: u32, query: &str, no_wait: bool) -> InvokeQueryResponse {
+    site.invoke_query(Request::new(InvokeQueryRequest {
+        query: query.to_string(),
+        write_set: vec![String::from("flights")],
+        single_stmt_transaction: true,
+        client_id,
+        no_wait,
+        ..Default::default()
+    })).await.unwrap().into_inner()
+}
+
+#[tokio::test]
+async fn failed_single_stmt_query_is_aborted_everywhere() {
+    let history_path = std::env::temp_dir().join(format!("sddms-site-failed-single-stmt-{}.history", std::process::id()));
+    let logger = FileHistoryLogger::open(&history_path, true).unwrap();
+    let (central_addr, central) = spawn_central().await;
+    let (site, _keeper) = connect_site_with_logger("failed-single-stmt", central_addr, "CREATE TABLE flights (id INTEGER);", Box::new(logger)).await;
+    let client_id = register_client(&site).await;
+
+    // the locks are taken, but the query itself fails
+    let response = single_stmt_insert(&site, client_id, "INSERT INTO flights VALUES (1, 2);", false).await;
+    assert_eq!(response.ret(), ReturnStatus::Error);
+
+    // the central controller let go of the failed transaction's locks
+    let transaction_id = begin_transaction(&site, client_id).await;
+    let response = insert(&site, client_id, transaction_id, "INSERT INTO flights VALUES (1);", true).await;
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+    finalize(&site, client_id, transaction_id, FinalizeMode::Commit).await;
+
+    // the failed transaction came before this one, and nothing of it is left for unregistering to roll back
+    let response = site.unregister_client(Request::new(UnregisterClientRequest { client_id })).await.unwrap().into_inner();
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+    drop(site);
+
+    assert_eq!(transaction_actions(&history_path, transaction_id - 1), vec!["Begin Txn", "ROLLBACK"]);
+    std::fs::remove_file(history_path).unwrap();
+    central.abort();
+}
+
+#[tokio::test]
+async fn single_stmt_query_that_cannot_lock_is_aborted() {
+    let history_path = std::env::temp_dir().join(format!("sddms-site-blocked-single-stmt-{}.history", std::process::id()));
+    let logger = FileHistoryLogger::open(&history_path, true).unwrap();
+    let (central_addr, central) = spawn_central().await;
+    let (site, _keeper) = connect_site_with_logger("blocked-single-stmt", central_addr, "CREATE TABLE flights (id INTEGER);", Box::new(logger)).await;
+    let holder = register_client(&site).await;
+    let blocked = register_client(&site).await;
+
+    let holder_transaction = begin_transaction(&site, holder).await;
+    let response = insert(&site, holder, holder_transaction, "INSERT INTO flights VALUES (1);", false).await;
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+
+    let response = single_stmt_insert(&site, blocked, "INSERT INTO flights VALUES (2);", true).await;
+    assert_eq!(response.ret(), ReturnStatus::WouldBlock);
+    finalize(&site, holder, holder_transaction, FinalizeMode::Commit).await;
+
+    let response = site.unregister_client(Request::new(UnregisterClientRequest { client_id: blocked })).await.unwrap().into_inner();
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+    drop(site);
+
+    // the blocked transaction was provisioned right after the holder's
+    assert_eq!(transaction_actions(&history_path, holder_transaction + 1), vec!["Begin Txn", "ROLLBACK"]);
+    std::fs::remove_file(history_path).unwrap();
+    central.abort();
+}
+
+#[tokio::test]
+async fn commit_that_misses_a_site_still_commits() {
+    let (central_addr, central) = spawn_central().await;
+    let (site, keeper) = connect_site("partial", central_addr, "CREATE TABLE flights (id INTEGER);").await;
+    // registered but never served, so replicating to it fails
+    let (_unreachable, _unreachable_keeper) = connect_site("partial-unreachable", central_addr, "CREATE TABLE flights (id INTEGER);").await;
+    let client_id = register_client(&site).await;
+
+    let transaction_id = begin_transaction(&site, client_id).await;
+    let response = insert(&site, client_id, transaction_id, "INSERT INTO flights VALUES (1);", false).await;
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+
+    let mut request = FinalizeTransactionRequest { client_id, transaction_id, ..Default::default() };
+    request.set_mode(FinalizeMode::Commit);
+    let response = site.finalize_transaction(Request::new(request.clone())).await.unwrap().into_inner();
+    assert_eq!(response.ret(), ReturnStatus::PartiallyReplicated);
+    let Some(FinalizeTransactionPayload::Results(results)) = response.finalize_transaction_payload else {
+        panic!("expected the commit to succeed with a warning");
+    };
+    assert!(results.replication_warning.is_some());
+
+    let flight_count: i64 = keeper.query_row("SELECT COUNT(*) FROM flights;", [], |row| row.get(0)).unwrap();
+    assert_eq!(flight_count, 1);
+
+    // it's finalized, so committing it again is an error instead of a second commit
+    let retried = site.finalize_transaction(Request::new(request)).await.unwrap().into_inner();
+    assert_eq!(retried.ret(), ReturnStatus::Error);
+
+    // and its locks were released
+    let next_transaction = begin_transaction(&site, client_id).await;
+    let response = insert(&site, client_id, next_transaction, "INSERT INTO flights VALUES (2);", true).await;
+    assert_eq!(response.ret(), ReturnStatus::Ok);
+    finalize(&site, client_id, next_transaction, FinalizeMode::Abort).await;
+
+    // single statement transactions give their results back along with the warning
+    let response = site.invoke_query(Request::new(InvokeQueryRequest {
+        query: String::from("INSERT INTO flights VALUES (3);"),
+        write_set: vec![String::from("flights")],
+        single_stmt_transaction: true,
+        client_id,
+        ..Default::default()
+    })).await.unwrap().into_inner();
+    assert_eq!(response.ret(), ReturnStatus::PartiallyReplicated);
+    let Some(InvokeQueryPayload::Results(results)) = response.invoke_query_payload else {
+        panic!("expected the query's results");
+    };
+    assert_eq!(results.affected_records, Some(1));
+    assert!(results.replication_warning.is_some());
+
+    central.abort();
+}
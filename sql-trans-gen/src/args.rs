@@ -1,5 +1,6 @@
 use std::path::PathBuf;
 use clap::Parser;
+use crate::config::{OperationWeights, TextCharset, TransactionMode, DEFAULT_MAX_ROWS, DEFAULT_MIN_ROWS, DEFAULT_MULTI_PROBABILITY};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -10,6 +11,43 @@ pub struct Args {
     /// Where to write the output to. Defaults to stdout
     #[arg(short, long)]
     pub output: Option<PathBuf>,
+    /// characters that generated text values are made of
+    #[arg(long, value_enum, default_value_t = TextCharset::Alphanumeric)]
+    pub text_charset: TextCharset,
+    /// how often SELECT statements are generated, relative to the other kinds
+    #[arg(long, default_value = "1")]
+    pub select_weight: u32,
+    /// how often UPDATE statements are generated, relative to the other kinds
+    #[arg(long, default_value = "1")]
+    pub update_weight: u32,
+    /// how often INSERT statements are generated, relative to the other kinds
+    #[arg(long, default_value = "1")]
+    pub insert_weight: u32,
+    /// how likely a SELECT is to join in a table that it references, between 0 and 1
+    #[arg(long, default_value = "0")]
+    pub join_probability: f64,
+    /// which kinds of transactions are generated
+    #[arg(long, value_enum, default_value_t = TransactionMode::Mixed)]
+    pub transaction_mode: TransactionMode,
+    /// how likely a transaction is to have multiple statements in mixed mode, between 0 and 1
+    #[arg(long, default_value_t = DEFAULT_MULTI_PROBABILITY)]
+    pub multi_probability: f64,
+    /// the fewest rows an INSERT adds
+    #[arg(long, default_value_t = DEFAULT_MIN_ROWS)]
+    pub min_rows: usize,
+    /// the most rows an INSERT adds
+    #[arg(long, default_value_t = DEFAULT_MAX_ROWS)]
+    pub max_rows: usize,
     /// path to the sqlite db to open. Creates if it doesn't exist
     pub db_path: PathBuf,
 }
+
+impl Args {
+    pub fn operation_weights(&self) -> OperationWeights {
+        OperationWeights {
+            select: self.select_weight,
+            update: self.update_weight,
+            insert: self.insert_weight,
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::f64;
 use rusqlite::types::Type;
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -53,7 +54,10 @@ pub struct TextGenRule {
     /// A regex to use to generate values
     pub format: Option<String>,
     /// Character classes available to choose characters from
-    pub available_char_classes: Option<HashSet<String>>
+    pub available_char_classes: Option<HashSet<String>>,
+    /// characters that randomly generated text is made of when there's no format
+    #[serde(default)]
+    pub charset: TextCharset,
 }
 
 impl Default for TextGenRule {
@@ -62,11 +66,25 @@ impl Default for TextGenRule {
             min_len: 3,
             max_len: 15,
             format: None,
-            available_char_classes: None
+            available_char_classes: None,
+            charset: TextCharset::default(),
         }
     }
 }
 
+/// The characters random text is drawn from
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TextCharset {
+    /// letters and digits
+    #[default]
+    Alphanumeric,
+    /// any printable ASCII character, including spaces, quotes and other punctuation. Semicolons are
+    /// left out because generated scripts are split into statements on them
+    #[value(name = "ascii_printable")]
+    AsciiPrintable,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct IntegerGenRule {
     pub min: i64,
@@ -114,6 +132,24 @@ impl Default for BlobGenRule {
     }
 }
 
+/// How often each kind of statement is generated, relative to the others
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct OperationWeights {
+    pub select: u32,
+    pub update: u32,
+    pub insert: u32,
+}
+
+impl Default for OperationWeights {
+    fn default() -> Self {
+        Self {
+            select: 1,
+            update: 1,
+            insert: 1,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GenerationStrategy {
     text: Option<TextGenRule>,
@@ -128,6 +164,28 @@ pub struct TableConfig {
     columns: HashMap<String, GenRule>,
 }
 
+/// Which kinds of transactions are generated
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionMode {
+    /// only single statements, without BEGIN and COMMIT around them
+    Single,
+    /// only statements wrapped in BEGIN and COMMIT
+    Multi,
+    /// both, with multi-statement transactions as often as the multi probability says
+    #[default]
+    Mixed,
+}
+
+/// How likely a generated transaction is to have multiple statements in mixed mode unless configured
+/// otherwise
+pub const DEFAULT_MULTI_PROBABILITY: f64 = 0.65;
+
+/// The fewest rows an insert adds unless configured otherwise
+pub const DEFAULT_MIN_ROWS: usize = 1;
+/// The most rows an insert adds unless configured otherwise
+pub const DEFAULT_MAX_ROWS: usize = 5;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub global: GenerationStrategy,
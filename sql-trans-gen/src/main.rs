@@ -7,8 +7,10 @@ use clap::Parser;
 use log::LevelFilter;
 use rusqlite::{Connection, OpenFlags};
 use crate::args::Args;
+use crate::config::TextGenRule;
 use crate::db_schema::DatabaseSchema;
 use crate::query_gen::QueryGenerator;
+use crate::query_gen::random_query_stmt::RandomQueryStmtKindGen;
 use crate::value_generator::ValueGeneratorMap;
 
 mod args;
@@ -27,6 +29,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     // parse arguments
     let args = Args::parse();
+    let kind_gen = RandomQueryStmtKindGen::new(&args.operation_weights())?;
 
     let connection = Connection::open_with_flags(args.db_path, OpenFlags::empty() | OpenFlags::SQLITE_OPEN_READ_ONLY)?;
 
@@ -37,7 +40,12 @@ fn main() -> Result<(), Box<dyn Error>> {
         schema
     };
 
-    let query_gen = QueryGenerator::new(db_schema, ValueGeneratorMap::default());
+    let text_rule = TextGenRule { charset: args.text_charset, ..TextGenRule::default() };
+    let query_gen = QueryGenerator::new(db_schema, ValueGeneratorMap::with_text_rule(text_rule), kind_gen)
+        .with_join_probability(args.join_probability)?
+        .with_insert_rows(args.min_rows, args.max_rows)?
+        .with_transaction_mode(args.transaction_mode)
+        .with_multi_probability(args.multi_probability)?;
 
     let transactions = query_gen.gen_transactions(args.count.unwrap_or(10) as usize);
     let mut txn_buffer = String::new();
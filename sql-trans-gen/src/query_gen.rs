@@ -2,15 +2,17 @@ pub mod random_query_stmt;
 mod query_specs;
 
 use std::collections::{HashMap};
-use std::ops::Range;
+use std::ops::RangeInclusive;
 use rand::{Rng, thread_rng};
-use rand::distributions::{Bernoulli, Distribution};
+use rand::distributions::{Bernoulli, BernoulliError, Distribution};
 use rand::seq::{IteratorRandom};
 use rusqlite::types::{Value};
+use sddms_shared::error::SddmsError;
+use crate::config::{TransactionMode, DEFAULT_MAX_ROWS, DEFAULT_MIN_ROWS, DEFAULT_MULTI_PROBABILITY};
 use crate::db_schema::{DatabaseSchema, TableInfo};
 use crate::db_schema::field_info::{FieldInfo, ForeignKey};
 use crate::query_gen::query_specs::{GeneratedTransaction, RandomQuerySpec, RandomTransactionSpec};
-use crate::query_gen::random_query_stmt::{RandomQueryStmt, RandomQueryStmtKind, RandomQueryStmtKindGen};
+use crate::query_gen::random_query_stmt::{RandomQueryStmt, RandomQueryStmtKind, RandomQueryStmtKindGen, SelectJoin};
 use crate::value_generator::{TableRecordGenerator, ValueGeneratorMap};
 
 fn sample_columns_pred<'table, RngT: Rng, PredT: Fn(&FieldInfo) -> bool>(rng: &mut RngT, table_spec: &'table TableInfo, pred: PredT) -> HashMap<&'table String, &'table FieldInfo> {
@@ -37,10 +39,18 @@ fn sample_columns<'table, RngT: Rng>(rng: &mut RngT, table_spec: &'table TableIn
 pub struct QueryGenerator {
     db_schema: DatabaseSchema,
     table_gens: HashMap<String, TableRecordGenerator>,
+    kind_gen: RandomQueryStmtKindGen,
+    /// how likely a select is to join in a table that it references
+    join_dist: Bernoulli,
+    /// how many rows an insert adds
+    insert_rows: RangeInclusive<usize>,
+    transaction_mode: TransactionMode,
+    /// how likely a transaction is to have multiple statements in mixed mode
+    multi_dist: Bernoulli,
 }
 
 impl QueryGenerator {
-    pub fn new(db_schema: DatabaseSchema, value_gen: ValueGeneratorMap) -> Self {
+    pub fn new(db_schema: DatabaseSchema, value_gen: ValueGeneratorMap, kind_gen: RandomQueryStmtKindGen) -> Self {
         let mut table_gens: HashMap<String, TableRecordGenerator> = HashMap::new();
 
         for (table_name, table_info) in db_schema.tables() {
@@ -51,10 +61,74 @@ impl QueryGenerator {
         Self {
             db_schema,
             table_gens,
+            kind_gen,
+            join_dist: Bernoulli::new(0f64).unwrap(),
+            insert_rows: DEFAULT_MIN_ROWS..=DEFAULT_MAX_ROWS,
+            transaction_mode: TransactionMode::default(),
+            multi_dist: Bernoulli::new(DEFAULT_MULTI_PROBABILITY).unwrap(),
         }
     }
 
-    fn gen_random_records_from_columns(&self, columns: &[String], table_gen: &TableRecordGenerator, foreign_keys: &HashMap<String, ForeignKey>, count_range: Range<usize>) -> Vec<HashMap<String, Value>> {
+    /// Has selects join in a table their table references with the given probability. Fails if the
+    /// probability isn't between 0 and 1
+    pub fn with_join_probability(mut self, probability: f64) -> Result<Self, BernoulliError> {
+        self.join_dist = Bernoulli::new(probability)?;
+        Ok(self)
+    }
+
+    /// Only generates the given kinds of transactions
+    pub fn with_transaction_mode(mut self, transaction_mode: TransactionMode) -> Self {
+        self.transaction_mode = transaction_mode;
+        self
+    }
+
+    /// Has transactions in mixed mode be multi-statement ones with the given probability. Fails if the
+    /// probability isn't between 0 and 1
+    pub fn with_multi_probability(mut self, probability: f64) -> Result<Self, BernoulliError> {
+        self.multi_dist = Bernoulli::new(probability)?;
+        Ok(self)
+    }
+
+    /// Has inserts add between `min_rows` and `max_rows` rows, inclusive. Fails unless there's at least
+    /// one row and the minimum is no more than the maximum
+    pub fn with_insert_rows(mut self, min_rows: usize, max_rows: usize) -> Result<Self, SddmsError> {
+        if min_rows == 0 {
+            return Err(SddmsError::general("Inserts have to add at least one row"));
+        }
+
+        if min_rows > max_rows {
+            return Err(SddmsError::general(format!("The minimum number of rows ({}) is more than the maximum ({})", min_rows, max_rows)));
+        }
+
+        self.insert_rows = min_rows..=max_rows;
+        Ok(self)
+    }
+
+    /// Picks one of the table's foreign keys to join the table it references through, along with the
+    /// columns to select from that table. There's nothing to join if the table has no foreign keys
+    fn choose_join<RngT: Rng>(&self, rng: &mut RngT, table_spec: &TableInfo) -> Option<SelectJoin> {
+        let (column, foreign_key) = table_spec.fields().iter()
+            .filter_map(|(column, field_info)| field_info.foreign_key().as_ref().map(|foreign_key| (column, foreign_key)))
+            .choose(rng)?;
+        let joined_spec = self.db_schema.tables().get(foreign_key.table())?;
+
+        let mut columns = sample_columns(rng, joined_spec)
+            .keys()
+            .cloned()
+            .cloned()
+            .collect::<Vec<_>>();
+        if columns.is_empty() {
+            columns.push(foreign_key.field().to_string());
+        }
+
+        Some(SelectJoin {
+            column: column.clone(),
+            foreign_key: foreign_key.clone(),
+            columns,
+        })
+    }
+
+    fn gen_random_records_from_columns(&self, columns: &[String], table_gen: &TableRecordGenerator, foreign_keys: &HashMap<String, ForeignKey>, count_range: RangeInclusive<usize>) -> Vec<HashMap<String, Value>> {
         let mut rng = thread_rng();
         let record_count = rng.gen_range(count_range);
         let mut records: Vec<HashMap<String, Value>> = Vec::with_capacity(record_count);
@@ -71,8 +145,7 @@ impl QueryGenerator {
     fn generate_query_spec(&self) -> RandomQuerySpec {
         let mut rng = thread_rng();
 
-        let kind_gen = RandomQueryStmtKindGen;
-        let operation_kind = kind_gen.sample(&mut rng);
+        let operation_kind = self.kind_gen.sample(&mut rng);
 
         // randomly choose a table
         let (table_name, table_spec) = self.db_schema.choose_table(&mut rng, Some(operation_kind.clone()));
@@ -88,7 +161,13 @@ impl QueryGenerator {
                     .cloned()
                     .collect::<Vec<_>>();
 
-                RandomQueryStmt::Select { columns }
+                let join = if rng.sample(self.join_dist) {
+                    self.choose_join(&mut rng, table_spec)
+                } else {
+                    None
+                };
+
+                RandomQueryStmt::Select { columns, join }
             }
             RandomQueryStmtKind::Update => {
                 let values = sample_columns_pred(&mut rng, table_spec, |field_info| !(field_info.generated() || field_info.auto_inc() || field_info.foreign_key().is_some())).into_iter()
@@ -124,7 +203,7 @@ impl QueryGenerator {
                     .map(|(col_name, info)| (col_name.clone(), info.foreign_key().clone().unwrap()))
                     .collect::<HashMap<_, _>>();
 
-                let records = self.gen_random_records_from_columns(&columns, table_gen, &foreign_keys, 1..6);
+                let records = self.gen_random_records_from_columns(&columns, table_gen, &foreign_keys, self.insert_rows.clone());
 
                 RandomQueryStmt::Insert { columns, values: records, foreign_keys }
             }
@@ -138,7 +217,11 @@ impl QueryGenerator {
 
     fn gen_transaction(&self) -> RandomTransactionSpec {
         let mut rng = thread_rng();
-        let is_multi = rng.sample(Bernoulli::new(0.65f64).unwrap());
+        let is_multi = match self.transaction_mode {
+            TransactionMode::Single => false,
+            TransactionMode::Multi => true,
+            TransactionMode::Mixed => rng.sample(self.multi_dist),
+        };
         let stmt_count = if is_multi {
             rng.gen_range(1..5)
         } else {
@@ -171,3 +254,87 @@ impl QueryGenerator {
         txns
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use crate::config::{OperationWeights, TransactionMode};
+    use crate::db_schema::DatabaseSchema;
+    use crate::query_gen::query_specs::{GeneratedTransaction, SqlQuery};
+    use crate::query_gen::QueryGenerator;
+    use crate::query_gen::random_query_stmt::{RandomQueryStmt, RandomQueryStmtKindGen};
+    use crate::value_generator::ValueGeneratorMap;
+
+    /// A generator that only makes inserts into students
+    fn insert_generator(connection: &Connection) -> QueryGenerator {
+        let mut schema = DatabaseSchema::new(connection);
+        schema.add_insert_restricted("classes");
+        let kind_gen = RandomQueryStmtKindGen::new(&OperationWeights { select: 0, update: 0, insert: 1 }).unwrap();
+        QueryGenerator::new(schema, ValueGeneratorMap::default(), kind_gen)
+    }
+
+    #[test]
+    fn inserts_add_at_least_the_minimum_rows() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("
+            CREATE TABLE classes (id INTEGER PRIMARY KEY);
+            CREATE TABLE students (id INTEGER PRIMARY KEY, age INTEGER, class_id INTEGER REFERENCES classes(id));
+            INSERT INTO classes VALUES (1);
+        ").unwrap();
+
+        let generator = insert_generator(&connection).with_insert_rows(20, 25).unwrap();
+        let spec = generator.generate_query_spec();
+        let RandomQueryStmt::Insert { values, .. } = &spec.stmt else {
+            panic!("expected an insert");
+        };
+        assert!((20..=25).contains(&values.len()), "{}", values.len());
+
+        let inserted = connection.execute(&SqlQuery::from(spec).to_string(), []).unwrap();
+        assert!(inserted >= 20, "{}", inserted);
+    }
+
+    #[test]
+    fn row_range_is_validated() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("CREATE TABLE classes (id INTEGER PRIMARY KEY);").unwrap();
+
+        assert!(insert_generator(&connection).with_insert_rows(5, 4).is_err());
+        assert!(insert_generator(&connection).with_insert_rows(0, 4).is_err());
+        assert!(insert_generator(&connection).with_insert_rows(4, 4).is_ok());
+    }
+
+    /// A generator that only makes selects from classes
+    fn select_generator(connection: &Connection) -> QueryGenerator {
+        connection.execute_batch("CREATE TABLE classes (id INTEGER PRIMARY KEY, title TEXT);").unwrap();
+        let kind_gen = RandomQueryStmtKindGen::new(&OperationWeights { select: 1, update: 0, insert: 0 }).unwrap();
+        QueryGenerator::new(DatabaseSchema::new(connection), ValueGeneratorMap::default(), kind_gen)
+    }
+
+    #[test]
+    fn single_mode_never_wraps_statements() {
+        let connection = Connection::open_in_memory().unwrap();
+        let generator = select_generator(&connection).with_transaction_mode(TransactionMode::Single);
+
+        for transaction in generator.gen_transactions(20) {
+            let sql = transaction.to_string();
+            assert!(matches!(transaction, GeneratedTransaction::Single(_)), "{}", sql);
+            assert!(!sql.contains("BEGIN") && !sql.contains("COMMIT"), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn multi_mode_always_wraps_statements() {
+        let connection = Connection::open_in_memory().unwrap();
+        // the probability only matters in mixed mode
+        let generator = select_generator(&connection)
+            .with_transaction_mode(TransactionMode::Multi)
+            .with_multi_probability(0.0)
+            .unwrap();
+
+        for transaction in generator.gen_transactions(20) {
+            let sql = transaction.to_string();
+            assert!(matches!(transaction, GeneratedTransaction::Transaction(_)), "{}", sql);
+            assert!(sql.contains("COMMIT"), "{}", sql);
+        }
+    }
+}
@@ -6,6 +6,9 @@ use sql_query_builder::Transaction;
 use rusqlite::types::Value;
 use crate::db_schema::field_info::ForeignKey;
 
+/// what a joined table is called in a select. It's aliased so that a table referencing itself can be joined
+const JOINED_ALIAS: &str = "joined";
+
 pub struct RandomQuerySpec {
     /// The table we are operating on
     pub(super) table_name: String,
@@ -16,7 +19,7 @@ pub struct RandomQuerySpec {
 impl RandomQuerySpec {
     pub fn is_empty(&self) -> bool {
         match &self.stmt {
-            RandomQueryStmt::Select { columns } => columns.is_empty(),
+            RandomQueryStmt::Select { columns, .. } => columns.is_empty(),
             RandomQueryStmt::Update { updates, .. } => updates.is_empty(),
             RandomQueryStmt::Insert { values, columns, foreign_keys } => columns.is_empty() || values.is_empty() || foreign_keys.is_empty()
         }
@@ -49,7 +52,7 @@ fn stringify_value(value: Value) -> String {
         Value::Null => "null".to_string(),
         Value::Integer(iv) => iv.to_string(),
         Value::Real(real) => real.to_string(),
-        Value::Text(string) => format!("'{}'", string),
+        Value::Text(string) => format!("'{}'", string.replace('\'', "''")),
         Value::Blob(blob) => String::from_utf8(blob).unwrap(),
     }
 }
@@ -87,6 +90,8 @@ fn create_foreign_keys_with_clauses(record_count: usize, foreign_keys: &HashMap<
         let foreign_table = foreign_key.table();
 
         let set_name = format!("{}_set", column_name);
+        // the key sets are cross joined with the values, so taking more than one key would multiply the
+        // inserted rows instead of giving each row its own key. The row count is set by the values alone
         let query = format!("{} AS (SELECT {} as {} FROM {} ORDER BY RANDOM() LIMIT {})", set_name, foreign_field, column_name, foreign_table, 1 /* was record_count */);
         columns.insert(column_name.clone(), (set_name, query));
     }
@@ -127,7 +132,7 @@ impl From<RandomQuerySpec> for SqlQuery {
         let stmt = value.stmt;
 
         match stmt {
-            RandomQueryStmt::Select { columns } => {
+            RandomQueryStmt::Select { columns, join: None } => {
                 let mut select_builder = sqlb::Select::new();
                 for column in &columns {
                     select_builder = select_builder.select(column);
@@ -136,6 +141,21 @@ impl From<RandomQuerySpec> for SqlQuery {
                 select_builder = select_builder.from(&name);
                 SqlQuery::Select(select_builder)
             }
+            RandomQueryStmt::Select { columns, join: Some(join) } => {
+                // columns are qualified, since both tables can have columns by the same name
+                let mut select_builder = sqlb::Select::new();
+                for column in &columns {
+                    select_builder = select_builder.select(&format!("{}.{}", name, column));
+                }
+                for column in &join.columns {
+                    select_builder = select_builder.select(&format!("{}.{}", JOINED_ALIAS, column));
+                }
+
+                let join_clause = format!("{} AS {} ON {}.{} = {}.{}", join.foreign_key.table(), JOINED_ALIAS, name, join.column, JOINED_ALIAS, join.foreign_key.field());
+                select_builder = select_builder.from(&name);
+                select_builder = select_builder.inner_join(&join_clause);
+                SqlQuery::Select(select_builder)
+            }
             RandomQueryStmt::Update { updates, predicate } => {
                 let sets = updates.into_iter()
                     .map(|(field, value)| stringify_update_set(field, value))
@@ -226,3 +246,68 @@ impl Display for GeneratedTransaction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use rusqlite::Connection;
+    use rusqlite::types::Value;
+    use sddms_shared::sql_metadata::parse_statements;
+    use crate::db_schema::field_info::ForeignKey;
+    use crate::query_gen::query_specs::{RandomQuerySpec, SqlQuery};
+    use crate::query_gen::random_query_stmt::{RandomQueryStmt, SelectJoin};
+
+    #[test]
+    fn quotes_in_text_are_escaped() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("
+            CREATE TABLE classes (id INTEGER PRIMARY KEY);
+            CREATE TABLE students (name TEXT, class_id INTEGER REFERENCES classes(id));
+            INSERT INTO classes VALUES (1);
+        ").unwrap();
+
+        let spec = RandomQuerySpec {
+            table_name: String::from("students"),
+            stmt: RandomQueryStmt::Insert {
+                columns: vec![String::from("name"), String::from("class_id")],
+                values: vec![HashMap::from([(String::from("name"), Value::Text(String::from("O'Brien, \"Pat\"")))])],
+                foreign_keys: HashMap::from([(String::from("class_id"), ForeignKey::new(String::from("classes"), String::from("id")))]),
+            },
+        };
+
+        connection.execute(&SqlQuery::from(spec).to_string(), []).unwrap();
+        let name: String = connection.query_row("SELECT name FROM students", [], |row| row.get(0)).unwrap();
+        assert_eq!(name, "O'Brien, \"Pat\"");
+    }
+
+    #[test]
+    fn joined_select_reads_both_tables() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch("
+            CREATE TABLE classes (id INTEGER PRIMARY KEY, title TEXT);
+            CREATE TABLE students (id INTEGER PRIMARY KEY, name TEXT, class_id INTEGER REFERENCES classes(id));
+            INSERT INTO classes VALUES (1, 'databases');
+            INSERT INTO students VALUES (1, 'pat', 1);
+        ").unwrap();
+
+        let spec = RandomQuerySpec {
+            table_name: String::from("students"),
+            stmt: RandomQueryStmt::Select {
+                columns: vec![String::from("name")],
+                join: Some(SelectJoin {
+                    column: String::from("class_id"),
+                    foreign_key: ForeignKey::new(String::from("classes"), String::from("id")),
+                    columns: vec![String::from("title")],
+                }),
+            },
+        };
+
+        let sql = format!("{};", SqlQuery::from(spec));
+        let (name, title): (String, String) = connection.query_row(&sql, [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((name.as_str(), title.as_str()), ("pat", "databases"));
+
+        let metadata = parse_statements(&sql).unwrap();
+        let read_tables = metadata[0].read_tables();
+        assert!(read_tables.contains("students") && read_tables.contains("classes"), "{:?}", read_tables);
+    }
+}
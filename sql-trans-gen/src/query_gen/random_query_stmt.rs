@@ -1,7 +1,8 @@
 use std::collections::HashMap;
-use rand::distributions::Distribution;
+use rand::distributions::{Distribution, WeightedError, WeightedIndex};
 use rand::Rng;
 use rusqlite::types::Value;
+use crate::config::OperationWeights;
 use crate::db_schema::field_info::ForeignKey;
 
 #[derive(Clone)]
@@ -11,11 +12,24 @@ pub enum RandomQueryStmtKind {
     Insert
 }
 
-pub struct RandomQueryStmtKindGen;
+/// Picks the kind of statement to generate, each as often as its weight says
+pub struct RandomQueryStmtKindGen {
+    index: WeightedIndex<u32>,
+}
+
+impl RandomQueryStmtKindGen {
+    /// Fails if every weight is zero
+    pub fn new(weights: &OperationWeights) -> Result<Self, WeightedError> {
+        let index = WeightedIndex::new([weights.select, weights.update, weights.insert])?;
+        Ok(Self {
+            index
+        })
+    }
+}
 
 impl Distribution<RandomQueryStmtKind> for RandomQueryStmtKindGen {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> RandomQueryStmtKind {
-        match rng.gen_range(0..3) {
+        match self.index.sample(rng) {
             0 => RandomQueryStmtKind::Select,
             1 => RandomQueryStmtKind::Update,
             2 => RandomQueryStmtKind::Insert,
@@ -24,10 +38,22 @@ impl Distribution<RandomQueryStmtKind> for RandomQueryStmtKindGen {
     }
 }
 
+/// Another table joined into a select through one of the selected table's foreign keys
+pub struct SelectJoin {
+    /// the column on the selected table that references the joined table
+    pub column: String,
+    /// the table and field that the column references
+    pub foreign_key: ForeignKey,
+    /// the columns we want to select from the joined table
+    pub columns: Vec<String>,
+}
+
 pub enum RandomQueryStmt {
     Select {
         /// the columns we want to select
         columns: Vec<String>,
+        /// a table to join in, if any
+        join: Option<SelectJoin>,
     },
     Update {
         /// the map of updates, where each key is a column name and the value is the updated value
@@ -44,3 +70,28 @@ pub enum RandomQueryStmt {
         foreign_keys: HashMap<String, ForeignKey>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::distributions::Distribution;
+    use rand::thread_rng;
+    use crate::config::OperationWeights;
+    use crate::query_gen::random_query_stmt::{RandomQueryStmtKind, RandomQueryStmtKindGen};
+
+    #[test]
+    fn weights_bias_the_operation_kind() {
+        let kind_gen = RandomQueryStmtKindGen::new(&OperationWeights { select: 98, update: 1, insert: 1 }).unwrap();
+        let mut rng = thread_rng();
+        let selects = (0..10_000)
+            .map(|_| kind_gen.sample(&mut rng))
+            .filter(|kind| matches!(kind, RandomQueryStmtKind::Select))
+            .count();
+
+        assert!(selects > 9_000, "only {} of 10000 statements were selects", selects);
+    }
+
+    #[test]
+    fn all_zero_weights_are_rejected() {
+        assert!(RandomQueryStmtKindGen::new(&OperationWeights { select: 0, update: 0, insert: 0 }).is_err());
+    }
+}
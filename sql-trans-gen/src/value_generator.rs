@@ -22,15 +22,19 @@ pub struct ValueGeneratorMap {
 
 impl Default for ValueGeneratorMap {
     fn default() -> Self {
+        Self::with_text_rule(TextGenRule::default())
+    }
+}
+
+impl ValueGeneratorMap {
+    pub fn with_text_rule(text_rule: TextGenRule) -> Self {
         Self {
-            text: TextValueGenerator::new_random(TextGenRule::default()),
+            text: TextValueGenerator::new_random(text_rule),
             real: FloatGenerator::new(0f64..=100f64),
             integer: IntegerGenerator::new(0..=100),
         }
     }
-}
 
-impl ValueGeneratorMap {
     pub fn generate(&self, tp: &Type) -> Value {
         match tp {
             Type::Null => Value::Null,
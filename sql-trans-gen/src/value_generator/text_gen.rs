@@ -5,13 +5,13 @@ use rand::distributions::Alphanumeric;
 use rand_regex::{Error, Regex};
 use rusqlite::types::Value;
 use sddms_shared::error::SddmsError;
-use crate::config::TextGenRule;
+use crate::config::{TextCharset, TextGenRule};
 use crate::value_generator::ValueGenerator;
 
 #[derive(Clone)]
 pub struct TextValueGenerator
 {
-    pattern: Alphanumeric,
+    charset: TextCharset,
     length_range: Range<usize>,
 }
 
@@ -53,10 +53,22 @@ impl TextValueGenerator
 
     pub fn new_random(config: TextGenRule) -> Self {
         Self {
-            pattern: Alphanumeric,
+            charset: config.charset,
             length_range: config.min_len..config.max_len
         }
     }
+
+    fn random_char<R: Rng>(&self, rng: &mut R) -> char {
+        match self.charset {
+            TextCharset::Alphanumeric => rng.sample(Alphanumeric) as char,
+            TextCharset::AsciiPrintable => loop {
+                let next = rng.gen_range(' '..='~');
+                if next != ';' {
+                    break next;
+                }
+            },
+        }
+    }
 }
 
 impl ValueGenerator for TextValueGenerator {
@@ -64,8 +76,31 @@ impl ValueGenerator for TextValueGenerator {
         let mut rng = thread_rng();
         let len = rng.gen_range(self.length_range.clone());
         let random_string: String = (0..len)
-            .map(|_| rng.sample(self.pattern) as char)
+            .map(|_| self.random_char(&mut rng))
             .collect();
         Ok(Value::Text(random_string))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+    use crate::config::{TextCharset, TextGenRule};
+    use crate::value_generator::text_gen::TextValueGenerator;
+    use crate::value_generator::ValueGenerator;
+
+    #[test]
+    fn ascii_printable_generates_punctuation() {
+        let generator = TextValueGenerator::new_random(TextGenRule { charset: TextCharset::AsciiPrintable, ..TextGenRule::default() });
+
+        let generated = (0..200)
+            .map(|_| match generator.generate().unwrap() {
+                Value::Text(text) => text,
+                other => panic!("expected text, got {:?}", other),
+            })
+            .collect::<String>();
+
+        assert!(generated.chars().all(|c| (' '..='~').contains(&c) && c != ';'));
+        assert!(generated.chars().any(|c| !c.is_ascii_alphanumeric()));
+    }
+}